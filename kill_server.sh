@@ -1,3 +1,4 @@
 #!/bin/bash
 
-killall lock_server;
+# the launcher stops its nodes, killing them directly would only restart them
+killall -TERM lock_launcher;
@@ -1,9 +1,10 @@
 use std::collections::HashMap;
 use messages::*;
+use status::*;
 
 pub struct Acceptor<CmdT> {
     ballot: Ballot,
-    accepted: HashMap<u64, (Ballot, CmdT)>,
+    accepted: HashMap<u64, (Ballot, CmdT, Option<RequestKey>)>,
     server_id: ServerID,
 }
 
@@ -17,37 +18,82 @@ impl<CmdT> Acceptor<CmdT> where
         }
     }
 
+    // the highest ballot this acceptor promised
+    pub fn ballot(&self) -> &Ballot {
+        &self.ballot
+    }
+
+    pub fn status(&self) -> AcceptorStatus {
+        let min = self.accepted.keys().min().cloned();
+        let max = self.accepted.keys().max().cloned();
+        AcceptorStatus {
+            id: self.server_id,
+            ballot: self.ballot.clone(),
+            accepted: self.accepted.len(),
+            slots: min.and_then(|min| max.map(|max| (min, max))),
+        }
+    }
+
     pub fn remove_before(&mut self, slot: u64) {
         // remove all accepeted values in range [0, slot)
-        self.accepted = std::mem::replace(&mut self.accepted, HashMap::new()).into_iter().filter(|(k, _v)| {
+        self.accepted = std::mem::take(&mut self.accepted).into_iter().filter(|(k, _v)| {
             *k < slot
         }).collect();
     }
 
-    pub fn handle_msg<ResultT>(&mut self, msg: &Message<CmdT, ResultT>) -> Vec<(ServerID, Message<CmdT, ResultT>)> where
+    pub fn handle_msg<ResultT>(&mut self, msg: &Message<CmdT, ResultT>) -> ToServers<CmdT, ResultT> where
         ResultT: std::fmt::Debug {
-        let mut ret: Vec<(ServerID, Message<CmdT, ResultT>)> = Vec::new();
+        let mut ret: ToServers<CmdT, ResultT> = Vec::new();
         match msg {
             Message::P1a { sender, ballot } => {
-                println!("{} got p1a: {:?}", std::process::id(), msg);
+                log_debug!("acceptor", { id = self.server_id, ballot = ballot }, "got p1a");
                 if *ballot > self.ballot {
                     self.ballot = ballot.clone();
                 }
-                let proposals = self.accepted.iter().map(|(k, (b, c))| {
+                let proposals = self.accepted.iter().map(|(k, (b, c, _))| {
                     (*k, b.clone(), c.clone())
                 }).collect();
+                let reqs = self.accepted.iter().filter_map(|(k, (_, _, r))| {
+                    r.as_ref().map(|r| (*k, r.clone()))
+                }).collect();
                 ret.push((*sender, Message::P1b { sender: self.server_id, ballot: self.ballot.clone(), 
-                                                  proposals: proposals }))
+                                                  proposals, reqs }))
             },
-            Message::P2a { sender, ballot, slot, cmd } => {
+            Message::P2a { sender, ballot, slot, cmd, req } => {
                 if *ballot == self.ballot {
-                    self.accepted.insert(*slot, (self.ballot.clone(), cmd.clone()));
+                    self.accepted.insert(*slot, (self.ballot.clone(), cmd.clone(), req.clone()));
                 } 
                 ret.push((*sender, Message::P2b { sender: self.server_id, ballot: self.ballot.clone(), 
                                                   slot: *slot }));
             },
+            Message::Heartbeat { sender, ballot } => {
+                log_trace!("acceptor", { id = self.server_id, ballot = ballot }, "got heartbeat");
+                ret.push((*sender, Message::HeartbeatAck { sender: self.server_id, ballot: self.ballot.clone() }));
+            },
             _ => (),
         };
         ret
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Msg = Message<u64, u64>;
+
+    #[test]
+    fn heartbeats_are_answered_with_the_promised_ballot_alone() {
+        let mut acceptor = Acceptor::new(1);
+        let promised = Ballot::zero(0).next().unwrap();
+        acceptor.handle_msg(&Msg::P1a { sender: 0, ballot: promised.clone() });
+        acceptor.handle_msg(&Msg::P2a { sender: 0, ballot: promised.clone(), slot: 1, cmd: 7, req: None });
+
+        let out = acceptor.handle_msg(&Msg::Heartbeat { sender: 0, ballot: Ballot::zero(0) });
+        match &out[..] {
+            [(0, Message::HeartbeatAck { sender: 1, ballot })] => assert_eq!(*ballot, promised),
+            _ => panic!("unexpected {:?}", out),
+        }
+        assert_eq!(acceptor.ballot(), &promised);
+    }
+}
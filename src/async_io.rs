@@ -0,0 +1,538 @@
+// Future-based adapters over the transports, for use under any executor. The
+// adapters never block waiting for input: a future that has nothing to read
+// hands its transport's fds and its next timeout to the reactor, a single
+// thread per process that polls the fds of every waiting future and wakes the
+// ones whose fds turned readable or whose timeout passed. Sends are made by the
+// polling task itself, so they take as long as the transport's connect and
+// write, which for the datagram transports does not involve waiting.
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::{ Arc, Mutex, MutexGuard };
+use std::thread::Builder;
+use std::time::{ Duration, Instant };
+use futures::future;
+use futures::task::{ Context, Poll, Waker };
+use futures::{ Future, Stream };
+use libc::c_int;
+use rand::{ thread_rng, Rng };
+use messaging::*;
+use messages::*;
+use error::*;
+use statemachine::*;
+use node::{ failover_pause_ms, first_req_id, MAX_FAILOVER_ROUNDS };
+use threaded::Waker as WakePipe;
+
+// a future waiting for some fds to turn readable or for a point in time
+struct Wait {
+    fds: Vec<c_int>,
+    deadline: Option<Instant>,
+    waker: Waker,
+}
+
+struct Reactor {
+    waits: HashMap<u64, Wait>,
+    next_id: u64,
+    // interrupts the reactor's poll when the waits change
+    pipe: Arc<WakePipe>,
+}
+
+static REACTOR: Mutex<Option<Reactor>> = Mutex::new(None);
+
+fn lock_reactor() -> MutexGuard<'static, Option<Reactor>> {
+    REACTOR.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// starts the reactor thread on first use
+fn with_reactor<R, F: FnOnce(&mut Reactor) -> R>(f: F) -> Result<R, Error> {
+    let mut reactor = lock_reactor();
+    if reactor.is_none() {
+        let pipe = Arc::new(WakePipe::new()?);
+        let thread_pipe = pipe.clone();
+        Builder::new().name("async-reactor".to_string()).spawn(move || run_reactor(thread_pipe))?;
+        *reactor = Some(Reactor { waits: HashMap::new(), next_id: 0, pipe });
+    }
+    Ok(reactor.as_mut().map(f).expect("the reactor was just started"))
+}
+
+// asks for `waker` to be woken once one of `fds` is readable or at `deadline`,
+// replacing the earlier wait `prev`
+fn wait_for(prev: Option<u64>, fds: Vec<c_int>, deadline: Option<Instant>, waker: &Waker) -> Result<u64, Error> {
+    with_reactor(|r| {
+        if let Some(id) = prev {
+            r.waits.remove(&id);
+        }
+        let id = r.next_id;
+        r.next_id += 1;
+        r.waits.insert(id, Wait { fds, deadline, waker: waker.clone() });
+        r.pipe.wake();
+        id
+    })
+}
+
+fn cancel_wait(id: Option<u64>) {
+    if let (Some(id), Some(r)) = (id, lock_reactor().as_mut()) {
+        r.waits.remove(&id);
+    }
+}
+
+fn run_reactor(pipe: Arc<WakePipe>) {
+    loop {
+        let (mut fds, timeout_ms) = match lock_reactor().as_ref() {
+            Some(r) => {
+                let now = Instant::now();
+                let fds = r.waits.values().flat_map(|w| w.fds.iter().cloned()).collect::<HashSet<_>>();
+                let timeout = r.waits.values().filter_map(|w| w.deadline)
+                    .map(|d| d.saturating_duration_since(now).as_millis() as i64 + 1)
+                    .min();
+                (fds.into_iter().collect::<Vec<_>>(), timeout.unwrap_or(-1))
+            },
+            None => return,
+        };
+        fds.push(pipe.fd());
+        let ready = poll_readable(fds.as_slice(), timeout_ms).unwrap_or_default();
+        pipe.clear();
+        let fired = match lock_reactor().as_mut() {
+            Some(r) => {
+                let now = Instant::now();
+                let ids = r.waits.iter()
+                    .filter(|(_, w)| w.deadline.is_some_and(|d| d <= now) || w.fds.iter().any(|fd| ready.contains(fd)))
+                    .map(|(id, _)| *id)
+                    .collect::<Vec<_>>();
+                ids.iter().filter_map(|id| r.waits.remove(id)).collect::<Vec<_>>()
+            },
+            None => return,
+        };
+        // outside the lock, a waker may poll right away
+        fired.into_iter().for_each(|w| w.waker.wake());
+    }
+}
+
+pub struct AsyncRecver<T, R> {
+    server: R,
+    wait: Option<u64>,
+    msg_type: PhantomData<T>,
+}
+
+// nothing is pinned in place
+impl<T, R> Unpin for AsyncRecver<T, R> {}
+
+impl<T, R> AsyncRecver<T, R> where
+    T: serde::Serialize + serde::de::DeserializeOwned,
+    R: MsgRecver<T> {
+    pub fn bind(addr: &Addr) -> Result<Self, Error> {
+        Ok(AsyncRecver {
+            server: R::bind(addr)?,
+            wait: None,
+            msg_type: PhantomData,
+        })
+    }
+}
+
+impl<T, R> Stream for AsyncRecver<T, R> where
+    T: serde::Serialize + serde::de::DeserializeOwned,
+    R: MsgRecver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        let this = self.get_mut();
+        if let Some(msg) = this.server.try_recv() {
+            return Poll::Ready(Some(msg));
+        }
+        match wait_for(this.wait.take(), this.server.get_io_fds(), None, cx.waker()) {
+            Ok(id) => this.wait = Some(id),
+            Err(e) => {
+                log_error!("async", "cannot wait for messages: {}", e);
+                return Poll::Ready(None);
+            },
+        }
+        // a message that came in before the wait was registered
+        match this.server.try_recv() {
+            Some(msg) => Poll::Ready(Some(msg)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<T, R> Drop for AsyncRecver<T, R> {
+    fn drop(&mut self) {
+        cancel_wait(self.wait.take());
+    }
+}
+
+// keeps one connection per destination, a connection that fails is replaced
+// once before the error is returned
+pub struct AsyncSender<T, S> {
+    conns: Arc<Mutex<HashMap<Addr, S>>>,
+    msg_type: PhantomData<T>,
+}
+
+impl<T, S> Clone for AsyncSender<T, S> {
+    fn clone(&self) -> Self {
+        AsyncSender { conns: self.conns.clone(), msg_type: PhantomData }
+    }
+}
+
+impl<T, S> Default for AsyncSender<T, S> where
+    T: serde::Serialize + serde::de::DeserializeOwned,
+    S: MsgSender<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, S> AsyncSender<T, S> where
+    T: serde::Serialize + serde::de::DeserializeOwned,
+    S: MsgSender<T> {
+    pub fn new() -> Self {
+        AsyncSender {
+            conns: Arc::new(Mutex::new(HashMap::new())),
+            msg_type: PhantomData,
+        }
+    }
+
+    pub fn send(&self, addr: &Addr, msg: T) -> future::Ready<Result<(), Error>> {
+        let mut conns = self.conns.lock().unwrap_or_else(|e| e.into_inner());
+        future::ready(send_cached(&mut conns, addr, &msg))
+    }
+}
+
+fn send_cached<T, S>(conns: &mut HashMap<Addr, S>, addr: &Addr, msg: &T) -> Result<(), Error> where
+    T: serde::Serialize + serde::de::DeserializeOwned,
+    S: MsgSender<T> {
+    let fresh = !conns.contains_key(addr);
+    let ret = send_on(conns, addr, msg);
+    if ret.is_err() && !fresh {
+        return send_on(conns, addr, msg);
+    }
+    ret
+}
+
+fn send_on<T, S>(conns: &mut HashMap<Addr, S>, addr: &Addr, msg: &T) -> Result<(), Error> where
+    T: serde::Serialize + serde::de::DeserializeOwned,
+    S: MsgSender<T> {
+    let mut c = match conns.remove(addr) {
+        Some(c) => c,
+        None => S::connect(addr)?,
+    };
+    c.send(msg).and_then(|_| c.flush())?;
+    conns.insert(addr.clone(), c);
+    Ok(())
+}
+
+// the answer that came in for a command in flight and the task waiting for it
+type InFlight<R> = (Option<Result<R, Error>>, Option<Waker>);
+
+// the state the commands of one client share
+struct ClientState<S: StateMachine, ServerT, ClientT> {
+    server: ServerT,
+    addr: Addr,
+    replicas: Vec<Addr>,
+    conns: HashMap<Addr, ClientT>,
+    next_req_id: ReqID,
+    attempt_timeout_ms: i64,
+    deadline_ms: i64,
+    inflight: HashMap<ReqID, InFlight<S::Result>>,
+}
+
+impl<S, ServerT, ClientT> ClientState<S, ServerT, ClientT> where
+    S: StateMachine,
+    S::Op: serde::Serialize + serde::de::DeserializeOwned,
+    S::Result: serde::Serialize + serde::de::DeserializeOwned,
+    ServerT: MsgRecver<Message<S::Op, S::Result>>,
+    ClientT: MsgSender<Message<S::Op, S::Result>> {
+    // hands every answer that came in to the command it belongs to
+    fn pump(&mut self) {
+        let mut msgs = Vec::new();
+        while let Some(msg) = self.server.try_recv() {
+            msgs.push(msg);
+        }
+        for conn in self.conns.values_mut() {
+            while let Some(msg) = conn.try_recv_reply(0) {
+                msgs.push(msg);
+            }
+        }
+        for msg in msgs {
+            let (req_id, answer) = match msg {
+                Message::Response { cid, result, req_id: Some(id) } if cid == self.addr => (id, Ok(result)),
+                Message::Rejected { cid, reason, req_id: Some(id) } if cid == self.addr =>
+                    (id, Err(ClientError::Rejected(reason).into())),
+                _ => continue,
+            };
+            if let Some((slot, waiter)) = self.inflight.get_mut(&req_id) {
+                *slot = Some(answer);
+                if let Some(w) = waiter.take() {
+                    w.wake();
+                }
+            }
+        }
+    }
+
+    fn fds(&self) -> Vec<c_int> {
+        let mut fds = self.server.get_io_fds();
+        fds.extend(self.conns.values().flat_map(|c| c.reply_fds()));
+        fds
+    }
+}
+
+type SharedState<S, ServerT, ClientT> = Arc<Mutex<ClientState<S, ServerT, ClientT>>>;
+
+// any number of commands may be in flight, answers are paired with their
+// command by req_id. Each command fails over between the replicas like the
+// blocking client does
+pub struct AsyncClient<S: StateMachine, ServerT, ClientT> {
+    state: SharedState<S, ServerT, ClientT>,
+}
+
+impl<S: StateMachine, ServerT, ClientT> Clone for AsyncClient<S, ServerT, ClientT> {
+    fn clone(&self) -> Self {
+        AsyncClient { state: self.state.clone() }
+    }
+}
+
+impl<S, ServerT, ClientT> AsyncClient<S, ServerT, ClientT> where
+    S: StateMachine,
+    S::Op: serde::Serialize + serde::de::DeserializeOwned,
+    S::Result: serde::Serialize + serde::de::DeserializeOwned,
+    ServerT: MsgRecver<Message<S::Op, S::Result>>,
+    ClientT: MsgSender<Message<S::Op, S::Result>> {
+    pub fn new(addr: &Addr, replicas: &HashSet<Addr>,
+               attempt_timeout_ms: i64, deadline_ms: i64) -> Result<Self, Error> {
+        Ok(AsyncClient {
+            state: Arc::new(Mutex::new(ClientState {
+                server: ServerT::bind(addr)?,
+                addr: addr.clone(),
+                replicas: replicas.iter().cloned().collect(),
+                conns: HashMap::new(),
+                next_req_id: first_req_id(),
+                attempt_timeout_ms,
+                deadline_ms,
+                inflight: HashMap::new(),
+            })),
+        })
+    }
+
+    pub fn send_cmd(&self, op: S::Op) -> Command<S, ServerT, ClientT> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let req_id = state.next_req_id;
+        state.next_req_id += 1;
+        state.inflight.insert(req_id, (None, None));
+        let now = Instant::now();
+        Command {
+            state: self.state.clone(),
+            req: Message::Request { cid: state.addr.clone(), cmd: op, req_id: Some(req_id) },
+            req_id,
+            first: if state.replicas.is_empty() { 0 } else { thread_rng().gen_range(0, state.replicas.len()) },
+            attempt: 0,
+            sent: false,
+            attempt_deadline: after_ms(now, state.attempt_timeout_ms),
+            deadline: after_ms(now, state.deadline_ms),
+            pause_until: None,
+            last_err: ClientError::Timeout.into(),
+            wait: None,
+        }
+    }
+}
+
+fn after_ms(now: Instant, ms: i64) -> Option<Instant> {
+    if ms < 0 { None } else { Some(now + Duration::from_millis(ms as u64)) }
+}
+
+fn earliest(a: Option<Instant>, b: Option<Instant>) -> Option<Instant> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(std::cmp::min(a, b)),
+        (a, b) => a.or(b),
+    }
+}
+
+// resolves to the result of one command
+pub struct Command<S: StateMachine, ServerT, ClientT> {
+    state: SharedState<S, ServerT, ClientT>,
+    req: Message<S::Op, S::Result>,
+    req_id: ReqID,
+    first: usize,
+    attempt: usize,
+    // whether the current attempt went out, it is waited for until attempt_deadline
+    sent: bool,
+    attempt_deadline: Option<Instant>,
+    deadline: Option<Instant>,
+    pause_until: Option<Instant>,
+    last_err: Error,
+    wait: Option<u64>,
+}
+
+// nothing is pinned in place
+impl<S: StateMachine, ServerT, ClientT> Unpin for Command<S, ServerT, ClientT> {}
+
+impl<S: StateMachine, ServerT, ClientT> Command<S, ServerT, ClientT> {
+    // moves on to the next replica, pausing after a round that failed early
+    fn fail(&mut self, e: Error, replicas: usize, now: Instant) {
+        log_debug!("client", { attempt = self.attempt }, "attempt failed: {}", e);
+        self.last_err = e;
+        self.sent = false;
+        self.attempt += 1;
+        if self.attempt.is_multiple_of(replicas) && self.last_err != ClientError::Timeout.into() {
+            let pause = failover_pause_ms(self.attempt / replicas - 1);
+            self.pause_until = Some(now + Duration::from_millis(pause));
+        }
+    }
+}
+
+impl<S, ServerT, ClientT> Future for Command<S, ServerT, ClientT> where
+    S: StateMachine,
+    S::Op: serde::Serialize + serde::de::DeserializeOwned,
+    S::Result: serde::Serialize + serde::de::DeserializeOwned,
+    ServerT: MsgRecver<Message<S::Op, S::Result>>,
+    ClientT: MsgSender<Message<S::Op, S::Result>> {
+    type Output = Result<S::Result, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let shared = this.state.clone();
+        let mut state = shared.lock().unwrap_or_else(|e| e.into_inner());
+        let replicas = state.replicas.len();
+        if replicas == 0 {
+            return Poll::Ready(Err(ClientError::NoReplicas.into()));
+        }
+        state.pump();
+        loop {
+            let now = Instant::now();
+            match state.inflight.get_mut(&this.req_id).and_then(|(slot, _)| slot.take()) {
+                Some(Ok(result)) => return Poll::Ready(Ok(result)),
+                Some(Err(e)) => this.fail(e, replicas, now),
+                None => (),
+            }
+            if this.deadline.is_some_and(|d| d <= now) {
+                return Poll::Ready(Err(std::mem::replace(&mut this.last_err, ClientError::Timeout.into())));
+            }
+            if this.sent && this.attempt_deadline.is_some_and(|d| d <= now) {
+                this.fail(ClientError::Timeout.into(), replicas, now);
+            }
+            let paused = this.pause_until.is_some_and(|p| p > now);
+            if !this.sent && !paused {
+                if this.attempt == MAX_FAILOVER_ROUNDS * replicas {
+                    return Poll::Ready(Err(std::mem::replace(&mut this.last_err, ClientError::Timeout.into())));
+                }
+                let replica = state.replicas[(this.first + this.attempt) % replicas].clone();
+                match send_cached(&mut state.conns, &replica, &this.req) {
+                    Ok(()) => {
+                        this.sent = true;
+                        this.attempt_deadline = after_ms(now, state.attempt_timeout_ms);
+                    },
+                    Err(e) => this.fail(e, replicas, now),
+                }
+                continue;
+            }
+            let timer = if this.sent { this.attempt_deadline } else { this.pause_until };
+            match wait_for(this.wait.take(), state.fds(), earliest(timer, this.deadline), cx.waker()) {
+                Ok(id) => this.wait = Some(id),
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+            if let Some((_, waiter)) = state.inflight.get_mut(&this.req_id) {
+                *waiter = Some(cx.waker().clone());
+            }
+            // an answer that came in before the wait was registered
+            state.pump();
+            if state.inflight.get(&this.req_id).is_some_and(|(slot, _)| slot.is_some()) {
+                continue;
+            }
+            return Poll::Pending;
+        }
+    }
+}
+
+impl<S: StateMachine, ServerT, ClientT> Drop for Command<S, ServerT, ClientT> {
+    fn drop(&mut self) {
+        cancel_wait(self.wait.take());
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).inflight.remove(&self.req_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use futures::executor::block_on;
+    use futures::future::join_all;
+    use futures::StreamExt;
+
+    type Msg = Message<String, String>;
+    type Client = AsyncClient<Echo, UdpRecver<Msg>, UdpSender<Msg>>;
+
+    #[derive(Default)]
+    struct Echo;
+
+    impl StateMachine for Echo {
+        type Op = String;
+        type Result = String;
+
+        fn apply_op(&mut self, op: &String) -> String {
+            op.clone()
+        }
+    }
+
+    fn addr(port: u16) -> Addr {
+        Addr::new("127.0.0.1", port)
+    }
+
+    // answers `n` requests once they are all in, the last one first, or rejects them
+    fn fake_replica(port: u16, n: usize, reject: bool) -> thread::JoinHandle<()> {
+        let mut server = UdpRecver::<Msg>::bind(&addr(port)).unwrap();
+        thread::spawn(move || {
+            let mut reqs = Vec::new();
+            while reqs.len() < n {
+                if let Some(Message::Request { cid, cmd, req_id }) = server.try_recv_timeout(5000) {
+                    reqs.push((cid, cmd, req_id));
+                }
+            }
+            for (cid, cmd, req_id) in reqs.into_iter().rev() {
+                let reply = if reject {
+                    Message::Rejected { cid: cid.clone(), reason: "busy".to_string(), req_id }
+                } else {
+                    Message::Response { cid: cid.clone(), result: format!("done {}", cmd), req_id }
+                };
+                UdpSender::<Msg>::connect(&cid).unwrap().send(&reply).unwrap();
+            }
+        })
+    }
+
+    #[test]
+    fn the_recver_wakes_when_a_message_arrives() {
+        let mut recver = AsyncRecver::<Msg, UdpRecver<Msg>>::bind(&addr(27311)).unwrap();
+        let sender = thread::spawn(|| {
+            thread::sleep(Duration::from_millis(50));
+            let sender = AsyncSender::<Msg, UdpSender<Msg>>::new();
+            block_on(sender.send(&addr(27311), Message::Tick)).unwrap();
+        });
+        assert!(matches!(block_on(recver.next()), Some(Message::Tick)));
+        sender.join().unwrap();
+    }
+
+    #[test]
+    fn commands_in_flight_are_paired_by_req_id() {
+        let replica = fake_replica(27313, 3, false);
+        let client = Client::new(&addr(27312), &vec![addr(27313)].into_iter().collect(), 2000, 5000).unwrap();
+        let cmds = vec!["a", "b", "c"].into_iter().map(|c| client.send_cmd(c.to_string())).collect::<Vec<_>>();
+        let results = block_on(join_all(cmds));
+        assert_eq!(results, vec![Ok("done a".to_string()), Ok("done b".to_string()), Ok("done c".to_string())]);
+        replica.join().unwrap();
+    }
+
+    #[test]
+    fn a_rejected_command_fails_over() {
+        let _busy = fake_replica(27315, 1, true);
+        let _ok = fake_replica(27316, 1, false);
+        let client = Client::new(&addr(27314), &vec![addr(27315), addr(27316)].into_iter().collect(), 2000, 5000).unwrap();
+        // whichever replica it starts at, the answer comes from the one that is not busy
+        assert_eq!(block_on(client.send_cmd("a".to_string())), Ok("done a".to_string()));
+    }
+
+    #[test]
+    fn commands_give_up_at_their_deadline() {
+        let client = Client::new(&addr(27317), &vec![addr(27318)].into_iter().collect(), 50, 300).unwrap();
+        let start = Instant::now();
+        assert_eq!(block_on(client.send_cmd("a".to_string())), Err(ClientError::Timeout.into()));
+        assert!(start.elapsed() < Duration::from_millis(1000));
+    }
+}
@@ -0,0 +1,595 @@
+use std::collections::HashMap;
+use std::sync::{ Arc, Mutex, RwLock };
+use std::time::{ SystemTime, UNIX_EPOCH };
+use libc::c_int;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use messaging::*;
+use messages::*;
+use error::*;
+
+// how far behind the newest counter a late message from the same sender may be
+const REPLAY_WINDOW: u64 = 64;
+// client counters are timestamps in microseconds, older client frames are
+// dropped so that a client whose window was forgotten cannot be replayed
+const CLIENT_MAX_AGE_US: u64 = 300_000_000;
+// clients heard from within CLIENT_MAX_AGE_US that a receiver keeps windows for,
+// frames from further clients are dropped
+const MAX_CLIENT_WINDOWS: usize = 65536;
+
+// signs what clients send, clients have no server id of their own
+pub const CLIENT_SENDER: ServerID = u64::MAX;
+
+#[derive(Clone, Debug)]
+pub struct AuthConfig {
+    // the servers this process hosts, the first one signs messages that do not
+    // name their sender; empty for clients
+    pub local_ids: Vec<ServerID>,
+    // between servers, clients are not given it
+    pub cluster_key: Option<Vec<u8>>,
+    // between a client and a server, only vouches for what clients may send
+    pub client_key: Option<Vec<u8>>,
+    // keyed by (smaller id, larger id), takes precedence over the cluster key
+    pub pair_keys: HashMap<(ServerID, ServerID), Vec<u8>>,
+    // lets senders find the pair key of the server behind an address
+    pub peers: HashMap<Addr, ServerID>,
+    // tells the clients apart, they all sign as CLIENT_SENDER; random per process
+    pub instance: u64,
+}
+
+impl AuthConfig {
+    pub fn key_between(&self, a: ServerID, b: ServerID) -> Option<&Vec<u8>> {
+        if a == CLIENT_SENDER || b == CLIENT_SENDER {
+            return self.client_key.as_ref();
+        }
+        let pair = if a < b { (a, b) } else { (b, a) };
+        self.pair_keys.get(&pair).or(self.cluster_key.as_ref())
+    }
+
+    // who signs a message that names `claimed` as its sender
+    fn signer(&self, claimed: Option<ServerID>) -> ServerID {
+        claimed.filter(|id| self.local_ids.contains(id))
+            .or(self.local_ids.first().cloned())
+            .unwrap_or(CLIENT_SENDER)
+    }
+
+    // the local server behind a bound address
+    fn local_at(&self, addr: &Addr) -> ServerID {
+        self.peers.get(addr).cloned()
+            .filter(|id| self.local_ids.contains(id))
+            .unwrap_or_else(|| self.signer(None))
+    }
+}
+
+// None until init_auth is called, Some(None) while authentication is off
+static AUTH_CFG: RwLock<Option<Option<Arc<AuthConfig>>>> = RwLock::new(None);
+
+// must be called before any AuthRecver/AuthSender is created, they keep the
+// config they were created with; without a config they pass messages through
+pub fn init_auth(config: Option<AuthConfig>) {
+    *AUTH_CFG.write().unwrap_or_else(|e| e.into_inner()) = Some(config.map(Arc::new));
+}
+
+fn get_auth_config() -> Result<Option<Arc<AuthConfig>>, TransportError> {
+    AUTH_CFG.read().unwrap_or_else(|e| e.into_inner()).clone()
+        .ok_or_else(|| TransportError::Setup("message authentication is not initialized".to_string()))
+}
+
+fn now_us() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() * 1_000_000 + d.subsec_micros() as u64)
+        .unwrap_or(0)
+}
+
+// the last counter each local signer sent to each destination, None for
+// destinations that are not cluster servers
+type CounterKey = (ServerID, Option<ServerID>);
+static COUNTERS: Mutex<Option<HashMap<CounterKey, u64>>> = Mutex::new(None);
+
+// goes up by one per message to a destination, so the replay window spans the
+// last REPLAY_WINDOW messages between two nodes; the first counter is the clock
+// in microseconds so that a restarted node does not reuse counters it sent before.
+// Client counters also keep up with the clock, receivers check their age
+fn next_counter(sender: ServerID, dest: Option<ServerID>) -> u64 {
+    let now = now_us();
+    let mut guard = COUNTERS.lock().unwrap_or_else(|e| e.into_inner());
+    let counter = guard.get_or_insert_with(HashMap::new).entry((sender, dest)).or_insert(now);
+    *counter = if sender == CLIENT_SENDER { std::cmp::max(*counter + 1, now) } else { *counter + 1 };
+    *counter
+}
+
+fn compute_mac(key: &[u8], sender: ServerID, instance: u64, counter: u64, payload: &[u8]) -> Option<Vec<u8>> {
+    let pkey = PKey::hmac(key).ok()?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &pkey).ok()?;
+    signer.update(&encode_u64(sender)).ok()?;
+    signer.update(&encode_u64(instance)).ok()?;
+    signer.update(&encode_u64(counter)).ok()?;
+    signer.update(payload).ok()?;
+    signer.sign_to_vec().ok()
+}
+
+#[derive(Serialize, Deserialize)]
+struct SignedEnvelope {
+    sender: ServerID,
+    // the client's AuthConfig::instance, 0 for servers
+    instance: u64,
+    counter: u64,
+    payload: String,
+    mac: String,
+}
+
+// signs `s` as `sender` for `dest`
+fn seal(config: &AuthConfig, sender: ServerID, dest: Option<ServerID>, s: &[u8]) -> Result<Vec<u8>, Error> {
+    let key = config.key_between(sender, dest.unwrap_or(CLIENT_SENDER)).ok_or(TransportError::Unauthenticated)?;
+    let instance = if sender == CLIENT_SENDER { config.instance } else { 0 };
+    let counter = next_counter(sender, dest);
+    let mac = compute_mac(key, sender, instance, counter, s)
+        .ok_or(CodecError::Encode("cannot compute mac".to_string()))?;
+    let envelope = SignedEnvelope {
+        sender,
+        instance,
+        counter,
+        payload: hex::encode(s),
+        mac: hex::encode(mac),
+    };
+    serde_json::to_vec(&envelope).map_err(|e| CodecError::Encode(e.to_string()).into())
+}
+
+// sliding window over the counters seen from one sender
+struct ReplayWindow {
+    highest: u64,
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn new() -> Self {
+        ReplayWindow { highest: 0, seen: 0 }
+    }
+
+    // returns false for duplicates and for counters that fell out of the window
+    fn check_and_update(&mut self, counter: u64) -> bool {
+        if counter > self.highest {
+            let shift = counter - self.highest;
+            self.seen = if shift >= REPLAY_WINDOW { 0 } else { self.seen << shift };
+            self.seen |= 1;
+            self.highest = counter;
+            true
+        } else {
+            let offset = self.highest - counter;
+            if offset >= REPLAY_WINDOW || self.seen & (1 << offset) != 0 {
+                false
+            } else {
+                self.seen |= 1 << offset;
+                true
+            }
+        }
+    }
+}
+
+// the replay windows of one receiver, by sender and client instance
+#[derive(Default)]
+struct ReplayWindows {
+    windows: HashMap<(ServerID, u64), ReplayWindow>,
+    clients: usize,
+}
+
+impl ReplayWindows {
+    // false if the counter was seen before or is too old
+    fn check_and_update(&mut self, sender: ServerID, instance: u64, counter: u64, stats: &mut AuthStats) -> bool {
+        let key = (sender, instance);
+        if sender == CLIENT_SENDER {
+            let oldest = now_us().saturating_sub(CLIENT_MAX_AGE_US);
+            if counter < oldest {
+                stats.stale += 1;
+                return false;
+            }
+            if !self.windows.contains_key(&key) && self.clients >= MAX_CLIENT_WINDOWS {
+                // frames below `oldest` are dropped anyway, their windows can go
+                self.windows.retain(|&(s, _), w| s != CLIENT_SENDER || w.highest >= oldest);
+                self.clients = self.windows.keys().filter(|&&(s, _)| s == CLIENT_SENDER).count();
+                if self.clients >= MAX_CLIENT_WINDOWS {
+                    stats.stale += 1;
+                    return false;
+                }
+            }
+        }
+        let clients = &mut self.clients;
+        let fresh = self.windows.entry(key).or_insert_with(|| {
+            if sender == CLIENT_SENDER {
+                *clients += 1;
+            }
+            ReplayWindow::new()
+        }).check_and_update(counter);
+        if !fresh {
+            stats.replayed += 1;
+        }
+        fresh
+    }
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct AuthStats {
+    pub accepted: u64,
+    pub unauthenticated: u64,
+    pub replayed: u64,
+    // client frames older than CLIENT_MAX_AGE_US, or from a client that found
+    // the window table full
+    pub stale: u64,
+    // authenticated, but the message claims another sender or a client sent
+    // what only servers may
+    pub impersonated: u64,
+}
+
+pub struct AuthRecver<R> {
+    inner: R,
+    // None passes messages through unchecked
+    config: Option<Arc<AuthConfig>>,
+    // the local server messages to this address are for
+    local: ServerID,
+    windows: ReplayWindows,
+    stats: AuthStats,
+    last_sender: Option<ServerID>,
+}
+
+impl<R> AuthRecver<R> {
+    pub fn stats(&self) -> AuthStats {
+        self.stats
+    }
+
+    pub fn get_inner(&self) -> &R {
+        &self.inner
+    }
+}
+
+// checks the mac and the replay window, returns the sender and the payload
+fn open(config: &AuthConfig, local: ServerID, windows: &mut ReplayWindows,
+        stats: &mut AuthStats, buf: &[u8]) -> Option<(ServerID, Vec<u8>)> {
+    let envelope: SignedEnvelope = match serde_json::from_slice(buf) {
+        Ok(e) => e,
+        Err(_) => {
+            stats.unauthenticated += 1;
+            return None;
+        },
+    };
+    let payload = hex::decode(&envelope.payload).ok();
+    let mac = hex::decode(&envelope.mac).ok();
+    let expected = match (config.key_between(envelope.sender, local), payload.as_ref()) {
+        (Some(key), Some(p)) => compute_mac(key, envelope.sender, envelope.instance, envelope.counter, p),
+        _ => None,
+    };
+    let valid = match (mac, expected) {
+        (Some(ref m), Some(ref e)) => m.len() == e.len() && memcmp::eq(m, e),
+        _ => false,
+    };
+    if !valid {
+        stats.unauthenticated += 1;
+        return None;
+    }
+    // only authenticated messages may advance the window
+    if !windows.check_and_update(envelope.sender, envelope.instance, envelope.counter, stats) {
+        return None;
+    }
+    stats.accepted += 1;
+    payload.map(|p| (envelope.sender, p))
+}
+
+impl<T, R> MsgRecver<T> for AuthRecver<R> where
+    T: serde::Serialize + serde::de::DeserializeOwned + Origin,
+    R: MsgRecver<T> {
+    type Ctx = R::Ctx;
+
+    fn bind(addr: &Addr) -> Result<Self, Error> {
+        let config = get_auth_config()?;
+        Ok(AuthRecver {
+            inner: R::bind(addr)?,
+            local: config.as_ref().map_or(CLIENT_SENDER, |c| c.local_at(addr)),
+            config,
+            windows: Default::default(),
+            stats: Default::default(),
+            last_sender: None,
+        })
+    }
+
+    fn try_recv_str(&mut self) -> Option<Vec<u8>> {
+        let config = match self.config {
+            Some(ref c) => c.clone(),
+            None => return self.inner.try_recv_str(),
+        };
+        while let Some(buf) = self.inner.try_recv_str() {
+            if let Some((sender, payload)) = open(&config, self.local, &mut self.windows, &mut self.stats, buf.as_slice()) {
+                self.last_sender = Some(sender);
+                return Some(payload);
+            }
+        }
+        None
+    }
+
+    fn get_io_fds(&self) -> Vec<c_int> {
+        self.inner.get_io_fds()
+    }
+
+    fn pending(&self) -> bool {
+        self.inner.pending()
+    }
+
+    // a key only vouches for its own server, messages naming another sender
+    // are dropped, and the client key only for what clients send
+    fn accepts(&mut self, msg: &T) -> bool {
+        if self.config.is_some() {
+            let sender = self.last_sender;
+            let impersonated = if sender == Some(CLIENT_SENDER) {
+                msg.server_only()
+            } else {
+                msg.claimed_sender().is_some_and(|s| Some(s) != sender)
+            };
+            if impersonated {
+                self.stats.impersonated += 1;
+                return false;
+            }
+        }
+        self.inner.accepts(msg)
+    }
+
+    // the sender id goes in front of the inner route so that replies are sealed
+    // with the key of the peer they go to
+    fn last_route(&self) -> Option<Vec<u8>> {
+        if self.config.is_none() {
+            return self.inner.last_route();
+        }
+        match (self.last_sender, self.inner.last_route()) {
+            (Some(sender), Some(route)) => {
+                let mut r = encode_u64(sender).to_vec();
+                r.extend_from_slice(route.as_slice());
+                Some(r)
+            },
+            _ => None,
+        }
+    }
+
+    fn reply_str(&mut self, route: &[u8], s: &[u8]) -> Result<(), Error> {
+        let config = match self.config {
+            Some(ref c) => c,
+            None => return self.inner.reply_str(route, s),
+        };
+        if route.len() < 8 {
+            return Err(TransportError::Unauthenticated.into());
+        }
+        let peer = decode_u64(&route[..8]);
+        let dest = if peer == CLIENT_SENDER { None } else { Some(peer) };
+        let sealed = seal(config, self.local, dest, s)?;
+        self.inner.reply_str(&route[8..], sealed.as_slice())
+    }
+}
+
+pub struct AuthSender<S> {
+    inner: S,
+    // None passes messages through unsigned
+    config: Option<Arc<AuthConfig>>,
+    // the server behind the address, if it is one
+    peer: Option<ServerID>,
+    windows: ReplayWindows,
+    stats: AuthStats,
+}
+
+impl<S> AuthSender<S> {
+    // counts the replies received over this connection
+    pub fn stats(&self) -> AuthStats {
+        self.stats
+    }
+}
+
+impl<T, S> MsgSender<T> for AuthSender<S> where
+    T: serde::Serialize + serde::de::DeserializeOwned + Origin,
+    S: MsgSender<T> {
+
+    fn connect(addr: &Addr) -> Result<Self, Error> {
+        let config = get_auth_config()?;
+        Ok(AuthSender {
+            inner: S::connect(addr)?,
+            peer: config.as_ref().and_then(|c| c.peers.get(addr).cloned()),
+            config,
+            windows: Default::default(),
+            stats: Default::default(),
+        })
+    }
+
+    fn send(&mut self, msg: &T) -> Result<(), Error> {
+        let s = serde_json::to_vec(msg).map_err(|e| CodecError::Encode(e.to_string()))?;
+        self.send_str_from(msg.claimed_sender(), s.as_slice())
+    }
+
+    fn send_str(&mut self, s: &[u8]) -> Result<(), Error> {
+        self.send_str_from(None, s)
+    }
+
+    // signs as `sender` if this process hosts it
+    fn send_str_from(&mut self, sender: Option<ServerID>, s: &[u8]) -> Result<(), Error> {
+        let sealed = match self.config {
+            Some(ref config) => seal(config, config.signer(sender), self.peer, s)?,
+            None => return self.inner.send_str_from(sender, s),
+        };
+        self.inner.send_str(sealed.as_slice())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.inner.flush()
+    }
+
+    fn try_recv_reply_str(&mut self, timeout_ms: i64) -> Option<Vec<u8>> {
+        let buf = self.inner.try_recv_reply_str(timeout_ms)?;
+        match self.config {
+            Some(ref config) => {
+                let local = config.signer(None);
+                open(config, local, &mut self.windows, &mut self.stats, buf.as_slice()).map(|(_, payload)| payload)
+            },
+            None => Some(buf),
+        }
+    }
+
+    fn reply_fds(&self) -> Vec<c_int> {
+        self.inner.reply_fds()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_window_rejects_duplicates() {
+        let mut w = ReplayWindow::new();
+        assert!(w.check_and_update(100));
+        assert!(!w.check_and_update(100));
+        assert!(w.check_and_update(101));
+        assert!(!w.check_and_update(101));
+    }
+
+    #[test]
+    fn replay_window_accepts_late_messages_inside() {
+        let mut w = ReplayWindow::new();
+        assert!(w.check_and_update(1000));
+        assert!(w.check_and_update(1000 - (REPLAY_WINDOW - 1)));
+        assert!(!w.check_and_update(1000 - (REPLAY_WINDOW - 1)));
+        assert!(w.check_and_update(999));
+        // just outside
+        assert!(!w.check_and_update(1000 - REPLAY_WINDOW));
+    }
+
+    #[test]
+    fn replay_window_slides() {
+        let mut w = ReplayWindow::new();
+        assert!(w.check_and_update(10));
+        assert!(w.check_and_update(10 + REPLAY_WINDOW - 1));
+        // 10 is still the lowest counter in the window and was seen
+        assert!(!w.check_and_update(10));
+        assert!(w.check_and_update(10 + REPLAY_WINDOW));
+        assert!(!w.check_and_update(10));
+        // the oldest counter still inside, not seen yet
+        assert!(w.check_and_update(11));
+        // a jump past the whole window forgets everything before it
+        assert!(w.check_and_update(10 + 3 * REPLAY_WINDOW));
+        assert!(!w.check_and_update(10 + REPLAY_WINDOW));
+        assert!(w.check_and_update(10 + 3 * REPLAY_WINDOW - 1));
+    }
+
+    fn config(local_ids: Vec<ServerID>) -> AuthConfig {
+        let servers = !local_ids.is_empty();
+        AuthConfig {
+            local_ids,
+            cluster_key: if servers { Some(vec![1; 16]) } else { None },
+            client_key: Some(vec![3; 16]),
+            pair_keys: if servers { vec![((0, 10), vec![2; 16])].into_iter().collect() } else { HashMap::new() },
+            peers: HashMap::new(),
+            instance: 7,
+        }
+    }
+
+    #[test]
+    fn seal_and_open_check_sender_key_and_counter() {
+        let config = config(vec![0]);
+        let mut windows = ReplayWindows::default();
+        let mut stats = AuthStats::default();
+        let sealed = seal(&config, 0, Some(10), b"payload").unwrap();
+        assert_eq!(open(&config, 10, &mut windows, &mut stats, sealed.as_slice()), Some((0, b"payload".to_vec())));
+        // the same frame again is a replay
+        assert_eq!(open(&config, 10, &mut windows, &mut stats, sealed.as_slice()), None);
+        assert_eq!(stats.replayed, 1);
+        // server 20 shares only the cluster key with 0, the pair key does not fit
+        assert_eq!(open(&config, 20, &mut windows, &mut stats, sealed.as_slice()), None);
+        assert_eq!(stats.unauthenticated, 1);
+        assert_eq!(stats.accepted, 1);
+    }
+
+    #[test]
+    fn client_frames_are_replay_protected_per_instance() {
+        let server = config(vec![10]);
+        let mut client = config(Vec::new());
+        let mut windows = ReplayWindows::default();
+        let mut stats = AuthStats::default();
+        let sealed = seal(&client, CLIENT_SENDER, Some(10), b"request").unwrap();
+        assert!(open(&server, 10, &mut windows, &mut stats, sealed.as_slice()).is_some());
+        assert_eq!(open(&server, 10, &mut windows, &mut stats, sealed.as_slice()), None);
+        assert_eq!(stats.replayed, 1);
+        // another client process has a window of its own
+        client.instance = 8;
+        let sealed = seal(&client, CLIENT_SENDER, Some(10), b"request").unwrap();
+        assert!(open(&server, 10, &mut windows, &mut stats, sealed.as_slice()).is_some());
+    }
+
+    #[test]
+    fn old_client_frames_are_dropped() {
+        let server = config(vec![10]);
+        let client = config(Vec::new());
+        let mut stats = AuthStats::default();
+        let counter = now_us() - CLIENT_MAX_AGE_US - 1_000_000;
+        let mac = compute_mac(&[3; 16], CLIENT_SENDER, 7, counter, b"request").unwrap();
+        let frame = serde_json::to_vec(&SignedEnvelope {
+            sender: CLIENT_SENDER,
+            instance: client.instance,
+            counter,
+            payload: hex::encode(b"request"),
+            mac: hex::encode(mac),
+        }).unwrap();
+        assert_eq!(open(&server, 10, &mut ReplayWindows::default(), &mut stats, frame.as_slice()), None);
+        assert_eq!(stats.stale, 1);
+    }
+
+    #[test]
+    fn the_client_key_does_not_vouch_for_servers() {
+        let server = config(vec![10]);
+        let mut stats = AuthStats::default();
+        // a client signing as leader 11 with the only key it has
+        let mac = compute_mac(&[3; 16], 11, 0, now_us(), b"x").unwrap();
+        let frame = serde_json::to_vec(&SignedEnvelope {
+            sender: 11,
+            instance: 0,
+            counter: now_us(),
+            payload: hex::encode(b"x"),
+            mac: hex::encode(mac),
+        }).unwrap();
+        assert_eq!(open(&server, 10, &mut ReplayWindows::default(), &mut stats, frame.as_slice()), None);
+        assert_eq!(stats.unauthenticated, 1);
+    }
+
+    struct Frames(Vec<Vec<u8>>);
+
+    impl MsgRecver<Message<String, String>> for Frames {
+        type Ctx = ();
+
+        fn bind(_addr: &Addr) -> Result<Self, Error> {
+            Ok(Frames(Vec::new()))
+        }
+
+        fn try_recv_str(&mut self) -> Option<Vec<u8>> {
+            if self.0.is_empty() { None } else { Some(self.0.remove(0)) }
+        }
+
+        fn get_io_fds(&self) -> Vec<c_int> {
+            Vec::new()
+        }
+    }
+
+    #[test]
+    fn clients_may_only_send_client_requests() {
+        let client = config(Vec::new());
+        let seal_msg = |msg: &Message<String, String>| {
+            seal(&client, CLIENT_SENDER, Some(0), serde_json::to_vec(msg).unwrap().as_slice()).unwrap()
+        };
+        let decision = Message::Decision { slot: 1, cmd: "steal".to_string(), req: None };
+        let request = Message::Request { cid: Addr::new("127.0.0.1", 100), cmd: "lock".to_string(), req_id: Some(1) };
+        let mut recver = AuthRecver {
+            inner: Frames(vec![seal_msg(&request), seal_msg(&decision)]),
+            config: Some(Arc::new(config(vec![0]))),
+            local: 0,
+            windows: Default::default(),
+            stats: Default::default(),
+            last_sender: None,
+        };
+        assert!(matches!(recver.try_recv(), Some(Message::Request { .. })));
+        assert!(recver.try_recv().is_none());
+        assert_eq!(recver.stats().impersonated, 1);
+        assert_eq!(recver.stats().accepted, 2);
+    }
+}
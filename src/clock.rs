@@ -0,0 +1,121 @@
+// Time as the protocol roles see it. Roles read a Clock instead of the system
+// time, so tests can drive them with a ManualClock and step through timeouts
+// without sleeping. Timers count from the last reset and fire once their period
+// has passed on the clock.
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::thread;
+use std::time::{ Duration, Instant };
+
+pub trait Clock: Send + Sync {
+    // milliseconds since a fixed point, never goes backwards
+    fn now_ms(&self) -> u64;
+
+    fn sleep_ms(&self, ms: u64) {
+        thread::sleep(Duration::from_millis(ms));
+    }
+}
+
+pub type SharedClock = Arc<dyn Clock>;
+
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        SystemClock { start: Instant::now() }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        SystemClock::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> u64 {
+        let elapsed = self.start.elapsed();
+        elapsed.as_secs() * 1000 + elapsed.subsec_millis() as u64
+    }
+}
+
+pub fn system_clock() -> SharedClock {
+    Arc::new(SystemClock::new())
+}
+
+// only moves when told to
+pub struct ManualClock {
+    now_ms: AtomicUsize,
+}
+
+impl ManualClock {
+    pub fn new(start_ms: u64) -> Self {
+        ManualClock { now_ms: AtomicUsize::new(start_ms as usize) }
+    }
+
+    pub fn advance(&self, ms: u64) {
+        self.now_ms.fetch_add(ms as usize, Ordering::SeqCst);
+    }
+
+    // moving back is ignored, the clock is monotonic
+    pub fn set(&self, ms: u64) {
+        let mut cur = self.now_ms.load(Ordering::SeqCst);
+        while (ms as usize) > cur {
+            match self.now_ms.compare_exchange(cur, ms as usize, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => break,
+                Err(actual) => cur = actual,
+            }
+        }
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_ms(&self) -> u64 {
+        self.now_ms.load(Ordering::SeqCst) as u64
+    }
+
+    // returns right away with the time moved on
+    fn sleep_ms(&self, ms: u64) {
+        self.advance(ms);
+    }
+}
+
+// a period counted from the last reset, a zero period never fires
+#[derive(Clone, Copy, Debug)]
+pub struct Timer {
+    period_ms: u64,
+    since_ms: u64,
+}
+
+impl Timer {
+    pub fn new(period_ms: u64, now_ms: u64) -> Self {
+        Timer { period_ms, since_ms: now_ms }
+    }
+
+    pub fn set_period(&mut self, period_ms: u64) {
+        self.period_ms = period_ms;
+    }
+
+    pub fn reset(&mut self, now_ms: u64) {
+        self.since_ms = now_ms;
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.period_ms > 0
+    }
+
+    pub fn expired(&self, now_ms: u64) -> bool {
+        self.enabled() && now_ms.saturating_sub(self.since_ms) >= self.period_ms
+    }
+
+    // milliseconds until the timer fires, None if it is disabled
+    pub fn remaining_ms(&self, now_ms: u64) -> Option<i64> {
+        if self.enabled() {
+            Some((self.since_ms + self.period_ms).saturating_sub(now_ms) as i64)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,270 @@
+// Optional deflate compression of large frames. Compressed frames start with a
+// flag byte, everything else goes out as it is: the frames handed to this layer
+// are JSON, which never starts with a flag byte. Every node and client has the
+// layer whatever its codec, the codec only decides whether it compresses what it
+// sends, so peers need not agree on it and there is nothing to negotiate. A
+// cluster is moved to deflate by upgrading every process first and changing the
+// codec afterwards, in any order. Frames flagged raw, from senders that still
+// flag them, are accepted as well.
+use std::io::{ Read, Write };
+use std::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+use libc::c_int;
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use messaging::*;
+use messages::ServerID;
+use error::*;
+
+const FLAG_RAW: u8 = 0;
+const FLAG_DEFLATE: u8 = 1;
+// the most a frame may inflate to, in line with the largest message the
+// transports deliver; anything past it counts as a decode error
+const MAX_DECODED_LEN: usize = 16 * 1024 * 1024;
+
+pub const DEFAULT_THRESHOLD: usize = 1024;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static THRESHOLD: AtomicUsize = AtomicUsize::new(DEFAULT_THRESHOLD);
+static LEVEL: AtomicUsize = AtomicUsize::new(6);
+
+static FRAMES_RAW: AtomicUsize = AtomicUsize::new(0);
+static FRAMES_COMPRESSED: AtomicUsize = AtomicUsize::new(0);
+static BYTES_BEFORE: AtomicUsize = AtomicUsize::new(0);
+static BYTES_AFTER: AtomicUsize = AtomicUsize::new(0);
+static DECODE_ERRORS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Debug)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    // frames smaller than this are always sent uncompressed
+    pub threshold: usize,
+    pub level: u32,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig { enabled: false, threshold: DEFAULT_THRESHOLD, level: 6 }
+    }
+}
+
+pub fn set_compression(config: &CompressionConfig) {
+    ENABLED.store(config.enabled, Ordering::SeqCst);
+    THRESHOLD.store(config.threshold, Ordering::SeqCst);
+    LEVEL.store(std::cmp::min(config.level, 9) as usize, Ordering::SeqCst);
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct CompressionStats {
+    pub frames_raw: u64,
+    pub frames_compressed: u64,
+    // payload bytes of compressed frames before and after compression
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+    pub decode_errors: u64,
+}
+
+impl CompressionStats {
+    pub fn ratio(&self) -> f64 {
+        if self.bytes_after == 0 {
+            1.0
+        } else {
+            self.bytes_before as f64 / self.bytes_after as f64
+        }
+    }
+}
+
+pub fn compression_stats() -> CompressionStats {
+    CompressionStats {
+        frames_raw: FRAMES_RAW.load(Ordering::Relaxed) as u64,
+        frames_compressed: FRAMES_COMPRESSED.load(Ordering::Relaxed) as u64,
+        bytes_before: BYTES_BEFORE.load(Ordering::Relaxed) as u64,
+        bytes_after: BYTES_AFTER.load(Ordering::Relaxed) as u64,
+        decode_errors: DECODE_ERRORS.load(Ordering::Relaxed) as u64,
+    }
+}
+
+fn deflate(s: &[u8]) -> Option<Vec<u8>> {
+    let level = Compression::new(LEVEL.load(Ordering::SeqCst) as u32);
+    let mut encoder = DeflateEncoder::new(Vec::with_capacity(s.len() / 2 + 1), level);
+    encoder.write_all(s).ok()?;
+    encoder.finish().ok()
+}
+
+pub fn encode_frame(s: &[u8]) -> Vec<u8> {
+    if ENABLED.load(Ordering::SeqCst) && s.len() >= THRESHOLD.load(Ordering::SeqCst) {
+        match deflate(s) {
+            // not worth it if the output did not shrink
+            Some(ref c) if c.len() < s.len() => {
+                FRAMES_COMPRESSED.fetch_add(1, Ordering::Relaxed);
+                BYTES_BEFORE.fetch_add(s.len(), Ordering::Relaxed);
+                BYTES_AFTER.fetch_add(c.len(), Ordering::Relaxed);
+                let mut frame = Vec::with_capacity(c.len() + 1);
+                frame.push(FLAG_DEFLATE);
+                frame.extend_from_slice(c.as_slice());
+                return frame;
+            },
+            _ => (),
+        }
+    }
+    FRAMES_RAW.fetch_add(1, Ordering::Relaxed);
+    s.to_vec()
+}
+
+pub fn decode_frame(buf: Vec<u8>) -> Option<Vec<u8>> {
+    match buf.first().cloned() {
+        Some(FLAG_RAW) => Some(buf[1..].to_vec()),
+        Some(FLAG_DEFLATE) => {
+            let mut out = Vec::with_capacity(buf.len() * 4);
+            let limit = MAX_DECODED_LEN as u64 + 1;
+            match DeflateDecoder::new(&buf[1..]).take(limit).read_to_end(&mut out) {
+                Ok(n) if n <= MAX_DECODED_LEN => Some(out),
+                _ => {
+                    DECODE_ERRORS.fetch_add(1, Ordering::Relaxed);
+                    None
+                },
+            }
+        },
+        _ => Some(buf),
+    }
+}
+
+pub struct CompressRecver<R> {
+    inner: R,
+}
+
+impl<T, R> MsgRecver<T> for CompressRecver<R> where
+    T: serde::Serialize + serde::de::DeserializeOwned,
+    R: MsgRecver<T> {
+    type Ctx = R::Ctx;
+
+    fn bind(addr: &Addr) -> Result<Self, Error> {
+        Ok(CompressRecver {
+            inner: R::bind(addr)?,
+        })
+    }
+
+    fn try_recv_str(&mut self) -> Option<Vec<u8>> {
+        while let Some(buf) = self.inner.try_recv_str() {
+            let frame = decode_frame(buf);
+            if frame.is_some() {
+                return frame;
+            }
+        }
+        None
+    }
+
+    fn get_io_fds(&self) -> Vec<c_int> {
+        self.inner.get_io_fds()
+    }
+
+    fn pending(&self) -> bool {
+        self.inner.pending()
+    }
+
+    // the inner transport checks who sent the decompressed message
+    fn accepts(&mut self, msg: &T) -> bool {
+        self.inner.accepts(msg)
+    }
+
+    fn last_route(&self) -> Option<Vec<u8>> {
+        self.inner.last_route()
+    }
+
+    fn reply_str(&mut self, route: &[u8], s: &[u8]) -> Result<(), Error> {
+        self.inner.reply_str(route, encode_frame(s).as_slice())
+    }
+}
+
+pub struct CompressSender<S> {
+    inner: S,
+}
+
+impl<T, S> MsgSender<T> for CompressSender<S> where
+    T: serde::Serialize + serde::de::DeserializeOwned,
+    S: MsgSender<T> {
+
+    fn connect(addr: &Addr) -> Result<Self, Error> {
+        Ok(CompressSender {
+            inner: S::connect(addr)?,
+        })
+    }
+
+    fn send_str(&mut self, s: &[u8]) -> Result<(), Error> {
+        self.inner.send_str(encode_frame(s).as_slice())
+    }
+
+    fn send_str_from(&mut self, sender: Option<ServerID>, s: &[u8]) -> Result<(), Error> {
+        self.inner.send_str_from(sender, encode_frame(s).as_slice())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.inner.flush()
+    }
+
+    fn try_recv_reply_str(&mut self, timeout_ms: i64) -> Option<Vec<u8>> {
+        self.inner.try_recv_reply_str(timeout_ms).and_then(decode_frame)
+    }
+
+    fn reply_fds(&self) -> Vec<c_int> {
+        self.inner.reply_fds()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{ thread_rng, Rng };
+
+    // every test sets the same config, they share the statics
+    fn enable() {
+        set_compression(&CompressionConfig { enabled: true, threshold: 64, level: 6 });
+    }
+
+    #[test]
+    fn large_frames_are_compressed() {
+        enable();
+        let msg = "{\"Decision\":{\"slot\":1,\"cmd\":\"x\"}}".repeat(20).into_bytes();
+        let frame = encode_frame(msg.as_slice());
+        assert_eq!(frame[0], FLAG_DEFLATE);
+        assert!(frame.len() < msg.len());
+        assert_eq!(decode_frame(frame), Some(msg));
+    }
+
+    #[test]
+    fn small_and_incompressible_frames_stay_raw() {
+        enable();
+        let frame = encode_frame(b"\"Tick\"");
+        assert_eq!(frame, b"\"Tick\"".to_vec());
+        assert_eq!(decode_frame(frame), Some(b"\"Tick\"".to_vec()));
+        let noise = (0..512).map(|_| thread_rng().gen::<u8>()).collect::<Vec<_>>();
+        assert_eq!(encode_frame(noise.as_slice()), noise);
+    }
+
+    #[test]
+    fn unflagged_and_raw_flagged_frames_pass_through() {
+        let json = b"{\"Tick\":null}".to_vec();
+        assert_eq!(decode_frame(json.clone()), Some(json.clone()));
+        let mut flagged = vec![FLAG_RAW];
+        flagged.extend_from_slice(json.as_slice());
+        assert_eq!(decode_frame(flagged), Some(json));
+        assert_eq!(decode_frame(Vec::new()), Some(Vec::new()));
+    }
+
+    #[test]
+    fn corrupt_deflate_frames_are_dropped() {
+        assert_eq!(decode_frame(vec![FLAG_DEFLATE, 0xff, 0xff, 0xff]), None);
+    }
+
+    #[test]
+    fn frames_that_inflate_past_the_limit_are_dropped() {
+        let errors = compression_stats().decode_errors;
+        let mut frame = vec![FLAG_DEFLATE];
+        frame.extend(deflate(vec![0u8; MAX_DECODED_LEN + 1].as_slice()).unwrap());
+        assert_eq!(decode_frame(frame), None);
+        assert!(compression_stats().decode_errors > errors);
+        let mut frame = vec![FLAG_DEFLATE];
+        frame.extend(deflate(vec![0u8; MAX_DECODED_LEN].as_slice()).unwrap());
+        assert_eq!(decode_frame(frame).map(|f| f.len()), Some(MAX_DECODED_LEN));
+    }
+}
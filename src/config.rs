@@ -0,0 +1,718 @@
+// Cluster configuration, read from a TOML file so that deployments do not need
+// recompiling:
+//
+//     transport = "zmq"              # zmq, udp, batch-udp or tls
+//     codec = "json"                 # json, or deflate to compress large frames;
+//                                    # either codec reads both
+//     runtime = "single"             # single, or threaded to split io, protocol
+//                                    # and state machine into threads
+//
+//     [timeouts]
+//     p1a_retry_ms = 1000            # phase 1 retry while the ballot is not adopted
+//     retransmit_ms = 500            # resend unanswered P2a
+//     lease_ms = 0                   # back off this long after being preempted
+//     heartbeat_ms = 0               # idle leader checks its ballot, 0 is off
+//     client_timeout_ms = 5000       # a client gives up on a request after this long
+//     client_attempt_ms = 1000       # and tries another replica after this long
+//     shutdown_grace_ms = 2000       # time to drain after SIGTERM/SIGINT
+//
+//     [udp]                          # optional, for udp and batch-udp
+//     max_datagram_size = 1000       # larger messages are sent in fragments
+//     reassembly_timeout_ms = 1000   # incomplete messages are dropped after this
+//
+//     [logging]                      # optional
+//     level = "info"                 # off, error, warn, info, debug or trace
+//     targets = { leader = "debug" } # per module: leader, acceptor, replica, messaging, ...
+//     json = false
+//
+//     [[servers]]
+//     id = 0
+//     role = "replica"               # replica, leader or acceptor
+//     addr = "127.0.0.1:8000"
+//     metrics = "127.0.0.1:9400"     # optional, serves GET /metrics for Prometheus
+//
+//     [multicast]                    # optional, groups for leader broadcasts
+//     acceptors = "239.255.0.1:9200"
+//     replicas = "239.255.0.2:9201"
+//
+//     [tls]                          # required by the tls transport
+//     cert_file = "node.pem"
+//     key_file = "node.key"
+//     ca_file = "ca.pem"
+//     identities = { "node0" = [0, 10, 20] }  # common name -> the ids of the
+//                                             # process's colocated roles, or one id
+//
+//     [auth]                         # optional, signs every message
+//     cluster_key = "00112233..."    # hex, at least 16 bytes, shared by the servers;
+//                                    # leave it out of the clients' copy
+//     client_key = "44556677..."     # between clients and servers, a client key
+//                                    # only vouches for client requests
+//     pair_keys = [ { servers = [0, 10], key = "..." } ]  # optional, per pair
+//
+//     [envelope]                     # optional, rejects other clusters' traffic
+//     cluster_id = "prod"
+//
+// Servers of one role are numbered in file order, `lock_server replica 1` runs
+// the second replica.
+//
+// Running servers reload the file on SIGHUP or `lock_server reload`: the leader
+// timeouts and [logging] apply right away, the client timeouts and
+// shutdown_grace_ms on the next start. Everything else must stay as it is.
+
+use std::collections::{ HashMap, HashSet };
+use std::fs;
+use std::net::IpAddr;
+use std::time::Duration;
+use auth::*;
+use compress::*;
+use envelope::*;
+use leader::*;
+use logging::*;
+use messages::*;
+use messaging::*;
+use tls::*;
+#[cfg(target_os = "linux")]
+use mmsg::*;
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Transport {
+    #[default]
+    Zmq,
+    Udp,
+    BatchUdp,
+    Tls,
+}
+
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Codec {
+    #[default]
+    Json,
+    Deflate,
+}
+
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Runtime {
+    #[default]
+    Single,
+    Threaded,
+}
+
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    Replica,
+    Leader,
+    Acceptor,
+}
+
+impl Role {
+    // as written in the config and on the command line
+    pub fn name(&self) -> &'static str {
+        match self {
+            Role::Replica => "replica",
+            Role::Leader => "leader",
+            Role::Acceptor => "acceptor",
+        }
+    }
+}
+
+fn deserialize_addr<'de, D>(d: D) -> Result<Addr, D::Error> where
+    D: serde::Deserializer<'de> {
+    let s = <String as serde::Deserialize>::deserialize(d)?;
+    Addr::parse(s.as_str()).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_opt_addr<'de, D>(d: D) -> Result<Option<Addr>, D::Error> where
+    D: serde::Deserializer<'de> {
+    deserialize_addr(d).map(Some)
+}
+
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    pub id: ServerID,
+    pub role: Role,
+    #[serde(deserialize_with = "deserialize_addr")]
+    pub addr: Addr,
+    // where the node serves Prometheus metrics, none if omitted
+    #[serde(default, deserialize_with = "deserialize_opt_addr")]
+    pub metrics: Option<Addr>,
+}
+
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutConfig {
+    pub p1a_retry_ms: u64,
+    pub retransmit_ms: u64,
+    pub lease_ms: u64,
+    pub heartbeat_ms: u64,
+    // how long a client waits for a response in all, and from one replica
+    pub client_timeout_ms: i64,
+    pub client_attempt_ms: i64,
+    pub shutdown_grace_ms: i64,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        let leader = LeaderTimeouts::default();
+        TimeoutConfig {
+            p1a_retry_ms: leader.p1a_retry_ms,
+            retransmit_ms: leader.retransmit_ms,
+            lease_ms: leader.lease_ms,
+            heartbeat_ms: leader.heartbeat_ms,
+            client_timeout_ms: 5000,
+            client_attempt_ms: 1000,
+            shutdown_grace_ms: 2000,
+        }
+    }
+}
+
+impl TimeoutConfig {
+    pub fn leader(&self) -> LeaderTimeouts {
+        LeaderTimeouts {
+            p1a_retry_ms: self.p1a_retry_ms,
+            retransmit_ms: self.retransmit_ms,
+            lease_ms: self.lease_ms,
+            heartbeat_ms: self.heartbeat_ms,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct UdpConfig {
+    pub max_datagram_size: usize,
+    pub reassembly_timeout_ms: u64,
+}
+
+impl Default for UdpConfig {
+    fn default() -> Self {
+        UdpConfig {
+            max_datagram_size: DEFAULT_MAX_DATAGRAM_SIZE,
+            reassembly_timeout_ms: DEFAULT_REASSEMBLY_TIMEOUT_MS,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct McastConfig {
+    #[serde(deserialize_with = "deserialize_addr")]
+    pub acceptors: Addr,
+    #[serde(deserialize_with = "deserialize_addr")]
+    pub replicas: Addr,
+}
+
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PairKey {
+    pub servers: (ServerID, ServerID),
+    pub key: String,
+}
+
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct AuthSection {
+    // only servers need it
+    #[serde(default)]
+    pub cluster_key: Option<String>,
+    pub client_key: String,
+    #[serde(default)]
+    pub pair_keys: Vec<PairKey>,
+}
+
+// shortest accepted hmac key
+const MIN_KEY_LEN: usize = 16;
+
+fn decode_key(name: &str, key: &str) -> Result<Vec<u8>, String> {
+    let bytes = hex::decode(key).map_err(|e| format!("{} is not hex: {}", name, e))?;
+    if bytes.len() < MIN_KEY_LEN {
+        return Err(format!("{} must be at least {} bytes", name, MIN_KEY_LEN));
+    }
+    Ok(bytes)
+}
+
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct EnvelopeSection {
+    pub cluster_id: String,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ClusterConfig {
+    #[serde(default)]
+    pub transport: Transport,
+    #[serde(default)]
+    pub codec: Codec,
+    #[serde(default)]
+    pub runtime: Runtime,
+    #[serde(default)]
+    pub timeouts: TimeoutConfig,
+    #[serde(default)]
+    pub udp: UdpConfig,
+    #[serde(default)]
+    pub logging: LogSettings,
+    pub servers: Vec<ServerConfig>,
+    #[serde(default)]
+    pub multicast: Option<McastConfig>,
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    #[serde(default)]
+    pub auth: Option<AuthSection>,
+    #[serde(default)]
+    pub envelope: Option<EnvelopeSection>,
+}
+
+impl ClusterConfig {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        ClusterConfig::parse(text.as_str()).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let config: ClusterConfig = toml::from_str(text).map_err(|e| e.to_string())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // loads `path` if given, the local test cluster otherwise
+    pub fn load_or_local(path: Option<&str>) -> Result<Self, String> {
+        path.map_or(Ok(ClusterConfig::local()), ClusterConfig::load)
+    }
+
+    // two replicas, two leaders and three acceptors on 127.0.0.1
+    pub fn local() -> Self {
+        let lh = "127.0.0.1";
+        let server = |id, role, port| ServerConfig { id, role, addr: Addr::new(lh, port), metrics: None };
+        ClusterConfig {
+            transport: Transport::Zmq,
+            codec: Codec::Json,
+            runtime: Runtime::Single,
+            timeouts: Default::default(),
+            udp: Default::default(),
+            logging: Default::default(),
+            servers: vec![server(0, Role::Replica, 8000),
+                          server(1, Role::Replica, 8001),
+                          server(10, Role::Leader, 9001),
+                          server(11, Role::Leader, 9002),
+                          server(20, Role::Acceptor, 9101),
+                          server(21, Role::Acceptor, 9102),
+                          server(22, Role::Acceptor, 9103)],
+            multicast: None,
+            tls: None,
+            auth: None,
+            envelope: None,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let mut ids = HashSet::new();
+        let mut addrs = HashSet::new();
+        for s in self.servers.iter() {
+            if !ids.insert(s.id) {
+                return Err(format!("duplicate server id {}", s.id));
+            }
+            if !addrs.insert(s.addr.clone()) {
+                return Err(format!("duplicate server address {}", s.addr));
+            }
+            if let Some(ref m) = s.metrics {
+                if !addrs.insert(m.clone()) {
+                    return Err(format!("duplicate server address {}", m));
+                }
+            }
+        }
+        for role in [Role::Replica, Role::Leader, Role::Acceptor].iter() {
+            if self.servers_with(*role).is_empty() {
+                return Err(format!("no {:?} configured", role));
+            }
+        }
+        if let Some(ref m) = self.multicast {
+            if self.runtime == Runtime::Threaded {
+                return Err("multicast needs the single-threaded runtime".to_string());
+            }
+            for group in [&m.acceptors, &m.replicas].iter() {
+                let is_mcast = group.addr.parse::<IpAddr>().map(|ip| ip.is_multicast()).unwrap_or(false);
+                if !is_mcast {
+                    return Err(format!("{} is not a multicast address", group));
+                }
+            }
+        }
+        match (self.transport, self.tls.as_ref()) {
+            (Transport::Tls, None) => return Err("the tls transport needs a [tls] section".to_string()),
+            (_, Some(tls)) => {
+                for (name, id) in tls.identities.iter().flat_map(|(name, ids)| ids.iter().map(move |id| (name, id))) {
+                    if !ids.contains(id) {
+                        return Err(format!("tls identity {} names unknown server {}", name, id));
+                    }
+                }
+            },
+            _ => (),
+        }
+        if let Some(ref auth) = self.auth {
+            if self.multicast.is_some() {
+                return Err("multicast traffic is not authenticated, drop [multicast] or [auth]".to_string());
+            }
+            if ids.contains(&CLIENT_SENDER) {
+                return Err(format!("server id {} is reserved for clients", CLIENT_SENDER));
+            }
+            // a server key equal to the client key would let clients pass as servers
+            let client_key = decode_key("client_key", auth.client_key.as_str())?;
+            if let Some(ref key) = auth.cluster_key {
+                if decode_key("cluster_key", key.as_str())? == client_key {
+                    return Err("cluster_key must differ from client_key".to_string());
+                }
+            }
+            let mut pairs = HashSet::new();
+            for p in auth.pair_keys.iter() {
+                let (a, b) = p.servers;
+                for id in [a, b].iter() {
+                    if !ids.contains(id) {
+                        return Err(format!("pair key names unknown server {}", id));
+                    }
+                }
+                if a == b {
+                    return Err(format!("pair key names server {} twice", a));
+                }
+                if !pairs.insert(if a < b { (a, b) } else { (b, a) }) {
+                    return Err(format!("duplicate pair key for servers {} and {}", a, b));
+                }
+                if decode_key("pair key", p.key.as_str())? == client_key {
+                    return Err(format!("pair key for servers {} and {} must differ from client_key", a, b));
+                }
+            }
+        }
+        if let Some(ref envelope) = self.envelope {
+            if envelope.cluster_id.is_empty() {
+                return Err("cluster_id must not be empty".to_string());
+            }
+        }
+        if cfg!(not(target_os = "linux")) && self.transport == Transport::BatchUdp {
+            return Err("batch-udp needs recvmmsg, use udp".to_string());
+        }
+        if self.timeouts.p1a_retry_ms == 0 {
+            return Err("p1a_retry_ms must be positive".to_string());
+        }
+        if self.timeouts.retransmit_ms == 0 {
+            return Err("retransmit_ms must be positive".to_string());
+        }
+        check_datagram_size(self.udp.max_datagram_size)?;
+        if self.udp.reassembly_timeout_ms == 0 {
+            return Err("reassembly_timeout_ms must be positive".to_string());
+        }
+        Ok(())
+    }
+
+    // sets up the process-wide transport state, call once before creating nodes
+    pub fn apply(&self) -> Result<(), String> {
+        configure(&self.logging);
+        set_compression(&CompressionConfig {
+            enabled: self.codec == Codec::Deflate,
+            ..Default::default()
+        });
+        set_max_datagram_size(self.udp.max_datagram_size).map_err(|e| e.to_string())?;
+        set_reassembly_timeout(Duration::from_millis(self.udp.reassembly_timeout_ms));
+        if let Some(ref tls) = self.tls {
+            let mut tls = tls.clone();
+            tls.servers = self.servers.iter().map(|s| (s.addr.clone(), s.id)).collect();
+            tls.roles = self.servers.iter().map(|s| (s.id, s.role)).collect();
+            init_tls(tls).map_err(|e| e.to_string())?;
+        }
+        init_envelope(self.envelope.as_ref().map(|e| EnvelopeConfig { cluster_id: e.cluster_id.clone() }));
+        self.set_local_servers(&[])
+    }
+
+    // signs messages as the servers this process hosts, clients host none and
+    // only get the client key
+    pub fn set_local_servers(&self, ids: &[ServerID]) -> Result<(), String> {
+        let auth = match self.auth {
+            Some(ref auth) => auth,
+            None => {
+                init_auth(None);
+                return Ok(());
+            },
+        };
+        if !ids.is_empty() && auth.cluster_key.is_none() {
+            return Err("servers need the [auth] cluster_key".to_string());
+        }
+        let server_key = |key: &str| if ids.is_empty() { None } else { hex::decode(key).ok() };
+        init_auth(Some(AuthConfig {
+            local_ids: ids.to_vec(),
+            // validate checked the keys
+            cluster_key: auth.cluster_key.as_ref().and_then(|k| server_key(k)),
+            client_key: hex::decode(&auth.client_key).ok(),
+            pair_keys: auth.pair_keys.iter().filter_map(|p| {
+                let (a, b) = p.servers;
+                let pair = if a < b { (a, b) } else { (b, a) };
+                server_key(&p.key).map(|k| (pair, k))
+            }).collect(),
+            peers: self.servers.iter().map(|s| (s.addr.clone(), s.id)).collect(),
+            instance: rand::random(),
+        }));
+        Ok(())
+    }
+
+    // the changes reloading `new` over this config makes, an error if anything
+    // that needs a restart differs
+    pub fn reload_changes(&self, new: &ClusterConfig) -> Result<Vec<String>, String> {
+        let fixed = [("transport", self.transport != new.transport),
+                     ("codec", self.codec != new.codec),
+                     ("runtime", self.runtime != new.runtime),
+                     ("udp", self.udp != new.udp),
+                     ("servers", self.servers != new.servers),
+                     ("multicast", self.multicast != new.multicast),
+                     ("tls", self.tls != new.tls),
+                     ("auth", self.auth != new.auth),
+                     ("envelope", self.envelope != new.envelope)];
+        if let Some((name, _)) = fixed.iter().find(|(_, changed)| *changed) {
+            return Err(format!("{} changed, that needs a restart", name));
+        }
+        let (old, t) = (&self.timeouts, &new.timeouts);
+        let mut changes = Vec::new();
+        {
+            let mut diff = |name: &str, a: String, b: String, note: &str| if a != b {
+                changes.push(format!("{}: {} -> {}{}", name, a, b, note));
+            };
+            diff("timeouts.p1a_retry_ms", old.p1a_retry_ms.to_string(), t.p1a_retry_ms.to_string(), "");
+            diff("timeouts.retransmit_ms", old.retransmit_ms.to_string(), t.retransmit_ms.to_string(), "");
+            diff("timeouts.lease_ms", old.lease_ms.to_string(), t.lease_ms.to_string(), "");
+            diff("timeouts.heartbeat_ms", old.heartbeat_ms.to_string(), t.heartbeat_ms.to_string(), "");
+            diff("timeouts.client_timeout_ms", old.client_timeout_ms.to_string(), t.client_timeout_ms.to_string(),
+                 " (on restart)");
+            diff("timeouts.client_attempt_ms", old.client_attempt_ms.to_string(), t.client_attempt_ms.to_string(),
+                 " (on restart)");
+            diff("timeouts.shutdown_grace_ms", old.shutdown_grace_ms.to_string(), t.shutdown_grace_ms.to_string(),
+                 " (on restart)");
+            diff("logging", self.logging.spec(), new.logging.spec(), "");
+            diff("logging.json", self.logging.json.to_string(), new.logging.json.to_string(), "");
+        }
+        Ok(changes)
+    }
+
+    // (id, address) of every server with `role`, in file order
+    pub fn servers_with(&self, role: Role) -> Vec<(ServerID, Addr)> {
+        self.servers.iter()
+            .filter(|s| s.role == role)
+            .map(|s| (s.id, s.addr.clone()))
+            .collect()
+    }
+
+    pub fn ids_with(&self, role: Role) -> HashSet<ServerID> {
+        self.servers.iter().filter(|s| s.role == role).map(|s| s.id).collect()
+    }
+
+    pub fn server(&self, id: ServerID) -> Option<&ServerConfig> {
+        self.servers.iter().find(|s| s.id == id)
+    }
+
+    pub fn server_addrs(&self) -> HashMap<ServerID, Addr> {
+        self.servers.iter().map(|s| (s.id, s.addr.clone())).collect()
+    }
+
+    pub fn replica_addrs(&self) -> HashSet<Addr> {
+        self.servers_with(Role::Replica).into_iter().map(|(_, a)| a).collect()
+    }
+
+    // calls `user` with the receiver and sender types of the configured transport
+    // and codec, wrapped in the [envelope] and [auth] layers, which pass messages
+    // through when their section is missing
+    pub fn with_transport<M, U>(&self, user: U) -> U::Output where
+        M: serde::Serialize + serde::de::DeserializeOwned + Origin + MessageKind + 'static,
+        U: TransportUser<M> {
+        match self.transport {
+            Transport::Zmq => self.with_codec::<M, U, ZmqServer<M>, ZmqClient<M>>(user),
+            Transport::Udp => self.with_codec::<M, U, UdpRecver<M>, UdpSender<M>>(user),
+            #[cfg(target_os = "linux")]
+            Transport::BatchUdp => self.with_codec::<M, U, BatchUdpRecver<M>, BatchUdpSender<M>>(user),
+            #[cfg(not(target_os = "linux"))]
+            Transport::BatchUdp => unreachable!("validate rejects batch-udp without recvmmsg"),
+            Transport::Tls => self.with_codec::<M, U, TlsServer<M>, TlsClient<M>>(user),
+        }
+    }
+
+    // frames are signed after compression, so nothing is decompressed before its
+    // mac is checked. The compression layer is there for either codec, so that
+    // nodes read compressed frames whatever they send; the codec only turns
+    // compression on, see apply
+    fn with_codec<M, U, R, S>(&self, user: U) -> U::Output where
+        M: serde::Serialize + serde::de::DeserializeOwned + Origin + MessageKind + 'static,
+        U: TransportUser<M>,
+        R: MsgRecver<M> + 'static,
+        S: MsgSender<M> + 'static {
+        user.run::<EnvelopeRecver<CompressRecver<AuthRecver<R>>>, EnvelopeSender<CompressSender<AuthSender<S>>>>()
+    }
+}
+
+// code that is generic over the transport, see ClusterConfig::with_transport
+pub trait TransportUser<M> where
+    M: serde::Serialize + serde::de::DeserializeOwned {
+    type Output;
+
+    fn run<R, S>(self) -> Self::Output where
+        R: MsgRecver<M> + 'static,
+        S: MsgSender<M> + 'static;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f";
+    const CLIENT_KEY: &str = "101112131415161718191a1b1c1d1e1f";
+
+    fn with_auth(pair_keys: Vec<PairKey>) -> ClusterConfig {
+        let mut config = ClusterConfig::local();
+        config.auth = Some(AuthSection {
+            cluster_key: Some(KEY.to_string()),
+            client_key: CLIENT_KEY.to_string(),
+            pair_keys,
+        });
+        config
+    }
+
+    fn pair(a: ServerID, b: ServerID) -> PairKey {
+        PairKey { servers: (a, b), key: KEY.to_string() }
+    }
+
+    fn error(config: &ClusterConfig) -> String {
+        config.validate().unwrap_err()
+    }
+
+    #[test]
+    fn timeouts_and_logging_reload_in_place() {
+        let old = ClusterConfig::local();
+        assert_eq!(old.reload_changes(&old), Ok(Vec::new()));
+
+        let mut new = old.clone();
+        new.timeouts.heartbeat_ms = 250;
+        new.timeouts.client_timeout_ms = old.timeouts.client_timeout_ms + 1;
+        new.logging = LogSettings::parse("warn,leader=debug").unwrap();
+        assert_eq!(old.reload_changes(&new), Ok(vec![
+            "timeouts.heartbeat_ms: 0 -> 250".to_string(),
+            format!("timeouts.client_timeout_ms: {} -> {} (on restart)",
+                    old.timeouts.client_timeout_ms, new.timeouts.client_timeout_ms),
+            "logging: info -> warn,leader=debug".to_string(),
+        ]));
+    }
+
+    #[test]
+    fn other_changes_need_a_restart() {
+        let old = ClusterConfig::local();
+        let mut new = old.clone();
+        new.timeouts.heartbeat_ms = 250;
+        new.runtime = Runtime::Threaded;
+        assert_eq!(old.reload_changes(&new), Err("runtime changed, that needs a restart".to_string()));
+
+        let mut new = old.clone();
+        new.servers[0].addr = Addr::new("127.0.0.1", 8100);
+        assert_eq!(old.reload_changes(&new), Err("servers changed, that needs a restart".to_string()));
+    }
+
+    #[test]
+    fn local_config_is_valid() {
+        assert_eq!(ClusterConfig::local().validate(), Ok(()));
+        assert_eq!(with_auth(vec![pair(0, 10), pair(20, 11)]).validate(), Ok(()));
+    }
+
+    #[test]
+    fn servers_must_be_unique_and_cover_every_role() {
+        let mut config = ClusterConfig::local();
+        config.servers[1].id = 0;
+        assert_eq!(error(&config), "duplicate server id 0");
+
+        let mut config = ClusterConfig::local();
+        config.servers[1].addr = config.servers[0].addr.clone();
+        assert_eq!(error(&config), "duplicate server address 127.0.0.1:8000");
+
+        let mut config = ClusterConfig::local();
+        config.servers.retain(|s| s.role != Role::Leader);
+        assert_eq!(error(&config), "no Leader configured");
+    }
+
+    #[test]
+    fn tls_transport_needs_its_section() {
+        let mut config = ClusterConfig::local();
+        config.transport = Transport::Tls;
+        assert_eq!(error(&config), "the tls transport needs a [tls] section");
+    }
+
+    #[test]
+    fn auth_keys_must_be_long_hex() {
+        let mut config = with_auth(Vec::new());
+        config.auth.as_mut().unwrap().cluster_key = Some("not hex".to_string());
+        assert!(error(&config).starts_with("cluster_key is not hex"));
+
+        config.auth.as_mut().unwrap().cluster_key = Some("00112233".to_string());
+        assert_eq!(error(&config), "cluster_key must be at least 16 bytes");
+
+        let mut short = pair(0, 10);
+        short.key = "0011".to_string();
+        assert_eq!(error(&with_auth(vec![short])), "pair key must be at least 16 bytes");
+    }
+
+    #[test]
+    fn server_keys_must_differ_from_the_client_key() {
+        let mut config = with_auth(Vec::new());
+        config.auth.as_mut().unwrap().cluster_key = Some(CLIENT_KEY.to_string());
+        assert_eq!(error(&config), "cluster_key must differ from client_key");
+
+        let mut client_pair = pair(0, 10);
+        client_pair.key = CLIENT_KEY.to_string();
+        assert_eq!(error(&with_auth(vec![client_pair])), "pair key for servers 0 and 10 must differ from client_key");
+    }
+
+    #[test]
+    fn only_servers_need_the_cluster_key() {
+        let mut config = with_auth(Vec::new());
+        config.auth.as_mut().unwrap().cluster_key = None;
+        assert_eq!(config.validate(), Ok(()));
+        assert_eq!(config.set_local_servers(&[]), Ok(()));
+        assert_eq!(config.set_local_servers(&[0]), Err("servers need the [auth] cluster_key".to_string()));
+    }
+
+    #[test]
+    fn pair_keys_must_name_two_known_servers_once() {
+        assert_eq!(error(&with_auth(vec![pair(0, 5)])), "pair key names unknown server 5");
+        assert_eq!(error(&with_auth(vec![pair(10, 10)])), "pair key names server 10 twice");
+        assert_eq!(error(&with_auth(vec![pair(0, 10), pair(10, 0)])),
+                   "duplicate pair key for servers 10 and 0");
+    }
+
+    #[test]
+    fn auth_rules_out_multicast_and_the_client_id() {
+        let mut config = with_auth(Vec::new());
+        config.multicast = Some(McastConfig {
+            acceptors: Addr::new("239.255.0.1", 9200),
+            replicas: Addr::new("239.255.0.2", 9201),
+        });
+        assert_eq!(error(&config), "multicast traffic is not authenticated, drop [multicast] or [auth]");
+
+        let mut config = with_auth(Vec::new());
+        config.servers[0].id = CLIENT_SENDER;
+        assert_eq!(error(&config), format!("server id {} is reserved for clients", CLIENT_SENDER));
+    }
+
+    #[test]
+    fn envelope_needs_a_cluster_id() {
+        let mut config = ClusterConfig::local();
+        config.envelope = Some(EnvelopeSection { cluster_id: String::new() });
+        assert_eq!(error(&config), "cluster_id must not be empty");
+    }
+
+    #[test]
+    fn udp_limits_are_checked() {
+        let mut config = ClusterConfig::local();
+        config.udp.max_datagram_size = 1;
+        assert!(error(&config).starts_with("datagram size must be between"));
+
+        let mut config = ClusterConfig::local();
+        config.udp.reassembly_timeout_ms = 0;
+        assert_eq!(error(&config), "reassembly_timeout_ms must be positive");
+    }
+}
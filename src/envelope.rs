@@ -0,0 +1,368 @@
+// Every message on the wire is wrapped in a header carrying the protocol version,
+// the cluster id, the sending server and the message kind.
+//
+// Compatibility rules for `Message`:
+// - new variants are appended and bump PROTOCOL_MINOR; older nodes drop (and count)
+//   kinds they do not know instead of misreading them
+// - new fields on an existing variant must be `#[serde(default)]` and bump PROTOCOL_MINOR
+// - renaming, removing or changing the meaning of a variant or field bumps
+//   PROTOCOL_MAJOR; nodes with different majors reject each other's traffic
+// The minor is carried for diagnostics only and never checked: an older node
+// tells newer traffic apart by its kind, not by the minor.
+use std::collections::HashMap;
+use std::sync::{ Arc, RwLock };
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use libc::c_int;
+use messaging::*;
+use messages::*;
+use error::*;
+
+pub const PROTOCOL_MAJOR: u32 = 1;
+pub const PROTOCOL_MINOR: u32 = 5;
+
+#[derive(Clone, Debug)]
+pub struct EnvelopeConfig {
+    pub cluster_id: String,
+}
+
+// None until init_envelope is called, Some(None) while envelopes are off
+static ENVELOPE_CFG: RwLock<Option<Option<Arc<EnvelopeConfig>>>> = RwLock::new(None);
+
+// must be called before any EnvelopeRecver/EnvelopeSender is created, they keep
+// the config they were created with; without a config they send bare messages
+pub fn init_envelope(config: Option<EnvelopeConfig>) {
+    *ENVELOPE_CFG.write().unwrap_or_else(|e| e.into_inner()) = Some(config.map(Arc::new));
+}
+
+fn get_envelope_config() -> Result<Option<Arc<EnvelopeConfig>>, TransportError> {
+    ENVELOPE_CFG.read().unwrap_or_else(|e| e.into_inner()).clone()
+        .ok_or_else(|| TransportError::Setup("message envelope is not initialized".to_string()))
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Header {
+    pub major: u32,
+    pub minor: u32,
+    pub cluster: String,
+    pub sender: Option<ServerID>,
+    pub kind: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    header: Header,
+    body: serde_json::Value,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Rejection {
+    Malformed,
+    ForeignCluster,
+    IncompatibleVersion,
+    UnknownKind,
+    Undecodable,
+}
+
+impl Rejection {
+    pub const ALL: [Rejection; 5] = [Rejection::Malformed, Rejection::ForeignCluster, Rejection::IncompatibleVersion,
+                                     Rejection::UnknownKind, Rejection::Undecodable];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Rejection::Malformed => "malformed",
+            Rejection::ForeignCluster => "foreign_cluster",
+            Rejection::IncompatibleVersion => "incompatible_version",
+            Rejection::UnknownKind => "unknown_kind",
+            Rejection::Undecodable => "undecodable",
+        }
+    }
+}
+
+// rejected messages of the whole process by reason, in the order of Rejection::ALL
+static REJECTED: [AtomicUsize; 5] = [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+                                     AtomicUsize::new(0), AtomicUsize::new(0)];
+
+pub fn envelope_rejections() -> Vec<(Rejection, u64)> {
+    Rejection::ALL.iter().map(|r| (*r, REJECTED[*r as usize].load(Ordering::Relaxed) as u64)).collect()
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct EnvelopeStats {
+    pub accepted: u64,
+    pub rejected: HashMap<Rejection, u64>,
+}
+
+pub struct EnvelopeRecver<R> {
+    inner: R,
+    config: Option<Arc<EnvelopeConfig>>,
+    stats: EnvelopeStats,
+}
+
+impl<R> EnvelopeRecver<R> {
+    pub fn stats(&self) -> &EnvelopeStats {
+        &self.stats
+    }
+
+    pub fn get_inner(&self) -> &R {
+        &self.inner
+    }
+}
+
+// a misconfigured peer sends a steady stream of rejected messages, so only the
+// 1st, 2nd, 4th, 8th... of each reason is a warning
+fn reject(stats: &mut EnvelopeStats, reason: Rejection, header: Option<&Header>) {
+    *stats.rejected.entry(reason).or_insert(0) += 1;
+    let count = REJECTED[reason as usize].fetch_add(1, Ordering::Relaxed) + 1;
+    if count.is_power_of_two() {
+        log_warn!("messaging", { reason = reason, header = header, count = count }, "rejected message");
+    } else {
+        log_debug!("messaging", { reason = reason, header = header }, "rejected message");
+    }
+}
+
+fn open<T>(config: &EnvelopeConfig, stats: &mut EnvelopeStats, buf: &[u8]) -> Option<T> where
+    T: serde::de::DeserializeOwned + MessageKind {
+    let envelope: Envelope = match serde_json::from_slice(buf) {
+        Ok(e) => e,
+        Err(_) => {
+            reject(stats, Rejection::Malformed, None);
+            return None;
+        },
+    };
+    let header = envelope.header;
+    if header.cluster != config.cluster_id {
+        reject(stats, Rejection::ForeignCluster, Some(&header));
+        None
+    } else if header.major != PROTOCOL_MAJOR {
+        reject(stats, Rejection::IncompatibleVersion, Some(&header));
+        None
+    } else if !T::known_kinds().contains(&header.kind.as_str()) {
+        reject(stats, Rejection::UnknownKind, Some(&header));
+        None
+    } else {
+        match serde_json::from_value::<T>(envelope.body) {
+            Ok(msg) => {
+                if msg.kind() == header.kind {
+                    stats.accepted += 1;
+                    Some(msg)
+                } else {
+                    reject(stats, Rejection::Undecodable, Some(&header));
+                    None
+                }
+            },
+            Err(_) => {
+                reject(stats, Rejection::Undecodable, Some(&header));
+                None
+            },
+        }
+    }
+}
+
+fn wrap<T>(config: &EnvelopeConfig, msg: &T) -> Result<Vec<u8>, CodecError> where
+    T: serde::Serialize + MessageKind + Origin {
+    let body = serde_json::to_value(msg).map_err(|e| CodecError::Encode(e.to_string()))?;
+    let envelope = Envelope {
+        header: Header {
+            major: PROTOCOL_MAJOR,
+            minor: PROTOCOL_MINOR,
+            cluster: config.cluster_id.clone(),
+            sender: msg.claimed_sender(),
+            kind: msg.kind().to_string(),
+        },
+        body,
+    };
+    serde_json::to_vec(&envelope).map_err(|e| CodecError::Encode(e.to_string()))
+}
+
+// wraps the message if envelopes are on
+fn encode<T>(config: &Option<Arc<EnvelopeConfig>>, msg: &T) -> Result<Vec<u8>, CodecError> where
+    T: serde::Serialize + MessageKind + Origin {
+    match *config {
+        Some(ref config) => wrap(config, msg),
+        None => serde_json::to_vec(msg).map_err(|e| CodecError::Encode(e.to_string())),
+    }
+}
+
+impl<T, R> MsgRecver<T> for EnvelopeRecver<R> where
+    T: serde::Serialize + serde::de::DeserializeOwned + MessageKind + Origin,
+    R: MsgRecver<T> {
+    type Ctx = R::Ctx;
+
+    fn bind(addr: &Addr) -> Result<Self, Error> {
+        let config = get_envelope_config()?;
+        Ok(EnvelopeRecver {
+            inner: R::bind(addr)?,
+            config,
+            stats: Default::default(),
+        })
+    }
+
+    // hands out the still wrapped bytes, use try_recv to get checked messages
+    fn try_recv_str(&mut self) -> Option<Vec<u8>> {
+        self.inner.try_recv_str()
+    }
+
+    fn try_recv(&mut self) -> Option<T> {
+        while let Some(buf) = self.inner.try_recv_str() {
+            let msg = match self.config {
+                Some(ref config) => open(config, &mut self.stats, buf.as_slice()),
+                None => serde_json::from_slice(buf.as_slice()).ok(),
+            };
+            if let Some(msg) = msg {
+                if self.inner.accepts(&msg) {
+                    return Some(msg);
+                }
+            }
+        }
+        None
+    }
+
+    fn get_io_fds(&self) -> Vec<c_int> {
+        self.inner.get_io_fds()
+    }
+
+    fn pending(&self) -> bool {
+        self.inner.pending()
+    }
+
+    fn accepts(&mut self, msg: &T) -> bool {
+        self.inner.accepts(msg)
+    }
+
+    fn last_route(&self) -> Option<Vec<u8>> {
+        self.inner.last_route()
+    }
+
+    fn reply_str(&mut self, route: &[u8], s: &[u8]) -> Result<(), Error> {
+        self.inner.reply_str(route, s)
+    }
+
+    fn reply(&mut self, route: &[u8], msg: &T) -> Result<(), Error> {
+        let buf = encode(&self.config, msg)?;
+        self.inner.reply_str(route, buf.as_slice())
+    }
+}
+
+pub struct EnvelopeSender<S> {
+    inner: S,
+    config: Option<Arc<EnvelopeConfig>>,
+    stats: EnvelopeStats,
+}
+
+impl<S> EnvelopeSender<S> {
+    // counts the replies received over this connection
+    pub fn stats(&self) -> &EnvelopeStats {
+        &self.stats
+    }
+}
+
+impl<T, S> MsgSender<T> for EnvelopeSender<S> where
+    T: serde::Serialize + serde::de::DeserializeOwned + MessageKind + Origin,
+    S: MsgSender<T> {
+
+    fn connect(addr: &Addr) -> Result<Self, Error> {
+        let config = get_envelope_config()?;
+        Ok(EnvelopeSender {
+            inner: S::connect(addr)?,
+            config,
+            stats: Default::default(),
+        })
+    }
+
+    fn send_str(&mut self, s: &[u8]) -> Result<(), Error> {
+        self.inner.send_str(s)
+    }
+
+    fn send(&mut self, msg: &T) -> Result<(), Error> {
+        let buf = encode(&self.config, msg)?;
+        self.inner.send_str_from(msg.claimed_sender(), buf.as_slice())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.inner.flush()
+    }
+
+    fn try_recv_reply_str(&mut self, timeout_ms: i64) -> Option<Vec<u8>> {
+        self.inner.try_recv_reply_str(timeout_ms)
+    }
+
+    fn reply_fds(&self) -> Vec<c_int> {
+        self.inner.reply_fds()
+    }
+
+    fn try_recv_reply(&mut self, timeout_ms: i64) -> Option<T> {
+        let buf = self.inner.try_recv_reply_str(timeout_ms)?;
+        match self.config {
+            Some(ref config) => open(config, &mut self.stats, buf.as_slice()),
+            None => serde_json::from_slice(buf.as_slice()).ok(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Msg = Message<u64, u64>;
+
+    fn config(cluster_id: &str) -> EnvelopeConfig {
+        EnvelopeConfig { cluster_id: cluster_id.to_string() }
+    }
+
+    fn frame(major: u32, minor: u32, cluster: &str) -> Vec<u8> {
+        let msg: Msg = Message::P2b { sender: 3, ballot: Ballot::zero(1), slot: 7 };
+        let mut envelope: serde_json::Value = serde_json::from_slice(&wrap(&config(cluster), &msg).unwrap()).unwrap();
+        envelope["header"]["major"] = major.into();
+        envelope["header"]["minor"] = minor.into();
+        serde_json::to_vec(&envelope).unwrap()
+    }
+
+    fn opened(buf: &[u8]) -> (Option<Msg>, EnvelopeStats) {
+        let mut stats = EnvelopeStats::default();
+        let msg = open(&config("prod"), &mut stats, buf);
+        (msg, stats)
+    }
+
+    #[test]
+    fn other_majors_are_rejected() {
+        let (msg, stats) = opened(&frame(PROTOCOL_MAJOR + 1, PROTOCOL_MINOR, "prod"));
+        assert!(msg.is_none());
+        assert_eq!(stats.rejected.get(&Rejection::IncompatibleVersion), Some(&1));
+        assert_eq!(stats.accepted, 0);
+    }
+
+    #[test]
+    fn other_clusters_are_rejected() {
+        let (msg, stats) = opened(&frame(PROTOCOL_MAJOR, PROTOCOL_MINOR, "staging"));
+        assert!(msg.is_none());
+        assert_eq!(stats.rejected.get(&Rejection::ForeignCluster), Some(&1));
+    }
+
+    #[test]
+    fn any_minor_of_the_same_major_is_read() {
+        for minor in &[0, PROTOCOL_MINOR, PROTOCOL_MINOR + 1] {
+            let (msg, stats) = opened(&frame(PROTOCOL_MAJOR, *minor, "prod"));
+            match msg {
+                Some(Message::P2b { sender: 3, slot: 7, .. }) => (),
+                m => panic!("minor {} gave {:?}", minor, m),
+            }
+            assert_eq!(stats.accepted, 1);
+        }
+    }
+
+    #[test]
+    fn unknown_kinds_and_garbage_are_counted() {
+        let mut envelope: serde_json::Value = serde_json::from_slice(&frame(PROTOCOL_MAJOR, PROTOCOL_MINOR, "prod")).unwrap();
+        envelope["header"]["kind"] = "FromTheFuture".into();
+        let before = envelope_rejections();
+        let (msg, stats) = opened(&serde_json::to_vec(&envelope).unwrap());
+        assert!(msg.is_none());
+        assert_eq!(stats.rejected.get(&Rejection::UnknownKind), Some(&1));
+        assert!(opened(b"not json").0.is_none());
+
+        let after = envelope_rejections();
+        let grew = |reason| before.iter().zip(after.iter()).any(|((r, b), (_, a))| *r == reason && a > b);
+        assert!(grew(Rejection::UnknownKind));
+        assert!(grew(Rejection::Malformed));
+    }
+}
@@ -0,0 +1,213 @@
+// Errors of the transports, the message codecs, the protocol roles and clients.
+// OS failures keep their errno, so callers can still tell a peer that is merely
+// busy (WouldBlock) from one that is gone.
+use std::fmt;
+use std::io;
+use messages::ServerID;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum TransportError {
+    // the peer cannot take more right now, the message may be retried
+    WouldBlock,
+    // the host name did not resolve
+    Resolve(String),
+    // any other OS level failure, by errno
+    Os(i32),
+    // the message does not fit into the transport's frames
+    TooLarge,
+    // only part of the message went out
+    Truncated,
+    // the transport cannot do this, e.g. answer over the request's connection
+    Unsupported,
+    // there is no key or identity to authenticate the peer with
+    Unauthenticated,
+    // the destination's queue is full, the message was dropped
+    QueueFull,
+    // this many messages were still queued when the deadline passed
+    Undelivered(usize),
+    // a socket or tls context could not be set up
+    Setup(String),
+}
+
+impl TransportError {
+    pub fn from_errno(eno: i32) -> Self {
+        if eno == libc::EAGAIN || eno == libc::EWOULDBLOCK {
+            TransportError::WouldBlock
+        } else if eno == libc::EMSGSIZE {
+            TransportError::TooLarge
+        } else {
+            TransportError::Os(eno)
+        }
+    }
+
+    // the closest errno, for exit statuses and C callers
+    pub fn errno(&self) -> i32 {
+        match self {
+            TransportError::WouldBlock => libc::EAGAIN,
+            TransportError::Resolve(_) => libc::EHOSTUNREACH,
+            TransportError::Os(eno) => *eno,
+            TransportError::TooLarge => libc::EMSGSIZE,
+            TransportError::Truncated => libc::EIO,
+            TransportError::Unsupported => libc::EOPNOTSUPP,
+            TransportError::Unauthenticated => libc::EACCES,
+            TransportError::QueueFull => libc::ENOBUFS,
+            TransportError::Undelivered(_) => libc::ETIMEDOUT,
+            TransportError::Setup(_) => libc::EINVAL,
+        }
+    }
+}
+
+impl From<io::Error> for TransportError {
+    fn from(e: io::Error) -> Self {
+        match e.raw_os_error() {
+            Some(eno) => TransportError::from_errno(eno),
+            None if e.kind() == io::ErrorKind::WouldBlock => TransportError::WouldBlock,
+            None => TransportError::Setup(e.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransportError::WouldBlock => write!(f, "peer would block"),
+            TransportError::Resolve(host) => write!(f, "cannot resolve {}", host),
+            TransportError::Os(eno) => write!(f, "{}", io::Error::from_raw_os_error(*eno)),
+            TransportError::TooLarge => write!(f, "message too large"),
+            TransportError::Truncated => write!(f, "message only partly sent"),
+            TransportError::Unsupported => write!(f, "not supported by the transport"),
+            TransportError::Unauthenticated => write!(f, "no key for the peer"),
+            TransportError::QueueFull => write!(f, "send queue full"),
+            TransportError::Undelivered(n) => write!(f, "{} messages left undelivered", n),
+            TransportError::Setup(e) => write!(f, "setup failed: {}", e),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum CodecError {
+    Encode(String),
+    Decode(String),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CodecError::Encode(e) => write!(f, "cannot encode message: {}", e),
+            CodecError::Decode(e) => write!(f, "cannot decode message: {}", e),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum ProtocolError {
+    // the leader ran out of ballot numbers
+    BallotExhausted(ServerID),
+    // this many client requests were neither answered nor handed off at shutdown
+    Unfinished(usize),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolError::BallotExhausted(id) => write!(f, "leader {} ran out of ballots", id),
+            ProtocolError::Unfinished(n) => write!(f, "{} requests left unfinished", n),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum ClientError {
+    // no replica to send the request to
+    NoReplicas,
+    // no answer within the client's timeout
+    Timeout,
+    // the replica turned the request away, with its reason
+    Rejected(String),
+    // the server answered with something other than a response, by kind
+    UnexpectedReply(&'static str),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::NoReplicas => write!(f, "no replicas configured"),
+            ClientError::Timeout => write!(f, "timed out"),
+            ClientError::Rejected(reason) => write!(f, "rejected: {}", reason),
+            ClientError::UnexpectedReply(kind) => write!(f, "unexpected {} reply", kind),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Error {
+    Transport(TransportError),
+    Codec(CodecError),
+    Protocol(ProtocolError),
+    Client(ClientError),
+}
+
+impl Error {
+    pub fn errno(&self) -> i32 {
+        match self {
+            Error::Transport(e) => e.errno(),
+            Error::Codec(_) => libc::EBADMSG,
+            Error::Protocol(ProtocolError::Unfinished(_)) => libc::ETIMEDOUT,
+            Error::Protocol(_) => libc::EOVERFLOW,
+            Error::Client(ClientError::NoReplicas) => libc::EHOSTUNREACH,
+            Error::Client(ClientError::Timeout) => libc::ETIMEDOUT,
+            Error::Client(ClientError::Rejected(_)) => libc::EBUSY,
+            Error::Client(ClientError::UnexpectedReply(_)) => libc::EPROTO,
+        }
+    }
+
+    // true if trying again later may succeed
+    pub fn is_transient(&self) -> bool {
+        matches!(self,
+                 Error::Transport(TransportError::WouldBlock) | Error::Transport(TransportError::QueueFull) |
+                 Error::Client(ClientError::Timeout) | Error::Client(ClientError::Rejected(_)))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Transport(e) => write!(f, "transport: {}", e),
+            Error::Codec(e) => write!(f, "codec: {}", e),
+            Error::Protocol(e) => write!(f, "protocol: {}", e),
+            Error::Client(e) => write!(f, "client: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<TransportError> for Error {
+    fn from(e: TransportError) -> Self {
+        Error::Transport(e)
+    }
+}
+
+impl From<CodecError> for Error {
+    fn from(e: CodecError) -> Self {
+        Error::Codec(e)
+    }
+}
+
+impl From<ProtocolError> for Error {
+    fn from(e: ProtocolError) -> Self {
+        Error::Protocol(e)
+    }
+}
+
+impl From<ClientError> for Error {
+    fn from(e: ClientError) -> Self {
+        Error::Client(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Transport(e.into())
+    }
+}
@@ -0,0 +1,480 @@
+// Local cluster launcher. Starts every server of a cluster config as its own
+// process (`<bin> --config <file> <role> <index>`), prefixes their output with
+// the node's name, restarts nodes that exit without being asked to, and takes
+// commands on stdin:
+//
+//     list               state, pid and restart count of every node
+//     kill <node>        SIGKILL, the node is restarted as after a crash
+//     stop <node>        SIGTERM, the node stays down
+//     start <node>       starts a stopped node
+//     restart <node>     SIGTERM, then starts the node again
+//     reload [<node>]    SIGHUP, the node reloads its settings; every node without a name
+//     quit               stops every node and exits
+//
+// Nodes are named by role and id (`replica-0`) or by the id alone. With a log
+// directory each node's output also goes to `<name>.log` and all of it, prefixed,
+// to `all.log`. Restarts back off exponentially while a node keeps crashing.
+use std::fs::{ self, File, OpenOptions };
+use std::io::{ self, BufRead, BufReader, Read, Write };
+use std::path::{ Path, PathBuf };
+use std::process::{ Child, Command, Stdio };
+use std::sync::{ Arc, Mutex };
+use std::sync::mpsc::{ channel, Receiver, TryRecvError };
+use std::thread;
+use std::time::{ Duration, Instant };
+use config::*;
+use messages::*;
+use shutdown::*;
+
+const POLL_MS: u64 = 100;
+// a node that stayed up this long starts over with the shortest backoff
+const STABLE_MS: u64 = 10_000;
+
+#[derive(Clone, Debug)]
+pub struct LaunchSettings {
+    // the server binary, it must take the Runner subcommands
+    pub bin: PathBuf,
+    // passed on as --config, servers use the local test cluster without it
+    pub config_path: Option<String>,
+    // passed on as --log
+    pub log_spec: Option<String>,
+    pub log_dir: Option<PathBuf>,
+    pub restart: bool,
+    pub min_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    // how long nodes get to exit after SIGTERM before they are killed
+    pub grace_ms: u64,
+}
+
+impl LaunchSettings {
+    pub fn new(bin: PathBuf, config_path: Option<&str>) -> Self {
+        LaunchSettings {
+            bin,
+            config_path: config_path.map(|p| p.to_string()),
+            log_spec: None,
+            log_dir: None,
+            restart: true,
+            min_backoff_ms: 500,
+            max_backoff_ms: 10_000,
+            grace_ms: 3000,
+        }
+    }
+}
+
+type SharedLog = Arc<Mutex<File>>;
+
+struct Proc {
+    name: String,
+    role: Role,
+    id: ServerID,
+    // position among the servers of its role, the subcommand argument
+    index: usize,
+    child: Option<Child>,
+    // false once stopped on command, the node is not restarted then
+    wanted: bool,
+    started_at: Instant,
+    restart_at: Option<Instant>,
+    backoff_ms: u64,
+    restarts: u64,
+    last_exit: Option<String>,
+    log: Option<SharedLog>,
+}
+
+pub struct Launcher {
+    settings: LaunchSettings,
+    procs: Vec<Proc>,
+    name_width: usize,
+    all_log: Option<SharedLog>,
+}
+
+fn open_log(dir: &Path, name: &str) -> Result<SharedLog, String> {
+    let path = dir.join(format!("{}.log", name));
+    OpenOptions::new().create(true).write(true).truncate(true).open(&path)
+        .map(|f| Arc::new(Mutex::new(f)))
+        .map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+fn describe_exit(status: std::process::ExitStatus) -> String {
+    use std::os::unix::process::ExitStatusExt;
+    match (status.code(), status.signal()) {
+        (Some(code), _) => format!("exit {}", code),
+        (None, Some(sig)) => format!("signal {}", sig),
+        _ => "unknown".to_string(),
+    }
+}
+
+// copies a child's output line by line, prefixed with its name
+fn forward<R: Read + Send + 'static>(out: R, prefix: String, log: Option<SharedLog>, all_log: Option<SharedLog>) {
+    thread::spawn(move || {
+        for line in BufReader::new(out).lines() {
+            let line = match line {
+                Ok(l) => l,
+                Err(_) => break,
+            };
+            let prefixed = format!("{} | {}\n", prefix, line);
+            let _ = io::stdout().write_all(prefixed.as_bytes());
+            if let Some(f) = log.as_ref() {
+                let _ = writeln!(f.lock().unwrap_or_else(|e| e.into_inner()), "{}", line);
+            }
+            if let Some(f) = all_log.as_ref() {
+                let _ = f.lock().unwrap_or_else(|e| e.into_inner()).write_all(prefixed.as_bytes());
+            }
+        }
+    });
+}
+
+impl Launcher {
+    pub fn new(settings: LaunchSettings, config: &ClusterConfig) -> Result<Self, String> {
+        if let Some(ref dir) = settings.log_dir {
+            fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        }
+        let mut procs = Vec::new();
+        for role in [Role::Replica, Role::Leader, Role::Acceptor].iter() {
+            for (index, (id, _)) in config.servers_with(*role).into_iter().enumerate() {
+                let name = format!("{}-{}", role.name(), id);
+                let log = match settings.log_dir {
+                    Some(ref dir) => Some(open_log(dir, name.as_str())?),
+                    None => None,
+                };
+                procs.push(Proc {
+                    name,
+                    role: *role,
+                    id,
+                    index,
+                    child: None,
+                    wanted: true,
+                    started_at: Instant::now(),
+                    restart_at: None,
+                    backoff_ms: settings.min_backoff_ms,
+                    restarts: 0,
+                    last_exit: None,
+                    log,
+                });
+            }
+        }
+        let all_log = match settings.log_dir {
+            Some(ref dir) => Some(open_log(dir, "all")?),
+            None => None,
+        };
+        let name_width = procs.iter().map(|p| p.name.len()).max().unwrap_or(0);
+        Ok(Launcher {
+            settings,
+            procs,
+            name_width,
+            all_log,
+        })
+    }
+
+    // a node by name or id
+    fn find(&self, node: &str) -> Result<usize, String> {
+        self.procs.iter()
+            .position(|p| p.name == node || p.id.to_string() == node)
+            .ok_or(format!("no node {}", node))
+    }
+
+    fn start(&mut self, i: usize) -> Result<(), String> {
+        let settings = &self.settings;
+        let p = &mut self.procs[i];
+        let mut cmd = Command::new(&settings.bin);
+        if let Some(ref path) = settings.config_path {
+            cmd.arg("--config").arg(path);
+        }
+        if let Some(ref spec) = settings.log_spec {
+            cmd.arg("--log").arg(spec);
+        }
+        cmd.arg(p.role.name()).arg(p.index.to_string())
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let mut child = cmd.spawn().map_err(|e| format!("failed to start {}: {}", p.name, e))?;
+        let prefix = format!("{:<w$}", p.name, w = self.name_width);
+        let all_log = &self.all_log;
+        if let Some(out) = child.stdout.take() {
+            forward(out, prefix.clone(), p.log.clone(), all_log.clone());
+        }
+        if let Some(err) = child.stderr.take() {
+            forward(err, prefix, p.log.clone(), all_log.clone());
+        }
+        log_info!("launcher", { node = p.name, pid = child.id() }, "started");
+        p.child = Some(child);
+        p.wanted = true;
+        p.started_at = Instant::now();
+        p.restart_at = None;
+        Ok(())
+    }
+
+    fn signal(&mut self, i: usize, sig: libc::c_int) {
+        let p = &mut self.procs[i];
+        p.child.as_ref().map(|c| unsafe { libc::kill(c.id() as libc::pid_t, sig) });
+    }
+
+    pub fn start_all(&mut self) -> Result<(), String> {
+        (0..self.procs.len()).try_for_each(|i| self.start(i))
+    }
+
+    // reaps nodes that exited and restarts those that are due
+    pub fn poll(&mut self) {
+        let now = Instant::now();
+        let (restart, min_backoff, max_backoff) =
+            (self.settings.restart, self.settings.min_backoff_ms, self.settings.max_backoff_ms);
+        for p in self.procs.iter_mut() {
+            let status = match p.child.as_mut().map(|c| c.try_wait()) {
+                Some(Ok(Some(status))) => status,
+                _ => continue,
+            };
+            p.child = None;
+            p.last_exit = Some(describe_exit(status));
+            if !p.wanted || !restart {
+                log_info!("launcher", { node = p.name, status = p.last_exit.as_ref().unwrap() }, "exited");
+                p.wanted = false;
+                continue;
+            }
+            if now.duration_since(p.started_at) >= Duration::from_millis(STABLE_MS) {
+                p.backoff_ms = min_backoff;
+            }
+            log_warn!("launcher", { node = p.name, status = p.last_exit.as_ref().unwrap(), restart_in_ms = p.backoff_ms },
+                      "crashed");
+            p.restart_at = Some(now + Duration::from_millis(p.backoff_ms));
+            p.backoff_ms = std::cmp::min(p.backoff_ms * 2, max_backoff);
+        }
+        let due = self.procs.iter().enumerate()
+            .filter(|(_, p)| p.wanted && p.child.is_none() && p.restart_at.is_some_and(|t| t <= now))
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        for i in due {
+            self.procs[i].restarts += 1;
+            if let Err(e) = self.start(i) {
+                log_error!("launcher", "{}", e);
+                let p = &mut self.procs[i];
+                p.restart_at = Some(now + Duration::from_millis(p.backoff_ms));
+                p.backoff_ms = std::cmp::min(p.backoff_ms * 2, max_backoff);
+            }
+        }
+    }
+
+    // sends SIGTERM, the node is not restarted after it exits
+    fn terminate(&mut self, i: usize) {
+        self.procs[i].wanted = false;
+        self.procs[i].restart_at = None;
+        self.signal(i, libc::SIGTERM);
+    }
+
+    // waits for a terminated node to exit, it is killed after the deadline
+    fn reap(&mut self, i: usize, deadline: Instant) {
+        let p = &mut self.procs[i];
+        while let Some(ref mut child) = p.child {
+            match child.try_wait() {
+                Ok(Some(status)) => {
+                    p.last_exit = Some(describe_exit(status));
+                    p.child = None;
+                },
+                Ok(None) if Instant::now() < deadline => thread::sleep(Duration::from_millis(10)),
+                _ => {
+                    log_warn!("launcher", { node = p.name }, "did not stop in time, killing");
+                    let _ = child.kill();
+                    p.last_exit = child.wait().ok().map(describe_exit);
+                    p.child = None;
+                },
+            }
+        }
+        log_info!("launcher", { node = p.name, status = p.last_exit.as_ref().map_or("", |s| s.as_str()) }, "stopped");
+    }
+
+    fn stop(&mut self, i: usize) {
+        self.terminate(i);
+        let deadline = Instant::now() + Duration::from_millis(self.settings.grace_ms);
+        self.reap(i, deadline);
+    }
+
+    // stops every node at once, returns the exit status
+    pub fn stop_all(&mut self) -> i32 {
+        (0..self.procs.len()).for_each(|i| self.terminate(i));
+        let deadline = Instant::now() + Duration::from_millis(self.settings.grace_ms);
+        (0..self.procs.len()).for_each(|i| self.reap(i, deadline));
+        let clean = self.procs.iter().all(|p| p.last_exit.as_ref().is_none_or(|s| s == "exit 0"));
+        if clean { EXIT_CLEAN } else { EXIT_UNCLEAN }
+    }
+
+    pub fn list(&self) -> String {
+        let now = Instant::now();
+        self.procs.iter().map(|p| {
+            let state = match (&p.child, p.restart_at) {
+                (Some(c), _) => format!("running pid {} for {}s", c.id(), now.duration_since(p.started_at).as_secs()),
+                (None, Some(t)) => format!("restarting in {}ms", t.saturating_duration_since(now).as_millis()),
+                (None, None) => "stopped".to_string(),
+            };
+            format!("{:<w$}  {:<28}  restarts {}  last exit {}\n", p.name, state, p.restarts,
+                    p.last_exit.as_ref().map_or("-", |s| s.as_str()), w = self.name_width)
+        }).collect()
+    }
+
+    // handles one command line, Ok(false) for quit
+    pub fn command(&mut self, line: &str) -> Result<bool, String> {
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        match words.as_slice() {
+            [] => (),
+            ["list"] => print!("{}", self.list()),
+            ["kill", node] => {
+                let i = self.find(node)?;
+                if self.procs[i].child.is_none() {
+                    return Err(format!("{} is not running", node));
+                }
+                log_info!("launcher", { node = self.procs[i].name }, "killing");
+                self.signal(i, libc::SIGKILL);
+            },
+            ["stop", node] => {
+                let i = self.find(node)?;
+                self.stop(i);
+            },
+            ["start", node] => {
+                let i = self.find(node)?;
+                if self.procs[i].child.is_some() {
+                    return Err(format!("{} is already running", node));
+                }
+                self.start(i)?;
+            },
+            ["restart", node] => {
+                let i = self.find(node)?;
+                self.stop(i);
+                self.start(i)?;
+            },
+            ["reload"] => (0..self.procs.len()).for_each(|i| self.signal(i, libc::SIGHUP)),
+            ["reload", node] => {
+                let i = self.find(node)?;
+                self.signal(i, libc::SIGHUP);
+            },
+            ["quit"] => return Ok(false),
+            _ => return Err(format!("unknown command: {} (list, kill, stop, start, restart, reload or quit)",
+                                    line.trim())),
+        }
+        Ok(true)
+    }
+
+    // starts the cluster and supervises it until SIGTERM/SIGINT or `quit`,
+    // returns the exit status
+    pub fn run(mut self) -> Result<i32, String> {
+        install_signal_handlers().map_err(|e| format!("failed to install signal handlers: {}", e))?;
+        self.start_all()?;
+        let commands = read_commands();
+        while !shutdown_requested() {
+            match commands.try_recv() {
+                Ok(line) => match self.command(line.as_str()) {
+                    Ok(true) => (),
+                    Ok(false) => break,
+                    Err(e) => log_error!("launcher", "{}", e),
+                },
+                // stdin closed, keep supervising until a signal arrives
+                Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => {
+                    thread::sleep(Duration::from_millis(POLL_MS));
+                },
+            }
+            self.poll();
+        }
+        log_info!("launcher", "stopping all nodes");
+        Ok(self.stop_all())
+    }
+}
+
+fn read_commands() -> Receiver<String> {
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            match line {
+                Ok(l) => if tx.send(l).is_err() { break },
+                Err(_) => break,
+            }
+        }
+    });
+    rx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::Once;
+
+    // replicas exit right away, every other role runs until it is signalled
+    fn node_script() -> PathBuf {
+        static WRITE: Once = Once::new();
+        let path = std::env::temp_dir().join(format!("launcher-test-{}.sh", std::process::id()));
+        // written once before any test forks, a write fd open across a fork fails the exec
+        WRITE.call_once(|| {
+            fs::write(&path, "#!/bin/sh\ncase \"$1\" in replica) exit 3;; *) exec sleep 5;; esac\n").unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        });
+        path
+    }
+
+    fn launcher(id: ServerID, restart: bool) -> Launcher {
+        let mut config = ClusterConfig::local();
+        config.servers.retain(|s| s.id == id);
+        let mut settings = LaunchSettings::new(node_script(), None);
+        settings.restart = restart;
+        settings.min_backoff_ms = 20;
+        settings.max_backoff_ms = 50;
+        settings.grace_ms = 1000;
+        Launcher::new(settings, &config).unwrap()
+    }
+
+    fn poll_until<F: Fn(&Proc) -> bool>(l: &mut Launcher, done: F) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done(&l.procs[0]) {
+            assert!(Instant::now() < deadline, "timed out: {}", l.list());
+            thread::sleep(Duration::from_millis(5));
+            l.poll();
+        }
+    }
+
+    #[test]
+    fn crashed_nodes_come_back_after_a_growing_backoff() {
+        let mut l = launcher(0, true);
+        l.start_all().unwrap();
+        // pauses of 20, 40 and 50 ms, the backoff stays at the cap
+        poll_until(&mut l, |p| p.restarts == 3);
+        assert_eq!(l.procs[0].backoff_ms, 50);
+        assert_eq!(l.procs[0].last_exit, Some("exit 3".to_string()));
+        // a node that stayed up long enough starts over with the shortest backoff
+        l.procs[0].started_at -= Duration::from_millis(STABLE_MS);
+        poll_until(&mut l, |p| p.restart_at.is_some());
+        assert_eq!(l.procs[0].backoff_ms, 40);
+        assert!(l.list().contains("restarting in"));
+    }
+
+    #[test]
+    fn without_restarts_a_crashed_node_stays_down() {
+        let mut l = launcher(0, false);
+        l.start_all().unwrap();
+        poll_until(&mut l, |p| p.child.is_none());
+        thread::sleep(Duration::from_millis(50));
+        l.poll();
+        let p = &l.procs[0];
+        assert!(!p.wanted && p.child.is_none() && p.restart_at.is_none());
+        assert_eq!(p.restarts, 0);
+        assert!(l.list().contains("stopped"));
+        assert_eq!(l.stop_all(), EXIT_UNCLEAN);
+    }
+
+    #[test]
+    fn stopped_nodes_stay_down_and_killed_ones_restart() {
+        let mut l = launcher(20, true);
+        l.start_all().unwrap();
+        assert_eq!(l.command("stop acceptor-20"), Ok(true));
+        assert_eq!(l.procs[0].last_exit, Some("signal 15".to_string()));
+        l.poll();
+        assert!(l.procs[0].child.is_none() && l.procs[0].restart_at.is_none());
+        assert_eq!(l.command("kill 20"), Err("20 is not running".to_string()));
+
+        assert_eq!(l.command("start 20"), Ok(true));
+        assert_eq!(l.command("start 20"), Err("20 is already running".to_string()));
+        assert_eq!(l.command("kill acceptor-20"), Ok(true));
+        poll_until(&mut l, |p| p.restarts == 1 && p.child.is_some());
+        assert_eq!(l.procs[0].last_exit, Some("signal 9".to_string()));
+
+        assert!(l.command("kill 21").is_err());
+        assert!(l.command("bogus").is_err());
+        assert_eq!(l.command("quit"), Ok(false));
+        assert_eq!(l.stop_all(), EXIT_UNCLEAN);
+    }
+}
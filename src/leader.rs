@@ -1,7 +1,38 @@
 use std::collections::HashSet;
 use std::collections::HashMap;
-use std::time::{ Duration, SystemTime };
+use clock::*;
 use messages::*;
+use status::*;
+use error::ProtocolError;
+
+// defaults of LeaderTimeouts
+pub const P1A_RETRY_MS: u64 = 1000;
+pub const RETRANSMIT_MS: u64 = 500;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct LeaderTimeouts {
+    // phase 1 is retried this often until a majority adopts the ballot
+    pub p1a_retry_ms: u64,
+    // P2a messages without a P2b are sent again this often
+    pub retransmit_ms: u64,
+    // after being preempted the leader leaves the other ballot this long before
+    // competing again, 0 competes right away
+    pub lease_ms: u64,
+    // an active leader checks its ballot with the acceptors this often, so that
+    // it notices being preempted while idle; 0 turns it off
+    pub heartbeat_ms: u64,
+}
+
+impl Default for LeaderTimeouts {
+    fn default() -> Self {
+        LeaderTimeouts {
+            p1a_retry_ms: P1A_RETRY_MS,
+            retransmit_ms: RETRANSMIT_MS,
+            lease_ms: 0,
+            heartbeat_ms: 0,
+        }
+    }
+}
 
 pub struct Leader<'a, CmdT> {
     acceptors: &'a HashSet<ServerID>,
@@ -9,144 +40,402 @@ pub struct Leader<'a, CmdT> {
     waitfor: HashSet<ServerID>,
     is_active: bool,
     ballot: Ballot,
-    proposals: HashMap<u64, (CmdT, HashSet<ServerID>)>,
+    proposals: HashMap<u64, (CmdT, Option<RequestKey>, HashSet<ServerID>)>,
     server_id: ServerID,
-    now: SystemTime,
+    clock: SharedClock,
+    timeouts: LeaderTimeouts,
+    p1a_timer: Timer,
+    p2a_timer: Timer,
+    heartbeat_timer: Timer,
+    // phase 1 waits for this time after a preemption
+    deferred_until: Option<u64>,
 }
 
 impl<'a, CmdT> Leader<'a, CmdT> {
     pub fn new(acceptors: &'a HashSet<ServerID>, replicas: &'a HashSet<ServerID>, my_id: ServerID) -> Self {
         Leader {
-            acceptors: acceptors,
-            replicas: replicas,
+            acceptors,
+            replicas,
             waitfor: acceptors.clone(),
             is_active: false,
             ballot: Ballot::zero(my_id),
             proposals: HashMap::new(),
             server_id: my_id,
-            now: SystemTime::now(),
+            clock: system_clock(),
+            timeouts: Default::default(),
+            p1a_timer: Timer::new(P1A_RETRY_MS, 0),
+            p2a_timer: Timer::new(RETRANSMIT_MS, 0),
+            heartbeat_timer: Timer::new(0, 0),
+            deferred_until: None,
         }
     }
+
+    // restarts the timers on the new clock
+    pub fn set_clock(&mut self, clock: SharedClock) {
+        let now = clock.now_ms();
+        self.clock = clock;
+        self.p1a_timer.reset(now);
+        self.p2a_timer.reset(now);
+        self.heartbeat_timer.reset(now);
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: LeaderTimeouts) {
+        self.timeouts = timeouts;
+        self.p1a_timer.set_period(timeouts.p1a_retry_ms);
+        self.p2a_timer.set_period(timeouts.retransmit_ms);
+        self.heartbeat_timer.set_period(timeouts.heartbeat_ms);
+    }
+
+    pub fn timeouts(&self) -> LeaderTimeouts {
+        self.timeouts
+    }
+
+    pub fn ballot(&self) -> &Ballot {
+        &self.ballot
+    }
+
+    // true once a majority of acceptors adopted the current ballot
+    pub fn is_active(&self) -> bool {
+        self.is_active
+    }
+
+    pub fn proposal_count(&self) -> usize {
+        self.proposals.len()
+    }
 }
 
 impl<'a, CmdT> Leader<'a, CmdT> where
     CmdT: serde::Serialize + serde::de::DeserializeOwned + Clone + std::fmt::Debug {
+    // milliseconds until the next timer fires, None while no timer is armed
+    pub fn next_timer_ms(&self) -> Option<i64> {
+        let now = self.clock.now_ms();
+        if let Some(until) = self.deferred_until {
+            return Some(until.saturating_sub(now) as i64);
+        }
+        let retry = match (self.is_active, self.proposals.is_empty()) {
+            (_, true) => None,
+            (false, false) => self.p1a_timer.remaining_ms(now),
+            (true, false) => self.p2a_timer.remaining_ms(now),
+        };
+        let heartbeat = if self.is_active { self.heartbeat_timer.remaining_ms(now) } else { None };
+        match (retry, heartbeat) {
+            (Some(a), Some(b)) => Some(std::cmp::min(a, b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    pub fn status(&self) -> LeaderStatus {
+        let mut proposals = self.proposals.iter().map(|(slot, (cmd, _, waitfor))| ProposalStatus {
+            slot: *slot,
+            cmd: format!("{:?}", cmd),
+            waitfor: sorted_ids(waitfor.iter()),
+        }).collect::<Vec<_>>();
+        proposals.sort_by_key(|p| p.slot);
+        LeaderStatus {
+            id: self.server_id,
+            ballot: self.ballot.clone(),
+            is_active: self.is_active,
+            waitfor: sorted_ids(self.waitfor.iter()),
+            proposals,
+        }
+    }
+
     pub fn remove_before(&mut self, slot: u64) {
         // remove all proposals in range [0, slot)
-        self.proposals = std::mem::replace(&mut self.proposals, HashMap::new()).into_iter().filter(|(k, _v)| {
+        self.proposals = std::mem::take(&mut self.proposals).into_iter().filter(|(k, _v)| {
             *k < slot
         }).collect();
     }
 
-    pub fn handle_msg<ResultT>(&mut self, msg: &Message<CmdT, ResultT>) -> Vec<(ServerID, Message<CmdT, ResultT>)> where
+    pub fn handle_msg<ResultT>(&mut self, msg: &Message<CmdT, ResultT>)
+                               -> Result<ToServers<CmdT, ResultT>, ProtocolError> where
         ResultT: std::fmt::Debug {
-        let mut ret: Vec<(ServerID, Message<CmdT, ResultT>)> = Vec::new();
+        let mut ret: ToServers<CmdT, ResultT> = Vec::new();
         match msg {
-            Message::Propose { slot, cmd } => {
-                self.proposals.insert(*slot, (cmd.clone(), self.acceptors.clone()));
+            Message::Propose { slot, cmd, req } => {
+                if self.proposals.is_empty() {
+                    self.p2a_timer.reset(self.clock.now_ms());
+                }
+                self.proposals.insert(*slot, (cmd.clone(), req.clone(), self.acceptors.clone()));
                 if self.is_active {
                     let mut msgs = self.acceptors.iter().map(|server| {
                         (*server, Message::P2a { sender: self.server_id, ballot: self.ballot.clone(), 
-                                                 slot: *slot, cmd: cmd.clone() })
+                                                 slot: *slot, cmd: cmd.clone(), req: req.clone() })
                     }).collect();
                     ret.append(&mut msgs);
                 }
-                //println!("{}: proposals: {:?}", std::process::id(), self.proposals);
+                log_trace!("leader", { id = self.server_id, slot = slot, proposals = self.proposals.len() }, "proposed");
             },
-            Message::P1b { sender, ballot, proposals } => {
+            Message::P1b { sender, ballot, proposals, reqs } => {
                 if *ballot == self.ballot && !self.is_active {
                     let mut p_max: HashMap<u64, (&Ballot, &CmdT)> = HashMap::new();
-                    proposals.into_iter().map(|(slot, b, c)| {
+                    proposals.iter().for_each(|(slot, b, c)| {
                         if p_max.contains_key(slot) {
                             p_max.insert(*slot, (b, c));
-                        } else {
-                            p_max.get_mut(slot).map(|r| {
-                                if *b > *r.0 {
-                                    r.0 = b;
-                                    r.1 = c;
-                                }
-                            });
+                        } else if let Some(r) = p_max.get_mut(slot) {
+                            if *b > *r.0 {
+                                r.0 = b;
+                                r.1 = c;
+                            }
                         }
-                    }).collect::<()>();
-                    p_max.into_iter().map(|(slot, v)| {
-                        self.proposals.insert(slot, (v.1.clone(), self.acceptors.clone()));
-                    }).collect::<()>();
+                    });
+                    let reqs = reqs.iter().cloned().collect::<HashMap<_, _>>();
+                    p_max.into_iter().for_each(|(slot, v)| {
+                        self.proposals.insert(slot, (v.1.clone(), reqs.get(&slot).cloned(), self.acceptors.clone()));
+                    });
                     self.waitfor.remove(sender);
-                    //println!("waitfor of {}: {:?}", self.server_id, self.waitfor);
+                    log_trace!("leader", { id = self.server_id, from = sender, waitfor = self.waitfor }, "got p1b");
                     if self.waitfor.len() <= self.acceptors.len() / 2 {
                         // got majority vote
-                        //println!("{} got majority vote", self.server_id);
+                        log_info!("leader", { id = self.server_id, ballot = self.ballot }, "adopted");
                         self.waitfor.clear();
-                        self.proposals.iter().map(|(slot, (cmd, _))| {
+                        self.proposals.iter().for_each(|(slot, (cmd, req, _))| {
                             let mut msgs = self.acceptors.iter().map(|server| {
                                 (*server, Message::P2a { sender: self.server_id, ballot: self.ballot.clone(),
-                                                         slot: *slot, cmd: cmd.clone() })
+                                                         slot: *slot, cmd: cmd.clone(), req: req.clone() })
                             }).collect();
                             ret.append(&mut msgs);
-                        }).collect::<()>();
+                        });
                         self.is_active = true;
+                        self.p2a_timer.reset(self.clock.now_ms());
+                        self.heartbeat_timer.reset(self.clock.now_ms());
                     }
                 } else {
-                    ret.append(&mut self.preempted(ballot))
+                    ret.append(&mut self.preempted(ballot)?)
                 }
             },
             Message::P2b { sender, ballot, slot } => {
                 if *ballot == self.ballot && self.is_active && self.proposals.contains_key(slot) {
-                    let mut proposals = std::mem::replace(&mut self.proposals, HashMap::new());
+                    let mut proposals = std::mem::take(&mut self.proposals);
                     let mut remove_entry = false;
-                    proposals.get_mut(slot).map(|(cmd, waitfor)| {
+                    if let Some((cmd, req, waitfor)) = proposals.get_mut(slot) {
                         waitfor.remove(sender);
                         if waitfor.len() <= self.acceptors.len() / 2 {
                             // can make decision, thus remove the entry
                             remove_entry = true;
+                            log_debug!("leader", { id = self.server_id, ballot = self.ballot, slot = slot }, "chosen");
                             let mut msgs = self.replicas.iter().map(|server| {
-                                (*server, Message::Decision { slot: *slot, cmd: cmd.clone()})
+                                (*server, Message::Decision { slot: *slot, cmd: cmd.clone(), req: req.clone() })
                             }).collect();
                             ret.append(&mut msgs);
                         }
-                    });
+                    }
                     if remove_entry {
                         proposals.remove(slot);
                     }
                     self.proposals = proposals;
                 } else {
-                    ret.append(&mut self.preempted(ballot))
+                    ret.append(&mut self.preempted(ballot)?)
                 }
             },
+            Message::HeartbeatAck { ballot, .. } => {
+                ret.append(&mut self.preempted(ballot)?)
+            },
             _ => (),
         };
-        if !self.proposals.is_empty() {
-            match self.now.elapsed() {
-                Ok(elapsed) => {
-                    if elapsed.as_secs() > 0 {
-                        let mut msgs = self.acceptors.iter().map(|server| {
-                            (*server, Message::P1a { sender: self.server_id, ballot: self.ballot.clone() })
-                        }).collect();
-                        //println!("{} p1a : {:?}", std::process::id(), msgs);
-                        ret.append(&mut msgs);
-                        self.now = SystemTime::now();
-                    }
-                }
-                Err(e) => {
-                    println!("Error: {:?}", e);
-                }
-            }
-        } else {
-            self.now = SystemTime::now();
+        ret.append(&mut self.on_timers());
+        Ok(ret)
+    }
+
+    fn on_timers<ResultT>(&mut self) -> ToServers<CmdT, ResultT> {
+        let now = self.clock.now_ms();
+        let mut ret = Vec::new();
+        match self.deferred_until {
+            Some(until) if now >= until => {
+                self.deferred_until = None;
+                log_debug!("leader", { id = self.server_id, ballot = self.ballot }, "lease expired, starting phase 1");
+                ret.append(&mut self.phase1(now));
+            },
+            Some(_) => return ret,
+            None => (),
+        }
+        if self.proposals.is_empty() {
+            self.p1a_timer.reset(now);
+            self.p2a_timer.reset(now);
+        } else if !self.is_active && self.p1a_timer.expired(now) {
+            log_debug!("leader", { id = self.server_id, ballot = self.ballot }, "retrying phase 1");
+            ret.append(&mut self.phase1(now));
+        } else if self.is_active && self.p2a_timer.expired(now) {
+            log_debug!("leader", { id = self.server_id, proposals = self.proposals.len() }, "retransmitting p2a");
+            self.proposals.iter().for_each(|(slot, (cmd, req, waitfor))| {
+                waitfor.iter().for_each(|server| {
+                    ret.push((*server, Message::P2a { sender: self.server_id, ballot: self.ballot.clone(),
+                                                      slot: *slot, cmd: cmd.clone(), req: req.clone() }));
+                });
+            });
+            self.p2a_timer.reset(now);
+        }
+        if !self.is_active {
+            self.heartbeat_timer.reset(now);
+        } else if self.heartbeat_timer.expired(now) {
+            // acceptors that moved on answer with a higher ballot, which preempts us
+            log_trace!("leader", { id = self.server_id, ballot = self.ballot }, "heartbeat");
+            self.acceptors.iter().for_each(|server| {
+                ret.push((*server, Message::Heartbeat { sender: self.server_id, ballot: self.ballot.clone() }));
+            });
+            self.heartbeat_timer.reset(now);
         }
         ret
     }
 
-    fn preempted<ResultT>(&mut self, b: &Ballot) -> Vec<(ServerID, Message<CmdT, ResultT>)> {
+    fn phase1<ResultT>(&mut self, now: u64) -> ToServers<CmdT, ResultT> {
+        self.p1a_timer.reset(now);
+        self.acceptors.iter().map(|server| {
+            (*server, Message::P1a { sender: self.server_id, ballot: self.ballot.clone() })
+        }).collect()
+    }
+
+    // a ballot that cannot be outbid leaves the leader inactive for good
+    fn preempted<ResultT>(&mut self, b: &Ballot) -> Result<ToServers<CmdT, ResultT>, ProtocolError> {
         if *b > self.ballot {
+            log_info!("leader", { id = self.server_id, ballot = self.ballot, by = b }, "preempted");
             self.is_active = false;
-            self.ballot = self.ballot.next().expect("ballot reaches maximum");
+            self.ballot = self.ballot.next().ok_or(ProtocolError::BallotExhausted(self.server_id))?;
             self.waitfor = self.acceptors.clone();
-            self.acceptors.iter().map(|server| {
-                (*server, Message::P1a { sender: self.server_id, ballot: self.ballot.clone() })
-            }).collect()
+            let now = self.clock.now_ms();
+            if self.timeouts.lease_ms > 0 {
+                // leave the other leader its lease instead of dueling for the acceptors
+                self.deferred_until = Some(now + self.timeouts.lease_ms);
+                Ok(Vec::new())
+            } else {
+                Ok(self.phase1(now))
+            }
         } else {
-            Vec::new()
+            Ok(Vec::new())
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    type Msg = Message<u64, u64>;
+
+    fn ids(ids: &[ServerID]) -> HashSet<ServerID> {
+        ids.iter().cloned().collect()
+    }
+
+    fn leader<'a>(acceptors: &'a HashSet<ServerID>, replicas: &'a HashSet<ServerID>,
+                  clock: &Arc<ManualClock>, timeouts: LeaderTimeouts) -> Leader<'a, u64> {
+        let mut leader = Leader::new(acceptors, replicas, 0);
+        leader.set_clock(clock.clone());
+        leader.set_timeouts(timeouts);
+        leader
+    }
+
+    fn adopt(leader: &mut Leader<u64>) {
+        for sender in &[1, 2] {
+            let p1b: Msg = Message::P1b { sender: *sender, ballot: leader.ballot().clone(),
+                                          proposals: Vec::new(), reqs: Vec::new() };
+            leader.handle_msg(&p1b).unwrap();
+        }
+        assert!(leader.is_active());
+    }
+
+    fn tick(leader: &mut Leader<u64>) -> ToServers<u64, u64> {
+        leader.handle_msg(&Msg::Tick).unwrap()
+    }
+
+    fn p1a_to(out: &ToServers<u64, u64>, ballot: &Ballot) -> HashSet<ServerID> {
+        out.iter().filter_map(|(id, m)| match m {
+            Message::P1a { ballot: b, .. } if b == ballot => Some(*id),
+            _ => None,
+        }).collect()
+    }
+
+    fn heartbeats_to(out: &ToServers<u64, u64>, ballot: &Ballot) -> HashSet<ServerID> {
+        out.iter().filter_map(|(id, m)| match m {
+            Message::Heartbeat { ballot: b, .. } if b == ballot => Some(*id),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn an_idle_leader_checks_its_ballot_every_heartbeat() {
+        let (acceptors, replicas) = (ids(&[1, 2, 3]), ids(&[1]));
+        let clock = Arc::new(ManualClock::new(0));
+        let timeouts = LeaderTimeouts { heartbeat_ms: 100, ..Default::default() };
+        let mut leader = leader(&acceptors, &replicas, &clock, timeouts);
+        adopt(&mut leader);
+        assert_eq!(leader.next_timer_ms(), Some(100));
+
+        clock.advance(99);
+        assert!(tick(&mut leader).is_empty());
+        clock.advance(1);
+        let ballot = leader.ballot().clone();
+        assert_eq!(heartbeats_to(&tick(&mut leader), &ballot), acceptors);
+        assert!(tick(&mut leader).is_empty());
+        assert_eq!(leader.next_timer_ms(), Some(100));
+
+        let ack: Msg = Message::HeartbeatAck { sender: 1, ballot: ballot.clone() };
+        assert!(leader.handle_msg(&ack).unwrap().is_empty());
+        assert!(leader.is_active());
+        let ack: Msg = Message::HeartbeatAck { sender: 2, ballot: Ballot::zero(5).next().unwrap() };
+        let out = leader.handle_msg(&ack).unwrap();
+        assert!(!leader.is_active());
+        assert_eq!(p1a_to(&out, leader.ballot()), acceptors);
+    }
+
+    #[test]
+    fn a_preempted_leader_leaves_the_other_its_lease() {
+        let (acceptors, replicas) = (ids(&[1, 2, 3]), ids(&[1]));
+        let clock = Arc::new(ManualClock::new(0));
+        let timeouts = LeaderTimeouts { lease_ms: 300, ..Default::default() };
+        let mut leader = leader(&acceptors, &replicas, &clock, timeouts);
+        adopt(&mut leader);
+
+        let higher = Ballot::zero(5).next().unwrap();
+        let p2b: Msg = Message::P2b { sender: 1, ballot: higher, slot: 1 };
+        assert!(leader.handle_msg(&p2b).unwrap().is_empty());
+        assert!(!leader.is_active());
+        assert_eq!(leader.next_timer_ms(), Some(300));
+
+        clock.advance(299);
+        assert!(tick(&mut leader).is_empty());
+        clock.advance(1);
+        let ballot = leader.ballot().clone();
+        assert_eq!(ballot, Ballot::zero(0).next().unwrap());
+        assert_eq!(p1a_to(&tick(&mut leader), &ballot), acceptors);
+    }
+
+    #[test]
+    fn without_a_lease_phase_1_restarts_and_is_retried() {
+        let (acceptors, replicas) = (ids(&[1, 2, 3]), ids(&[1]));
+        let clock = Arc::new(ManualClock::new(0));
+        let mut leader = leader(&acceptors, &replicas, &clock, Default::default());
+        adopt(&mut leader);
+        leader.handle_msg(&Msg::Propose { slot: 1, cmd: 7, req: None }).unwrap();
+
+        let higher = Ballot::zero(5).next().unwrap();
+        let p2b: Msg = Message::P2b { sender: 1, ballot: higher, slot: 1 };
+        let out = leader.handle_msg(&p2b).unwrap();
+        let ballot = leader.ballot().clone();
+        assert_eq!(p1a_to(&out, &ballot), acceptors);
+
+        clock.advance(P1A_RETRY_MS - 1);
+        assert!(tick(&mut leader).is_empty());
+        clock.advance(1);
+        assert_eq!(p1a_to(&tick(&mut leader), &ballot), acceptors);
+    }
+
+    #[test]
+    fn unanswered_p2a_are_sent_again() {
+        let (acceptors, replicas) = (ids(&[1, 2, 3]), ids(&[1]));
+        let clock = Arc::new(ManualClock::new(0));
+        let mut leader = leader(&acceptors, &replicas, &clock, Default::default());
+        adopt(&mut leader);
+        let out = leader.handle_msg(&Msg::Propose { slot: 1, cmd: 7, req: None }).unwrap();
+        assert_eq!(out.len(), 3);
+        let p2b: Msg = Message::P2b { sender: 1, ballot: leader.ballot().clone(), slot: 1 };
+        assert!(leader.handle_msg(&p2b).unwrap().is_empty());
+
+        clock.advance(RETRANSMIT_MS);
+        let resent = tick(&mut leader).into_iter().map(|(id, m)| match m {
+            Message::P2a { slot: 1, cmd: 7, .. } => id,
+            m => panic!("unexpected {:?}", m),
+        }).collect::<HashSet<_>>();
+        assert_eq!(resent, ids(&[2, 3]));
+    }
+}
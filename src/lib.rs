@@ -4,13 +4,42 @@ extern crate serde;
 extern crate serde_json;
 extern crate libc;
 extern crate rand;
+extern crate openssl;
+extern crate hex;
+extern crate futures;
+extern crate flate2;
+extern crate toml;
+extern crate clap;
+
+#[macro_use]
+pub mod logging;
+pub mod metrics;
+pub mod clock;
 
 pub mod statemachine;
 pub mod lockmachine;
+pub mod error;
 pub mod messages;
+pub mod status;
 pub mod messaging;
+pub mod tls;
+pub mod mcast;
+#[cfg(target_os = "linux")]
+pub mod mmsg;
+pub mod auth;
+pub mod envelope;
+pub mod compress;
 pub mod leader;
 pub mod acceptor;
 pub mod replica;
 
 pub mod node;
+pub mod reactor;
+pub mod shutdown;
+pub mod reload;
+pub mod threaded;
+pub mod outbox;
+pub mod async_io;
+pub mod config;
+pub mod runner;
+pub mod launcher;
@@ -0,0 +1,63 @@
+extern crate rs_parliament;
+use rs_parliament::config::*;
+use rs_parliament::launcher::*;
+use rs_parliament::shutdown::*;
+extern crate clap;
+use clap::{ App, Arg };
+use std::path::PathBuf;
+
+// lock_server next to this binary
+fn default_bin() -> PathBuf {
+    std::env::current_exe().ok()
+        .and_then(|p| p.parent().map(|d| d.join("lock_server")))
+        .unwrap_or(PathBuf::from("lock_server"))
+}
+
+fn main() {
+    let matches = App::new("lock_launcher")
+        .version("1.0")
+        .about("runs every node of a cluster locally, reads list/kill/stop/start/restart/reload/quit from stdin")
+        .arg(Arg::with_name("config")
+             .long("config")
+             .takes_value(true)
+             .help("cluster configuration file, defaults to a local test cluster"))
+        .arg(Arg::with_name("bin")
+             .long("bin")
+             .takes_value(true)
+             .help("server binary, defaults to lock_server next to the launcher"))
+        .arg(Arg::with_name("log")
+             .long("log")
+             .takes_value(true)
+             .help("log verbosity passed on to every node"))
+        .arg(Arg::with_name("log-dir")
+             .long("log-dir")
+             .takes_value(true)
+             .help("directory for <node>.log and all.log"))
+        .arg(Arg::with_name("no-restart")
+             .long("no-restart")
+             .help("leaves crashed nodes down"))
+        .get_matches();
+
+    let config = match ClusterConfig::load_or_local(matches.value_of("config")) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("lock_launcher: {}", e);
+            std::process::exit(EXIT_UNCLEAN);
+        },
+    };
+    let _ = config.apply();
+
+    let mut settings = LaunchSettings::new(matches.value_of("bin").map_or_else(default_bin, PathBuf::from),
+                                           matches.value_of("config"));
+    settings.log_spec = matches.value_of("log").map(|s| s.to_string());
+    settings.log_dir = matches.value_of("log-dir").map(PathBuf::from);
+    settings.restart = !matches.is_present("no-restart");
+
+    match Launcher::new(settings, &config).and_then(|l| l.run()) {
+        Ok(code) => std::process::exit(code),
+        Err(e) => {
+            eprintln!("lock_launcher: {}", e);
+            std::process::exit(EXIT_UNCLEAN);
+        },
+    }
+}
@@ -1,41 +1,19 @@
 extern crate rs_parliament;
-use rs_parliament::node::*;
 use rs_parliament::lockmachine::*;
 use rs_parliament::messaging::*;
-use rs_parliament::messages::*;
+use rs_parliament::config::*;
+use rs_parliament::runner::*;
 extern crate clap;
 use clap::{ App, Arg, SubCommand, AppSettings };
-use std::collections::{ HashMap, HashSet };
 
 fn main() {
-    let lh = "127.0.0.1";
-
-    // replica
-    let replica_vec = vec![(0, Addr::new(lh, 8000)),
-                           (1, Addr::new(lh, 8001))];
-    // leader
-    let leader_vec = vec![(10, Addr::new(lh, 9001)),
-                          (11, Addr::new(lh, 9002))];
-    // acceptor
-    let acceptor_vec = vec![(20, Addr::new(lh, 9101)),
-                            (21, Addr::new(lh, 9102)),
-                            (22, Addr::new(lh, 9103))];
-
-    let replicas: HashMap<_, _> = replica_vec.into_iter().collect();
-    let leaders: HashMap<_, _> = leader_vec.into_iter().collect();
-    let acceptors: HashMap<_, _> = acceptor_vec.into_iter().collect();
-    
-    let mut server_addrs = replicas.clone();
-    leaders.iter().map(|(k, v)| { server_addrs.insert(*k, v.clone()); }).collect::<()>();
-    acceptors.iter().map(|(k, v)| { server_addrs.insert(*k, v.clone()); }).collect::<()>();
-
-    let replicas: HashSet<_> = replicas.into_iter().map(|(_k, v)| v).collect();
-    //let leaders: HashSet<_> = leaders.into_iter().map(|(k, _v)| k).collect();
-    //let acceptors: HashSet<_> = acceptors.into_iter().map(|(k, _v)| k).collect();
-
     let matches = App::new("lock_client")
         .version("1.0")
         .setting(AppSettings::SubcommandRequired)
+        .arg(Arg::with_name("config")
+             .long("config")
+             .takes_value(true)
+             .help("cluster configuration file, defaults to a local test cluster"))
         .arg(Arg::with_name("PORT")
              .required(true)
              .index(1))
@@ -47,6 +25,8 @@ fn main() {
                          .required(true).index(1)))
         .get_matches();
 
+    let config = ClusterConfig::load_or_local(matches.value_of("config")).expect("failed to load config");
+    config.apply().expect("failed to apply config");
 
     let port = matches.value_of("PORT").expect("port num");
     let port_num = port.to_string().parse::<u16>().expect("parse port");
@@ -54,24 +34,18 @@ fn main() {
 
     println!("addr: {:?}, pid: {}", addr, std::process::id());
 
-    let mut client = ClientNode::<LockMachine, 
-                                  UdpRecver<_>, UdpSender<_>>::new(&addr, &replicas);
-    
-    matches.subcommand_matches("lock").map(|m| {
-        let lockid = m.value_of("LOCKID").expect("lock id arg").parse().expect("lock id parse");
-        let cmd = LockOp::TryLock(lockid, port_num as u64);
-        let req = Message::Request { cid: addr.clone(), cmd: cmd };
-        client.send_cmd(&req).ok().map(|r| {
-            println!("result: {:?}", r);
-        });
-    });
-
-    matches.subcommand_matches("unlock").map(|m| {
-        let lockid = m.value_of("LOCKID").expect("lock id arg").parse().expect("lock id parse");
-        let cmd = LockOp::TryUnlock(lockid, port_num as u64);
-        let req = Message::Request { cid: addr.clone(), cmd: cmd };
-        client.send_cmd(&req).ok().map(|r| {
-            println!("result: {:?}", r);
-        });
-    });
+    let cmd = match matches.subcommand() {
+        ("lock", Some(m)) => LockOp::TryLock(m.value_of("LOCKID").expect("lock id arg").parse().expect("lock id parse"),
+                                             port_num as u64),
+        ("unlock", Some(m)) => LockOp::TryUnlock(m.value_of("LOCKID").expect("lock id arg").parse().expect("lock id parse"),
+                                                 port_num as u64),
+        _ => unreachable!(),
+    };
+    match submit::<LockMachine>(&config, &addr, cmd) {
+        Ok(r) => println!("result: {:?}", r),
+        Err(e) => {
+            eprintln!("request failed: {}", e);
+            std::process::exit(1);
+        },
+    }
 }
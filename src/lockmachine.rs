@@ -26,9 +26,9 @@ impl StateMachine for LockMachine {
     fn apply_op(&mut self, op: &Self::Op) -> Self::Result {
         match op {
             LockOp::TryLock(lockid, cid) => {
-                let mut maybe_c: Option<u64>;
+                let maybe_c: Option<u64>;
                 {
-                    maybe_c = self.locks.get(lockid).map(|c| *c);
+                    maybe_c = self.locks.get(lockid).copied();
                 }
 
                 maybe_c.map_or_else(|| {
@@ -43,13 +43,13 @@ impl StateMachine for LockMachine {
                 })
             },
             LockOp::TryUnlock(lockid, cid) => {
-                let mut c: u64;
+                let c: u64;
                 {
                     let maybe_c = self.locks.get(lockid);
                     if maybe_c.is_none() {
                         return LockResult::Fail
                     }
-                    c = maybe_c.unwrap().clone();
+                    c = *maybe_c.unwrap();
                 }
                 if c == *cid {
                     self.locks.remove(lockid);
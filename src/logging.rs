@@ -0,0 +1,276 @@
+// Leveled logging with per-module targets. Records carry key/value fields next
+// to the message and are written to stderr, as text or one JSON object a line:
+//
+//     log_debug!("leader", { id = self.server_id, slot = slot }, "decided");
+//     log_info!("runner", "starting");
+//
+// Verbosity is a default level plus overrides per target, written like
+// "info,leader=debug,messaging=off", and can be changed while nodes run. The
+// level check happens before any formatting, so disabled records cost an atomic
+// load.
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+use std::sync::RwLock;
+use std::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Level {
+    Off,
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    fn from_usize(n: usize) -> Level {
+        match n {
+            0 => Level::Off,
+            1 => Level::Error,
+            2 => Level::Warn,
+            3 => Level::Info,
+            4 => Level::Debug,
+            _ => Level::Trace,
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Level, String> {
+        match s.trim().to_lowercase().as_str() {
+            "off" => Ok(Level::Off),
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            other => Err(format!("unknown log level {}", other)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Level::Off => "off",
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+}
+
+
+// the [logging] section of the cluster config
+#[derive(Deserialize, Clone, Default, PartialEq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogSettings {
+    pub level: Level,
+    pub targets: HashMap<String, Level>,
+    pub json: bool,
+}
+
+impl LogSettings {
+    // "info,leader=debug": a default level followed by target=level overrides
+    pub fn parse(spec: &str) -> Result<LogSettings, String> {
+        let mut settings = LogSettings::default();
+        for part in spec.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+            match part.find('=') {
+                Some(i) => {
+                    let level = Level::parse(&part[i + 1..])?;
+                    settings.targets.insert(part[..i].trim().to_string(), level);
+                },
+                None => settings.level = Level::parse(part)?,
+            }
+        }
+        Ok(settings)
+    }
+
+    // the verbosity written like `parse` takes it, targets sorted
+    pub fn spec(&self) -> String {
+        let mut parts = self.targets.iter().map(|(t, l)| format!("{}={}", t, l.name())).collect::<Vec<_>>();
+        parts.sort();
+        parts.insert(0, self.level.name().to_string());
+        parts.join(",")
+    }
+}
+
+static DEFAULT_LEVEL: AtomicUsize = AtomicUsize::new(Level::Info as usize);
+// the most verbose level of the default and all targets, checked before the lock
+static MAX_LEVEL: AtomicUsize = AtomicUsize::new(Level::Info as usize);
+static JSON: AtomicBool = AtomicBool::new(false);
+static TARGETS: RwLock<Vec<(String, Level)>> = RwLock::new(Vec::new());
+
+fn update_max_level(targets: &[(String, Level)]) {
+    let max = targets.iter().map(|(_, l)| *l as usize)
+        .fold(DEFAULT_LEVEL.load(Ordering::Relaxed), std::cmp::max);
+    MAX_LEVEL.store(max, Ordering::Relaxed);
+}
+
+pub fn configure(settings: &LogSettings) {
+    let mut targets = TARGETS.write().unwrap_or_else(|e| e.into_inner());
+    *targets = settings.targets.iter().map(|(t, l)| (t.clone(), *l)).collect();
+    DEFAULT_LEVEL.store(settings.level as usize, Ordering::Relaxed);
+    JSON.store(settings.json, Ordering::Relaxed);
+    update_max_level(targets.as_slice());
+}
+
+// the settings in effect, for reporting and reloading
+pub fn current() -> LogSettings {
+    let targets = TARGETS.read().unwrap_or_else(|e| e.into_inner());
+    LogSettings {
+        level: Level::from_usize(DEFAULT_LEVEL.load(Ordering::Relaxed)),
+        targets: targets.iter().cloned().collect(),
+        json: JSON.load(Ordering::Relaxed),
+    }
+}
+
+pub fn set_default_level(level: Level) {
+    DEFAULT_LEVEL.store(level as usize, Ordering::Relaxed);
+    update_max_level(TARGETS.read().unwrap_or_else(|e| e.into_inner()).as_slice());
+}
+
+pub fn set_level(target: &str, level: Level) {
+    let mut targets = TARGETS.write().unwrap_or_else(|e| e.into_inner());
+    targets.retain(|(t, _)| t != target);
+    targets.push((target.to_string(), level));
+    update_max_level(targets.as_slice());
+}
+
+pub fn set_json(json: bool) {
+    JSON.store(json, Ordering::Relaxed);
+}
+
+pub fn enabled(target: &str, level: Level) -> bool {
+    if level == Level::Off || level as usize > MAX_LEVEL.load(Ordering::Relaxed) {
+        return false;
+    }
+    let targets = TARGETS.read().unwrap_or_else(|e| e.into_inner());
+    passes(Level::from_usize(DEFAULT_LEVEL.load(Ordering::Relaxed)), targets.as_slice(), target, level)
+}
+
+// a target's own level wins over the default
+fn passes(default: Level, targets: &[(String, Level)], target: &str, level: Level) -> bool {
+    let max = targets.iter().find(|(t, _)| t == target).map_or(default, |(_, l)| *l);
+    level != Level::Off && level <= max
+}
+
+fn timestamp() -> f64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as f64 + d.subsec_millis() as f64 / 1000.0)
+        .unwrap_or(0.0)
+}
+
+// used by the log macros, which check `enabled` first
+pub fn emit(level: Level, target: &str, fields: &[(&str, String)], args: fmt::Arguments) {
+    let line = format_record(JSON.load(Ordering::Relaxed), level, target, fields, fmt::format(args));
+    let stderr = std::io::stderr();
+    let mut out = stderr.lock();
+    let _ = writeln!(out, "{}", line);
+}
+
+fn format_record(json: bool, level: Level, target: &str, fields: &[(&str, String)], msg: String) -> String {
+    if json {
+        let mut record = serde_json::Map::new();
+        record.insert("ts".to_string(), serde_json::Value::from(timestamp()));
+        record.insert("level".to_string(), serde_json::Value::from(level.name()));
+        record.insert("target".to_string(), serde_json::Value::from(target));
+        record.insert("pid".to_string(), serde_json::Value::from(std::process::id()));
+        record.insert("msg".to_string(), serde_json::Value::from(msg));
+        fields.iter().for_each(|(k, v)| {
+            record.insert(k.to_string(), serde_json::Value::from(v.as_str()));
+        });
+        serde_json::Value::Object(record).to_string()
+    } else {
+        let mut line = format!("{:.3} {:5} {} {}: {}", timestamp(), level.name().to_uppercase(),
+                               std::process::id(), target, msg);
+        fields.iter().for_each(|(k, v)| line.push_str(&format!(" {}={}", k, v)));
+        line
+    }
+}
+
+#[macro_export]
+macro_rules! log_at {
+    ($level:expr, $target:expr, { $($key:ident = $value:expr),* }, $($arg:tt)+) => {{
+        let level = $level;
+        if $crate::logging::enabled($target, level) {
+            $crate::logging::emit(level, $target, &[$((stringify!($key), format!("{:?}", $value))),*],
+                                  format_args!($($arg)+));
+        }
+    }};
+    ($level:expr, $target:expr, $($arg:tt)+) => {{
+        let level = $level;
+        if $crate::logging::enabled($target, level) {
+            $crate::logging::emit(level, $target, &[], format_args!($($arg)+));
+        }
+    }};
+}
+
+#[macro_export]
+macro_rules! log_error {
+    ($target:expr, $($rest:tt)+) => { log_at!($crate::logging::Level::Error, $target, $($rest)+) };
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    ($target:expr, $($rest:tt)+) => { log_at!($crate::logging::Level::Warn, $target, $($rest)+) };
+}
+
+#[macro_export]
+macro_rules! log_info {
+    ($target:expr, $($rest:tt)+) => { log_at!($crate::logging::Level::Info, $target, $($rest)+) };
+}
+
+#[macro_export]
+macro_rules! log_debug {
+    ($target:expr, $($rest:tt)+) => { log_at!($crate::logging::Level::Debug, $target, $($rest)+) };
+}
+
+#[macro_export]
+macro_rules! log_trace {
+    ($target:expr, $($rest:tt)+) => { log_at!($crate::logging::Level::Trace, $target, $($rest)+) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_parse_and_print_alike() {
+        let settings = LogSettings::parse("warn, leader=debug,messaging=off").unwrap();
+        assert_eq!(settings.level, Level::Warn);
+        assert_eq!(settings.targets.get("leader"), Some(&Level::Debug));
+        assert_eq!(settings.spec(), "warn,leader=debug,messaging=off");
+        assert_eq!(LogSettings::parse(settings.spec().as_str()), Ok(settings));
+        assert_eq!(LogSettings::parse(""), Ok(LogSettings::default()));
+        assert_eq!(LogSettings::parse("info,leader=loud"), Err("unknown log level loud".to_string()));
+    }
+
+    #[test]
+    fn targets_override_the_default_level() {
+        let targets = vec![("leader".to_string(), Level::Trace), ("messaging".to_string(), Level::Off)];
+        assert!(passes(Level::Info, &targets, "replica", Level::Info));
+        assert!(!passes(Level::Info, &targets, "replica", Level::Debug));
+        assert!(passes(Level::Info, &targets, "leader", Level::Trace));
+        assert!(!passes(Level::Info, &targets, "messaging", Level::Error));
+        assert!(!passes(Level::Trace, &[], "replica", Level::Off));
+    }
+
+    #[test]
+    fn records_are_text_or_one_json_object() {
+        let fields = [("slot", "7".to_string()), ("id", "10".to_string())];
+        let text = format_record(false, Level::Debug, "leader", &fields, "decided".to_string());
+        assert!(text.ends_with(&format!(" DEBUG {} leader: decided slot=7 id=10", std::process::id())));
+
+        let json = format_record(true, Level::Warn, "messaging", &fields, "dropped".to_string());
+        let record: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(record["level"], "warn");
+        assert_eq!(record["target"], "messaging");
+        assert_eq!(record["msg"], "dropped");
+        assert_eq!(record["slot"], "7");
+        assert!(!json.contains('\n'));
+    }
+}
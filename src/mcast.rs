@@ -0,0 +1,211 @@
+// UDP multicast transport. Receivers join a group so that one datagram from the
+// leader reaches every acceptor or replica on the LAN. Messages are fragmented
+// like plain UDP ones.
+use std::io;
+use std::marker::PhantomData;
+use std::mem;
+use std::net::{ UdpSocket, SocketAddr, Ipv4Addr };
+use std::os::unix::io::{ AsRawFd, FromRawFd };
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use libc::c_int;
+use messaging::*;
+use error::*;
+
+static MCAST_TTL: AtomicUsize = AtomicUsize::new(1);
+// IPv4 address of the interface used for sending and joining, 0 lets the kernel pick
+static MCAST_IFACE: AtomicUsize = AtomicUsize::new(0);
+
+pub fn set_multicast_ttl(ttl: u32) {
+    MCAST_TTL.store(ttl as usize, Ordering::SeqCst);
+}
+
+pub fn set_multicast_interface(iface: Ipv4Addr) {
+    MCAST_IFACE.store(u32::from(iface) as usize, Ordering::SeqCst);
+}
+
+fn multicast_interface() -> Ipv4Addr {
+    Ipv4Addr::from(MCAST_IFACE.load(Ordering::SeqCst) as u32)
+}
+
+fn set_flag(fd: c_int, opt: c_int) -> io::Result<()> {
+    let optval: c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(fd, libc::SOL_SOCKET, opt,
+                         &optval as *const _ as *const libc::c_void,
+                         mem::size_of_val(&optval) as libc::socklen_t)
+    };
+    if ret == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+}
+
+// several members of a group may live on one host, so the port has to be shared
+// before binding, which std::net cannot do
+fn bind_reusable(addr: &SocketAddr) -> io::Result<UdpSocket> {
+    let domain = if addr.is_ipv4() { libc::AF_INET } else { libc::AF_INET6 };
+    let fd = unsafe { libc::socket(domain, libc::SOCK_DGRAM, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let sock = unsafe { UdpSocket::from_raw_fd(fd) };
+    set_flag(fd, libc::SO_REUSEADDR)?;
+    set_flag(fd, libc::SO_REUSEPORT)?;
+    let ret = unsafe {
+        match *addr {
+            SocketAddr::V4(ref a) => {
+                let mut sa: libc::sockaddr_in = mem::zeroed();
+                sa.sin_family = libc::AF_INET as libc::sa_family_t;
+                sa.sin_port = a.port().to_be();
+                sa.sin_addr.s_addr = u32::from(*a.ip()).to_be();
+                libc::bind(fd, &sa as *const _ as *const libc::sockaddr,
+                           mem::size_of_val(&sa) as libc::socklen_t)
+            },
+            SocketAddr::V6(ref a) => {
+                let mut sa: libc::sockaddr_in6 = mem::zeroed();
+                sa.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sa.sin6_port = a.port().to_be();
+                sa.sin6_addr.s6_addr = a.ip().octets();
+                libc::bind(fd, &sa as *const _ as *const libc::sockaddr,
+                           mem::size_of_val(&sa) as libc::socklen_t)
+            },
+        }
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(sock)
+}
+
+pub struct McastRecver<T> {
+    sock: UdpSocket,
+    buf: Vec<u8>,
+    reassembler: Reassembler<SocketAddr>,
+    msg_type: PhantomData<T>,
+}
+
+impl<T> McastRecver<T> {
+    pub fn get_sock(&self) -> &UdpSocket {
+        &self.sock
+    }
+}
+
+impl<T> MsgRecver<T> for McastRecver<T> where
+    T: serde::Serialize + serde::de::DeserializeOwned {
+    type Ctx = ();
+
+    // `addr` is the group address
+    fn bind(addr: &Addr) -> Result<Self, Error> {
+        let group = resolve(addr)?;
+        let sock = bind_reusable(&group)?;
+        match group {
+            SocketAddr::V4(ref g) => sock.join_multicast_v4(g.ip(), &multicast_interface()),
+            SocketAddr::V6(ref g) => sock.join_multicast_v6(g.ip(), 0),
+        }?;
+        sock.set_nonblocking(true)?;
+        Ok(McastRecver {
+            sock,
+            buf: vec![0; MAX_UDP_PAYLOAD],
+            reassembler: Reassembler::new(reassembly_timeout()),
+            msg_type: PhantomData,
+        })
+    }
+
+    fn try_recv_str(&mut self) -> Option<Vec<u8>> {
+        self.reassembler.expire();
+        loop {
+            match self.sock.recv_from(self.buf.as_mut_slice()) {
+                Ok((size, from)) => {
+                    let msg = self.reassembler.push(&from, &self.buf[..size]);
+                    if msg.is_some() {
+                        return msg;
+                    }
+                },
+                Err(_) => return None,
+            }
+        }
+    }
+
+    fn get_io_fds(&self) -> Vec<c_int> {
+        vec![self.sock.as_raw_fd()]
+    }
+}
+
+pub struct McastSender<T> {
+    sock: UdpSocket,
+    group: SocketAddr,
+    fragmenter: Fragmenter,
+    msg_type: PhantomData<T>,
+}
+
+impl<T> MsgSender<T> for McastSender<T> where
+    T: serde::Serialize + serde::de::DeserializeOwned {
+
+    // `addr` is the group address
+    fn connect(addr: &Addr) -> Result<Self, Error> {
+        let group = resolve(addr)?;
+        let sock = UdpSocket::bind(if group.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+        let ttl = MCAST_TTL.load(Ordering::SeqCst) as u32;
+        if group.is_ipv4() {
+            sock.set_multicast_ttl_v4(ttl)?;
+            // colocated group members must see our own datagrams
+            sock.set_multicast_loop_v4(true)?;
+            let iface = multicast_interface();
+            if !iface.is_unspecified() {
+                let addr = libc::in_addr { s_addr: u32::from(iface).to_be() };
+                let ret = unsafe {
+                    libc::setsockopt(sock.as_raw_fd(), libc::IPPROTO_IP, libc::IP_MULTICAST_IF,
+                                     &addr as *const _ as *const libc::c_void,
+                                     mem::size_of_val(&addr) as libc::socklen_t)
+                };
+                if ret != 0 {
+                    return Err(io::Error::last_os_error().into());
+                }
+            }
+        } else {
+            sock.set_multicast_loop_v6(true)?;
+        }
+        sock.set_nonblocking(true)?;
+        Ok(McastSender {
+            sock,
+            group,
+            fragmenter: Fragmenter::new(max_datagram_size())?,
+            msg_type: PhantomData,
+        })
+    }
+
+    fn send_str(&mut self, s: &[u8]) -> Result<(), Error> {
+        let frags = self.fragmenter.fragment(s)?;
+        for frag in frags.iter() {
+            let size = self.sock.send_to(frag.as_slice(), self.group)?;
+            if size != frag.len() {
+                return Err(TransportError::Truncated.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_member_of_the_group_gets_one_send() {
+        let group = Addr::new("239.255.73.1", 27331);
+        let mut members = [McastRecver::<String>::bind(&group).unwrap(), McastRecver::<String>::bind(&group).unwrap()];
+        let mut sender = McastSender::<String>::connect(&group).unwrap();
+        sender.send(&"decided".to_string()).unwrap();
+        for member in members.iter_mut() {
+            assert_eq!(member.try_recv_timeout(2000), Some("decided".to_string()));
+            assert_eq!(member.try_recv(), None);
+        }
+    }
+
+    #[test]
+    fn large_messages_are_fragmented_and_reassembled() {
+        let group = Addr::new("239.255.73.2", 27332);
+        let mut member = McastRecver::<String>::bind(&group).unwrap();
+        let mut sender = McastSender::<String>::connect(&group).unwrap();
+        let large = "x".repeat(max_datagram_size() * 3);
+        sender.send(&large).unwrap();
+        assert_eq!(member.try_recv_timeout(2000), Some(large));
+    }
+}
@@ -1,8 +1,14 @@
 use std::cmp::Ordering;
 use messaging::Addr;
+use status::NodeStatus;
+use config::Role;
 
 pub type ClientID = Addr;
 pub type ServerID = u64;
+// numbers the requests of one client in increasing order
+pub type ReqID = u64;
+// a client request as replicas know it across failovers
+pub type RequestKey = (ClientID, ReqID);
 
 #[derive(Eq, PartialEq, Serialize, Deserialize, Clone, Debug)]
 pub struct Ballot {
@@ -13,7 +19,7 @@ pub struct Ballot {
 
 impl Ballot {
     pub fn next(&self) -> Option<Self> {
-        if self.idx != std::u64::MAX {
+        if self.idx != u64::MAX {
             Some(Ballot { server: self.server, idx: self.idx + 1, is_bot: false })
         } else {
             None
@@ -58,21 +64,120 @@ impl Ord for Ballot {
     }
 }
 
+// what a role hands back to its node to send
+pub type ToServers<CmdT, ResultT> = Vec<(ServerID, Message<CmdT, ResultT>)>;
+pub type ToClients<CmdT, ResultT> = Vec<(ClientID, Message<CmdT, ResultT>)>;
+
+// new variants go at the end, see envelope.rs for the compatibility rules
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Message<CmdT, ResultT> {
-    Request { cid: ClientID, cmd: CmdT },
-    Response { cid: ClientID, result: ResultT },
+    // replies carry the req_id of the request they answer
+    Request { cid: ClientID, cmd: CmdT, #[serde(default)] req_id: Option<ReqID> },
+    Response { cid: ClientID, result: ResultT, #[serde(default)] req_id: Option<ReqID> },
 
-    Propose { slot: u64, cmd: CmdT },
+    // `req` follows the command through the protocol so that every replica can
+    // skip a request that was decided twice
+    Propose { slot: u64, cmd: CmdT, #[serde(default)] req: Option<RequestKey> },
     Adopted { slot: u64, ballot: Ballot, cmd: CmdT },
-    Decision { slot: u64, cmd: CmdT },
+    Decision { slot: u64, cmd: CmdT, #[serde(default)] req: Option<RequestKey> },
     
     P1a { sender: ServerID, ballot: Ballot },
-    P1b { sender: ServerID, ballot: Ballot, proposals: Vec<(u64, Ballot, CmdT)> },
-    P2a { sender: ServerID, ballot: Ballot, slot: u64, cmd: CmdT },
+    // `reqs` holds the request of each slot in `proposals` that has one
+    P1b { sender: ServerID, ballot: Ballot, proposals: Vec<(u64, Ballot, CmdT)>,
+          #[serde(default)] reqs: Vec<(u64, RequestKey)> },
+    P2a { sender: ServerID, ballot: Ballot, slot: u64, cmd: CmdT, #[serde(default)] req: Option<RequestKey> },
     P2b { sender: ServerID, ballot: Ballot, slot: u64 },
 
     Tick,
+
+    // admin introspection, answered by any node with its status
+    StatusRequest { cid: ClientID },
+    Status { status: NodeStatus },
+
+    // admin request to reload the settings that can change while running, the
+    // report lists the changes or says why nothing was applied
+    ReloadRequest { cid: ClientID },
+    ReloadReport { changes: Result<Vec<String>, String> },
+
+    // a replica refusing a client request, the client tries another one
+    Rejected { cid: ClientID, reason: String, #[serde(default)] req_id: Option<ReqID> },
+
+    // an active leader checking that its ballot still holds; unlike P1a the
+    // acceptors answer with their ballot alone, not with what they accepted
+    Heartbeat { sender: ServerID, ballot: Ballot },
+    HeartbeatAck { sender: ServerID, ballot: Ballot },
+}
+
+pub trait MessageKind {
+    fn kind(&self) -> &'static str;
+    fn known_kinds() -> &'static [&'static str];
+}
+
+impl<CmdT, ResultT> MessageKind for Message<CmdT, ResultT> {
+    fn kind(&self) -> &'static str {
+        match self {
+            Message::Request { .. } => "Request",
+            Message::Response { .. } => "Response",
+            Message::Propose { .. } => "Propose",
+            Message::Adopted { .. } => "Adopted",
+            Message::Decision { .. } => "Decision",
+            Message::P1a { .. } => "P1a",
+            Message::P1b { .. } => "P1b",
+            Message::P2a { .. } => "P2a",
+            Message::P2b { .. } => "P2b",
+            Message::Tick => "Tick",
+            Message::StatusRequest { .. } => "StatusRequest",
+            Message::Status { .. } => "Status",
+            Message::ReloadRequest { .. } => "ReloadRequest",
+            Message::ReloadReport { .. } => "ReloadReport",
+            Message::Rejected { .. } => "Rejected",
+            Message::Heartbeat { .. } => "Heartbeat",
+            Message::HeartbeatAck { .. } => "HeartbeatAck",
+        }
+    }
+
+    fn known_kinds() -> &'static [&'static str] {
+        &["Request", "Response", "Propose", "Adopted", "Decision", "P1a", "P1b", "P2a", "P2b", "Tick",
+          "StatusRequest", "Status", "ReloadRequest", "ReloadReport", "Rejected",
+          "Heartbeat", "HeartbeatAck"]
+    }
+}
+
+// lets transports that know who is on the other end check what a message claims
+pub trait Origin {
+    fn claimed_sender(&self) -> Option<ServerID>;
+    // false for messages that clients are allowed to send
+    fn server_only(&self) -> bool;
+    // the role of the servers that send this kind, None if any server may
+    fn sender_role(&self) -> Option<Role>;
+}
+
+impl<CmdT, ResultT> Origin for Message<CmdT, ResultT> {
+    fn claimed_sender(&self) -> Option<ServerID> {
+        match self {
+            Message::P1a { sender, .. } => Some(*sender),
+            Message::P1b { sender, .. } => Some(*sender),
+            Message::P2a { sender, .. } => Some(*sender),
+            Message::P2b { sender, .. } => Some(*sender),
+            Message::Heartbeat { sender, .. } => Some(*sender),
+            Message::HeartbeatAck { sender, .. } => Some(*sender),
+            _ => None,
+        }
+    }
+
+    fn server_only(&self) -> bool {
+        !matches!(self, Message::Request { .. } | Message::StatusRequest { .. } | Message::ReloadRequest { .. })
+    }
+
+    fn sender_role(&self) -> Option<Role> {
+        match self {
+            Message::Response { .. } | Message::Propose { .. } | Message::Rejected { .. } => Some(Role::Replica),
+            Message::Adopted { .. } | Message::Decision { .. } | Message::P1a { .. } | Message::P2a { .. } |
+            Message::Heartbeat { .. } => Some(Role::Leader),
+            Message::P1b { .. } | Message::P2b { .. } | Message::HeartbeatAck { .. } => Some(Role::Acceptor),
+            _ => None,
+        }
+    }
 }
 
 /*
@@ -1,77 +1,290 @@
 extern crate zmq;
 
-use std::net::UdpSocket;
+use std::net::{ UdpSocket, SocketAddr, ToSocketAddrs, IpAddr, Ipv6Addr };
 use std::marker::PhantomData;
 use std::os::unix::io::AsRawFd;
+use std::cell::RefCell;
+use std::collections::{ BTreeMap, HashMap, HashSet };
+use std::hash::Hash;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::sync::RwLock;
+use std::time::{ Duration, Instant };
+use std::thread;
+use error::*;
+use messages::ServerID;
 use libc::c_int;
 use std::mem;
+use rand::{thread_rng, Rng};
 
-static mut CTX: Option<zmq::Context> = None;
-
-fn get_zmq_context() -> &'static zmq::Context {
-    unsafe {
-        if CTX.is_none() {
-            let ctx = zmq::Context::new();
-            CTX = Some(ctx);
-            get_zmq_context()
-        } else {
-            CTX.as_ref().unwrap()
-        }
-    }
-}
-
-#[derive(Clone, Serialize, Deserialize, Hash, Debug)]
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq, Hash, Debug)]
 pub struct Addr {
     pub addr: String,
     pub port: u16,
 }
 
+pub const DEFAULT_RESOLVE_TTL_MS: usize = 30_000;
+
+static RESOLVE_TTL_MS: AtomicUsize = AtomicUsize::new(DEFAULT_RESOLVE_TTL_MS);
+
+// how long a resolved host name is used before it is looked up again
+pub fn set_resolve_ttl(ttl: Duration) {
+    let ms = ttl.as_secs() * 1000 + ttl.subsec_millis() as u64;
+    RESOLVE_TTL_MS.store(ms as usize, Ordering::SeqCst);
+}
+
+pub fn resolve_ttl() -> Duration {
+    Duration::from_millis(RESOLVE_TTL_MS.load(Ordering::SeqCst) as u64)
+}
+
+thread_local! {
+    static RESOLVED: RefCell<HashMap<Addr, (Vec<SocketAddr>, Instant)>> = RefCell::new(HashMap::new());
+}
+
 impl Addr {
+    // `a` is an IPv4 address, an IPv6 address with or without brackets, or a host name
     pub fn new(a: &str, p: u16) -> Self {
         Addr {
-            addr: a.to_string(),
+            addr: a.trim_start_matches('[').trim_end_matches(']').to_string(),
             port: p,
         }
     }
+
+    // parses `host:port`, IPv6 addresses need brackets: `[::1]:8000`
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (host, port) = if s.starts_with('[') {
+            let end = s.find(']').ok_or(format!("missing ']' in {}", s))?;
+            let rest = &s[end + 1..];
+            if !rest.starts_with(':') {
+                return Err(format!("missing port in {}", s));
+            }
+            (&s[1..end], &rest[1..])
+        } else {
+            let idx = s.rfind(':').ok_or(format!("missing port in {}", s))?;
+            if s[..idx].contains(':') {
+                return Err(format!("IPv6 address needs brackets: {}", s));
+            }
+            (&s[..idx], &s[idx + 1..])
+        };
+        if host.is_empty() {
+            return Err(format!("missing host in {}", s));
+        }
+        let port = port.parse::<u16>().map_err(|e| format!("bad port in {}: {}", s, e))?;
+        Ok(Addr::new(host, port))
+    }
+
+    pub fn is_ipv6(&self) -> bool {
+        self.addr.parse::<Ipv6Addr>().is_ok()
+    }
+
+    // false for IP addresses, which never need to be looked up again
+    pub fn is_hostname(&self) -> bool {
+        self.addr.parse::<IpAddr>().is_err()
+    }
+
+    // host names are looked up again once the cached answer is older than the TTL,
+    // a stale answer is kept if the lookup fails
+    pub fn resolve(&self) -> io::Result<Vec<SocketAddr>> {
+        let ttl = resolve_ttl();
+        let cached = RESOLVED.with(|r| r.borrow().get(self).cloned());
+        match cached {
+            Some((addrs, at)) if at.elapsed() < ttl => Ok(addrs),
+            stale => {
+                let fresh = (self.addr.as_str(), self.port).to_socket_addrs()
+                    .map(|it| it.collect::<Vec<_>>())
+                    .and_then(|v| if v.is_empty() {
+                        Err(io::Error::new(io::ErrorKind::NotFound, format!("no address for {}", self)))
+                    } else {
+                        Ok(v)
+                    });
+                match (fresh, stale) {
+                    (Ok(v), _) => {
+                        RESOLVED.with(|r| r.borrow_mut().insert(self.clone(), (v.clone(), Instant::now())));
+                        Ok(v)
+                    },
+                    (Err(_), Some((v, _))) => Ok(v),
+                    (Err(e), None) => Err(e),
+                }
+            },
+        }
+    }
+
+    pub fn socket_addr(&self) -> io::Result<SocketAddr> {
+        self.resolve().map(|v| v[0])
+    }
+
+    pub fn zmq_endpoint(&self) -> io::Result<String> {
+        self.socket_addr().map(|a| format!("tcp://{}", a))
+    }
 }
 
-impl PartialEq for Addr {
-    fn eq(&self, other: &Addr) -> bool {
-        self.addr == other.addr && self.port == other.port
+impl fmt::Display for Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.addr.contains(':') {
+            write!(f, "[{}]:{}", self.addr, self.port)
+        } else {
+            write!(f, "{}:{}", self.addr, self.port)
+        }
     }
 }
 
-impl Eq for Addr {}
+impl FromStr for Addr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        Addr::parse(s)
+    }
+}
 
 pub trait MsgRecver<Message> where
     Message: serde::Serialize + serde::de::DeserializeOwned {
     type Ctx;
     
-    fn bind(addr: &Addr) -> Self;
+    fn bind(addr: &Addr) -> Result<Self, Error> where Self: Sized;
     fn try_recv_str(&mut self) -> Option<Vec<u8>>; // non-blocking
     fn get_io_fds(&self) -> Vec<c_int>;
 
+    // true if a message may be read without waiting on the fds
+    fn pending(&self) -> bool {
+        false
+    }
+
     fn try_recv(&mut self) -> Option<Message> {
-        self.try_recv_str().map_or(None, |msg_buf| {
-            serde_json::from_reader(msg_buf.as_slice()).ok()
-        })
+        while let Some(msg_buf) = self.try_recv_str() {
+            if let Ok(msg) = serde_json::from_reader(msg_buf.as_slice()) {
+                if self.accepts(&msg) {
+                    return Some(msg);
+                }
+            }
+        }
+        None
     }
 
-    fn try_recv_timeout(&mut self, timeout_ms: i64) -> Option<Message>;
+    // checks a message decoded from the frame try_recv_str returned last, false
+    // drops it; layers that transform frames ask the transport below them
+    fn accepts(&mut self, _msg: &Message) -> bool {
+        true
+    }
+
+    // identifies the connection the last received message came in on, for
+    // transports that can answer over it
+    fn last_route(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    // answers over the connection of a route returned by last_route
+    fn reply_str(&mut self, _route: &[u8], _s: &[u8]) -> Result<(), Error> {
+        Err(TransportError::Unsupported.into())
+    }
+
+    fn reply(&mut self, route: &[u8], msg: &Message) -> Result<(), Error> {
+        let s = serde_json::to_string(msg).map_err(|e| CodecError::Encode(e.to_string()))?;
+        self.reply_str(route, s.as_bytes())
+    }
+
+    // waits up to timeout_ms for a message, a negative timeout waits forever
+    fn try_recv_timeout(&mut self, timeout_ms: i64) -> Option<Message> {
+        let deadline = deadline_after(timeout_ms);
+        loop {
+            let msg = self.try_recv();
+            if msg.is_some() {
+                return msg;
+            }
+            let remaining = remaining_ms(deadline);
+            if remaining == 0 || !wait_readable(self.get_io_fds().as_slice(), remaining) {
+                return None;
+            }
+        }
+    }
+}
+
+pub fn deadline_after(timeout_ms: i64) -> Option<Instant> {
+    if timeout_ms < 0 {
+        None
+    } else {
+        Some(Instant::now() + Duration::from_millis(timeout_ms as u64))
+    }
+}
+
+// milliseconds left until the deadline, -1 if there is none
+pub fn remaining_ms(deadline: Option<Instant>) -> i64 {
+    deadline.map_or(-1, |d| {
+        let now = Instant::now();
+        if d <= now {
+            0
+        } else {
+            let left = d - now;
+            // round up so that we never wake up just before the deadline
+            (left.as_secs() * 1000) as i64 + left.subsec_nanos().div_ceil(1_000_000) as i64
+        }
+    })
+}
+
+// blocks until one of the fds becomes readable, returns false on timeout or error
+pub fn wait_readable(fds: &[c_int], timeout_ms: i64) -> bool {
+    poll_readable(fds, timeout_ms).is_ok_and(|ready| !ready.is_empty())
+}
+
+// returns the subset of fds that are readable
+pub fn poll_readable(fds: &[c_int], timeout_ms: i64) -> Result<Vec<c_int>, TransportError> {
+    let mut pollfds = fds.iter().map(|fd| {
+        libc::pollfd { fd: *fd, events: libc::POLLIN, revents: 0 }
+    }).collect::<Vec<_>>();
+    let timeout = if timeout_ms < 0 {
+        -1
+    } else {
+        std::cmp::min(timeout_ms, i32::MAX as i64) as c_int
+    };
+    let ret = unsafe {
+        libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, timeout)
+    };
+    if ret < 0 {
+        Err(std::io::Error::last_os_error().into())
+    } else {
+        Ok(pollfds.into_iter()
+           .filter(|p| p.revents & (libc::POLLIN | libc::POLLERR | libc::POLLHUP | libc::POLLNVAL) != 0)
+           .map(|p| p.fd)
+           .collect())
+    }
 }
 
 pub trait MsgSender<Message> where 
     Message: serde::Serialize + serde::de::DeserializeOwned {
 
-    fn connect(addr: &Addr) -> Self;
-    fn send_str(&mut self, s: &[u8]) -> Result<(), i32>;
-    fn send(&mut self, msg: &Message) -> Result<(), i32> {
-        match serde_json::to_string(msg) {
-            Ok(s) => {
-                self.send_str(s.into_bytes().as_slice())
-            },
-            Err(_) => Err(-1),
-        }
+    fn connect(addr: &Addr) -> Result<Self, Error> where Self: Sized;
+    fn send_str(&mut self, s: &[u8]) -> Result<(), Error>;
+    fn send(&mut self, msg: &Message) -> Result<(), Error> {
+        let s = serde_json::to_string(msg).map_err(|e| CodecError::Encode(e.to_string()))?;
+        self.send_str(s.into_bytes().as_slice())
+    }
+
+    // like send_str, for layers below one that knows which server the message
+    // is from
+    fn send_str_from(&mut self, _sender: Option<ServerID>, s: &[u8]) -> Result<(), Error> {
+        self.send_str(s)
+    }
+
+    // hands anything the sender buffered to the transport
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    // waits up to timeout_ms for an answer sent back over this connection, see MsgRecver::reply
+    fn try_recv_reply_str(&mut self, _timeout_ms: i64) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn try_recv_reply(&mut self, timeout_ms: i64) -> Option<Message> {
+        self.try_recv_reply_str(timeout_ms).and_then(|buf| {
+            serde_json::from_slice(buf.as_slice()).ok()
+        })
+    }
+
+    // the fds that turn readable when try_recv_reply may have something
+    fn reply_fds(&self) -> Vec<c_int> {
+        Vec::new()
     }
 }
 
@@ -81,155 +294,727 @@ pub trait Messager {
     type Recver: MsgSender<Self::Message>;
 }
 
+// every datagram starts with: message id (u64), fragment index (u16), fragment count (u16)
+const FRAG_HEADER_LEN: usize = 12;
+// largest payload a single UDP datagram can carry over IPv4
+pub const MAX_UDP_PAYLOAD: usize = 65507;
+pub const DEFAULT_MAX_DATAGRAM_SIZE: usize = 1000;
+pub const DEFAULT_REASSEMBLY_TIMEOUT_MS: u64 = 1000;
+// incomplete messages a receiver keeps at most, the oldest one makes room
+pub const MAX_PENDING_REASSEMBLIES: usize = 1024;
+// the largest message a receiver reassembles, and the most bytes it holds for
+// incomplete messages, the oldest ones make room
+pub const MAX_REASSEMBLED_LEN: usize = 16 * 1024 * 1024;
+pub const MAX_REASSEMBLY_BYTES: usize = 64 * 1024 * 1024;
+
+static MAX_DATAGRAM_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_DATAGRAM_SIZE);
+static REASSEMBLY_TIMEOUT_MS: AtomicUsize = AtomicUsize::new(DEFAULT_REASSEMBLY_TIMEOUT_MS as usize);
+
+pub fn check_datagram_size(size: usize) -> Result<(), String> {
+    if size > FRAG_HEADER_LEN && size <= MAX_UDP_PAYLOAD {
+        Ok(())
+    } else {
+        Err(format!("datagram size must be between {} and {}, not {}", FRAG_HEADER_LEN + 1, MAX_UDP_PAYLOAD, size))
+    }
+}
+
+// sets the datagram size used by senders created afterwards
+pub fn set_max_datagram_size(size: usize) -> Result<(), TransportError> {
+    check_datagram_size(size).map_err(TransportError::Setup)?;
+    MAX_DATAGRAM_SIZE.store(size, Ordering::SeqCst);
+    Ok(())
+}
+
+pub fn max_datagram_size() -> usize {
+    MAX_DATAGRAM_SIZE.load(Ordering::SeqCst)
+}
+
+// sets the reassembly timeout of receivers bound afterwards
+pub fn set_reassembly_timeout(timeout: Duration) {
+    let ms = timeout.as_secs() * 1000 + timeout.subsec_millis() as u64;
+    REASSEMBLY_TIMEOUT_MS.store(ms as usize, Ordering::SeqCst);
+}
+
+pub fn reassembly_timeout() -> Duration {
+    Duration::from_millis(REASSEMBLY_TIMEOUT_MS.load(Ordering::SeqCst) as u64)
+}
+
+pub struct Fragmenter {
+    max_datagram: usize,
+    next_id: u64,
+}
+
+impl Fragmenter {
+    pub fn new(max_datagram: usize) -> Result<Self, TransportError> {
+        check_datagram_size(max_datagram).map_err(TransportError::Setup)?;
+        Ok(Fragmenter {
+            max_datagram,
+            // senders are short-lived, a random start keeps ids from different instances apart
+            next_id: thread_rng().gen::<u64>(),
+        })
+    }
+
+    pub fn max_datagram(&self) -> usize {
+        self.max_datagram
+    }
+
+    pub fn fragment(&mut self, s: &[u8]) -> Result<Vec<Vec<u8>>, TransportError> {
+        let chunk_size = self.max_datagram - FRAG_HEADER_LEN;
+        let count = std::cmp::max(1, s.len().div_ceil(chunk_size));
+        if count > u16::MAX as usize {
+            return Err(TransportError::TooLarge);
+        }
+        let msg_id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let frags = (0..count).map(|idx| {
+            let begin = idx * chunk_size;
+            let end = std::cmp::min(s.len(), begin + chunk_size);
+            let mut frag = Vec::with_capacity(FRAG_HEADER_LEN + end - begin);
+            frag.extend_from_slice(&encode_u64(msg_id));
+            frag.extend_from_slice(&encode_u16(idx as u16));
+            frag.extend_from_slice(&encode_u16(count as u16));
+            frag.extend_from_slice(&s[begin..end]);
+            frag
+        }).collect();
+        Ok(frags)
+    }
+}
+
+// fragments are kept by index as they arrive, so a forged count costs nothing
+// until the fragments themselves show up
+struct PartialMsg {
+    frags: BTreeMap<usize, Vec<u8>>,
+    count: usize,
+    bytes: usize,
+    started: Instant,
+}
+
+impl PartialMsg {
+    fn new(count: usize) -> Self {
+        PartialMsg { frags: BTreeMap::new(), count, bytes: 0, started: Instant::now() }
+    }
+}
+
+pub struct Reassembler<K> {
+    partial: HashMap<(K, u64), PartialMsg>,
+    timeout: Duration,
+    max_message: usize,
+    max_buffered: usize,
+    // payload bytes held by the incomplete messages
+    buffered: usize,
+    expired: u64,
+}
+
+impl<K> Reassembler<K> where
+    K: Eq + Hash + Clone {
+    pub fn new(timeout: Duration) -> Self {
+        Reassembler {
+            partial: HashMap::new(),
+            timeout,
+            max_message: MAX_REASSEMBLED_LEN,
+            max_buffered: MAX_REASSEMBLY_BYTES,
+            buffered: 0,
+            expired: 0,
+        }
+    }
+
+    pub fn with_limits(mut self, max_message: usize, max_buffered: usize) -> Self {
+        self.max_message = max_message;
+        self.max_buffered = max_buffered;
+        self
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    // number of incomplete messages dropped because they timed out, were
+    // evicted to make room or grew past the largest message
+    pub fn expired_count(&self) -> u64 {
+        self.expired
+    }
+
+    pub fn buffered_bytes(&self) -> usize {
+        self.buffered
+    }
+
+    pub fn pending_count(&self) -> usize {
+        self.partial.len()
+    }
+
+    // feeds one datagram, returns the whole message once its last fragment arrives
+    pub fn push(&mut self, from: &K, datagram: &[u8]) -> Option<Vec<u8>> {
+        if datagram.len() < FRAG_HEADER_LEN {
+            return None;
+        }
+        let msg_id = decode_u64(&datagram[0..8]);
+        let idx = decode_u16(&datagram[8..10]) as usize;
+        let count = decode_u16(&datagram[10..12]) as usize;
+        let payload = &datagram[FRAG_HEADER_LEN..];
+        if count == 0 || idx >= count {
+            return None;
+        }
+        if count == 1 {
+            return Some(payload.to_vec());
+        }
+
+        let key = (from.clone(), msg_id);
+        if self.partial.get(&key).is_some_and(|entry| entry.count != count) {
+            // inconsistent fragment count, the id was reused; start over
+            self.remove_partial(&key);
+        }
+        if !self.partial.contains_key(&key) && self.partial.len() >= MAX_PENDING_REASSEMBLIES {
+            self.expire();
+            self.evict_oldest(&key);
+        }
+        if self.partial.get(&key).is_some_and(|entry| entry.frags.contains_key(&idx)) {
+            return None;
+        }
+        if self.partial.get(&key).map_or(0, |entry| entry.bytes) + payload.len() > self.max_message {
+            self.drop_partial(&key);
+            return None;
+        }
+        if self.buffered + payload.len() > self.max_buffered {
+            self.expire();
+            while self.buffered + payload.len() > self.max_buffered && self.evict_oldest(&key) {}
+            if self.buffered + payload.len() > self.max_buffered {
+                self.drop_partial(&key);
+                return None;
+            }
+        }
+        let entry = self.partial.entry(key.clone()).or_insert_with(|| PartialMsg::new(count));
+        entry.frags.insert(idx, payload.to_vec());
+        entry.bytes += payload.len();
+        self.buffered += payload.len();
+        if entry.frags.len() == count {
+            self.partial.remove(&key).map(|entry| {
+                self.buffered -= entry.bytes;
+                entry.frags.into_values().flatten().collect()
+            })
+        } else {
+            None
+        }
+    }
+
+    // drops incomplete messages older than the timeout
+    pub fn expire(&mut self) {
+        let timeout = self.timeout;
+        let before = self.partial.len();
+        let mut freed = 0;
+        self.partial.retain(|_, entry| {
+            let keep = entry.started.elapsed() < timeout;
+            if !keep {
+                freed += entry.bytes;
+            }
+            keep
+        });
+        self.buffered -= freed;
+        self.expired += (before - self.partial.len()) as u64;
+    }
+
+    // evicts the oldest incomplete message other than `keep`, false if there is none
+    fn evict_oldest(&mut self, keep: &(K, u64)) -> bool {
+        let oldest = self.partial.iter()
+            .filter(|(key, _)| *key != keep)
+            .min_by_key(|(_, entry)| entry.started)
+            .map(|(key, _)| key.clone());
+        match oldest {
+            Some(key) => self.drop_partial(&key),
+            None => false,
+        }
+    }
+
+    // removes an incomplete message, counting it as expired
+    fn drop_partial(&mut self, key: &(K, u64)) -> bool {
+        let dropped = self.remove_partial(key);
+        if dropped {
+            self.expired += 1;
+        }
+        dropped
+    }
+
+    fn remove_partial(&mut self, key: &(K, u64)) -> bool {
+        match self.partial.remove(key) {
+            Some(entry) => {
+                self.buffered -= entry.bytes;
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+pub(crate) fn encode_u64(v: u64) -> [u8; 8] {
+    v.to_be_bytes()
+}
+
+fn encode_u16(v: u16) -> [u8; 2] {
+    [(v >> 8) as u8, v as u8]
+}
+
+pub(crate) fn decode_u64(b: &[u8]) -> u64 {
+    b.iter().take(8).fold(0u64, |acc, x| (acc << 8) | (*x as u64))
+}
+
+fn decode_u16(b: &[u8]) -> u16 {
+    ((b[0] as u16) << 8) | (b[1] as u16)
+}
+
+fn set_reuseport(sock: &UdpSocket) -> io::Result<()> {
+    let ret = unsafe {
+        let optval: libc::c_int = 1;
+        libc::setsockopt(sock.as_raw_fd(),
+                         libc::SOL_SOCKET,
+                         libc::SO_REUSEPORT,
+                         &optval as *const _ as *const libc::c_void,
+                         mem::size_of_val(&optval) as libc::socklen_t)
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+// resolves `addr`, a failed lookup becomes TransportError::Resolve
+pub fn resolve(addr: &Addr) -> Result<SocketAddr, TransportError> {
+    addr.socket_addr().map_err(|e| TransportError::Resolve(format!("{}: {}", addr, e)))
+}
+
 pub struct UdpRecver<T> {
     sock: UdpSocket,
+    buf: Vec<u8>,
+    reassembler: Reassembler<SocketAddr>,
     msg_type: PhantomData<T>,
 }
 
 impl<T> UdpRecver<T> {
+    pub fn set_reassembly_timeout(&mut self, timeout: Duration) {
+        self.reassembler.set_timeout(timeout);
+    }
+
+    pub fn reassembler(&self) -> &Reassembler<SocketAddr> {
+        &self.reassembler
+    }
 }
 
 impl<T> MsgRecver<T> for UdpRecver<T> where
     T: serde::Serialize + serde::de::DeserializeOwned {
     type Ctx = ();
 
-    fn bind(addr: &Addr) -> Self {
-        let sock = UdpSocket::bind(format!("{}:{}", addr.addr, addr.port)).expect("failed to bind");
-        sock.set_nonblocking(true).expect("failed to set socket non_blocking");
-        unsafe {
-            let optval: libc::c_int = 1;
-            let ret = libc::setsockopt(sock.as_raw_fd(),
-                                       libc::SOL_SOCKET,
-                                       libc::SO_REUSEPORT,
-                                       &optval as *const _ as *const libc::c_void,
-                                       mem::size_of_val(&optval) as libc::socklen_t);
-            assert!(ret == 0, "failed to setsockopt");
-        }
-        UdpRecver {
-            sock: sock,
+    fn bind(addr: &Addr) -> Result<Self, Error> {
+        let sock = UdpSocket::bind(resolve(addr)?)?;
+        sock.set_nonblocking(true)?;
+        set_reuseport(&sock)?;
+        Ok(UdpRecver {
+            sock,
+            buf: vec![0; MAX_UDP_PAYLOAD],
+            reassembler: Reassembler::new(reassembly_timeout()),
             msg_type: PhantomData,
-        }
+        })
     }
 
     fn try_recv_str(&mut self) -> Option<Vec<u8>> {
-        let mut v: Vec<u8> = Vec::with_capacity(1000);
-        v.resize(1000, 0);
-        let recv_result;
-        {
-            recv_result = self.sock.recv(v.as_mut_slice());
-        }
-        if recv_result.is_err() {
-            None
-        } else {
-            recv_result.ok().map(|size| {
-                v.truncate(size);
-                v
-            })
+        self.reassembler.expire();
+        loop {
+            match self.sock.recv_from(self.buf.as_mut_slice()) {
+                Ok((size, from)) => {
+                    let msg = self.reassembler.push(&from, &self.buf[..size]);
+                    if msg.is_some() {
+                        return msg;
+                    }
+                },
+                Err(_) => return None,
+            }
         }
     }
 
     fn get_io_fds(&self) -> Vec<c_int> {
         vec![self.sock.as_raw_fd()]
     }
-
-    fn try_recv_timeout(&mut self, timeout_ms: i64) -> Option<T> {
-        self.try_recv()
-    }
 }
 
 pub struct UdpSender<T> {
-    sock: UdpSocket,
-    addr: String,
+    sock: Option<UdpSocket>,
+    addr: Addr,
+    fragmenter: Fragmenter,
     msg_type: PhantomData<T>,
 }
 
 impl<T> UdpSender<T> {
+    pub fn set_max_datagram_size(&mut self, size: usize) -> Result<(), TransportError> {
+        self.fragmenter = Fragmenter::new(size)?;
+        Ok(())
+    }
+
+    // the destination may switch address family when it is re-resolved
+    fn sock_for(&mut self, dest: &SocketAddr) -> io::Result<&UdpSocket> {
+        let matches = self.sock.as_ref()
+            .and_then(|s| s.local_addr().ok())
+            .is_some_and(|local| local.is_ipv4() == dest.is_ipv4());
+        if !matches {
+            let sock = UdpSocket::bind(if dest.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+            sock.set_nonblocking(true)?;
+            set_reuseport(&sock)?;
+            self.sock = Some(sock);
+        }
+        Ok(self.sock.as_ref().unwrap())
+    }
 }
 
 impl<T> MsgSender<T> for UdpSender<T> where
     T: serde::Serialize + serde::de::DeserializeOwned {
 
-    fn connect(addr: &Addr) -> Self {
-        let sock = UdpSocket::bind("0.0.0.0:0").expect("failed to create socket");
-        sock.set_nonblocking(true).expect("failed to set socket non_blocking");
-        unsafe {
-            let optval: libc::c_int = 1;
-            let ret = libc::setsockopt(sock.as_raw_fd(),
-                                       libc::SOL_SOCKET,
-                                       libc::SO_REUSEPORT,
-                                       &optval as *const _ as *const libc::c_void,
-                                       mem::size_of_val(&optval) as libc::socklen_t);
-            assert!(ret == 0, "failed to setsockopt");
-        }
-        UdpSender {
-            sock: sock,
-            addr: format!("{}:{}", addr.addr, addr.port),
+    // resolution is deferred to the first send
+    fn connect(addr: &Addr) -> Result<Self, Error> {
+        Ok(UdpSender {
+            sock: None,
+            addr: addr.clone(),
+            fragmenter: Fragmenter::new(max_datagram_size())?,
             msg_type: PhantomData,
+        })
+    }
+
+    fn send_str(&mut self, s: &[u8]) -> Result<(), Error> {
+        let dest = resolve(&self.addr)?;
+        let frags = self.fragmenter.fragment(s)?;
+        let sock = self.sock_for(&dest)?;
+        for frag in frags.iter() {
+            let size = sock.send_to(frag.as_slice(), dest)?;
+            if size != frag.len() {
+                return Err(TransportError::Truncated.into());
+            }
         }
+        Ok(())
     }
+}
 
-    fn send_str(&mut self, s: &[u8]) -> Result<(), i32> {
-        assert!(s.len() < 1000, "message too large: {}", s.len());
-        match self.sock.send_to(s, self.addr.clone()) {
-            Ok(size) => 
-                if size == s.len() { 
-                    Ok(())
-                } else { 
-                    Err(-1) 
-                },
-            Err(e) => e.raw_os_error().map_or(Err(-1), |eno| Err(eno))
+
+fn zmq_error(e: zmq::Error) -> Error {
+    TransportError::from_errno(e.to_raw()).into()
+}
+
+const ZAP_ENDPOINT: &str = "inproc://zeromq.zap.01";
+
+// generates a CURVE key pair, returned as z85 encoded (public, secret)
+pub fn generate_curve_keypair() -> Result<(String, String), Error> {
+    let pair = zmq::CurveKeyPair::new().map_err(zmq_error)?;
+    let public = zmq::z85_encode(&pair.public_key).map_err(|e| CodecError::Encode(e.to_string()))?;
+    let secret = zmq::z85_encode(&pair.secret_key).map_err(|e| CodecError::Encode(e.to_string()))?;
+    Ok((public, secret))
+}
+
+fn decode_curve_key(key: &str) -> Result<Vec<u8>, Error> {
+    zmq::z85_decode(key).ok().filter(|k| k.len() == 32)
+        .ok_or_else(|| TransportError::Setup(format!("invalid curve key {}", key)).into())
+}
+
+#[derive(Clone)]
+struct CurveServer {
+    secret_key: Vec<u8>,
+    // None lets any client that knows the server key connect
+    allowed_clients: Option<HashSet<Vec<u8>>>,
+}
+
+#[derive(Clone)]
+struct CurveClient {
+    public_key: Vec<u8>,
+    secret_key: Vec<u8>,
+    server_key: Vec<u8>,
+    peer_keys: HashMap<Addr, Vec<u8>>,
+}
+
+// Context and socket options for the ZMQ transport. `bind`/`connect` use the
+// builder passed to init_zmq, or a default one with its own context.
+#[derive(Clone)]
+pub struct ZmqBuilder {
+    ctx: zmq::Context,
+    sndhwm: Option<i32>,
+    rcvhwm: Option<i32>,
+    linger_ms: Option<i32>,
+    reconnect_ivl_ms: Option<i32>,
+    reconnect_ivl_max_ms: Option<i32>,
+    identity: Option<Vec<u8>>,
+    curve_server: Option<CurveServer>,
+    curve_client: Option<CurveClient>,
+}
+
+impl ZmqBuilder {
+    pub fn new() -> Self {
+        ZmqBuilder::with_context(zmq::Context::new())
+    }
+
+    pub fn with_context(ctx: zmq::Context) -> Self {
+        ZmqBuilder {
+            ctx,
+            sndhwm: None,
+            rcvhwm: None,
+            linger_ms: None,
+            reconnect_ivl_ms: None,
+            reconnect_ivl_max_ms: None,
+            identity: None,
+            curve_server: None,
+            curve_client: None,
         }
     }
+
+    pub fn context(&self) -> &zmq::Context {
+        &self.ctx
+    }
+
+    pub fn sndhwm(mut self, hwm: i32) -> Self {
+        self.sndhwm = Some(hwm);
+        self
+    }
+
+    pub fn rcvhwm(mut self, hwm: i32) -> Self {
+        self.rcvhwm = Some(hwm);
+        self
+    }
+
+    // how long unsent messages are kept after a socket is dropped, -1 forever
+    pub fn linger(mut self, ms: i32) -> Self {
+        self.linger_ms = Some(ms);
+        self
+    }
+
+    pub fn reconnect_ivl(mut self, ms: i32, max_ms: i32) -> Self {
+        self.reconnect_ivl_ms = Some(ms);
+        self.reconnect_ivl_max_ms = Some(max_ms);
+        self
+    }
+
+    // identity of connecting sockets as seen by the ROUTER on the other side
+    pub fn identity(mut self, identity: &[u8]) -> Self {
+        self.identity = Some(identity.to_vec());
+        self
+    }
+
+    // servers encrypt with this key, clients are checked against `allowed_clients`
+    // (z85 public keys) if given
+    pub fn curve_server(mut self, secret_key: &str, allowed_clients: Option<&[String]>) -> Result<Self, Error> {
+        let allowed_clients = match allowed_clients {
+            Some(keys) => Some(keys.iter().map(|k| decode_curve_key(k)).collect::<Result<_, _>>()?),
+            None => None,
+        };
+        self.curve_server = Some(CurveServer {
+            secret_key: decode_curve_key(secret_key)?,
+            allowed_clients,
+        });
+        Ok(self)
+    }
+
+    // `server_key` is the public key expected from servers without a key of their own
+    pub fn curve_client(mut self, public_key: &str, secret_key: &str, server_key: &str) -> Result<Self, Error> {
+        self.curve_client = Some(CurveClient {
+            public_key: decode_curve_key(public_key)?,
+            secret_key: decode_curve_key(secret_key)?,
+            server_key: decode_curve_key(server_key)?,
+            peer_keys: self.curve_client.map(|c| c.peer_keys).unwrap_or_default(),
+        });
+        Ok(self)
+    }
+
+    // public key of the server at `addr`, requires curve_client
+    pub fn curve_peer(mut self, addr: &Addr, server_key: &str) -> Result<Self, Error> {
+        let key = decode_curve_key(server_key)?;
+        self.curve_client.as_mut()
+            .ok_or_else(|| TransportError::Setup("curve_peer needs curve_client first".to_string()))?
+            .peer_keys.insert(addr.clone(), key);
+        Ok(self)
+    }
+
+    fn configure(&self, sock: &zmq::Socket) -> Result<(), zmq::Error> {
+        sock.set_ipv6(true)?;
+        if let Some(hwm) = self.sndhwm {
+            sock.set_sndhwm(hwm)?;
+        }
+        if let Some(hwm) = self.rcvhwm {
+            sock.set_rcvhwm(hwm)?;
+        }
+        if let Some(ms) = self.linger_ms {
+            sock.set_linger(ms)?;
+        }
+        if let Some(ms) = self.reconnect_ivl_ms {
+            sock.set_reconnect_ivl(ms)?;
+        }
+        if let Some(ms) = self.reconnect_ivl_max_ms {
+            sock.set_reconnect_ivl_max(ms)?;
+        }
+        Ok(())
+    }
+
+    fn server_socket(&self, addr: &Addr) -> Result<zmq::Socket, zmq::Error> {
+        let sock = self.ctx.socket(zmq::ROUTER)?;
+        self.configure(&sock)?;
+        // unroutable replies fail instead of vanishing, a reconnecting client takes
+        // over its old identity
+        sock.set_router_mandatory(true)?;
+        sock.set_router_handover(true)?;
+        if let Some(ref curve) = self.curve_server {
+            if let Some(ref allowed) = curve.allowed_clients {
+                start_zap_handler(&self.ctx, allowed.clone())?;
+            }
+            sock.set_curve_server(true)?;
+            sock.set_curve_secretkey(curve.secret_key.as_slice())?;
+        }
+        let tcp_str = addr.zmq_endpoint().map_err(|_| zmq::Error::EINVAL)?;
+        sock.bind(tcp_str.as_str())?;
+        Ok(sock)
+    }
+
+    fn client_socket(&self, addr: &Addr) -> Result<zmq::Socket, zmq::Error> {
+        let sock = self.ctx.socket(zmq::DEALER)?;
+        self.configure(&sock)?;
+        if let Some(ref identity) = self.identity {
+            sock.set_identity(identity.as_slice())?;
+        }
+        if let Some(ref curve) = self.curve_client {
+            let server_key = curve.peer_keys.get(addr).unwrap_or(&curve.server_key);
+            sock.set_curve_serverkey(server_key.as_slice())?;
+            sock.set_curve_publickey(curve.public_key.as_slice())?;
+            sock.set_curve_secretkey(curve.secret_key.as_slice())?;
+        }
+        let tcp_str = addr.zmq_endpoint().map_err(|_| zmq::Error::EINVAL)?;
+        sock.connect(tcp_str.as_str())?;
+        Ok(sock)
+    }
+}
+
+impl Default for ZmqBuilder {
+    fn default() -> Self {
+        ZmqBuilder::new()
+    }
+}
+
+// answers ZAP requests for every CURVE server socket of the context; there is one
+// handler per context, later calls keep the first allow list
+fn start_zap_handler(ctx: &zmq::Context, allowed: HashSet<Vec<u8>>) -> Result<(), zmq::Error> {
+    let sock = ctx.socket(zmq::REP)?;
+    match sock.bind(ZAP_ENDPOINT) {
+        Ok(()) => (),
+        Err(zmq::Error::EADDRINUSE) => return Ok(()),
+        Err(e) => return Err(e),
+    }
+    thread::spawn(move || {
+        while let Ok(req) = sock.recv_multipart(0) {
+            // version, request id, domain, address, identity, mechanism, client key
+            let ok = req.len() >= 7 && req[5] == b"CURVE" && allowed.contains(&req[6]);
+            let request_id = req.get(1).cloned().unwrap_or_default();
+            let (status, text): (&[u8], &[u8]) = if ok { (b"200", b"OK") } else { (b"400", b"unknown client key") };
+            let parts: [&[u8]; 6] = [b"1.0", request_id.as_slice(), status, text, b"", b""];
+            for (i, part) in parts.iter().enumerate() {
+                let flags = if i + 1 < parts.len() { zmq::SNDMORE } else { 0 };
+                if sock.send(*part, flags).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    Ok(())
 }
 
+static ZMQ_BUILDER: RwLock<Option<ZmqBuilder>> = RwLock::new(None);
+
+// sockets created with bind/connect afterwards use `builder`
+pub fn init_zmq(builder: ZmqBuilder) {
+    *ZMQ_BUILDER.write().unwrap_or_else(|e| e.into_inner()) = Some(builder);
+}
+
+// the builder given to init_zmq, or a default one that all sockets share so they
+// share its context
+fn get_zmq_builder() -> ZmqBuilder {
+    if let Some(ref builder) = *ZMQ_BUILDER.read().unwrap_or_else(|e| e.into_inner()) {
+        return builder.clone();
+    }
+    ZMQ_BUILDER.write().unwrap_or_else(|e| e.into_inner()).get_or_insert_with(ZmqBuilder::new).clone()
+}
 
 pub struct ZmqServer<T> {
-    ctx: &'static zmq::Context,
+    ctx: zmq::Context,
     socket: zmq::Socket,
+    last_route: Option<Vec<u8>>,
     msg_type: PhantomData<T>,
 }
 
 impl<T> ZmqServer<T> {
+    pub fn bind_with(addr: &Addr, builder: &ZmqBuilder) -> Result<Self, Error> {
+        Ok(ZmqServer {
+            ctx: builder.ctx.clone(),
+            socket: builder.server_socket(addr).map_err(zmq_error)?,
+            last_route: None,
+            msg_type: PhantomData,
+        })
+    }
+
     pub fn get_sock(&self) -> &zmq::Socket {
         &self.socket
     }
+
+    pub fn get_context(&self) -> &zmq::Context {
+        &self.ctx
+    }
 }
 
 impl<T> MsgRecver<T> for ZmqServer<T> where
     T: serde::Serialize + serde::de::DeserializeOwned {
     type Ctx = zmq::Context;
 
-    fn bind(addr: &Addr) -> Self {
-        let ctx = get_zmq_context();//zmq::Context::new();
-        let sock = ctx.socket(zmq::ROUTER).expect("failed to create socket");
-        let tcp_str = format!("tcp://{}:{}", addr.addr.as_str(), addr.port);
-        sock.bind(tcp_str.as_str()).expect("failed to bind");
-        ZmqServer {
-            ctx: ctx,
-            socket: sock,
-            msg_type: PhantomData,
-        }
+    fn bind(addr: &Addr) -> Result<Self, Error> {
+        ZmqServer::bind_with(addr, &get_zmq_builder())
     }
     
     fn try_recv_str(&mut self) -> Option<Vec<u8>> {
-        self.socket.recv_multipart(0).ok().and_then(|mut msg| {
+        self.socket.recv_multipart(zmq::DONTWAIT).ok().and_then(|mut msg| {
             if msg.len() == 2 {
-                String::from_utf8(msg[1].clone()).ok().map(|s| {
-                    println!("{} received: {}", std::process::id(), s);
-                });
-                Some(msg.swap_remove(1).clone())
+                log_trace!("messaging", "received: {}", String::from_utf8_lossy(&msg[1]));
+                let payload = msg.swap_remove(1);
+                self.last_route = msg.pop();
+                Some(payload)
             } else {
                 None
             }
         })
     }
 
+    // the zmq fd only signals that socket events changed, check `get_events` before blocking on it
     fn get_io_fds(&self) -> Vec<c_int> {
-        Vec::new()
+        self.socket.get_fd().map(|fd| vec![fd]).unwrap_or_default()
+    }
+
+    fn pending(&self) -> bool {
+        self.socket.get_events().is_ok_and(|e| e.contains(zmq::POLLIN))
+    }
+
+    // the route is the peer's ROUTER identity
+    fn last_route(&self) -> Option<Vec<u8>> {
+        self.last_route.clone()
+    }
+
+    fn reply_str(&mut self, route: &[u8], s: &[u8]) -> Result<(), Error> {
+        self.socket.send(route, zmq::SNDMORE | zmq::DONTWAIT).map_err(zmq_error)?;
+        self.socket.send(s, zmq::DONTWAIT).map_err(zmq_error)
     }
 
     fn try_recv_timeout(&mut self, timeout_ms: i64) -> Option<T> {
-        return self.try_recv();
+        let deadline = deadline_after(timeout_ms);
+        loop {
+            let msg = self.try_recv();
+            if msg.is_some() {
+                return msg;
+            }
+            let remaining = remaining_ms(deadline);
+            if remaining == 0 {
+                return None;
+            }
+            let ready = {
+                let mut items = [self.socket.as_poll_item(zmq::POLLIN)];
+                zmq::poll(&mut items, remaining).map(|n| n > 0 && items[0].is_readable())
+            };
+            if ready != Ok(true) {
+                return None;
+            }
+        }
     }
 }
 
@@ -239,6 +1024,13 @@ pub struct ZmqClient<T> {
 }
 
 impl<T> ZmqClient<T> {
+    pub fn connect_with(addr: &Addr, builder: &ZmqBuilder) -> Result<Self, Error> {
+        Ok(ZmqClient {
+            socket: builder.client_socket(addr).map_err(zmq_error)?,
+            msg_type: PhantomData,
+        })
+    }
+
     pub fn get_sock(&self) -> &zmq::Socket {
         &self.socket
     }
@@ -246,22 +1038,250 @@ impl<T> ZmqClient<T> {
 
 impl<T> MsgSender<T> for ZmqClient<T> where
     T: serde::Serialize + serde::de::DeserializeOwned {
-    fn connect(addr: &Addr) -> Self {
-        let ctx = get_zmq_context();//zmq::Context::new();
-        let sock = ctx.socket(zmq::DEALER).expect("failed to create socket");
-        let tcp_str = format!("tcp://{}:{}", addr.addr.as_str(), addr.port);
-        sock.connect(tcp_str.as_str()).expect("failed to connect");
-        ZmqClient {
-            socket: sock,
-            msg_type: PhantomData,
+    fn connect(addr: &Addr) -> Result<Self, Error> {
+        ZmqClient::connect_with(addr, &get_zmq_builder())
+    }
+
+    fn send_str(&mut self, s: &[u8]) -> Result<(), Error> {
+        // WouldBlock once the high water mark is reached, callers may queue and retry
+        let ret = self.socket.send(s, zmq::DONTWAIT).map_err(zmq_error);
+        log_trace!("messaging", { ok = ret.is_ok() }, "sent: {}", String::from_utf8_lossy(s));
+        ret
+    }
+
+    fn try_recv_reply_str(&mut self, timeout_ms: i64) -> Option<Vec<u8>> {
+        let ready = {
+            let mut items = [self.socket.as_poll_item(zmq::POLLIN)];
+            zmq::poll(&mut items, timeout_ms).map(|n| n > 0 && items[0].is_readable())
+        };
+        if ready == Ok(true) {
+            self.socket.recv_bytes(zmq::DONTWAIT).ok()
+        } else {
+            None
         }
     }
 
-    fn send_str(&mut self, s: &[u8]) -> Result<(), i32> {
-        let ret = self.socket.send(s, 0).map_err(|e| e as i32);
-        std::str::from_utf8(s).ok().map(|msg| {
-            println!("{} sent: {}", std::process::id(), msg);
+    fn reply_fds(&self) -> Vec<c_int> {
+        self.socket.get_fd().map(|fd| vec![fd]).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_ipv4_and_host_names() {
+        assert_eq!(Addr::parse("127.0.0.1:8000"), Ok(Addr::new("127.0.0.1", 8000)));
+        assert_eq!(Addr::parse("node0.example:9001"), Ok(Addr::new("node0.example", 9001)));
+        assert!(Addr::parse("node0.example:9001").unwrap().is_hostname());
+        assert!(!Addr::parse("127.0.0.1:8000").unwrap().is_hostname());
+    }
+
+    #[test]
+    fn parse_ipv6_needs_brackets() {
+        let addr = Addr::parse("[::1]:8000").unwrap();
+        assert_eq!(addr, Addr::new("::1", 8000));
+        assert!(addr.is_ipv6());
+        assert_eq!(addr.to_string(), "[::1]:8000");
+        assert!(Addr::parse("::1:8000").is_err());
+    }
+
+    #[test]
+    fn parse_rejects_bad_input() {
+        assert!(Addr::parse("127.0.0.1").is_err());
+        assert!(Addr::parse(":8000").is_err());
+        assert!(Addr::parse("127.0.0.1:port").is_err());
+        assert!(Addr::parse("127.0.0.1:70000").is_err());
+        assert!(Addr::parse("[::1]8000").is_err());
+        assert!(Addr::parse("[::1:8000").is_err());
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for s in ["127.0.0.1:8000", "[fe80::1]:9001", "localhost:1"].iter() {
+            assert_eq!(Addr::parse(s).unwrap().to_string(), *s);
+        }
+    }
+
+    fn message(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn small_message_is_one_fragment() {
+        let mut frag = Fragmenter::new(100).unwrap();
+        let mut reasm = Reassembler::new(Duration::from_secs(1));
+        let frags = frag.fragment(b"hello").unwrap();
+        assert_eq!(frags.len(), 1);
+        assert_eq!(reasm.push(&1, frags[0].as_slice()), Some(b"hello".to_vec()));
+        assert_eq!(reasm.pending_count(), 0);
+    }
+
+    #[test]
+    fn reassembles_out_of_order() {
+        let mut frag = Fragmenter::new(FRAG_HEADER_LEN + 10).unwrap();
+        let mut reasm = Reassembler::new(Duration::from_secs(1));
+        let msg = message(95);
+        let frags = frag.fragment(msg.as_slice()).unwrap();
+        assert_eq!(frags.len(), 10);
+        // the first fragment arrives last
+        let (first, rest) = frags.split_first().unwrap();
+        for f in rest.iter().rev() {
+            assert_eq!(reasm.push(&1, f.as_slice()), None);
+            // duplicates do not count twice
+            assert_eq!(reasm.push(&1, f.as_slice()), None);
+        }
+        assert_eq!(reasm.push(&1, first.as_slice()), Some(msg));
+        assert_eq!(reasm.pending_count(), 0);
+    }
+
+    #[test]
+    fn senders_are_kept_apart() {
+        let mut frag = Fragmenter::new(FRAG_HEADER_LEN + 10).unwrap();
+        let mut reasm = Reassembler::new(Duration::from_secs(1));
+        let frags = frag.fragment(message(20).as_slice()).unwrap();
+        assert_eq!(reasm.push(&1, frags[0].as_slice()), None);
+        assert_eq!(reasm.push(&2, frags[1].as_slice()), None);
+        assert_eq!(reasm.pending_count(), 2);
+        assert_eq!(reasm.push(&1, frags[1].as_slice()), Some(message(20)));
+    }
+
+    #[test]
+    fn lost_fragment_expires() {
+        let mut frag = Fragmenter::new(FRAG_HEADER_LEN + 10).unwrap();
+        let mut reasm = Reassembler::new(Duration::from_secs(60));
+        let frags = frag.fragment(message(30).as_slice()).unwrap();
+        assert_eq!(reasm.push(&1, frags[0].as_slice()), None);
+        assert_eq!(reasm.push(&1, frags[2].as_slice()), None);
+        reasm.expire();
+        assert_eq!(reasm.pending_count(), 1);
+        reasm.set_timeout(Duration::from_millis(0));
+        reasm.expire();
+        assert_eq!(reasm.pending_count(), 0);
+        assert_eq!(reasm.expired_count(), 1);
+    }
+
+    #[test]
+    fn pending_reassemblies_are_capped() {
+        let mut frag = Fragmenter::new(FRAG_HEADER_LEN + 10).unwrap();
+        let mut reasm = Reassembler::new(Duration::from_secs(60));
+        for _ in 0..MAX_PENDING_REASSEMBLIES + 5 {
+            let frags = frag.fragment(message(20).as_slice()).unwrap();
+            assert_eq!(reasm.push(&1, frags[0].as_slice()), None);
+        }
+        assert_eq!(reasm.pending_count(), MAX_PENDING_REASSEMBLIES);
+        assert_eq!(reasm.expired_count(), 5);
+    }
+
+    #[test]
+    fn oversized_messages_are_dropped() {
+        let mut frag = Fragmenter::new(FRAG_HEADER_LEN + 10).unwrap();
+        let mut reasm = Reassembler::new(Duration::from_secs(60)).with_limits(25, 1000);
+        let frags = frag.fragment(message(40).as_slice()).unwrap();
+        assert_eq!(reasm.push(&1, frags[0].as_slice()), None);
+        assert_eq!(reasm.push(&1, frags[1].as_slice()), None);
+        assert_eq!(reasm.buffered_bytes(), 20);
+        // the third fragment takes it past 25 bytes
+        assert_eq!(reasm.push(&1, frags[2].as_slice()), None);
+        assert_eq!(reasm.pending_count(), 0);
+        assert_eq!(reasm.buffered_bytes(), 0);
+        assert_eq!(reasm.expired_count(), 1);
+        // a message up to the limit still goes through
+        let frags = frag.fragment(message(25).as_slice()).unwrap();
+        let whole = frags.iter().filter_map(|f| reasm.push(&1, f.as_slice())).next();
+        assert_eq!(whole, Some(message(25)));
+    }
+
+    #[test]
+    fn buffered_bytes_are_capped() {
+        let mut frag = Fragmenter::new(FRAG_HEADER_LEN + 10).unwrap();
+        let mut reasm = Reassembler::new(Duration::from_secs(60)).with_limits(1000, 30);
+        let first = frag.fragment(message(40).as_slice()).unwrap();
+        let second = frag.fragment(message(40).as_slice()).unwrap();
+        assert_eq!(reasm.push(&1, first[0].as_slice()), None);
+        assert_eq!(reasm.push(&1, first[1].as_slice()), None);
+        assert_eq!(reasm.push(&2, second[0].as_slice()), None);
+        assert_eq!(reasm.buffered_bytes(), 30);
+        // the oldest message makes room
+        assert_eq!(reasm.push(&2, second[1].as_slice()), None);
+        assert_eq!(reasm.pending_count(), 1);
+        assert_eq!(reasm.buffered_bytes(), 20);
+        assert_eq!(reasm.expired_count(), 1);
+        assert_eq!(reasm.push(&2, second[2].as_slice()), None);
+        // a message larger than the whole buffer cannot be kept
+        assert_eq!(reasm.push(&2, second[3].as_slice()), None);
+        assert_eq!(reasm.pending_count(), 0);
+        assert_eq!(reasm.buffered_bytes(), 0);
+    }
+
+    #[test]
+    fn rejects_malformed_datagrams() {
+        let mut reasm: Reassembler<u8> = Reassembler::new(Duration::from_secs(1));
+        assert_eq!(reasm.push(&1, &[0; FRAG_HEADER_LEN - 1]), None);
+        // fragment 2 of 2, and a zero count
+        let mut bad = encode_u64(7).to_vec();
+        bad.extend_from_slice(&[0, 2, 0, 2, 1]);
+        assert_eq!(reasm.push(&1, bad.as_slice()), None);
+        bad[11] = 0;
+        assert_eq!(reasm.push(&1, bad.as_slice()), None);
+        assert_eq!(reasm.pending_count(), 0);
+    }
+
+    #[test]
+    fn datagram_size_bounds() {
+        assert!(check_datagram_size(FRAG_HEADER_LEN).is_err());
+        assert!(check_datagram_size(FRAG_HEADER_LEN + 1).is_ok());
+        assert!(check_datagram_size(MAX_UDP_PAYLOAD).is_ok());
+        assert!(check_datagram_size(MAX_UDP_PAYLOAD + 1).is_err());
+        assert!(Fragmenter::new(FRAG_HEADER_LEN).is_err());
+        assert!(Fragmenter::new(MAX_UDP_PAYLOAD + 1).is_err());
+        let mut frag = Fragmenter::new(FRAG_HEADER_LEN + 1).unwrap();
+        assert!(frag.fragment(message(u16::MAX as usize + 1).as_slice()).is_err());
+    }
+
+    #[test]
+    fn poll_reports_readable_and_closed_fds() {
+        let mut fds = [0 as c_int; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let (rd, wr) = (fds[0], fds[1]);
+        assert_eq!(poll_readable(&[rd], 0), Ok(Vec::new()));
+        assert_eq!(unsafe { libc::write(wr, b"x".as_ptr() as *const libc::c_void, 1) }, 1);
+        assert_eq!(poll_readable(&[rd, wr], 0), Ok(vec![rd]));
+        assert!(wait_readable(&[rd], -1));
+        unsafe {
+            libc::close(rd);
+            libc::close(wr);
+        }
+        // an fd that is not open is handed back so that the caller notices
+        assert_eq!(poll_readable(&[c_int::MAX], 0), Ok(vec![c_int::MAX]));
+    }
+
+    #[test]
+    fn deadlines_round_up_and_negative_means_none() {
+        assert_eq!(deadline_after(-1), None);
+        assert_eq!(remaining_ms(None), -1);
+        assert_eq!(remaining_ms(deadline_after(0)), 0);
+        let left = remaining_ms(deadline_after(100));
+        assert!(left > 0 && left <= 100);
+    }
+
+    #[test]
+    fn timed_receive_waits_for_a_message_or_the_deadline() {
+        let addr = Addr::new("127.0.0.1", 27321);
+        let mut recver = UdpRecver::<String>::bind(&addr).unwrap();
+        let start = Instant::now();
+        assert_eq!(recver.try_recv_timeout(50), None);
+        let waited = start.elapsed();
+        assert!(waited >= Duration::from_millis(50) && waited < Duration::from_millis(1000));
+
+        let sender = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            UdpSender::<String>::connect(&addr).unwrap().send(&"hello".to_string()).unwrap();
         });
-        ret
+        let start = Instant::now();
+        assert_eq!(recver.try_recv_timeout(5000), Some("hello".to_string()));
+        assert!(start.elapsed() < Duration::from_millis(2000));
+        sender.join().unwrap();
     }
 }
@@ -0,0 +1,385 @@
+// Process-wide metrics, served in the Prometheus text format by a small HTTP
+// endpoint (GET /metrics). Nothing is recorded until `serve` starts the
+// endpoint, so nodes without one pay a single atomic load per update. Every
+// series carries the id of the node it describes, several roles may share a
+// process.
+use std::collections::{ BTreeMap, HashMap };
+use std::io::{ BufRead, BufReader, Write };
+use std::net::{ TcpListener, TcpStream };
+use std::sync::Mutex;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::thread;
+use std::time::{ Duration, Instant };
+use messages::*;
+use messaging::*;
+use compress::compression_stats;
+use envelope::envelope_rejections;
+use error::TransportError;
+
+// upper bounds in seconds of the latency histogram buckets
+const LATENCY_BUCKETS: [f64; 12] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+// decisions per second are measured over windows this long
+const RATE_WINDOW_MS: u64 = 1000;
+
+// name, type and help text of every metric
+const METRICS: [(&str, &str, &str); 16] = [
+    ("paxos_messages_received_total", "counter", "Messages received, by variant."),
+    ("paxos_messages_sent_total", "counter", "Messages sent, by variant."),
+    ("paxos_leader_ballot", "gauge", "Round of the leader's current ballot."),
+    ("paxos_leader_active", "gauge", "1 while the leader's ballot is adopted."),
+    ("paxos_leader_proposals", "gauge", "Proposals the leader has not seen chosen yet."),
+    ("paxos_acceptor_ballot", "gauge", "Round of the ballot the acceptor promised."),
+    ("paxos_replica_slot_in", "gauge", "Next slot the replica proposes for."),
+    ("paxos_replica_slot_out", "gauge", "Next slot the replica applies."),
+    ("paxos_replica_requests", "gauge", "Client requests waiting to be proposed."),
+    ("paxos_decisions_total", "counter", "Slots the replica applied."),
+    ("paxos_decisions_per_second", "gauge", "Slots the replica applied per second, over the last window."),
+    ("paxos_propose_to_decide_seconds", "histogram", "Time from proposing a slot to learning its decision."),
+    ("paxos_compression_frames_total", "counter", "Frames sent through the compression layer, by whether they were compressed."),
+    ("paxos_compression_ratio", "gauge", "Size of the compressed frames before compression over their size after."),
+    ("paxos_compression_decode_errors_total", "counter", "Received frames that failed to decompress."),
+    ("paxos_envelope_rejected_total", "counter", "Received messages dropped by the envelope check, by reason."),
+];
+
+type Labels = Vec<(&'static str, String)>;
+
+struct Histogram {
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+struct Rate {
+    window_start: Instant,
+    count: u64,
+    per_second: f64,
+}
+
+#[derive(Default)]
+struct Registry {
+    counters: BTreeMap<(&'static str, Labels), u64>,
+    gauges: BTreeMap<(&'static str, Labels), f64>,
+    histograms: BTreeMap<(&'static str, Labels), Histogram>,
+    rates: HashMap<ServerID, Rate>,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static REGISTRY: Mutex<Option<Registry>> = Mutex::new(None);
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+fn with_registry<F: FnOnce(&mut Registry)>(f: F) {
+    if !enabled() {
+        return;
+    }
+    let mut registry = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+    f(registry.get_or_insert_with(Default::default));
+}
+
+fn node_labels(node: ServerID) -> Labels {
+    vec![("node", node.to_string())]
+}
+
+pub fn inc_counter(name: &'static str, labels: Labels, by: u64) {
+    with_registry(|r| *r.counters.entry((name, labels)).or_insert(0) += by);
+}
+
+pub fn set_gauge(name: &'static str, labels: Labels, value: f64) {
+    with_registry(|r| {
+        r.gauges.insert((name, labels), value);
+    });
+}
+
+pub fn observe(name: &'static str, labels: Labels, value: f64) {
+    with_registry(|r| {
+        let h = r.histograms.entry((name, labels)).or_insert_with(|| Histogram {
+            buckets: vec![0; LATENCY_BUCKETS.len()],
+            sum: 0.0,
+            count: 0,
+        });
+        LATENCY_BUCKETS.iter().zip(h.buckets.iter_mut())
+            .filter(|(bound, _)| value <= **bound)
+            .for_each(|(_, b)| *b += 1);
+        h.sum += value;
+        h.count += 1;
+    });
+}
+
+pub fn message_received<M: MessageKind>(node: ServerID, m: &M) {
+    if enabled() {
+        inc_counter("paxos_messages_received_total",
+                    vec![("node", node.to_string()), ("kind", m.kind().to_string())], 1);
+    }
+}
+
+pub fn message_sent<M: MessageKind>(node: ServerID, m: &M) {
+    if enabled() {
+        inc_counter("paxos_messages_sent_total",
+                    vec![("node", node.to_string()), ("kind", m.kind().to_string())], 1);
+    }
+}
+
+pub fn leader_state(node: ServerID, ballot: &Ballot, active: bool, proposals: usize) {
+    if enabled() {
+        set_gauge("paxos_leader_ballot", node_labels(node), ballot.idx as f64);
+        set_gauge("paxos_leader_active", node_labels(node), if active { 1.0 } else { 0.0 });
+        set_gauge("paxos_leader_proposals", node_labels(node), proposals as f64);
+    }
+}
+
+pub fn acceptor_state(node: ServerID, ballot: &Ballot) {
+    if enabled() {
+        set_gauge("paxos_acceptor_ballot", node_labels(node), ballot.idx as f64);
+    }
+}
+
+pub fn replica_state(node: ServerID, slot_in: u64, slot_out: u64, requests: usize) {
+    if enabled() {
+        set_gauge("paxos_replica_slot_in", node_labels(node), slot_in as f64);
+        set_gauge("paxos_replica_slot_out", node_labels(node), slot_out as f64);
+        set_gauge("paxos_replica_requests", node_labels(node), requests as f64);
+    }
+}
+
+// `n` more slots were applied
+pub fn decided(node: ServerID, n: u64) {
+    if n == 0 {
+        return;
+    }
+    inc_counter("paxos_decisions_total", node_labels(node), n);
+    with_registry(|r| {
+        let now = Instant::now();
+        let rate = r.rates.entry(node).or_insert(Rate { window_start: now, count: 0, per_second: 0.0 });
+        rate.count += n;
+        roll_window(rate, now);
+    });
+}
+
+fn roll_window(rate: &mut Rate, now: Instant) {
+    let elapsed = now.duration_since(rate.window_start);
+    if elapsed >= Duration::from_millis(RATE_WINDOW_MS) {
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        // a window without any decision reads as zero
+        rate.per_second = if elapsed >= Duration::from_millis(2 * RATE_WINDOW_MS) { 0.0 } else { rate.count as f64 / secs };
+        rate.window_start = now;
+        rate.count = 0;
+    }
+}
+
+pub fn propose_to_decide(node: ServerID, elapsed: Duration) {
+    if enabled() {
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        observe("paxos_propose_to_decide_seconds", node_labels(node), secs);
+    }
+}
+
+fn format_labels(labels: &Labels, extra: Option<(&str, String)>) -> String {
+    let parts = labels.iter()
+        .map(|(k, v)| (k.to_string(), v.clone()))
+        .chain(extra.into_iter().map(|(k, v)| (k.to_string(), v)))
+        .map(|(k, v)| format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\"")))
+        .collect::<Vec<_>>();
+    if parts.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", parts.join(","))
+    }
+}
+
+// the current values in the Prometheus text exposition format
+pub fn render() -> String {
+    let mut registry = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+    let r = registry.get_or_insert_with(Default::default);
+    let now = Instant::now();
+    let rates = r.rates.iter_mut().map(|(node, rate)| {
+        roll_window(rate, now);
+        (*node, rate.per_second)
+    }).collect::<Vec<_>>();
+    rates.into_iter().for_each(|(node, per_second)| {
+        r.gauges.insert(("paxos_decisions_per_second", node_labels(node)), per_second);
+    });
+    // kept by the compression layer for the whole process
+    let stats = compression_stats();
+    r.counters.insert(("paxos_compression_frames_total", vec![("compressed", "false".to_string())]), stats.frames_raw);
+    r.counters.insert(("paxos_compression_frames_total", vec![("compressed", "true".to_string())]), stats.frames_compressed);
+    r.counters.insert(("paxos_compression_decode_errors_total", Vec::new()), stats.decode_errors);
+    r.gauges.insert(("paxos_compression_ratio", Vec::new()), stats.ratio());
+    envelope_rejections().into_iter().for_each(|(reason, count)| {
+        r.counters.insert(("paxos_envelope_rejected_total", vec![("reason", reason.label().to_string())]), count);
+    });
+
+    let mut out = String::new();
+    for (name, kind, help) in METRICS.iter() {
+        out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind));
+        r.counters.iter().filter(|((n, _), _)| n == name).for_each(|((_, labels), v)| {
+            out.push_str(&format!("{}{} {}\n", name, format_labels(labels, None), v));
+        });
+        r.gauges.iter().filter(|((n, _), _)| n == name).for_each(|((_, labels), v)| {
+            out.push_str(&format!("{}{} {}\n", name, format_labels(labels, None), v));
+        });
+        r.histograms.iter().filter(|((n, _), _)| n == name).for_each(|((_, labels), h)| {
+            LATENCY_BUCKETS.iter().zip(h.buckets.iter()).for_each(|(bound, count)| {
+                out.push_str(&format!("{}_bucket{} {}\n", name,
+                                      format_labels(labels, Some(("le", bound.to_string()))), count));
+            });
+            out.push_str(&format!("{}_bucket{} {}\n", name, format_labels(labels, Some(("le", "+Inf".to_string()))), h.count));
+            out.push_str(&format!("{}_sum{} {}\n", name, format_labels(labels, None), h.sum));
+            out.push_str(&format!("{}_count{} {}\n", name, format_labels(labels, None), h.count));
+        });
+    }
+    out
+}
+
+fn handle_scrape(mut stream: TcpStream) {
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+    let mut request_line = String::new();
+    {
+        let mut reader = BufReader::new(&stream);
+        if reader.read_line(&mut request_line).is_err() {
+            return;
+        }
+        // skip the headers
+        let mut line = String::new();
+        while reader.read_line(&mut line).map(|n| n > 0).unwrap_or(false) && line.trim() != "" {
+            line.clear();
+        }
+    }
+    let path = request_line.split_whitespace().nth(1).unwrap_or("");
+    let (status, content_type, body) = if request_line.starts_with("GET ") && (path == "/metrics" || path == "/") {
+        ("200 OK", "text/plain; version=0.0.4", render())
+    } else {
+        ("404 Not Found", "text/plain", "not found\n".to_string())
+    };
+    let _ = write!(stream, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                   status, content_type, body.len(), body);
+}
+
+// starts recording and serves /metrics on `addr` from a background thread
+pub fn serve(addr: &Addr) -> Result<(), TransportError> {
+    let listener = TcpListener::bind(resolve(addr)?)?;
+    ENABLED.store(true, Ordering::Relaxed);
+    thread::Builder::new().name("metrics".to_string()).spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(s) => handle_scrape(s),
+                Err(e) => log_warn!("metrics", "accept failed: {}", e),
+            }
+        }
+    })?;
+    log_info!("metrics", { addr = addr }, "serving metrics");
+    Ok(())
+}
+
+// remembers when a replica proposed each slot, for the propose-to-decide latency
+#[derive(Default)]
+pub struct ProposalTimer {
+    proposed_at: HashMap<u64, Instant>,
+}
+
+impl ProposalTimer {
+    // a replica handled a message: it proposed the Propose messages in `to_send`
+    // and slot_out moved from `slot_out` to `decided_to`
+    pub fn observe<CmdT, ResultT>(&mut self, node: ServerID, slot_out: u64, decided_to: u64,
+                                  to_send: &[(ServerID, Message<CmdT, ResultT>)]) {
+        if !enabled() {
+            return;
+        }
+        let now = Instant::now();
+        to_send.iter().for_each(|(_, m)| {
+            if let Message::Propose { slot, .. } = m {
+                self.proposed_at.entry(*slot).or_insert(now);
+            }
+        });
+        (slot_out..decided_to).for_each(|slot| {
+            if let Some(at) = self.proposed_at.remove(&slot) {
+                propose_to_decide(node, now.duration_since(at));
+            }
+        });
+        decided(node, decided_to - slot_out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compression_stats_are_exported() {
+        let out = render();
+        assert!(out.contains("# TYPE paxos_compression_ratio gauge\n"));
+        assert!(out.contains("paxos_compression_frames_total{compressed=\"true\"} "));
+        assert!(out.contains("paxos_compression_decode_errors_total "));
+    }
+
+    #[test]
+    fn envelope_rejections_are_exported() {
+        let out = render();
+        assert!(out.contains("# TYPE paxos_envelope_rejected_total counter\n"));
+        assert!(out.contains("paxos_envelope_rejected_total{reason=\"foreign_cluster\"} "));
+    }
+
+    #[test]
+    fn recorded_series_render_in_the_text_format() {
+        ENABLED.store(true, Ordering::Relaxed);
+        let tick: Message<u64, u64> = Message::Tick;
+        message_received(901, &tick);
+        message_received(901, &tick);
+        leader_state(901, &Ballot::zero(901).next().unwrap(), true, 3);
+        propose_to_decide(901, Duration::from_millis(3));
+
+        let out = render();
+        assert!(out.contains("# TYPE paxos_messages_received_total counter\n"));
+        assert!(out.contains("paxos_messages_received_total{node=\"901\",kind=\"Tick\"} 2\n"));
+        assert!(out.contains("paxos_leader_ballot{node=\"901\"} 1\n"));
+        assert!(out.contains("paxos_leader_active{node=\"901\"} 1\n"));
+        assert!(out.contains("paxos_leader_proposals{node=\"901\"} 3\n"));
+        assert!(out.contains("paxos_propose_to_decide_seconds_bucket{node=\"901\",le=\"0.0025\"} 0\n"));
+        assert!(out.contains("paxos_propose_to_decide_seconds_bucket{node=\"901\",le=\"0.005\"} 1\n"));
+        assert!(out.contains("paxos_propose_to_decide_seconds_bucket{node=\"901\",le=\"+Inf\"} 1\n"));
+        assert!(out.contains("paxos_propose_to_decide_seconds_count{node=\"901\"} 1\n"));
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        let labels = vec![("kind", "a\"b\\c".to_string())];
+        assert_eq!(format_labels(&labels, Some(("le", "1".to_string()))), "{kind=\"a\\\"b\\\\c\",le=\"1\"}");
+        assert_eq!(format_labels(&Vec::new(), None), "");
+    }
+
+    #[test]
+    fn the_decision_rate_covers_the_last_window() {
+        let now = Instant::now();
+        let mut rate = Rate { window_start: now - Duration::from_millis(1500), count: 3, per_second: 0.0 };
+        roll_window(&mut rate, now);
+        assert!((rate.per_second - 2.0).abs() < 0.01);
+        assert_eq!(rate.count, 0);
+
+        // too early to roll
+        rate.count = 5;
+        roll_window(&mut rate, now + Duration::from_millis(10));
+        assert_eq!(rate.count, 5);
+
+        // a window without a roll reads as idle
+        roll_window(&mut rate, now + Duration::from_millis(3 * RATE_WINDOW_MS));
+        assert_eq!(rate.per_second, 0.0);
+    }
+
+    #[test]
+    fn scrapes_are_answered_over_http() {
+        use std::io::Read;
+        serve(&Addr::new("127.0.0.1", 27351)).unwrap();
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect("127.0.0.1:27351").unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+            let mut reply = String::new();
+            stream.read_to_string(&mut reply).unwrap();
+            reply
+        };
+        let reply = get("/metrics");
+        assert!(reply.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(reply.contains("# TYPE paxos_decisions_total counter\n"));
+        assert!(get("/other").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
@@ -0,0 +1,319 @@
+// UDP transport that moves several datagrams per syscall with recvmmsg/sendmmsg.
+// Receive buffers are allocated once and reused; senders collect the datagrams of
+// consecutive messages to their destination and hand them to the kernel on flush,
+// or as soon as a batch is full. Datagrams use the same fragment format as
+// UdpRecver/UdpSender, so the two interoperate.
+use std::collections::VecDeque;
+use std::io;
+use std::marker::PhantomData;
+use std::mem;
+use std::net::{ UdpSocket, SocketAddr, SocketAddrV4, SocketAddrV6, Ipv4Addr, Ipv6Addr };
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::time::Duration;
+use libc::c_int;
+use messaging::*;
+use error::*;
+
+pub const DEFAULT_BATCH_SIZE: usize = 32;
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct BatchStats {
+    pub syscalls: u64,
+    pub datagrams: u64,
+}
+
+fn last_error() -> TransportError {
+    io::Error::last_os_error().into()
+}
+
+fn to_socket_addr(ss: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match ss.ss_family as c_int {
+        libc::AF_INET => {
+            let sin = unsafe { &*(ss as *const _ as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr));
+            Some(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(sin.sin_port))))
+        },
+        libc::AF_INET6 => {
+            let sin6 = unsafe { &*(ss as *const _ as *const libc::sockaddr_in6) };
+            let ip = Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+            Some(SocketAddr::V6(SocketAddrV6::new(ip, u16::from_be(sin6.sin6_port),
+                                                  sin6.sin6_flowinfo, sin6.sin6_scope_id)))
+        },
+        _ => None,
+    }
+}
+
+pub struct BatchUdpRecver<T> {
+    sock: UdpSocket,
+    bufs: Vec<Vec<u8>>,
+    addrs: Vec<libc::sockaddr_storage>,
+    ready: VecDeque<Vec<u8>>,
+    reassembler: Reassembler<SocketAddr>,
+    stats: BatchStats,
+    msg_type: PhantomData<T>,
+}
+
+impl<T> BatchUdpRecver<T> {
+    // number of datagrams read per syscall
+    pub fn set_batch_size(&mut self, size: usize) {
+        let size = std::cmp::max(size, 1);
+        self.bufs.resize(size, Vec::new());
+        self.bufs.iter_mut().for_each(|b| b.resize(MAX_UDP_PAYLOAD, 0));
+        self.addrs = vec![unsafe { mem::zeroed() }; size];
+    }
+
+    pub fn set_reassembly_timeout(&mut self, timeout: Duration) {
+        self.reassembler.set_timeout(timeout);
+    }
+
+    pub fn reassembler(&self) -> &Reassembler<SocketAddr> {
+        &self.reassembler
+    }
+
+    pub fn stats(&self) -> BatchStats {
+        self.stats
+    }
+
+    // reads whatever the kernel has queued, up to one batch
+    fn fill(&mut self) -> usize {
+        let n = self.bufs.len();
+        let mut iovecs = self.bufs.iter_mut().map(|b| libc::iovec {
+            iov_base: b.as_mut_ptr() as *mut libc::c_void,
+            iov_len: b.len(),
+        }).collect::<Vec<_>>();
+        let mut hdrs = (0..n).map(|i| {
+            let mut hdr: libc::mmsghdr = unsafe { mem::zeroed() };
+            hdr.msg_hdr.msg_name = &mut self.addrs[i] as *mut _ as *mut libc::c_void;
+            hdr.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            hdr.msg_hdr.msg_iov = &mut iovecs[i];
+            hdr.msg_hdr.msg_iovlen = 1;
+            hdr
+        }).collect::<Vec<_>>();
+        let ret = unsafe {
+            libc::recvmmsg(self.sock.as_raw_fd(), hdrs.as_mut_ptr(), n as libc::c_uint,
+                           libc::MSG_DONTWAIT, ptr::null_mut())
+        };
+        if ret <= 0 {
+            return 0;
+        }
+        let count = ret as usize;
+        self.stats.syscalls += 1;
+        self.stats.datagrams += count as u64;
+        for (i, hdr) in hdrs.iter().enumerate().take(count) {
+            let size = hdr.msg_len as usize;
+            let from = match to_socket_addr(&self.addrs[i]) {
+                Some(a) => a,
+                None => continue,
+            };
+            if let Some(msg) = self.reassembler.push(&from, &self.bufs[i][..size]) {
+                self.ready.push_back(msg);
+            }
+        }
+        count
+    }
+}
+
+impl<T> MsgRecver<T> for BatchUdpRecver<T> where
+    T: serde::Serialize + serde::de::DeserializeOwned {
+    type Ctx = ();
+
+    fn bind(addr: &Addr) -> Result<Self, Error> {
+        let sock = UdpSocket::bind(resolve(addr)?)?;
+        sock.set_nonblocking(true)?;
+        let mut recver = BatchUdpRecver {
+            sock,
+            bufs: Vec::new(),
+            addrs: Vec::new(),
+            ready: VecDeque::new(),
+            reassembler: Reassembler::new(reassembly_timeout()),
+            stats: Default::default(),
+            msg_type: PhantomData,
+        };
+        recver.set_batch_size(DEFAULT_BATCH_SIZE);
+        Ok(recver)
+    }
+
+    fn try_recv_str(&mut self) -> Option<Vec<u8>> {
+        self.reassembler.expire();
+        while self.ready.is_empty() {
+            if self.fill() == 0 {
+                break;
+            }
+        }
+        self.ready.pop_front()
+    }
+
+    fn get_io_fds(&self) -> Vec<c_int> {
+        vec![self.sock.as_raw_fd()]
+    }
+
+    fn pending(&self) -> bool {
+        !self.ready.is_empty()
+    }
+}
+
+pub struct BatchUdpSender<T> {
+    sock: Option<UdpSocket>,
+    addr: Addr,
+    fragmenter: Fragmenter,
+    batch: Vec<Vec<u8>>,
+    batch_size: usize,
+    stats: BatchStats,
+    msg_type: PhantomData<T>,
+}
+
+impl<T> BatchUdpSender<T> {
+    pub fn set_max_datagram_size(&mut self, size: usize) -> Result<(), TransportError> {
+        self.fragmenter = Fragmenter::new(size)?;
+        Ok(())
+    }
+
+    // number of datagrams collected before they are sent without waiting for flush
+    pub fn set_batch_size(&mut self, size: usize) {
+        self.batch_size = std::cmp::max(size, 1);
+    }
+
+    pub fn stats(&self) -> BatchStats {
+        self.stats
+    }
+
+    // the socket is connected to the destination, which is resolved on first use
+    fn sock(&mut self) -> Result<&UdpSocket, TransportError> {
+        if self.sock.is_none() {
+            let dest = resolve(&self.addr)?;
+            let sock = UdpSocket::bind(if dest.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })
+                .and_then(|s| s.connect(dest).map(|_| s))
+                .and_then(|s| s.set_nonblocking(true).map(|_| s))?;
+            self.sock = Some(sock);
+        }
+        Ok(self.sock.as_ref().unwrap())
+    }
+
+    // sends the collected datagrams, those the kernel did not take stay queued on WouldBlock
+    fn send_batch(&mut self) -> Result<(), TransportError> {
+        while !self.batch.is_empty() {
+            let fd = self.sock()?.as_raw_fd();
+            let mut iovecs = self.batch.iter().map(|d| libc::iovec {
+                iov_base: d.as_ptr() as *mut libc::c_void,
+                iov_len: d.len(),
+            }).collect::<Vec<_>>();
+            let mut hdrs = iovecs.iter_mut().map(|iov| {
+                let mut hdr: libc::mmsghdr = unsafe { mem::zeroed() };
+                hdr.msg_hdr.msg_iov = iov;
+                hdr.msg_hdr.msg_iovlen = 1;
+                hdr
+            }).collect::<Vec<_>>();
+            let ret = unsafe {
+                libc::sendmmsg(fd, hdrs.as_mut_ptr(), hdrs.len() as libc::c_uint, libc::MSG_DONTWAIT)
+            };
+            if ret < 0 {
+                let e = last_error();
+                if e != TransportError::WouldBlock {
+                    self.batch.clear();
+                }
+                return Err(e);
+            }
+            self.stats.syscalls += 1;
+            self.stats.datagrams += ret as u64;
+            self.batch.drain(..ret as usize);
+        }
+        Ok(())
+    }
+}
+
+impl<T> MsgSender<T> for BatchUdpSender<T> where
+    T: serde::Serialize + serde::de::DeserializeOwned {
+
+    fn connect(addr: &Addr) -> Result<Self, Error> {
+        Ok(BatchUdpSender {
+            sock: None,
+            addr: addr.clone(),
+            fragmenter: Fragmenter::new(max_datagram_size())?,
+            batch: Vec::new(),
+            batch_size: DEFAULT_BATCH_SIZE,
+            stats: Default::default(),
+            msg_type: PhantomData,
+        })
+    }
+
+    // a message is either queued whole or refused with WouldBlock
+    fn send_str(&mut self, s: &[u8]) -> Result<(), Error> {
+        let frags = self.fragmenter.fragment(s)?;
+        if !self.batch.is_empty() && self.batch.len() + frags.len() > self.batch_size {
+            if let Err(TransportError::WouldBlock) = self.send_batch() {
+                return Err(TransportError::WouldBlock.into());
+            }
+        }
+        self.batch.extend(frags);
+        if self.batch.len() >= self.batch_size {
+            match self.send_batch() {
+                Err(TransportError::WouldBlock) | Ok(()) => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.send_batch().map_err(|e| e.into())
+    }
+}
+
+impl<T> Drop for BatchUdpSender<T> {
+    fn drop(&mut self) {
+        let _ = self.send_batch();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> Addr {
+        Addr::new("127.0.0.1", port)
+    }
+
+    #[test]
+    fn queued_messages_go_out_and_come_in_with_one_syscall() {
+        let mut recver = BatchUdpRecver::<String>::bind(&addr(27341)).unwrap();
+        let mut sender = BatchUdpSender::<String>::connect(&addr(27341)).unwrap();
+        for i in 0..5 {
+            sender.send(&i.to_string()).unwrap();
+        }
+        assert_eq!(sender.stats().syscalls, 0);
+        sender.flush().unwrap();
+        assert_eq!((sender.stats().syscalls, sender.stats().datagrams), (1, 5));
+
+        let got = (0..5).map(|_| recver.try_recv_timeout(2000).unwrap()).collect::<Vec<_>>();
+        assert_eq!(got, vec!["0", "1", "2", "3", "4"]);
+        assert_eq!((recver.stats().syscalls, recver.stats().datagrams), (1, 5));
+        assert!(!recver.pending());
+    }
+
+    #[test]
+    fn a_full_batch_goes_out_without_a_flush() {
+        let _recver = BatchUdpRecver::<String>::bind(&addr(27342)).unwrap();
+        let mut sender = BatchUdpSender::<String>::connect(&addr(27342)).unwrap();
+        sender.set_batch_size(3);
+        sender.send(&"a".to_string()).unwrap();
+        sender.send(&"b".to_string()).unwrap();
+        assert_eq!(sender.stats().syscalls, 0);
+        sender.send(&"c".to_string()).unwrap();
+        assert_eq!((sender.stats().syscalls, sender.stats().datagrams), (1, 3));
+    }
+
+    #[test]
+    fn fragments_interoperate_with_plain_udp() {
+        let large = "x".repeat(max_datagram_size() * 3);
+        let mut plain = UdpRecver::<String>::bind(&addr(27343)).unwrap();
+        let mut sender = BatchUdpSender::<String>::connect(&addr(27343)).unwrap();
+        sender.send(&large).unwrap();
+        sender.flush().unwrap();
+        assert_eq!(plain.try_recv_timeout(2000).as_ref(), Some(&large));
+
+        let mut batched = BatchUdpRecver::<String>::bind(&addr(27344)).unwrap();
+        UdpSender::<String>::connect(&addr(27344)).unwrap().send(&large).unwrap();
+        assert_eq!(batched.try_recv_timeout(2000), Some(large));
+    }
+}
@@ -1,4 +1,4 @@
-use std::collections::{ HashMap, HashSet };
+use std::collections::{ BTreeMap, HashMap, HashSet };
 use std::vec::Vec;
 use std::hash::Hash;
 use messaging::*;
@@ -7,22 +7,419 @@ use leader::*;
 use replica::*;
 use acceptor::*;
 use statemachine::*;
+use reactor::LocalBus;
+use outbox::*;
+use metrics;
+use clock::{ Clock, SharedClock, system_clock };
+use status::*;
+use error::*;
+use reload;
+use threaded::Outgoing;
 use std::marker::PhantomData;
+use std::rc::Rc;
+use libc::c_int;
 
 use rand::{thread_rng, Rng};
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+// how long recv_response waits on one source before checking the other
+const REPLY_SLICE_MS: i64 = 10;
+// routes of clients that never got an answer are kept up to this many, the
+// oldest one makes room
+pub const MAX_ROUTES: usize = 4096;
+// a client that every replica turned away waits this long before the next round,
+// twice as long after each further round up to MAX_FAILOVER_PAUSE_MS
+const FAILOVER_PAUSE_MS: u64 = 50;
+const MAX_FAILOVER_PAUSE_MS: u64 = 1000;
+// rounds over the replicas before a client gives up, also without a deadline
+pub const MAX_FAILOVER_ROUNDS: usize = 10;
 
 pub trait Node {
     type PollItem;
 
-    fn non_blocking_processing(&mut self) -> Result<(), i32>;
+    // waits up to timeout_ms for a message and handles it, a negative timeout waits forever
+    fn process_timeout(&mut self, timeout_ms: i64) -> Result<(), Error>;
+    fn get_io_fds(&self) -> Vec<c_int>;
+
+    // milliseconds until the next protocol timer fires, None if no timer is pending
+    fn next_timer_ms(&self) -> Option<i64> {
+        None
+    }
+
+    // true if a message can be handled without waiting on the fds
+    fn pending(&self) -> bool {
+        false
+    }
+
+    fn non_blocking_processing(&mut self) -> Result<(), Error> {
+        self.process_timeout(0)
+    }
+
+    // sleeps until a message arrives or the next timer fires
+    fn run_once(&mut self) -> Result<(), Error> {
+        let timeout = self.next_timer_ms().unwrap_or(-1);
+        self.process_timeout(timeout)
+    }
+
+    // stops taking new work, finishes or hands off what is in flight and sends
+    // whatever is still queued, giving up after timeout_ms; an error if
+    // something was left behind. Nodes keep no state on disk, so there is
+    // nothing else to flush.
+    fn shutdown(&mut self, _timeout_ms: i64) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+// object-safe view of a bound receiver, used for extra inputs such as multicast groups
+pub trait Inbox<M> {
+    fn poll_msg(&mut self) -> Option<M>;
+    fn inbox_fds(&self) -> Vec<c_int>;
+    fn inbox_pending(&self) -> bool;
+}
+
+impl<M, R> Inbox<M> for R where
+    M: serde::Serialize + serde::de::DeserializeOwned,
+    R: MsgRecver<M> {
+    fn poll_msg(&mut self) -> Option<M> {
+        self.try_recv()
+    }
+
+    fn inbox_fds(&self) -> Vec<c_int> {
+        self.get_io_fds()
+    }
+
+    fn inbox_pending(&self) -> bool {
+        self.pending()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Group {
+    Acceptors,
+    Replicas,
+}
+
+// messages the leader sends identically to every member of a group
+pub fn broadcast_group<CmdT, ResultT>(m: &Message<CmdT, ResultT>) -> Option<Group> {
+    match m {
+        Message::P1a { .. } | Message::P2a { .. } | Message::Heartbeat { .. } => Some(Group::Acceptors),
+        Message::Decision { .. } => Some(Group::Replicas),
+        _ => None,
+    }
+}
+
+pub struct McastGroups<M> {
+    pub acceptors: Addr,
+    pub replicas: Addr,
+    send: fn(&Addr, &M) -> Result<(), Error>,
+}
+
+impl<M> McastGroups<M> {
+    pub fn addr(&self, group: Group) -> &Addr {
+        match group {
+            Group::Acceptors => &self.acceptors,
+            Group::Replicas => &self.replicas,
+        }
+    }
+}
+
+fn send_via<M, S>(addr: &Addr, m: &M) -> Result<(), Error> where
+    M: serde::Serialize + serde::de::DeserializeOwned,
+    S: MsgSender<M> {
+    let mut c = S::connect(addr)?;
+    c.send(m)?;
+    c.flush()
+}
+
+// takes the next message for `my_id`: local deliveries first, then the multicast
+// group and the node's own transport
+fn recv_msg<'a, M, ServerT>(server: &mut ServerT, bus: &Option<Rc<LocalBus<M>>>,
+                            group: &mut Option<Box<dyn Inbox<M> + 'a>>,
+                            my_id: ServerID, timeout_ms: i64) -> Option<M> where
+    M: serde::Serialize + serde::de::DeserializeOwned,
+    ServerT: MsgRecver<M> {
+    let local = bus.as_ref().and_then(|b| b.pop(my_id));
+    if local.is_some() {
+        return local;
+    }
+    let group = match *group {
+        Some(ref mut g) => g,
+        None => return server.try_recv_timeout(timeout_ms),
+    };
+    let deadline = deadline_after(timeout_ms);
+    loop {
+        let msg = group.poll_msg().or_else(|| server.try_recv());
+        if msg.is_some() {
+            return msg;
+        }
+        let remaining = remaining_ms(deadline);
+        if remaining == 0 {
+            return None;
+        }
+        if !server.pending() && !group.inbox_pending() {
+            let mut fds = server.get_io_fds();
+            fds.extend(group.inbox_fds());
+            if !wait_readable(fds.as_slice(), remaining) {
+                return None;
+            }
+        }
+    }
+}
+
+fn group_fds<'a, M>(group: &Option<Box<dyn Inbox<M> + 'a>>) -> Vec<c_int> {
+    group.as_ref().map_or(Vec::new(), |g| g.inbox_fds())
+}
+
+fn group_pending<'a, M>(group: &Option<Box<dyn Inbox<M> + 'a>>) -> bool {
+    group.as_ref().is_some_and(|g| g.inbox_pending())
+}
+
+// short-circuits messages to colocated roles, everything else is queued for the network
+fn send_to_server<M, ClientT>(bus: &Option<Rc<LocalBus<M>>>, outbox: &mut Outbox<M, ClientT>,
+                              server_addrs: &HashMap<ServerID, Addr>, my_id: ServerID,
+                              server_id: ServerID, m: M) where
+    M: serde::Serialize + serde::de::DeserializeOwned + MessageKind,
+    ClientT: MsgSender<M> {
+    metrics::message_sent(my_id, &m);
+    let m = match *bus {
+        Some(ref b) => match b.push(server_id, m) {
+            Ok(()) => return,
+            Err(m) => m,
+        },
+        None => m,
+    };
+    if let Some(addr) = server_addrs.get(&server_id) {
+        let _ = outbox.push(addr, m);
+    }
+}
+
+// answers an admin request over the connection it came in on, or at the
+// client's own address
+fn reply_admin<CmdT, ResultT, ServerT, ClientT>(server: &mut ServerT, outbox: &mut Outbox<Message<CmdT, ResultT>, ClientT>,
+                                                my_id: ServerID, cid: &ClientID, m: Message<CmdT, ResultT>) where
+    CmdT: serde::Serialize + serde::de::DeserializeOwned,
+    ResultT: serde::Serialize + serde::de::DeserializeOwned,
+    ServerT: MsgRecver<Message<CmdT, ResultT>>,
+    ClientT: MsgSender<Message<CmdT, ResultT>> {
+    metrics::message_sent(my_id, &m);
+    let replied = server.last_route()
+        .is_some_and(|route| server.reply(route.as_slice(), &m).is_ok());
+    if !replied {
+        let _ = outbox.push(cid, m);
+    }
+    outbox.flush();
+}
+
+// replies may come back over the request's connection or to the client's own
+// server, the two are checked in turns
+pub fn recv_response<M, ServerT, ClientT>(server: &mut ServerT, conn: &mut ClientT,
+                                          timeout_ms: i64) -> Option<M> where
+    M: serde::Serialize + serde::de::DeserializeOwned,
+    ServerT: MsgRecver<M>,
+    ClientT: MsgSender<M> {
+    let deadline = deadline_after(timeout_ms);
+    loop {
+        let msg = conn.try_recv_reply(0).or_else(|| {
+            let remaining = remaining_ms(deadline);
+            let slice = if remaining < 0 { REPLY_SLICE_MS } else { std::cmp::min(remaining, REPLY_SLICE_MS) };
+            server.try_recv_timeout(slice)
+        });
+        if msg.is_some() || remaining_ms(deadline) == 0 {
+            return msg;
+        }
+    }
+}
+
+// clients number their requests from the clock in microseconds, so that a
+// restarted client does not reuse the ids of requests that were applied before
+pub fn first_req_id() -> ReqID {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() * 1_000_000 + d.subsec_micros() as u64)
+        .unwrap_or(0)
+}
+
+// sends a request to one replica and waits up to timeout_ms for its answer
+fn attempt_request<CmdT, ResultT, ServerT, ClientT>(server: &mut ServerT, replica: &Addr, req: &Message<CmdT, ResultT>,
+                                                    cid: &ClientID, req_id: Option<ReqID>,
+                                                    timeout_ms: i64) -> Result<ResultT, Error> where
+    CmdT: serde::Serialize + serde::de::DeserializeOwned,
+    ResultT: serde::Serialize + serde::de::DeserializeOwned,
+    ServerT: MsgRecver<Message<CmdT, ResultT>>,
+    ClientT: MsgSender<Message<CmdT, ResultT>> {
+    let mut c = ClientT::connect(replica)?;
+    c.send(req)?;
+    let _ = c.flush();
+    let deadline = deadline_after(timeout_ms);
+    loop {
+        match recv_response(server, &mut c, remaining_ms(deadline)) {
+            Some(Message::Response { cid: ref to, result, req_id: id }) if to == cid && id == req_id =>
+                return Ok(result),
+            Some(Message::Rejected { cid: ref to, reason, req_id: id }) if to == cid && id == req_id =>
+                return Err(ClientError::Rejected(reason).into()),
+            // meant for an earlier request
+            Some(_) => (),
+            None => return Err(ClientError::Timeout.into()),
+        }
+        if remaining_ms(deadline) == 0 {
+            return Err(ClientError::Timeout.into());
+        }
+    }
+}
+
+// sends `cmd` as request `req_id` of client `cid` to the replicas in turn,
+// starting at a random one, until one answers with its response. Each attempt
+// waits up to attempt_ms, a rejection or a transport error moves on to the next
+// replica right away, and a round that failed without waiting is followed by a
+// growing pause. The call gives up after deadline_ms on `clock` or after
+// MAX_FAILOVER_ROUNDS rounds; negative timeouts do not expire. The error is what
+// happened to the last attempt.
+#[allow(clippy::too_many_arguments)]
+pub fn request_with_failover<CmdT, ResultT, ServerT, ClientT>(server: &mut ServerT, replicas: &[Addr],
+                                                              cid: &ClientID, req_id: ReqID, cmd: CmdT,
+                                                              attempt_ms: i64, deadline_ms: i64,
+                                                              clock: &dyn Clock) -> Result<ResultT, Error> where
+    CmdT: serde::Serialize + serde::de::DeserializeOwned,
+    ResultT: serde::Serialize + serde::de::DeserializeOwned,
+    ServerT: MsgRecver<Message<CmdT, ResultT>>,
+    ClientT: MsgSender<Message<CmdT, ResultT>> {
+    if replicas.is_empty() {
+        return Err(ClientError::NoReplicas.into());
+    }
+    let req = Message::Request { cid: cid.clone(), cmd, req_id: Some(req_id) };
+    let deadline = if deadline_ms < 0 { None } else { Some(clock.now_ms() + deadline_ms as u64) };
+    let remaining = || deadline.map_or(-1, |d| d.saturating_sub(clock.now_ms()) as i64);
+    let first = thread_rng().gen_range(0, replicas.len());
+    let mut last_err: Error = ClientError::Timeout.into();
+    let mut attempt = 0;
+    loop {
+        let left = remaining();
+        if left == 0 || attempt == MAX_FAILOVER_ROUNDS * replicas.len() {
+            return Err(last_err);
+        }
+        let timeout = match (attempt_ms, left) {
+            (a, l) if a < 0 => l,
+            (a, l) if l < 0 => a,
+            (a, l) => std::cmp::min(a, l),
+        };
+        let replica = &replicas[(first + attempt) % replicas.len()];
+        match attempt_request::<_, _, _, ClientT>(server, replica, &req, cid, Some(req_id), timeout) {
+            Ok(result) => return Ok(result),
+            Err(e) => {
+                log_debug!("client", { replica = replica, attempt = attempt }, "attempt failed: {}", e);
+                last_err = e;
+            },
+        }
+        attempt += 1;
+        // a full round that failed early, e.g. every replica is saturated
+        if attempt % replicas.len() == 0 && last_err != ClientError::Timeout.into() {
+            let pause = failover_pause_ms(attempt / replicas.len() - 1);
+            clock.sleep_ms(match remaining() { -1 => pause, l => std::cmp::min(l as u64, pause) });
+        }
+    }
+}
+
+// the pause after the given failover round, counted from 0, failed without waiting
+pub fn failover_pause_ms(round: usize) -> u64 {
+    let round = std::cmp::min(round, 16) as u32;
+    std::cmp::min(FAILOVER_PAUSE_MS.saturating_mul(1 << round), MAX_FAILOVER_PAUSE_MS)
+}
+
+fn min_timer(a: Option<i64>, b: Option<i64>) -> Option<i64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(std::cmp::min(a, b)),
+        (a, b) => a.or(b),
+    }
+}
+
+fn bus_pending<M>(bus: &Option<Rc<LocalBus<M>>>, my_id: ServerID) -> bool {
+    bus.as_ref().is_some_and(|b| b.has_pending(my_id))
+}
+
+// the connections that client requests came in on, so that the answers go back
+// over them; a route is dropped once it is used
+pub struct Routes {
+    routes: HashMap<ClientID, (u64, Vec<u8>)>,
+    // client by insertion number, oldest first
+    order: BTreeMap<u64, ClientID>,
+    next: u64,
+    cap: usize,
+}
+
+impl Default for Routes {
+    fn default() -> Self {
+        Routes::new(MAX_ROUTES)
+    }
+}
+
+impl Routes {
+    pub fn new(cap: usize) -> Self {
+        Routes { routes: HashMap::new(), order: BTreeMap::new(), next: 0, cap }
+    }
+
+    pub fn insert(&mut self, cid: ClientID, route: Vec<u8>) {
+        self.remove(&cid);
+        if self.routes.len() >= self.cap {
+            if let Some((_, oldest)) = self.order.pop_first() {
+                self.routes.remove(&oldest);
+            }
+        }
+        self.order.insert(self.next, cid.clone());
+        self.routes.insert(cid, (self.next, route));
+        self.next += 1;
+    }
+
+    pub fn remove(&mut self, cid: &ClientID) -> Option<Vec<u8>> {
+        let (n, route) = self.routes.remove(cid)?;
+        self.order.remove(&n);
+        Some(route)
+    }
+
+    pub fn len(&self) -> usize {
+        self.routes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+}
+
+// takes over the client requests a replica receives while it shuts down: they
+// go round the other replicas, or are turned away when there is none
+pub struct HandOff {
+    peers: Vec<ServerID>,
+    next: usize,
+}
+
+impl HandOff {
+    pub fn new(my_id: ServerID, replicas: &HashSet<ServerID>) -> Self {
+        let mut peers: Vec<ServerID> = replicas.iter().cloned().filter(|id| *id != my_id).collect();
+        peers.sort();
+        HandOff { peers, next: 0 }
+    }
+
+    // the request for the next peer, or the rejection for the client
+    pub fn route<CmdT, ResultT>(&mut self, cid: ClientID, req_id: Option<ReqID>,
+                                cmd: CmdT) -> Outgoing<Message<CmdT, ResultT>> {
+        if self.peers.is_empty() {
+            let reason = "replica is shutting down".to_string();
+            return Outgoing::ToClient(cid.clone(), Message::Rejected { cid, reason, req_id });
+        }
+        let peer = self.peers[self.next % self.peers.len()];
+        self.next += 1;
+        Outgoing::ToServer(peer, Message::Request { cid, cmd, req_id })
+    }
 }
 
 pub struct LeaderNode<'a, CmdT, ResultT, ServerT, ClientT> {
     server: ServerT,
     leader: Leader<'a, CmdT>,
+    my_id: ServerID,
     server_addrs: &'a HashMap<ServerID, Addr>,
-    result_type: PhantomData<ResultT>,
-    client_type: PhantomData<ClientT>,
+    bus: Option<Rc<LocalBus<Message<CmdT, ResultT>>>>,
+    mcast: Option<McastGroups<Message<CmdT, ResultT>>>,
+    outbox: Outbox<Message<CmdT, ResultT>, ClientT>,
+    // the settings reload whose timeouts the leader runs with
+    reload_gen: usize,
 }
 
 impl<'a, CmdT, ResultT, ServerT, ClientT> LeaderNode<'a, CmdT, ResultT, ServerT, ClientT> where
@@ -34,13 +431,59 @@ impl<'a, CmdT, ResultT, ServerT, ClientT> LeaderNode<'a, CmdT, ResultT, ServerT,
                acceptors: &'a HashSet<ServerID>,
                replica: &'a HashSet<ServerID>,
                my_id: ServerID,
-               server_addrs: &'a HashMap<ServerID, Addr>) -> Self {
-        LeaderNode {
-            server: ServerT::bind(addr),
+               server_addrs: &'a HashMap<ServerID, Addr>) -> Result<Self, Error> {
+        Ok(LeaderNode {
+            server: ServerT::bind(addr)?,
             leader: Leader::new(acceptors, replica, my_id),
-            server_addrs: server_addrs,
-            result_type: PhantomData,
-            client_type: PhantomData,
+            my_id,
+            server_addrs,
+            bus: None,
+            mcast: None,
+            outbox: Outbox::new(Default::default()),
+            reload_gen: reload::generation(),
+        })
+    }
+
+    // broadcasts go out once to the group, `G` is the sender used for the groups
+    pub fn with_multicast<G>(mut self, acceptor_group: &Addr, replica_group: &Addr) -> Self where
+        G: MsgSender<Message<CmdT, ResultT>> {
+        self.mcast = Some(McastGroups {
+            acceptors: acceptor_group.clone(),
+            replicas: replica_group.clone(),
+            send: send_via::<_, G>,
+        });
+        self
+    }
+
+    pub fn with_local_bus(mut self, bus: Rc<LocalBus<Message<CmdT, ResultT>>>) -> Self {
+        bus.register(self.my_id);
+        self.bus = Some(bus);
+        self
+    }
+
+    pub fn with_outbox(mut self, config: OutboxConfig) -> Self {
+        self.outbox.set_config(config);
+        self
+    }
+
+    pub fn with_timeouts(mut self, timeouts: LeaderTimeouts) -> Self {
+        self.leader.set_timeouts(timeouts);
+        self
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.leader.set_clock(clock);
+        self
+    }
+
+    pub fn outbox_stats(&self) -> OutboxStats {
+        self.outbox.stats()
+    }
+
+    // timeouts of a reload, by this node or another role in the process
+    fn take_reloaded_timeouts(&mut self) {
+        if let Some(timeouts) = reload::reloaded_leader_timeouts(&mut self.reload_gen) {
+            self.leader.set_timeouts(timeouts);
         }
     }
 }
@@ -52,28 +495,74 @@ impl<'a, CmdT, ResultT, ServerT, ClientT> Node for LeaderNode<'a, CmdT, ResultT,
     ClientT: MsgSender<Message<CmdT, ResultT>> {
     type PollItem = ();
     
-    fn non_blocking_processing(&mut self) -> Result<(), i32> {
-        let maybe_msg = self.server.try_recv_timeout(100);
-        let msg = maybe_msg.unwrap_or(Message::Tick);
-        let to_send = self.leader.handle_msg(&msg);
-        to_send.into_iter().map(|(server_id, m)| {
-            //println!("{} sending to {} {:?}", std::process::id(), server_id, m);
-            self.server_addrs.get(&server_id).map(|addr| {
-                let mut c = ClientT::connect(addr);
-                c.send(&m);
-            });
-        }).collect::<()>();
+    fn process_timeout(&mut self, timeout_ms: i64) -> Result<(), Error> {
+        self.outbox.flush();
+        let maybe_msg = recv_msg(&mut self.server, &self.bus, &mut None, self.my_id, timeout_ms);
+        if let Some(m) = maybe_msg.as_ref() {
+            metrics::message_received(self.my_id, m);
+        }
+        let msg = match maybe_msg {
+            Some(Message::StatusRequest { cid }) => {
+                let status = Message::Status { status: NodeStatus::Leader(self.leader.status()) };
+                reply_admin(&mut self.server, &mut self.outbox, self.my_id, &cid, status);
+                return Ok(());
+            },
+            Some(Message::ReloadRequest { cid }) => {
+                let report = Message::ReloadReport { changes: reload::reload() };
+                reply_admin(&mut self.server, &mut self.outbox, self.my_id, &cid, report);
+                self.take_reloaded_timeouts();
+                return Ok(());
+            },
+            msg => msg.unwrap_or(Message::Tick),
+        };
+        self.take_reloaded_timeouts();
+        let to_send = self.leader.handle_msg(&msg)?;
+        metrics::leader_state(self.my_id, self.leader.ballot(), self.leader.is_active(), self.leader.proposal_count());
+        // the leader addresses broadcasts to each member, only one copy goes to the group
+        let mut broadcast: HashSet<String> = HashSet::new();
+        to_send.into_iter().for_each(|(server_id, m)| {
+            match (self.mcast.as_ref(), broadcast_group(&m)) {
+                (Some(mcast), Some(group)) => {
+                    let key = serde_json::to_string(&m).unwrap_or_default();
+                    if broadcast.insert(key) {
+                        metrics::message_sent(self.my_id, &m);
+                        let _ = (mcast.send)(mcast.addr(group), &m);
+                    }
+                },
+                _ => send_to_server(&self.bus, &mut self.outbox, self.server_addrs, self.my_id, server_id, m),
+            }
+        });
+        self.outbox.flush();
         Ok(())
     }
+
+    fn get_io_fds(&self) -> Vec<c_int> {
+        self.server.get_io_fds()
+    }
+
+    fn next_timer_ms(&self) -> Option<i64> {
+        min_timer(self.leader.next_timer_ms(), self.outbox.next_retry_ms())
+    }
+
+    fn pending(&self) -> bool {
+        bus_pending(&self.bus, self.my_id) || self.server.pending()
+    }
+
+    // proposals still in flight are picked up by the next leader's phase 1
+    fn shutdown(&mut self, timeout_ms: i64) -> Result<(), Error> {
+        self.outbox.drain(deadline_after(timeout_ms)).map_err(|e| e.into())
+    }
 }
 
 
 pub struct AcceptorNode<'a, CmdT, ResultT, ServerT, ClientT> {
     server: ServerT,
     acceptor: Acceptor<CmdT>,
+    my_id: ServerID,
     server_addrs: &'a HashMap<ServerID, Addr>,
-    result_type: PhantomData<ResultT>,
-    client_type: PhantomData<ClientT>,
+    bus: Option<Rc<LocalBus<Message<CmdT, ResultT>>>>,
+    group: Option<Box<dyn Inbox<Message<CmdT, ResultT>> + 'a>>,
+    outbox: Outbox<Message<CmdT, ResultT>, ClientT>,
 }
 
 impl<'a, CmdT, ResultT, ServerT, ClientT> AcceptorNode<'a, CmdT, ResultT, ServerT, ClientT> where
@@ -83,14 +572,38 @@ impl<'a, CmdT, ResultT, ServerT, ClientT> AcceptorNode<'a, CmdT, ResultT, Server
     ClientT: MsgSender<Message<CmdT, ResultT>> {
     pub fn new(addr: &Addr,
                my_id: ServerID,
-               server_addrs: &'a HashMap<ServerID, Addr>) -> Self {
-        AcceptorNode {
-            server: ServerT::bind(addr),
+               server_addrs: &'a HashMap<ServerID, Addr>) -> Result<Self, Error> {
+        Ok(AcceptorNode {
+            server: ServerT::bind(addr)?,
             acceptor: Acceptor::new(my_id),
-            server_addrs: server_addrs,
-            result_type: PhantomData,
-            client_type: PhantomData,
-        }
+            my_id,
+            server_addrs,
+            bus: None,
+            group: None,
+            outbox: Outbox::new(Default::default()),
+        })
+    }
+
+    pub fn with_local_bus(mut self, bus: Rc<LocalBus<Message<CmdT, ResultT>>>) -> Self {
+        bus.register(self.my_id);
+        self.bus = Some(bus);
+        self
+    }
+
+    pub fn with_outbox(mut self, config: OutboxConfig) -> Self {
+        self.outbox.set_config(config);
+        self
+    }
+
+    pub fn outbox_stats(&self) -> OutboxStats {
+        self.outbox.stats()
+    }
+
+    // also listens on the acceptors' multicast group
+    pub fn with_multicast<G>(mut self, group: &Addr) -> Result<Self, Error> where
+        G: MsgRecver<Message<CmdT, ResultT>> + 'a {
+        self.group = Some(Box::new(G::bind(group)?));
+        Ok(self)
     }
 }
 
@@ -101,27 +614,70 @@ impl<'a, CmdT, ResultT, ServerT, ClientT> Node for AcceptorNode<'a, CmdT, Result
     ClientT: MsgSender<Message<CmdT, ResultT>> {
     type PollItem = ();
 
-    fn non_blocking_processing(&mut self) -> Result<(), i32> {
-        let maybe_msg = self.server.try_recv_timeout(100);
-        maybe_msg.map_or(Err(-1), |msg| {
+    fn process_timeout(&mut self, timeout_ms: i64) -> Result<(), Error> {
+        self.outbox.flush();
+        let maybe_msg = recv_msg(&mut self.server, &self.bus, &mut self.group, self.my_id, timeout_ms);
+        // nothing arrived in time
+        maybe_msg.map_or(Ok(()), |msg| {
+            metrics::message_received(self.my_id, &msg);
+            match msg {
+                Message::StatusRequest { ref cid } => {
+                    let status = Message::Status { status: NodeStatus::Acceptor(self.acceptor.status()) };
+                    reply_admin(&mut self.server, &mut self.outbox, self.my_id, cid, status);
+                    return Ok(());
+                },
+                Message::ReloadRequest { ref cid } => {
+                    let report = Message::ReloadReport { changes: reload::reload() };
+                    reply_admin(&mut self.server, &mut self.outbox, self.my_id, cid, report);
+                    return Ok(());
+                },
+                _ => (),
+            }
             let to_send = self.acceptor.handle_msg::<ResultT>(&msg);
-            to_send.into_iter().map(|(server_id, m)| {
-                self.server_addrs.get(&server_id).map(|addr| {
-                    let mut c = ClientT::connect(addr);
-                    c.send(&m);
-                });
-            }).collect::<()>();
+            metrics::acceptor_state(self.my_id, self.acceptor.ballot());
+            to_send.into_iter().for_each(|(server_id, m)| {
+                send_to_server(&self.bus, &mut self.outbox, self.server_addrs, self.my_id, server_id, m);
+            });
+            self.outbox.flush();
             Ok(())
         })
     }
+
+    fn get_io_fds(&self) -> Vec<c_int> {
+        let mut fds = self.server.get_io_fds();
+        fds.extend(group_fds(&self.group));
+        fds
+    }
+
+    fn next_timer_ms(&self) -> Option<i64> {
+        self.outbox.next_retry_ms()
+    }
+
+    fn pending(&self) -> bool {
+        bus_pending(&self.bus, self.my_id) || self.server.pending() || group_pending(&self.group)
+    }
+
+    fn shutdown(&mut self, timeout_ms: i64) -> Result<(), Error> {
+        self.outbox.drain(deadline_after(timeout_ms)).map_err(|e| e.into())
+    }
 }
 
 
+type ReplicaMsg<S> = Message<<S as StateMachine>::Op, <S as StateMachine>::Result>;
+
 pub struct ReplicaNode<'a, S: StateMachine, ServerT, ClientT> {
     server: ServerT,
     replica: Replica<'a, S>,
+    my_id: ServerID,
     server_addrs: &'a HashMap<ServerID, Addr>,
-    client_type: PhantomData<ClientT>,
+    bus: Option<Rc<LocalBus<ReplicaMsg<S>>>>,
+    group: Option<Box<dyn Inbox<ReplicaMsg<S>> + 'a>>,
+    outbox: Outbox<ReplicaMsg<S>, ClientT>,
+    // connections that requests came in on, responses go back over them
+    routes: Routes,
+    hand_off: HandOff,
+    draining: bool,
+    proposal_timer: metrics::ProposalTimer,
 }
 
 impl<'a, S, ServerT, ClientT> ReplicaNode<'a, S, ServerT, ClientT> where
@@ -133,12 +689,71 @@ impl<'a, S, ServerT, ClientT> ReplicaNode<'a, S, ServerT, ClientT> where
     pub fn new(addr: &Addr,
                my_id: ServerID,
                server_addrs: &'a HashMap<ServerID, Addr>,
-               leaders: &'a HashSet<ServerID>) -> Self {
-        ReplicaNode {
-            server: ServerT::bind(addr),
+               leaders: &'a HashSet<ServerID>) -> Result<Self, Error> {
+        Ok(ReplicaNode {
+            server: ServerT::bind(addr)?,
             replica: Replica::new(leaders),
-            server_addrs: server_addrs,
-            client_type: PhantomData,
+            my_id,
+            server_addrs,
+            bus: None,
+            group: None,
+            outbox: Outbox::new(Default::default()),
+            routes: Routes::default(),
+            hand_off: HandOff::new(my_id, &HashSet::new()),
+            draining: false,
+            proposal_timer: Default::default(),
+        })
+    }
+
+    // the other replicas take over requests while this one shuts down
+    pub fn with_peers(mut self, replicas: &HashSet<ServerID>) -> Self {
+        self.hand_off = HandOff::new(self.my_id, replicas);
+        self
+    }
+
+    pub fn with_local_bus(mut self, bus: Rc<LocalBus<Message<S::Op, S::Result>>>) -> Self {
+        bus.register(self.my_id);
+        self.bus = Some(bus);
+        self
+    }
+
+    pub fn with_outbox(mut self, config: OutboxConfig) -> Self {
+        self.outbox.set_config(config);
+        self
+    }
+
+    pub fn outbox_stats(&self) -> OutboxStats {
+        self.outbox.stats()
+    }
+
+    // also listens on the replicas' multicast group
+    pub fn with_multicast<G>(mut self, group: &Addr) -> Result<Self, Error> where
+        G: MsgRecver<Message<S::Op, S::Result>> + 'a {
+        self.group = Some(Box::new(G::bind(group)?));
+        Ok(self)
+    }
+
+    // forwards a client request to the next peer replica, or rejects it if there
+    // is none and returns false
+    fn pass_on(&mut self, cid: ClientID, req_id: Option<ReqID>, cmd: S::Op, route: Option<Vec<u8>>) -> bool {
+        match self.hand_off.route(cid, req_id, cmd) {
+            Outgoing::ToServer(peer, m) => {
+                send_to_server(&self.bus, &mut self.outbox, self.server_addrs, self.my_id, peer, m);
+                true
+            },
+            Outgoing::ToClient(cid, m) => {
+                self.send_to_client(cid, route, m);
+                false
+            },
+        }
+    }
+
+    // answers over the connection the request came in on, if there is one
+    fn send_to_client(&mut self, cid: ClientID, route: Option<Vec<u8>>, m: ReplicaMsg<S>) {
+        metrics::message_sent(self.my_id, &m);
+        let replied = route.is_some_and(|route| self.server.reply(route.as_slice(), &m).is_ok());
+        if !replied {
+            let _ = self.outbox.push(&cid, m);
         }
     }
 }
@@ -151,28 +766,102 @@ impl<'a, S, ServerT, ClientT> Node for ReplicaNode<'a, S, ServerT, ClientT> wher
     ClientT: MsgSender<Message<S::Op, S::Result>> {
     type PollItem = ();
 
-    fn non_blocking_processing(&mut self) -> Result<(), i32> {
-        let maybe_msg = self.server.try_recv_timeout(100);
-        let msg = maybe_msg.unwrap_or(Message::Tick);
+    fn process_timeout(&mut self, timeout_ms: i64) -> Result<(), Error> {
+        self.outbox.flush();
+        let maybe_msg = recv_msg(&mut self.server, &self.bus, &mut self.group, self.my_id, timeout_ms);
+        if let Some(m) = maybe_msg.as_ref() {
+            metrics::message_received(self.my_id, m);
+        }
+        let msg = match maybe_msg {
+            Some(Message::StatusRequest { cid }) => {
+                let status = Message::Status { status: NodeStatus::Replica(self.replica.status(self.my_id)) };
+                reply_admin(&mut self.server, &mut self.outbox, self.my_id, &cid, status);
+                return Ok(());
+            },
+            Some(Message::ReloadRequest { cid }) => {
+                let report = Message::ReloadReport { changes: reload::reload() };
+                reply_admin(&mut self.server, &mut self.outbox, self.my_id, &cid, report);
+                return Ok(());
+            },
+            Some(Message::Request { cid, cmd, req_id }) if self.draining => {
+                let route = self.server.last_route();
+                self.pass_on(cid, req_id, cmd, route);
+                self.outbox.flush();
+                return Ok(());
+            },
+            Some(msg) => msg,
+            None => Message::Tick,
+        };
+        if let Message::Request { ref cid, .. } = msg {
+            if let Some(route) = self.server.last_route() {
+                self.routes.insert(cid.clone(), route);
+            }
+        }
+        // new requests are turned away while a peer cannot keep up
+        self.replica.set_accepting(!self.draining && !self.outbox.saturated());
+        let slot_out = self.replica.slot_out();
         let (to_send_server, to_send_client) = self.replica.handle_msg(&msg);
-        to_send_server.into_iter().map(|(server_id, m)| {
-            self.server_addrs.get(&server_id).map(|addr| {
-                let mut c = ClientT::connect(addr);
-                c.send(&m);
-            });
-        }).collect::<()>();
-        to_send_client.into_iter().map(|(addr, m)| {
-            let mut c = ClientT::connect(&addr);
-            c.send(&m);
-        }).collect::<()>();
+        self.proposal_timer.observe(self.my_id, slot_out, self.replica.slot_out(), &to_send_server);
+        metrics::replica_state(self.my_id, self.replica.slot_in(), self.replica.slot_out(), self.replica.pending_requests());
+        to_send_server.into_iter().for_each(|(server_id, m)| {
+            send_to_server(&self.bus, &mut self.outbox, self.server_addrs, self.my_id, server_id, m);
+        });
+        for (addr, m) in to_send_client {
+            let route = self.routes.remove(&addr);
+            self.send_to_client(addr, route, m);
+        }
+        self.outbox.flush();
         Ok(())
     }
+
+    fn get_io_fds(&self) -> Vec<c_int> {
+        let mut fds = self.server.get_io_fds();
+        fds.extend(group_fds(&self.group));
+        fds
+    }
+
+    fn next_timer_ms(&self) -> Option<i64> {
+        self.outbox.next_retry_ms()
+    }
+
+    fn pending(&self) -> bool {
+        bus_pending(&self.bus, self.my_id) || self.server.pending() || group_pending(&self.group)
+    }
+
+    // new requests go to the peers; proposed ones are answered if their decisions
+    // arrive in time, the rest are handed off too
+    fn shutdown(&mut self, timeout_ms: i64) -> Result<(), Error> {
+        let deadline = deadline_after(timeout_ms);
+        self.draining = true;
+        while self.replica.in_flight() > 0 && remaining_ms(deadline) != 0 {
+            let _ = self.process_timeout(remaining_ms(deadline));
+        }
+        let mut left_behind = self.replica.in_flight();
+        for (cid, req_id, cmd) in self.replica.take_requests() {
+            let route = self.routes.remove(&cid);
+            if !self.pass_on(cid, req_id, cmd, route) {
+                left_behind += 1;
+            }
+        }
+        self.outbox.drain(deadline)?;
+        if left_behind > 0 {
+            Err(ProtocolError::Unfinished(left_behind).into())
+        } else {
+            Ok(())
+        }
+    }
 }
 
 
 pub struct ClientNode<'a, S: StateMachine, ServerT, ClientT> {
     server: ServerT,
+    // where the client listens, requests carry it as their ClientID
+    addr: Addr,
     replicas: &'a HashSet<Addr>,
+    timeout_ms: i64,
+    attempt_timeout_ms: i64,
+    next_req_id: ReqID,
+    clock: SharedClock,
     state_machine_type: PhantomData<S>,
     client_type: PhantomData<ClientT>,
 }
@@ -184,35 +873,399 @@ impl<'a, S, ServerT, ClientT> ClientNode<'a, S, ServerT, ClientT> where
     ServerT: MsgRecver<Message<S::Op, S::Result>>,
     ClientT: MsgSender<Message<S::Op, S::Result>> {
     pub fn new(addr: &Addr,
-               replicas: &'a HashSet<Addr>) -> Self {
-        ClientNode {
-            server: ServerT::bind(addr),
-            replicas: replicas,
+               replicas: &'a HashSet<Addr>) -> Result<Self, Error> {
+        Ok(ClientNode {
+            server: ServerT::bind(addr)?,
+            addr: addr.clone(),
+            replicas,
+            timeout_ms: 5000,
+            attempt_timeout_ms: 1000,
+            next_req_id: first_req_id(),
+            clock: system_clock(),
             state_machine_type: PhantomData,
             client_type: PhantomData,
-        }
+        })
     }
 
-    pub fn send_cmd(&mut self, op: &Message<S::Op, S::Result>) -> Result<S::Result, i32> {
-        let addr_vec = self.replicas.iter()
-            .map(|a| a.clone())
-            .collect::<Vec<_>>();
-        let maybe_addr = thread_rng().choose(addr_vec.as_slice());
-        maybe_addr.map_or(Err(-1), |addr| {
-            let mut c = ClientT::connect(addr);
-            c.send(op);
-            loop {
-            self.server.try_recv_timeout(1000).map_or(Err(-2), |msg| {
-                match msg {
-                    Message::Response { cid: _, result } => {
-                        println!("got result: {:?}", result);
-                        Ok(result)
-                    },
-                    _ => Err(-3),
-                }
-            });
+    // how long to wait for a response in all
+    pub fn with_timeout(mut self, timeout_ms: i64) -> Self {
+        self.timeout_ms = timeout_ms;
+        self
+    }
+
+    // how long to wait for one replica before trying the next
+    pub fn with_attempt_timeout(mut self, timeout_ms: i64) -> Self {
+        self.attempt_timeout_ms = timeout_ms;
+        self
+    }
+
+    // paces the failover rounds and counts down their deadline
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    // sends an admin request to `server` and waits for the reply `accept` picks out
+    fn admin_request<T, F>(&mut self, server: &Addr, req: Message<S::Op, S::Result>, accept: F) -> Result<T, Error> where
+        F: Fn(Message<S::Op, S::Result>) -> Option<T> {
+        let mut c = ClientT::connect(server)?;
+        c.send(&req)?;
+        let _ = c.flush();
+        let deadline = deadline_after(self.timeout_ms);
+        loop {
+            // anything else is a late response to an earlier request
+            match recv_response(&mut self.server, &mut c, remaining_ms(deadline)).map(&accept) {
+                Some(Some(reply)) => return Ok(reply),
+                Some(None) => (),
+                None => return Err(ClientError::Timeout.into()),
             }
-            Err(-1)
+            if remaining_ms(deadline) == 0 {
+                return Err(ClientError::Timeout.into());
+            }
+        }
+    }
+
+    // asks the server at `server` for its status, `my_addr` is where this client listens
+    pub fn query_status(&mut self, server: &Addr, my_addr: &Addr) -> Result<NodeStatus, Error> {
+        self.admin_request(server, Message::StatusRequest { cid: my_addr.clone() }, |m| match m {
+            Message::Status { status } => Some(status),
+            _ => None,
+        })
+    }
+
+    // asks the server at `server` to reload its settings, returns its report
+    pub fn reload_settings(&mut self, server: &Addr, my_addr: &Addr) -> Result<Result<Vec<String>, String>, Error> {
+        self.admin_request(server, Message::ReloadRequest { cid: my_addr.clone() }, |m| match m {
+            Message::ReloadReport { changes } => Some(changes),
+            _ => None,
         })
     }
+
+    // submits `op` and returns the result from the first replica that answers,
+    // trying the others in turn, see request_with_failover
+    pub fn send_cmd(&mut self, op: S::Op) -> Result<S::Result, Error> {
+        let req_id = self.next_req_id;
+        self.next_req_id += 1;
+        let replicas = self.replicas.iter().cloned().collect::<Vec<_>>();
+        let result = request_with_failover::<_, _, _, ClientT>(&mut self.server, replicas.as_slice(), &self.addr, req_id, op,
+                                                               self.attempt_timeout_ms, self.timeout_ms, &*self.clock)?;
+        log_debug!("client", "got result: {:?}", result);
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::{ Cell, RefCell };
+    use std::collections::VecDeque;
+    use clock::ManualClock;
+
+    type Msg = Message<String, String>;
+
+    // replicas answer on the request's connection right away: the one on port
+    // `ANSWERING` with the response, every other one with a rejection
+    thread_local! {
+        static ANSWERING: Cell<u16> = const { Cell::new(0) };
+        static ATTEMPTS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    struct FakeReplica {
+        port: u16,
+        reply: Option<Msg>,
+    }
+
+    impl MsgSender<Msg> for FakeReplica {
+        fn connect(addr: &Addr) -> Result<Self, Error> {
+            Ok(FakeReplica { port: addr.port, reply: None })
+        }
+
+        fn send_str(&mut self, s: &[u8]) -> Result<(), Error> {
+            ATTEMPTS.with(|a| a.set(a.get() + 1));
+            if let Ok(Message::Request { cid, cmd, req_id }) = serde_json::from_slice::<Msg>(s) {
+                self.reply = Some(if ANSWERING.with(|a| a.get()) == self.port {
+                    Message::Response { cid, result: cmd, req_id }
+                } else {
+                    Message::Rejected { cid, reason: "busy".to_string(), req_id }
+                });
+            }
+            Ok(())
+        }
+
+        fn try_recv_reply(&mut self, _timeout_ms: i64) -> Option<Msg> {
+            self.reply.take()
+        }
+    }
+
+    struct NoServer;
+
+    impl MsgRecver<Msg> for NoServer {
+        type Ctx = ();
+
+        fn bind(_addr: &Addr) -> Result<Self, Error> {
+            Ok(NoServer)
+        }
+
+        fn try_recv_str(&mut self) -> Option<Vec<u8>> {
+            None
+        }
+
+        fn get_io_fds(&self) -> Vec<c_int> {
+            Vec::new()
+        }
+    }
+
+    fn replicas() -> Vec<Addr> {
+        vec![Addr::new("127.0.0.1", 1), Addr::new("127.0.0.1", 2)]
+    }
+
+    fn request(deadline_ms: i64, clock: &ManualClock) -> Result<String, Error> {
+        let cid = Addr::new("127.0.0.1", 100);
+        request_with_failover::<_, _, _, FakeReplica>(&mut NoServer, replicas().as_slice(), &cid, 7,
+                                                      "cmd".to_string(), 100, deadline_ms, clock)
+    }
+
+    #[test]
+    fn the_answering_replica_is_found() {
+        ANSWERING.with(|a| a.set(2));
+        let clock = ManualClock::new(0);
+        assert_eq!(request(-1, &clock), Ok("cmd".to_string()));
+        assert!(ATTEMPTS.with(|a| a.get()) <= 2);
+        assert_eq!(clock.now_ms(), 0);
+    }
+
+    #[test]
+    fn rounds_back_off_until_the_cap() {
+        let clock = ManualClock::new(0);
+        assert_eq!(request(-1, &clock), Err(ClientError::Rejected("busy".to_string()).into()));
+        assert_eq!(ATTEMPTS.with(|a| a.get()), MAX_FAILOVER_ROUNDS * 2);
+        // 50, 100, 200, 400 and 800 ms, then capped at a second
+        let pauses = 1550 + (MAX_FAILOVER_ROUNDS as u64 - 5) * MAX_FAILOVER_PAUSE_MS;
+        assert_eq!(clock.now_ms(), pauses);
+    }
+
+    #[test]
+    fn the_deadline_runs_on_the_clock() {
+        let clock = ManualClock::new(1000);
+        assert_eq!(request(300, &clock), Err(ClientError::Rejected("busy".to_string()).into()));
+        // pauses of 50 and 100 ms, the third is cut short by the deadline
+        assert_eq!(ATTEMPTS.with(|a| a.get()), 6);
+        assert_eq!(clock.now_ms(), 1300);
+    }
+
+    #[test]
+    fn no_replicas_is_an_error() {
+        let cid = Addr::new("127.0.0.1", 100);
+        let ret = request_with_failover::<String, String, _, FakeReplica>(&mut NoServer, &[], &cid, 7, "cmd".to_string(),
+                                                                          100, -1, &ManualClock::new(0));
+        assert_eq!(ret, Err(ClientError::NoReplicas.into()));
+    }
+
+    // a replica node reads from INBOX and every send lands in SENT
+    thread_local! {
+        static INBOX: RefCell<VecDeque<Msg>> = const { RefCell::new(VecDeque::new()) };
+        static SENT: RefCell<Vec<(u16, Msg)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    struct Inbox;
+
+    impl MsgRecver<Msg> for Inbox {
+        type Ctx = ();
+
+        fn bind(_addr: &Addr) -> Result<Self, Error> {
+            Ok(Inbox)
+        }
+
+        fn try_recv_str(&mut self) -> Option<Vec<u8>> {
+            INBOX.with(|i| i.borrow_mut().pop_front()).map(|m| serde_json::to_vec(&m).unwrap())
+        }
+
+        fn get_io_fds(&self) -> Vec<c_int> {
+            Vec::new()
+        }
+    }
+
+    struct Recorder {
+        port: u16,
+    }
+
+    impl MsgSender<Msg> for Recorder {
+        fn connect(addr: &Addr) -> Result<Self, Error> {
+            Ok(Recorder { port: addr.port })
+        }
+
+        fn send_str(&mut self, s: &[u8]) -> Result<(), Error> {
+            let m = serde_json::from_slice::<Msg>(s).unwrap();
+            SENT.with(|sent| sent.borrow_mut().push((self.port, m)));
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct Echo;
+
+    impl StateMachine for Echo {
+        type Op = String;
+        type Result = String;
+
+        fn apply_op(&mut self, op: &String) -> String {
+            op.clone()
+        }
+    }
+
+    // server i listens on port i + 1, the client on 100
+    fn drain_one_request(replicas: &[ServerID]) -> Vec<(u16, Msg)> {
+        let addrs = vec![0, 1, 2, 10].into_iter().map(|id| (id, Addr::new("127.0.0.1", id as u16 + 1))).collect();
+        let leaders = vec![10].into_iter().collect();
+        let mut node = ReplicaNode::<Echo, Inbox, Recorder>::new(&Addr::new("127.0.0.1", 1), 0, &addrs, &leaders).unwrap()
+            .with_peers(&replicas.iter().cloned().collect());
+        node.draining = true;
+        INBOX.with(|i| i.borrow_mut().push_back(Message::Request {
+            cid: Addr::new("127.0.0.1", 100), cmd: "cmd".to_string(), req_id: Some(3),
+        }));
+        node.process_timeout(0).unwrap();
+        assert_eq!(node.replica.pending_requests(), 0);
+        SENT.with(|sent| sent.borrow_mut().drain(..).collect())
+    }
+
+    #[test]
+    fn a_draining_replica_passes_requests_on() {
+        let sent = drain_one_request(&[0, 1, 2]);
+        assert_eq!(sent.len(), 1);
+        assert!(matches!(sent[0], (2, Message::Request { req_id: Some(3), .. })));
+    }
+
+    #[test]
+    fn a_draining_replica_without_peers_rejects_requests() {
+        let sent = drain_one_request(&[0]);
+        assert_eq!(sent.len(), 1);
+        match sent[0] {
+            (100, Message::Rejected { ref cid, ref reason, req_id: Some(3) }) => {
+                assert_eq!(*cid, Addr::new("127.0.0.1", 100));
+                assert_eq!(reason, "replica is shutting down");
+            },
+            ref other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn the_oldest_route_makes_room() {
+        let client = |port| Addr::new("127.0.0.1", port);
+        let mut routes = Routes::new(2);
+        routes.insert(client(1), vec![1]);
+        routes.insert(client(2), vec![2]);
+        // a new request from client 1 makes its route the newest
+        routes.insert(client(1), vec![11]);
+        routes.insert(client(3), vec![3]);
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes.remove(&client(2)), None);
+        assert_eq!(routes.remove(&client(1)), Some(vec![11]));
+        assert_eq!(routes.remove(&client(1)), None);
+        routes.insert(client(4), vec![4]);
+        assert_eq!(routes.remove(&client(3)), Some(vec![3]));
+        assert_eq!(routes.remove(&client(4)), Some(vec![4]));
+        assert!(routes.is_empty());
+    }
+
+    #[test]
+    fn requests_go_round_the_peers() {
+        let mut hand_off = HandOff::new(1, &vec![0, 1, 2].into_iter().collect());
+        let cid = Addr::new("127.0.0.1", 100);
+        let peers = (0..4).map(|_| match hand_off.route::<String, String>(cid.clone(), None, "cmd".to_string()) {
+            Outgoing::ToServer(peer, Message::Request { .. }) => peer,
+            _ => panic!("not handed off"),
+        }).collect::<Vec<_>>();
+        assert_eq!(peers, vec![0, 2, 0, 2]);
+    }
+
+    #[test]
+    fn leader_broadcasts_go_to_their_group() {
+        let ballot = Ballot::zero(10);
+        let p1a: Msg = Message::P1a { sender: 10, ballot: ballot.clone() };
+        let decision: Msg = Message::Decision { slot: 1, cmd: "cmd".to_string(), req: None };
+        let p1b: Msg = Message::P1b { sender: 20, ballot, proposals: Vec::new(), reqs: Vec::new() };
+        assert_eq!(broadcast_group(&p1a), Some(Group::Acceptors));
+        assert_eq!(broadcast_group(&decision), Some(Group::Replicas));
+        assert_eq!(broadcast_group(&p1b), None);
+    }
+
+    #[test]
+    fn one_copy_of_a_broadcast_goes_to_the_group() {
+        let addrs = vec![0, 10, 20, 21, 22].into_iter().map(|id| (id, Addr::new("127.0.0.1", id as u16 + 1))).collect();
+        let (acceptors, replicas) = (vec![20, 21, 22].into_iter().collect(), vec![0].into_iter().collect());
+        let mut node = LeaderNode::<String, String, Inbox, Recorder>::new(&Addr::new("127.0.0.1", 11), &acceptors,
+                                                                          &replicas, 10, &addrs).unwrap()
+            .with_multicast::<Recorder>(&Addr::new("239.255.73.3", 50), &Addr::new("239.255.73.4", 60));
+        // preempted, the leader starts phase 1 with its next ballot
+        INBOX.with(|i| i.borrow_mut().push_back(Message::P2b { sender: 20, ballot: Ballot::zero(30).next().unwrap(), slot: 1 }));
+        node.process_timeout(0).unwrap();
+        let sent: Vec<(u16, Msg)> = SENT.with(|sent| sent.borrow_mut().drain(..).collect());
+        assert_eq!(sent.len(), 1);
+        assert!(matches!(sent[0], (50, Message::P1a { sender: 10, .. })));
+    }
+
+    fn status_request() -> Msg {
+        Message::StatusRequest { cid: Addr::new("127.0.0.1", 100) }
+    }
+
+    #[test]
+    fn status_requests_are_answered_with_the_role_status() {
+        let addrs = vec![0, 10, 20].into_iter().map(|id| (id, Addr::new("127.0.0.1", id as u16 + 1))).collect();
+        let (acceptors, replicas) = (vec![20].into_iter().collect(), vec![0].into_iter().collect());
+        let mut leader = LeaderNode::<String, String, Inbox, Recorder>::new(&Addr::new("127.0.0.1", 11), &acceptors,
+                                                                            &replicas, 10, &addrs).unwrap();
+        INBOX.with(|i| i.borrow_mut().push_back(status_request()));
+        leader.process_timeout(0).unwrap();
+        let leaders = vec![10].into_iter().collect();
+        let mut replica = ReplicaNode::<Echo, Inbox, Recorder>::new(&Addr::new("127.0.0.1", 1), 0, &addrs, &leaders).unwrap();
+        INBOX.with(|i| i.borrow_mut().extend(vec![
+            Message::Request { cid: Addr::new("127.0.0.1", 100), cmd: "cmd".to_string(), req_id: Some(3) },
+            status_request(),
+        ]));
+        replica.process_timeout(0).unwrap();
+        replica.process_timeout(0).unwrap();
+        let sent: Vec<(u16, Msg)> = SENT.with(|sent| sent.borrow_mut().drain(..).collect());
+        let statuses = sent.into_iter().filter_map(|(port, m)| match m {
+            Message::Status { status } => Some((port, status)),
+            _ => None,
+        }).collect::<Vec<_>>();
+        assert_eq!(statuses.len(), 2);
+        match statuses[0] {
+            (100, NodeStatus::Leader(ref s)) => {
+                assert_eq!(s.id, 10);
+                assert!(!s.is_active);
+                assert_eq!(s.waitfor, vec![20]);
+            },
+            ref other => panic!("unexpected {:?}", other),
+        }
+        match statuses[1] {
+            (100, NodeStatus::Replica(ref s)) => {
+                assert_eq!(s.id, 0);
+                assert_eq!((s.slot_in, s.slot_out), (2, 1));
+                assert_eq!(s.proposed, vec![1]);
+            },
+            ref other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn the_client_waits_out_other_replies_for_the_status() {
+        let replicas = HashSet::new();
+        let status = NodeStatus::Acceptor(AcceptorStatus { id: 20, ballot: Ballot::zero(10), accepted: 2, slots: Some((1, 2)) });
+        // a late response to an earlier request comes first
+        INBOX.with(|i| i.borrow_mut().extend(vec![
+            Message::Response { cid: Addr::new("127.0.0.1", 100), result: "old".to_string(), req_id: Some(1) },
+            Message::Status { status },
+        ]));
+        let mut client = ClientNode::<Echo, Inbox, Recorder>::new(&Addr::new("127.0.0.1", 100), &replicas).unwrap()
+            .with_timeout(200);
+        match client.query_status(&Addr::new("127.0.0.1", 21), &Addr::new("127.0.0.1", 100)) {
+            Ok(NodeStatus::Acceptor(s)) => assert_eq!((s.id, s.accepted, s.slots), (20, 2, Some((1, 2)))),
+            other => panic!("unexpected {:?}", other),
+        }
+        let sent: Vec<(u16, Msg)> = SENT.with(|sent| sent.borrow_mut().drain(..).collect());
+        assert_eq!(sent.len(), 1);
+        assert!(matches!(sent[0], (21, Message::StatusRequest { ref cid }) if cid.port == 100));
+        assert!(client.query_status(&Addr::new("127.0.0.1", 21), &Addr::new("127.0.0.1", 100)).is_err());
+    }
 }
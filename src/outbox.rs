@@ -0,0 +1,377 @@
+// Bounded per-destination queues in front of the senders. Each destination keeps
+// one connected sender; messages wait in its queue while the peer cannot take
+// them and the overflow policy decides what happens once the queue is full.
+// Senders are connected again after a send error, and for host names once the
+// resolve TTL has passed, so that a peer that moved is found at its new address.
+use std::collections::{ HashMap, VecDeque };
+use std::thread;
+use std::time::{ Duration, Instant };
+use messaging::*;
+use error::*;
+
+pub const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+pub const DEFAULT_BLOCK_TIMEOUT_MS: i64 = 100;
+// how soon a node retries destinations that still have queued messages
+pub const RETRY_MS: i64 = 10;
+// idle senders are closed once there are more destinations than this, replicas
+// talk to many short-lived clients
+const MAX_IDLE_PEERS: usize = 256;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OverflowPolicy {
+    // the message that does not fit is dropped
+    DropNewest,
+    // the oldest queued message makes room for the new one
+    DropOldest,
+    // keeps retrying for up to block_timeout_ms, then drops the new message
+    Block,
+}
+
+#[derive(Clone, Debug)]
+pub struct OutboxConfig {
+    pub capacity: usize,
+    pub policy: OverflowPolicy,
+    pub block_timeout_ms: i64,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        OutboxConfig {
+            capacity: DEFAULT_QUEUE_CAPACITY,
+            policy: OverflowPolicy::DropNewest,
+            block_timeout_ms: DEFAULT_BLOCK_TIMEOUT_MS,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct OutboxStats {
+    pub sent: u64,
+    // discarded because a queue was full
+    pub dropped: u64,
+    // discarded because the transport failed with something other than WouldBlock
+    pub send_errors: u64,
+}
+
+struct Peer<M, S> {
+    sender: S,
+    queue: VecDeque<M>,
+    dropped: u64,
+    connected_at: Instant,
+    // the sender failed and is replaced before the next send
+    failed: bool,
+}
+
+impl<M, S> Peer<M, S> where
+    M: serde::Serialize + serde::de::DeserializeOwned,
+    S: MsgSender<M> {
+    fn connect(addr: &Addr) -> Result<Self, Error> {
+        Ok(Peer {
+            sender: S::connect(addr)?,
+            queue: VecDeque::new(),
+            dropped: 0,
+            connected_at: Instant::now(),
+            failed: false,
+        })
+    }
+
+    // connects a new sender if the old one failed or its address may have moved;
+    // the old one is kept if that fails, the next send tells whether it still works
+    fn refresh(&mut self, addr: &Addr) {
+        let expired = addr.is_hostname() && self.connected_at.elapsed() >= resolve_ttl();
+        if !self.failed && !expired {
+            return;
+        }
+        match S::connect(addr) {
+            Ok(sender) => {
+                self.sender = sender;
+                self.failed = false;
+            },
+            Err(e) => log_debug!("messaging", { addr = addr }, "reconnect failed: {}", e),
+        }
+        self.connected_at = Instant::now();
+    }
+
+    // hands queued messages to the sender in order until the transport would block
+    // or fails, the failed message is dropped
+    fn drain(&mut self, stats: &mut OutboxStats) {
+        loop {
+            let ret = match self.queue.front() {
+                Some(m) => self.sender.send(m),
+                None => return,
+            };
+            match ret {
+                Ok(()) => stats.sent += 1,
+                Err(Error::Transport(TransportError::WouldBlock)) => return,
+                Err(_) => {
+                    stats.send_errors += 1;
+                    self.failed = true;
+                    self.queue.pop_front();
+                    return;
+                },
+            }
+            self.queue.pop_front();
+        }
+    }
+
+    fn flush(&mut self, addr: &Addr, stats: &mut OutboxStats) {
+        self.refresh(addr);
+        self.drain(stats);
+        match self.sender.flush() {
+            Ok(()) | Err(Error::Transport(TransportError::WouldBlock)) => (),
+            Err(_) => {
+                stats.send_errors += 1;
+                self.failed = true;
+            },
+        }
+    }
+}
+
+pub struct Outbox<M, S> {
+    config: OutboxConfig,
+    peers: HashMap<Addr, Peer<M, S>>,
+    stats: OutboxStats,
+}
+
+impl<M, S> Outbox<M, S> where
+    M: serde::Serialize + serde::de::DeserializeOwned,
+    S: MsgSender<M> {
+    pub fn new(config: OutboxConfig) -> Self {
+        Outbox {
+            config,
+            peers: HashMap::new(),
+            stats: Default::default(),
+        }
+    }
+
+    pub fn config(&self) -> &OutboxConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: OutboxConfig) {
+        self.config = config;
+    }
+
+    pub fn stats(&self) -> OutboxStats {
+        self.stats
+    }
+
+    pub fn dropped_for(&self, addr: &Addr) -> u64 {
+        self.peers.get(addr).map_or(0, |p| p.dropped)
+    }
+
+    pub fn queued(&self) -> usize {
+        self.peers.values().map(|p| p.queue.len()).sum()
+    }
+
+    // true while some destination's queue is full
+    pub fn saturated(&self) -> bool {
+        let capacity = self.config.capacity;
+        self.peers.values().any(|p| p.queue.len() >= capacity)
+    }
+
+    // returns QueueFull if the message was dropped; senders that batch may hold on
+    // to the message until the next flush
+    pub fn push(&mut self, addr: &Addr, msg: M) -> Result<(), Error> {
+        let config = self.config.clone();
+        let stats = &mut self.stats;
+        if !self.peers.contains_key(addr) {
+            self.peers.insert(addr.clone(), Peer::connect(addr)?);
+        }
+        let peer = self.peers.get_mut(addr).unwrap();
+        peer.refresh(addr);
+        peer.drain(stats);
+        if peer.queue.len() >= config.capacity {
+            match config.policy {
+                OverflowPolicy::DropNewest => {
+                    stats.dropped += 1;
+                    peer.dropped += 1;
+                    return Err(TransportError::QueueFull.into());
+                },
+                OverflowPolicy::DropOldest => {
+                    peer.queue.pop_front();
+                    stats.dropped += 1;
+                    peer.dropped += 1;
+                },
+                OverflowPolicy::Block => {
+                    let deadline = deadline_after(config.block_timeout_ms);
+                    while peer.queue.len() >= config.capacity {
+                        if remaining_ms(deadline) == 0 {
+                            stats.dropped += 1;
+                            peer.dropped += 1;
+                            return Err(TransportError::QueueFull.into());
+                        }
+                        thread::sleep(Duration::from_millis(1));
+                        peer.flush(addr, stats);
+                    }
+                },
+            }
+        }
+        peer.queue.push_back(msg);
+        peer.drain(stats);
+        Ok(())
+    }
+
+    // sends everything the destinations can take right now
+    pub fn flush(&mut self) {
+        let stats = &mut self.stats;
+        self.peers.iter_mut().for_each(|(addr, p)| p.flush(addr, stats));
+        if self.peers.len() > MAX_IDLE_PEERS {
+            self.peers.retain(|_, p| !p.queue.is_empty());
+        }
+    }
+
+    // retries queued messages until all of them are sent, Undelivered if the
+    // deadline passes first
+    pub fn drain(&mut self, deadline: Option<Instant>) -> Result<(), TransportError> {
+        loop {
+            self.flush();
+            if self.queued() == 0 {
+                return Ok(());
+            }
+            if remaining_ms(deadline) == 0 {
+                return Err(TransportError::Undelivered(self.queued()));
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    // when the node should come back to retry queued messages
+    pub fn next_retry_ms(&self) -> Option<i64> {
+        if self.peers.values().any(|p| !p.queue.is_empty()) {
+            Some(RETRY_MS)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::{ Cell, RefCell };
+
+    // the fake transport is per test thread: it takes `OPEN` more messages, then
+    // would block, or fails every send while `BROKEN` is set
+    thread_local! {
+        static OPEN: Cell<usize> = const { Cell::new(0) };
+        static BROKEN: Cell<bool> = const { Cell::new(false) };
+        static CONNECTS: Cell<usize> = const { Cell::new(0) };
+        static SENT: RefCell<Vec<u32>> = const { RefCell::new(Vec::new()) };
+    }
+
+    struct FakeSender;
+
+    impl MsgSender<u32> for FakeSender {
+        fn connect(_addr: &Addr) -> Result<Self, Error> {
+            CONNECTS.with(|c| c.set(c.get() + 1));
+            Ok(FakeSender)
+        }
+
+        fn send_str(&mut self, s: &[u8]) -> Result<(), Error> {
+            if BROKEN.with(|b| b.get()) {
+                return Err(TransportError::Os(32).into());
+            }
+            if OPEN.with(|o| o.get()) == 0 {
+                return Err(TransportError::WouldBlock.into());
+            }
+            OPEN.with(|o| o.set(o.get() - 1));
+            let msg = serde_json::from_slice(s).unwrap();
+            SENT.with(|sent| sent.borrow_mut().push(msg));
+            Ok(())
+        }
+    }
+
+    fn open(n: usize) {
+        OPEN.with(|o| o.set(n));
+    }
+
+    fn sent() -> Vec<u32> {
+        SENT.with(|sent| sent.borrow().clone())
+    }
+
+    fn outbox(policy: OverflowPolicy) -> Outbox<u32, FakeSender> {
+        Outbox::new(OutboxConfig { capacity: 2, policy, block_timeout_ms: 20 })
+    }
+
+    fn addr() -> Addr {
+        Addr::new("127.0.0.1", 7000)
+    }
+
+    #[test]
+    fn messages_wait_while_the_peer_would_block() {
+        let mut outbox = outbox(OverflowPolicy::DropNewest);
+        open(1);
+        outbox.push(&addr(), 1).unwrap();
+        outbox.push(&addr(), 2).unwrap();
+        assert_eq!(sent(), vec![1]);
+        assert_eq!(outbox.queued(), 1);
+        assert_eq!(outbox.next_retry_ms(), Some(RETRY_MS));
+        open(10);
+        outbox.flush();
+        assert_eq!(sent(), vec![1, 2]);
+        assert_eq!(outbox.next_retry_ms(), None);
+        assert_eq!(outbox.stats().sent, 2);
+    }
+
+    #[test]
+    fn drop_newest_rejects_the_message_that_does_not_fit() {
+        let mut outbox = outbox(OverflowPolicy::DropNewest);
+        outbox.push(&addr(), 1).unwrap();
+        outbox.push(&addr(), 2).unwrap();
+        assert!(outbox.saturated());
+        assert_eq!(outbox.push(&addr(), 3), Err(TransportError::QueueFull.into()));
+        assert_eq!(outbox.stats().dropped, 1);
+        assert_eq!(outbox.dropped_for(&addr()), 1);
+        open(10);
+        outbox.flush();
+        assert_eq!(sent(), vec![1, 2]);
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_newest_messages() {
+        let mut outbox = outbox(OverflowPolicy::DropOldest);
+        for msg in 1..5 {
+            outbox.push(&addr(), msg).unwrap();
+        }
+        assert_eq!(outbox.stats().dropped, 2);
+        open(10);
+        outbox.flush();
+        assert_eq!(sent(), vec![3, 4]);
+    }
+
+    #[test]
+    fn block_gives_up_after_the_timeout() {
+        let mut outbox = outbox(OverflowPolicy::Block);
+        outbox.push(&addr(), 1).unwrap();
+        outbox.push(&addr(), 2).unwrap();
+        let start = Instant::now();
+        assert_eq!(outbox.push(&addr(), 3), Err(TransportError::QueueFull.into()));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert_eq!(outbox.stats().dropped, 1);
+        assert_eq!(outbox.queued(), 2);
+    }
+
+    #[test]
+    fn send_errors_drop_the_message_and_reconnect() {
+        let mut outbox = outbox(OverflowPolicy::DropNewest);
+        BROKEN.with(|b| b.set(true));
+        outbox.push(&addr(), 1).unwrap();
+        assert_eq!(outbox.stats().send_errors, 1);
+        assert_eq!(outbox.queued(), 0);
+        BROKEN.with(|b| b.set(false));
+        open(10);
+        outbox.push(&addr(), 2).unwrap();
+        assert_eq!(CONNECTS.with(|c| c.get()), 2);
+        assert_eq!(sent(), vec![2]);
+    }
+
+    #[test]
+    fn drain_reports_what_is_left_at_the_deadline() {
+        let mut outbox = outbox(OverflowPolicy::DropNewest);
+        outbox.push(&addr(), 1).unwrap();
+        assert_eq!(outbox.drain(deadline_after(5)), Err(TransportError::Undelivered(1)));
+        open(10);
+        assert_eq!(outbox.drain(deadline_after(5)), Ok(()));
+    }
+}
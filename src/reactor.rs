@@ -0,0 +1,222 @@
+use std::cell::RefCell;
+use std::collections::{ HashMap, VecDeque };
+use std::rc::Rc;
+use libc::c_int;
+use messages::*;
+use messaging::*;
+use node::*;
+use shutdown::*;
+use reload::reload_if_requested;
+use error::*;
+
+// in-process mailboxes for roles hosted by the same reactor, so that messages
+// between colocated roles never hit the network
+pub struct LocalBus<M> {
+    queues: RefCell<HashMap<ServerID, VecDeque<M>>>,
+}
+
+impl<M> LocalBus<M> {
+    pub fn new() -> Rc<Self> {
+        Rc::new(LocalBus {
+            queues: RefCell::new(HashMap::new()),
+        })
+    }
+
+    pub fn register(&self, id: ServerID) {
+        self.queues.borrow_mut().entry(id).or_default();
+    }
+
+    pub fn is_local(&self, id: ServerID) -> bool {
+        self.queues.borrow().contains_key(&id)
+    }
+
+    // returns the message back if `id` is not hosted locally
+    pub fn push(&self, id: ServerID, msg: M) -> Result<(), M> {
+        match self.queues.borrow_mut().get_mut(&id) {
+            Some(q) => {
+                q.push_back(msg);
+                Ok(())
+            },
+            None => Err(msg),
+        }
+    }
+
+    pub fn pop(&self, id: ServerID) -> Option<M> {
+        self.queues.borrow_mut().get_mut(&id).and_then(|q| q.pop_front())
+    }
+
+    pub fn has_pending(&self, id: ServerID) -> bool {
+        self.queues.borrow().get(&id).is_some_and(|q| !q.is_empty())
+    }
+}
+
+pub struct Reactor<'a> {
+    nodes: Vec<Box<dyn Node<PollItem = ()> + 'a>>,
+}
+
+impl<'a> Default for Reactor<'a> {
+    fn default() -> Self {
+        Reactor::new()
+    }
+}
+
+impl<'a> Reactor<'a> {
+    pub fn new() -> Self {
+        Reactor {
+            nodes: Vec::new(),
+        }
+    }
+
+    pub fn register(&mut self, node: Box<dyn Node<PollItem = ()> + 'a>) -> usize {
+        self.nodes.push(node);
+        self.nodes.len() - 1
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    // waits for any node to become readable or for the earliest timer, then lets
+    // every ready node handle one message
+    pub fn run_once(&mut self) -> Result<(), Error> {
+        if self.nodes.is_empty() {
+            return Err(TransportError::Setup("no nodes registered".to_string()).into());
+        }
+        let timeout = if self.nodes.iter().any(|n| n.pending()) {
+            0
+        } else {
+            bounded_timeout(self.nodes.iter()
+                            .filter_map(|n| n.next_timer_ms())
+                            .min())
+        };
+
+        let node_fds: Vec<Vec<c_int>> = self.nodes.iter().map(|n| n.get_io_fds()).collect();
+        let all_fds: Vec<c_int> = node_fds.iter().flat_map(|fds| fds.iter().cloned()).collect();
+        let ready = match poll_readable(all_fds.as_slice(), timeout) {
+            Ok(ready) => ready,
+            Err(TransportError::Os(eno)) if eno == libc::EINTR => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        for (node, fds) in self.nodes.iter_mut().zip(node_fds.iter()) {
+            let fd_ready = fds.iter().any(|fd| ready.contains(fd));
+            let timer_fired = node.next_timer_ms().is_some_and(|t| t <= 0);
+            if fd_ready || timer_fired || node.pending() {
+                if let Err(e) = node.process_timeout(0) {
+                    log_warn!("reactor", "node failed to handle a message: {}", e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // runs until a shutdown is requested, reloads settings on SIGHUP
+    pub fn run(&mut self) -> Result<(), Error> {
+        while !shutdown_requested() {
+            reload_if_requested();
+            self.run_once()?;
+        }
+        Ok(())
+    }
+
+    // shuts every node down within the same deadline
+    pub fn shutdown(&mut self, timeout_ms: i64) -> Result<(), Error> {
+        let deadline = deadline_after(timeout_ms);
+        let results = self.nodes.iter_mut()
+            .map(|n| n.shutdown(remaining_ms(deadline)))
+            .collect::<Vec<_>>();
+        results.into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn the_bus_queues_for_registered_ids_only() {
+        let bus: Rc<LocalBus<u32>> = LocalBus::new();
+        bus.register(10);
+        assert!(bus.is_local(10));
+        assert!(!bus.is_local(20));
+        assert_eq!(bus.push(20, 1), Err(1));
+        assert!(!bus.has_pending(10));
+        bus.push(10, 1).unwrap();
+        bus.push(10, 2).unwrap();
+        assert!(bus.has_pending(10));
+        assert_eq!(bus.pop(10), Some(1));
+        assert_eq!(bus.pop(10), Some(2));
+        assert_eq!(bus.pop(10), None);
+        assert_eq!(bus.pop(20), None);
+    }
+
+    struct Fake {
+        fds: Vec<c_int>,
+        timer: Option<i64>,
+        pending: bool,
+        handled: Rc<Cell<usize>>,
+    }
+
+    impl Node for Fake {
+        type PollItem = ();
+
+        fn process_timeout(&mut self, _timeout_ms: i64) -> Result<(), Error> {
+            self.handled.set(self.handled.get() + 1);
+            self.pending = false;
+            Ok(())
+        }
+
+        fn get_io_fds(&self) -> Vec<c_int> {
+            self.fds.clone()
+        }
+
+        fn next_timer_ms(&self) -> Option<i64> {
+            self.timer
+        }
+
+        fn pending(&self) -> bool {
+            self.pending
+        }
+    }
+
+    fn fake(fds: Vec<c_int>, timer: Option<i64>, pending: bool) -> (Box<Fake>, Rc<Cell<usize>>) {
+        let handled = Rc::new(Cell::new(0));
+        (Box::new(Fake { fds, timer, pending, handled: handled.clone() }), handled)
+    }
+
+    #[test]
+    fn only_ready_nodes_handle_a_message() {
+        let mut fds = [0 as c_int; 4];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        assert_eq!(unsafe { libc::pipe(fds[2..].as_mut_ptr()) }, 0);
+        assert_eq!(unsafe { libc::write(fds[1], b"x".as_ptr() as *const libc::c_void, 1) }, 1);
+
+        let mut reactor = Reactor::new();
+        let (readable, readable_handled) = fake(vec![fds[0]], None, false);
+        let (quiet, quiet_handled) = fake(vec![fds[2]], Some(60_000), false);
+        let (pending, pending_handled) = fake(Vec::new(), None, true);
+        let (due, due_handled) = fake(Vec::new(), Some(0), false);
+        reactor.register(readable);
+        reactor.register(quiet);
+        reactor.register(pending);
+        reactor.register(due);
+        assert_eq!(reactor.len(), 4);
+
+        reactor.run_once().unwrap();
+        assert_eq!(readable_handled.get(), 1);
+        assert_eq!(quiet_handled.get(), 0);
+        assert_eq!(pending_handled.get(), 1);
+        assert_eq!(due_handled.get(), 1);
+        fds.iter().for_each(|fd| unsafe { libc::close(*fd); });
+    }
+
+    #[test]
+    fn an_empty_reactor_is_an_error() {
+        assert!(Reactor::new().run_once().is_err());
+    }
+}
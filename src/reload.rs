@@ -0,0 +1,153 @@
+// Reloading settings while nodes run. On SIGHUP or an admin ReloadRequest the
+// config file is read and validated again and compared with the one in use, see
+// ClusterConfig::reload_changes. If only reloadable settings differ they are
+// applied: log verbosity here, leader timeouts by the leaders, which pick them up
+// through reloaded_leader_timeouts. Otherwise nothing changes and the error says
+// why. Every reload logs what changed.
+use std::sync::Mutex;
+use std::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+use libc::c_int;
+use config::*;
+use leader::LeaderTimeouts;
+use logging;
+
+static RELOAD: AtomicBool = AtomicBool::new(false);
+// counts the reloads that changed something
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+static STATE: Mutex<Option<ReloadState>> = Mutex::new(None);
+
+struct ReloadState {
+    // None for the built-in local cluster
+    path: Option<String>,
+    config: ClusterConfig,
+    // logging was set on the command line, which wins over the file
+    log_pinned: bool,
+}
+
+// remembers the config in use and where it came from, call after applying it
+pub fn init(path: Option<&str>, config: &ClusterConfig, log_pinned: bool) {
+    *STATE.lock().unwrap_or_else(|e| e.into_inner()) = Some(ReloadState {
+        path: path.map(|p| p.to_string()),
+        config: config.clone(),
+        log_pinned,
+    });
+}
+
+extern "C" fn on_hangup(_sig: c_int) {
+    RELOAD.store(true, Ordering::SeqCst);
+}
+
+// SIGHUP asks for a reload instead of ending the process
+pub fn install_reload_handler() -> Result<(), i32> {
+    let ret = unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_hangup as extern "C" fn(c_int) as libc::sighandler_t;
+        libc::sigemptyset(&mut action.sa_mask);
+        action.sa_flags = 0;
+        libc::sigaction(libc::SIGHUP, &action, std::ptr::null_mut())
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error().raw_os_error().unwrap_or(-1));
+    }
+    Ok(())
+}
+
+pub fn request_reload() {
+    RELOAD.store(true, Ordering::SeqCst);
+}
+
+// reloads if SIGHUP arrived since the last call, node loops call this
+pub fn reload_if_requested() {
+    if RELOAD.swap(false, Ordering::SeqCst) {
+        let _ = reload();
+    }
+}
+
+// reads the config file again and applies it, returns the changes
+pub fn reload() -> Result<Vec<String>, String> {
+    let result = reload_config();
+    match result {
+        Ok(ref changes) if changes.is_empty() => log_info!("reload", "no settings changed"),
+        Ok(ref changes) => changes.iter().for_each(|c| log_info!("reload", "{}", c)),
+        Err(ref e) => log_warn!("reload", "settings not reloaded: {}", e),
+    }
+    result
+}
+
+fn reload_config() -> Result<Vec<String>, String> {
+    let mut guard = STATE.lock().unwrap_or_else(|e| e.into_inner());
+    let state = guard.as_mut().ok_or("the node was not started from a config")?;
+    let path = state.path.clone().ok_or("the node runs the built-in local cluster, there is no file to reload")?;
+    let mut new = ClusterConfig::load(path.as_str())?;
+    if state.log_pinned {
+        new.logging = state.config.logging.clone();
+    }
+    let changes = state.config.reload_changes(&new)?;
+    if !changes.is_empty() {
+        logging::configure(&new.logging);
+        state.config = new;
+        GENERATION.fetch_add(1, Ordering::SeqCst);
+    }
+    Ok(changes)
+}
+
+pub fn generation() -> usize {
+    GENERATION.load(Ordering::SeqCst)
+}
+
+// the leader timeouts once after each reload, `seen` is the generation the
+// caller applied last
+pub fn reloaded_leader_timeouts(seen: &mut usize) -> Option<LeaderTimeouts> {
+    let current = generation();
+    if *seen == current {
+        return None;
+    }
+    *seen = current;
+    STATE.lock().unwrap_or_else(|e| e.into_inner()).as_ref().map(|s| s.config.timeouts.leader())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const SERVERS: &str = "
+[[servers]]
+id = 0
+role = \"replica\"
+addr = \"127.0.0.1:8000\"
+[[servers]]
+id = 10
+role = \"leader\"
+addr = \"127.0.0.1:9001\"
+[[servers]]
+id = 20
+role = \"acceptor\"
+addr = \"127.0.0.1:9101\"
+";
+
+    #[test]
+    fn reloads_report_what_changed_and_hand_leaders_the_timeouts() {
+        let path = std::env::temp_dir().join(format!("reload-test-{}.toml", std::process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path, format!("[timeouts]\nheartbeat_ms = 100\n{}", SERVERS)).unwrap();
+        let config = ClusterConfig::load(path).unwrap();
+        init(Some(path), &config, false);
+        let mut seen = generation();
+        assert_eq!(reload(), Ok(Vec::new()));
+        assert_eq!(reloaded_leader_timeouts(&mut seen), None);
+
+        fs::write(path, format!("[timeouts]\nheartbeat_ms = 300\n{}", SERVERS)).unwrap();
+        assert_eq!(reload(), Ok(vec!["timeouts.heartbeat_ms: 100 -> 300".to_string()]));
+        assert_eq!(reloaded_leader_timeouts(&mut seen).map(|t| t.heartbeat_ms), Some(300));
+        assert_eq!(reloaded_leader_timeouts(&mut seen), None);
+
+        fs::write(path, format!("runtime = \"threaded\"\n{}", SERVERS)).unwrap();
+        assert_eq!(reload(), Err("runtime changed, that needs a restart".to_string()));
+        assert_eq!(reloaded_leader_timeouts(&mut seen), None);
+
+        init(None, &config, false);
+        assert!(reload().unwrap_err().contains("no file to reload"));
+        let _ = fs::remove_file(path);
+    }
+}
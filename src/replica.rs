@@ -1,49 +1,246 @@
 use messages::*;
 use statemachine::*;
-use std::collections::{ HashSet, HashMap };
+use status::*;
+use std::collections::{ BTreeMap, HashSet, HashMap };
 use std::hash::Hash;
+use std::sync::{ Arc, Mutex };
 
 static WINDOW: u64 = 64;
 
+// the last applied request of each client and its result. Clients number their
+// requests in increasing order and wait for each one, so an id at or below the
+// last one was applied before. Only the `cap` clients applied most recently are
+// kept; age is counted in applied requests, so every replica forgets the same ones
+pub struct AppliedRequests<R> {
+    last: HashMap<ClientID, (ReqID, R, u64)>,
+    // clients by the count at their last applied request, oldest first
+    order: BTreeMap<u64, ClientID>,
+    count: u64,
+    cap: usize,
+}
+
+pub const MAX_APPLIED_CLIENTS: usize = 65536;
+
+impl<R> Default for AppliedRequests<R> {
+    fn default() -> Self {
+        AppliedRequests::new(MAX_APPLIED_CLIENTS)
+    }
+}
+
+// the applied requests, shared with the thread that applies decided operations
+pub type SharedApplied<R> = Arc<Mutex<AppliedRequests<R>>>;
+
+impl<R> AppliedRequests<R> {
+    pub fn new(cap: usize) -> Self {
+        AppliedRequests { last: HashMap::new(), order: BTreeMap::new(), count: 0, cap: cap.max(1) }
+    }
+
+    pub fn len(&self) -> usize {
+        self.last.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.last.is_empty()
+    }
+
+    fn record(&mut self, cid: ClientID, id: ReqID, result: R) {
+        self.count += 1;
+        if let Some((_, _, count)) = self.last.insert(cid.clone(), (id, result, self.count)) {
+            self.order.remove(&count);
+        }
+        self.order.insert(self.count, cid);
+        if self.last.len() > self.cap {
+            let oldest = *self.order.keys().next().unwrap();
+            let cid = self.order.remove(&oldest).unwrap();
+            self.last.remove(&cid);
+        }
+    }
+}
+
+impl<R: Clone> AppliedRequests<R> {
+
+    // Some if `req` was applied, with its result unless a later request replaced it
+    pub fn lookup(&self, req: &RequestKey) -> Option<Option<R>> {
+        let (cid, id) = req;
+        self.last.get(cid).and_then(|(last, result, _)| {
+            if id == last {
+                Some(Some(result.clone()))
+            } else if id < last {
+                Some(None)
+            } else {
+                None
+            }
+        })
+    }
+
+    // applies `op` unless its request was applied before, returns the result to
+    // answer with, the first one for a duplicate
+    pub fn apply<S>(&mut self, state: &mut S, op: &S::Op, req: &Option<RequestKey>) -> Option<R> where
+        S: StateMachine<Result = R> {
+        match req {
+            Some(req) => match self.lookup(req) {
+                Some(result) => result,
+                None => {
+                    let result = state.apply_op(op);
+                    self.record(req.0.clone(), req.1, result.clone());
+                    Some(result)
+                },
+            },
+            None => Some(state.apply_op(op)),
+        }
+    }
+}
+
+// a decided operation left to the caller: slot, operation, the request it
+// carries and the client to answer if this replica proposed it
+pub type Committed<Op> = (u64, Op, Option<RequestKey>, Option<ClientID>);
+
 pub struct Replica<'a, S: StateMachine> {
     state: S,
+    applied: SharedApplied<S::Result>,
     slot_in: u64,
     slot_out: u64,
-    requests: HashSet<(ClientID, S::Op)>,
-    proposals: HashMap<u64, (ClientID, S::Op)>,
-    log: HashMap<u64, S::Op>,
+    requests: HashSet<(ClientID, Option<ReqID>, S::Op)>,
+    proposals: HashMap<u64, (ClientID, Option<ReqID>, S::Op)>,
+    log: HashMap<u64, (S::Op, Option<RequestKey>)>,
     leaders: &'a HashSet<ServerID>,
+    accepting: bool,
+    turned_away: u64,
+    // when false, decided operations are collected for take_committed instead of
+    // being applied here
+    apply_inline: bool,
+    committed: Vec<Committed<S::Op>>,
 }
 
 impl<'a, S> Replica<'a, S> where
     S: StateMachine,
     S::Op: Clone + Eq + Hash + std::fmt::Debug,
-    S::Result: std::fmt::Debug {
+    S::Result: Clone + std::fmt::Debug {
 
     pub fn new(leaders: &'a HashSet<ServerID>) -> Self {
         Replica {
             state: S::init_state(),
+            applied: Default::default(),
             slot_in: 1,
             slot_out: 1,
             requests: HashSet::new(),
             proposals: HashMap::new(),
             log: HashMap::new(),
-            leaders: leaders,
+            leaders,
+            accepting: true,
+            turned_away: 0,
+            apply_inline: true,
+            committed: Vec::new(),
         }
     }
 
+    // while not accepting, new requests are answered with Rejected and clients
+    // try another replica
+    pub fn set_accepting(&mut self, accepting: bool) {
+        self.accepting = accepting;
+    }
+
+    pub fn is_accepting(&self) -> bool {
+        self.accepting
+    }
+
+    pub fn turned_away(&self) -> u64 {
+        self.turned_away
+    }
+
+    // next slot to propose for
+    pub fn slot_in(&self) -> u64 {
+        self.slot_in
+    }
+
+    // next slot to apply
+    pub fn slot_out(&self) -> u64 {
+        self.slot_out
+    }
+
+    // requests waiting for a free slot
+    pub fn pending_requests(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn status(&self, id: ServerID) -> ReplicaStatus {
+        let highest = self.log.keys().max().cloned().unwrap_or(0);
+        let mut requests = self.requests.iter()
+            .map(|(cid, _, op)| (cid.clone(), format!("{:?}", op)))
+            .collect::<Vec<_>>();
+        requests.sort_by(|a, b| a.1.cmp(&b.1));
+        let mut proposed = self.proposals.keys().cloned().collect::<Vec<_>>();
+        proposed.sort();
+        ReplicaStatus {
+            id,
+            slot_in: self.slot_in,
+            slot_out: self.slot_out,
+            requests,
+            proposed,
+            gaps: (self.slot_out..highest).filter(|slot| !self.log.contains_key(slot)).collect(),
+        }
+    }
+
+    // lets another thread run the state machine, see take_committed
+    pub fn set_apply_inline(&mut self, inline: bool) {
+        self.apply_inline = inline;
+    }
+
+    // the applied requests retries are answered from; a thread applying
+    // take_committed records into the same map
+    pub fn set_applied(&mut self, applied: SharedApplied<S::Result>) {
+        self.applied = applied;
+    }
+
+    // decided operations in slot order with their requests, and the client to
+    // answer if this replica proposed the operation; the caller applies them
+    // through AppliedRequests so that duplicates are skipped
+    pub fn take_committed(&mut self) -> Vec<Committed<S::Op>> {
+        std::mem::take(&mut self.committed)
+    }
+
+    // proposals this replica made that are not decided yet, their clients wait on us
+    pub fn in_flight(&self) -> usize {
+        self.proposals.len()
+    }
+
+    // removes the requests that have not been proposed yet
+    pub fn take_requests(&mut self) -> Vec<(ClientID, Option<ReqID>, S::Op)> {
+        std::mem::take(&mut self.requests).into_iter().collect()
+    }
+
+    #[allow(clippy::type_complexity)]
     pub fn handle_msg(&mut self, msg: &Message<S::Op, S::Result>) 
-                      -> (Vec<(ServerID, Message<S::Op, S::Result>)>, Vec<(ClientID, Message<S::Op, S::Result>)>) {
-        let mut to_server: Vec<(ServerID, Message<S::Op, S::Result>)> = Vec::new();
-        let mut to_client: Vec<(ClientID, Message<S::Op, S::Result>)> = Vec::new();
+                      -> (ToServers<S::Op, S::Result>, ToClients<S::Op, S::Result>) {
+        let mut to_server: ToServers<S::Op, S::Result> = Vec::new();
+        let mut to_client: ToClients<S::Op, S::Result> = Vec::new();
+        let applied = match msg {
+            Message::Request { cid, req_id: Some(id), .. } =>
+                self.applied.lock().unwrap_or_else(|e| e.into_inner()).lookup(&(cid.clone(), *id)),
+            _ => None,
+        };
         match msg {
-            Message::Request { cid, cmd } => {
-                self.requests.insert((cid.clone(), cmd.clone()));
-                println!("{} requests: {:?}", std::process::id(), self.requests);
+            // a retry of a request this replica applied already, e.g. after the
+            // client failed over while the first attempt was being decided
+            Message::Request { cid, req_id: Some(id), .. } if applied.is_some() => {
+                log_debug!("replica", { cid = cid, req_id = id }, "request already applied");
+                if let Some(Some(result)) = applied {
+                    to_client.push((cid.clone(), Message::Response { cid: cid.clone(), result, req_id: Some(*id) }));
+                }
+            },
+            Message::Request { cid, req_id, .. } if !self.accepting => {
+                self.turned_away += 1;
+                log_debug!("replica", { cid = cid, turned_away = self.turned_away }, "not accepting requests");
+                let reason = "replica is not accepting requests".to_string();
+                to_client.push((cid.clone(), Message::Rejected { cid: cid.clone(), reason, req_id: *req_id }));
+            },
+            Message::Request { cid, cmd, req_id } => {
+                self.requests.insert((cid.clone(), *req_id, cmd.clone()));
+                log_trace!("replica", { cid = cid, requests = self.requests.len() }, "got request");
             },
-            Message::Decision { slot, cmd } => {
-                self.log.insert(*slot, cmd.clone());
-                println!("{} decided: {} {:?}", std::process::id(), slot, self.log);
+            Message::Decision { slot, cmd, req } => {
+                self.log.insert(*slot, (cmd.clone(), req.clone()));
+                log_debug!("replica", { slot = slot, slot_out = self.slot_out }, "decided");
                 to_client.append(&mut self.try_perform());
             },
             _ => (),
@@ -52,21 +249,34 @@ impl<'a, S> Replica<'a, S> where
         (to_server, to_client)
     }
 
-    fn try_perform(&mut self) -> Vec<(ClientID, Message<S::Op, S::Result>)> {
-        let mut ret: Vec<(ClientID, Message<S::Op, S::Result>)> = Vec::new();
+    fn try_perform(&mut self) -> ToClients<S::Op, S::Result> {
+        let mut ret: ToClients<S::Op, S::Result> = Vec::new();
         loop {
             let mut slot_out = self.slot_out;
             let log_ref = &self.log;
             let mut state = std::mem::replace(&mut self.state, S::init_state());
-            let cont = log_ref.get(&slot_out).map(|op| {
-                println!("{}: applying {} {:?}", std::process::id(), slot_out, op);
-                let result = state.apply_op(op);
-                assert!(slot_out != std::u64::MAX, "slot number overflow");
-                self.proposals.get(&slot_out).map(|(client, _)| {
-                    println!("{}: sending reply {:?}", std::process::id(), result);
-                    let result_msg = Message::Response { cid: client.clone(), result: result };
-                    ret.push((client.clone(), result_msg));
-                });
+            let apply_inline = self.apply_inline;
+            let mut applied = self.applied.lock().unwrap_or_else(|e| e.into_inner());
+            let proposals = &self.proposals;
+            let committed = &mut self.committed;
+            let cont = log_ref.get(&slot_out).map(|(op, req)| {
+                assert!(slot_out != u64::MAX, "slot number overflow");
+                // the client waits on us only if the slot went to its request
+                let client = proposals.get(&slot_out)
+                    .filter(|(cid, id, _)| *req == id.map(|id| (cid.clone(), id)))
+                    .map(|(client, _, _)| client.clone());
+                if apply_inline {
+                    log_trace!("replica", { slot = slot_out }, "applying {:?}", op);
+                    let result = applied.apply(&mut state, op, req);
+                    if let (Some(client), Some(result)) = (client, result) {
+                        log_trace!("replica", { slot = slot_out, cid = client }, "replying {:?}", result);
+                        let req_id = req.as_ref().map(|(_, id)| *id);
+                        let result_msg = Message::Response { cid: client.clone(), result, req_id };
+                        ret.push((client, result_msg));
+                    }
+                } else {
+                    committed.push((slot_out, op.clone(), req.clone(), client));
+                }
                 slot_out += 1;
             });
             self.state = state;
@@ -75,42 +285,96 @@ impl<'a, S> Replica<'a, S> where
                 break;
             }
         }
-        self.proposals = std::mem::replace(&mut self.proposals, HashMap::new())
-            .into_iter().filter(|(slot, (cid, op))| {
-                let has_key = self.log.contains_key(slot);
-                if has_key {
-                    // put the proposal back to requests set
-                    //self.requests.insert((cid.clone(), op.clone()));
+        let log = &self.log;
+        let requests = &mut self.requests;
+        self.proposals = std::mem::take(&mut self.proposals)
+            .into_iter().filter(|(slot, (cid, id, op))| {
+                match log.get(slot) {
+                    // another request won the slot, ours goes in the next free one
+                    Some((_, req)) if *req != id.map(|id| (cid.clone(), id)) => {
+                        requests.insert((cid.clone(), *id, op.clone()));
+                        false
+                    },
+                    Some(_) => false,
+                    None => true,
                 }
-                !has_key
             }).collect();
         ret
     }
 
-    fn propose(&mut self) -> Vec<(ServerID, Message<S::Op, S::Result>)> {
-        let requests = std::mem::replace(&mut self.requests, Default::default());
+    fn propose(&mut self) -> ToServers<S::Op, S::Result> {
+        let requests = std::mem::take(&mut self.requests);
         let upper_bound = self.slot_out + WINDOW;
-        let mut ret: Vec<(ServerID, Message<S::Op, S::Result>)> = Vec::new();
-        self.requests = requests.into_iter().filter(|(cid, op)| {
+        let mut ret: ToServers<S::Op, S::Result> = Vec::new();
+        self.requests = requests.into_iter().filter(|(cid, req_id, op)| {
             let mut should_keep = true;
             while self.slot_in < upper_bound {
                 if !self.log.contains_key(&self.slot_in) {
-                    self.leaders.iter().map(|l| {
-                        ret.push((l.clone(), Message::Propose { slot: self.slot_in, cmd: op.clone() }));
-                    }).collect::<()>();
-                    self.proposals.insert(self.slot_in, (cid.clone(), op.clone()));
+                    self.leaders.iter().for_each(|l| {
+                        ret.push((*l, Message::Propose { slot: self.slot_in, cmd: op.clone(),
+                                                         req: req_id.map(|id| (cid.clone(), id)) }));
+                    });
+                    self.proposals.insert(self.slot_in, (cid.clone(), *req_id, op.clone()));
                     should_keep = false;
                 }
-                self.slot_in = self.slot_in + 1;
+                self.slot_in += 1;
                 if !should_keep {
                     break;
                 }
             }
             should_keep
         }).collect();
-        if self.requests.len() > 0 {
-            println!("{} requests after propose: {:?} {:?} {} {}", std::process::id(), self.requests, self.log, self.slot_in, self.slot_out);
+        if !self.requests.is_empty() {
+            log_debug!("replica", { requests = self.requests.len(), slot_in = self.slot_in, slot_out = self.slot_out }, "window full");
         }
         ret
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use messaging::Addr;
+
+    #[derive(Default)]
+    struct Counter(u64);
+
+    impl StateMachine for Counter {
+        type Op = u64;
+        type Result = u64;
+
+        fn apply_op(&mut self, op: &u64) -> u64 {
+            self.0 += op;
+            self.0
+        }
+    }
+
+    fn client(port: u16) -> ClientID {
+        Addr::new("127.0.0.1", port)
+    }
+
+    #[test]
+    fn duplicates_get_the_first_result() {
+        let mut state = Counter::default();
+        let mut applied = AppliedRequests::default();
+        assert_eq!(applied.apply(&mut state, &2, &Some((client(1), 1))), Some(2));
+        assert_eq!(applied.apply(&mut state, &2, &Some((client(1), 1))), Some(2));
+        assert_eq!(applied.apply(&mut state, &3, &Some((client(1), 2))), Some(5));
+        assert_eq!(applied.apply(&mut state, &2, &Some((client(1), 1))), None);
+        assert_eq!(state.0, 5);
+    }
+
+    #[test]
+    fn the_client_applied_longest_ago_is_forgotten() {
+        let mut state = Counter::default();
+        let mut applied = AppliedRequests::new(2);
+        applied.apply(&mut state, &1, &Some((client(1), 1)));
+        applied.apply(&mut state, &1, &Some((client(2), 1)));
+        applied.apply(&mut state, &1, &Some((client(1), 2)));
+        applied.apply(&mut state, &1, &Some((client(3), 1)));
+        assert_eq!(applied.len(), 2);
+        assert_eq!(applied.lookup(&(client(2), 1)), None);
+        assert_eq!(applied.lookup(&(client(1), 2)), Some(Some(3)));
+        assert_eq!(applied.lookup(&(client(3), 1)), Some(Some(4)));
+    }
+}
@@ -0,0 +1,626 @@
+// Command line front end for replicated state machines. A server binary for any
+// StateMachine only needs
+//
+//     fn main() {
+//         Runner::<LockMachine>::new("lock_server").run();
+//     }
+//
+// which gives it the replica, leader, acceptor and server (all three roles with
+// the same index in one process) subcommands, and a client that submits
+// operations written as JSON: `lock_server client 7000 op '{"TryLock":[1,7000]}'`.
+// Friendlier client subcommands can be added with `with_client_command`.
+// `lock_server status 7000 10` prints what server 10 is working on, and
+// `lock_server --config cluster.toml reload 7000 10` makes it reload its settings
+// from its config file, as SIGHUP does, and prints what changed. The client,
+// status and reload subcommands listen for answers on the given port of
+// 127.0.0.1, or of the address given with --host when the servers are elsewhere.
+// Logging follows the config's [logging] section unless --log or --log-json
+// override it. Servers shut down gracefully on SIGTERM/SIGINT and exit with EXIT_CLEAN or
+// EXIT_UNCLEAN.
+use std::collections::HashSet;
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::marker::PhantomData;
+use clap::{ App, Arg, AppSettings, ArgMatches, SubCommand };
+use config::*;
+use error::Error;
+use logging::*;
+use mcast::*;
+use messages::*;
+use messaging::*;
+use metrics;
+use node::*;
+use reactor::*;
+use reload;
+use shutdown::*;
+use statemachine::*;
+use status::*;
+use threaded::*;
+
+// builds the operation for a client subcommand, the ClientID is the client's address
+pub type OpParser<Op> = fn(&ArgMatches, &ClientID) -> Result<Op, String>;
+
+pub struct Runner<S: StateMachine> {
+    name: String,
+    client_commands: Vec<(App<'static, 'static>, OpParser<S::Op>)>,
+}
+
+impl<S> Runner<S> where
+    S: StateMachine + 'static,
+    S::Op: serde::Serialize + serde::de::DeserializeOwned + Clone + Eq + Hash + Debug + Send + 'static,
+    S::Result: serde::Serialize + serde::de::DeserializeOwned + Clone + Debug + Send + 'static {
+    pub fn new(name: &str) -> Self {
+        Runner {
+            name: name.to_string(),
+            client_commands: Vec::new(),
+        }
+    }
+
+    pub fn with_client_command(mut self, cmd: App<'static, 'static>, parse: OpParser<S::Op>) -> Self {
+        self.client_commands.push((cmd, parse));
+        self
+    }
+
+    pub fn app(&self) -> App<'static, 'static> {
+        let idx_arg = Arg::with_name("IDX")
+            .required(true)
+            .index(1);
+        let client = SubCommand::with_name("client")
+            .setting(AppSettings::SubcommandRequired)
+            .arg(Arg::with_name("port").required(true))
+            .subcommand(SubCommand::with_name("op")
+                        .about("submits an operation written as JSON")
+                        .arg(Arg::with_name("json").required(true).index(1)))
+            .subcommands(self.client_commands.iter().map(|(cmd, _)| cmd.clone()));
+        App::new(self.name.clone())
+            .version("1.0")
+            .setting(AppSettings::SubcommandRequired)
+            .arg(Arg::with_name("config")
+                 .long("config")
+                 .takes_value(true)
+                 .help("cluster configuration file, defaults to a local test cluster"))
+            .arg(Arg::with_name("log")
+                 .long("log")
+                 .takes_value(true)
+                 .help("log verbosity like info,leader=debug, overrides the config"))
+            .arg(Arg::with_name("log-json")
+                 .long("log-json")
+                 .help("writes log records as JSON"))
+            .arg(Arg::with_name("host")
+                 .long("host")
+                 .takes_value(true)
+                 .help("address that client, status and reload listen on for answers, defaults to 127.0.0.1; \
+                        the servers must be able to reach it"))
+            .subcommand(SubCommand::with_name("replica")
+                        .arg(idx_arg.clone()))
+            .subcommand(SubCommand::with_name("leader")
+                        .arg(idx_arg.clone()))
+            .subcommand(SubCommand::with_name("acceptor")
+                        .arg(idx_arg.clone()))
+            .subcommand(SubCommand::with_name("server")
+                        .arg(idx_arg.clone()))
+            .subcommand(client)
+            .subcommand(SubCommand::with_name("status")
+                        .about("prints the status of a server as JSON")
+                        .arg(Arg::with_name("port").required(true).index(1))
+                        .arg(Arg::with_name("ID").required(true).index(2)))
+            .subcommand(SubCommand::with_name("reload")
+                        .about("makes a server reload its settings and prints what changed")
+                        .arg(Arg::with_name("port").required(true).index(1))
+                        .arg(Arg::with_name("ID").required(true).index(2)))
+    }
+
+    // parses the process arguments, runs the selected role and exits with its status
+    pub fn run(self) {
+        let matches = self.app().get_matches();
+        match self.run_matches(&matches) {
+            Ok(code) => std::process::exit(code),
+            Err(e) => {
+                log_error!("runner", "{}: {}", self.name, e);
+                std::process::exit(EXIT_UNCLEAN);
+            },
+        }
+    }
+
+    // returns the exit status
+    pub fn run_matches(&self, matches: &ArgMatches) -> Result<i32, String> {
+        let mut config = ClusterConfig::load_or_local(matches.value_of("config"))?;
+        if let Some(spec) = matches.value_of("log") {
+            let json = config.logging.json;
+            config.logging = LogSettings::parse(spec)?;
+            config.logging.json = json;
+        }
+        if matches.is_present("log-json") {
+            config.logging.json = true;
+        }
+        config.apply()?;
+        let log_pinned = matches.is_present("log") || matches.is_present("log-json");
+        reload::init(matches.value_of("config"), &config, log_pinned);
+
+        let parse_idx = |m: &ArgMatches| -> Result<usize, String> {
+            m.value_of("IDX").unwrap_or("").parse::<usize>().map_err(|e| format!("bad index: {}", e))
+        };
+        let nth = |role: Role, m: &ArgMatches| -> Result<ServerID, String> {
+            let i = parse_idx(m)?;
+            config.servers_with(role).get(i).map(|(id, _)| *id)
+                .ok_or(format!("no {:?} with index {}", role, i))
+        };
+
+        let reply_addr = |m: &ArgMatches| reply_addr(matches, m);
+
+        match matches.subcommand() {
+            ("replica", Some(m)) => run_role::<S>(&config, Role::Replica, nth(Role::Replica, m)?),
+            ("leader", Some(m)) => run_role::<S>(&config, Role::Leader, nth(Role::Leader, m)?),
+            ("acceptor", Some(m)) => run_role::<S>(&config, Role::Acceptor, nth(Role::Acceptor, m)?),
+            ("server", Some(m)) => run_colocated::<S>(&config, parse_idx(m)?),
+            ("client", Some(m)) => self.run_client(&config, &reply_addr(m)?, m).map(|_| EXIT_CLEAN),
+            ("status", Some(m)) => run_status::<S>(&config, &reply_addr(m)?, m).map(|_| EXIT_CLEAN),
+            ("reload", Some(m)) => run_reload::<S>(&config, &reply_addr(m)?, m).map(|_| EXIT_CLEAN),
+            _ => Err("unknown subcommand".to_string()),
+        }
+    }
+
+    fn run_client(&self, config: &ClusterConfig, addr: &Addr, matches: &ArgMatches) -> Result<(), String> {
+        let op = self.client_op(addr, matches)?;
+        let result = submit::<S>(config, addr, op).map_err(|e| format!("request failed: {}", e))?;
+        println!("result: {:?}", result);
+        Ok(())
+    }
+
+    // the operation a client subcommand asks for
+    fn client_op(&self, addr: &Addr, matches: &ArgMatches) -> Result<S::Op, String> {
+        match matches.subcommand() {
+            ("op", Some(m)) => serde_json::from_str(m.value_of("json").unwrap_or(""))
+                .map_err(|e| format!("bad operation: {}", e)),
+            (name, Some(m)) => {
+                let parse = self.client_commands.iter()
+                    .find(|(cmd, _)| cmd.get_name() == name)
+                    .map(|(_, parse)| *parse)
+                    .ok_or(format!("unknown client command {}", name))?;
+                parse(m, addr)
+            },
+            _ => Err("missing client command".to_string()),
+        }
+    }
+}
+
+// where the client, status and reload subcommands listen for answers
+fn reply_addr(matches: &ArgMatches, sub: &ArgMatches) -> Result<Addr, String> {
+    let port = sub.value_of("port").unwrap_or("")
+        .parse::<u16>().map_err(|e| format!("bad port: {}", e))?;
+    Ok(Addr::new(matches.value_of("host").unwrap_or("127.0.0.1"), port))
+}
+
+fn run_status<S>(config: &ClusterConfig, addr: &Addr, matches: &ArgMatches) -> Result<(), String> where
+    S: StateMachine + 'static,
+    S::Op: serde::Serialize + serde::de::DeserializeOwned + Clone + Eq + Hash + Debug + Send + 'static,
+    S::Result: serde::Serialize + serde::de::DeserializeOwned + Clone + Debug + Send + 'static {
+    let id = matches.value_of("ID").unwrap_or("")
+        .parse::<ServerID>().map_err(|e| format!("bad server id: {}", e))?;
+    let status = query_status::<S>(config, addr, id)?;
+    println!("{}", serde_json::to_string_pretty(&status).map_err(|e| e.to_string())?);
+    Ok(())
+}
+
+fn run_reload<S>(config: &ClusterConfig, addr: &Addr, matches: &ArgMatches) -> Result<(), String> where
+    S: StateMachine + 'static,
+    S::Op: serde::Serialize + serde::de::DeserializeOwned + Clone + Eq + Hash + Debug + Send + 'static,
+    S::Result: serde::Serialize + serde::de::DeserializeOwned + Clone + Debug + Send + 'static {
+    let id = matches.value_of("ID").unwrap_or("")
+        .parse::<ServerID>().map_err(|e| format!("bad server id: {}", e))?;
+    let changes = reload_settings::<S>(config, addr, id)?
+        .map_err(|e| format!("server {} did not reload: {}", id, e))?;
+    if changes.is_empty() {
+        println!("no settings changed");
+    }
+    changes.iter().for_each(|c| println!("{}", c));
+    Ok(())
+}
+
+// runs server `id` in the given role until a shutdown is requested, returns the exit status
+pub fn run_role<S>(config: &ClusterConfig, role: Role, id: ServerID) -> Result<i32, String> where
+    S: StateMachine + 'static,
+    S::Op: serde::Serialize + serde::de::DeserializeOwned + Clone + Eq + Hash + Debug + Send + 'static,
+    S::Result: serde::Serialize + serde::de::DeserializeOwned + Clone + Debug + Send + 'static {
+    match config.server(id) {
+        Some(s) if s.role == role => (),
+        _ => return Err(format!("no {:?} with id {} in the config", role, id)),
+    };
+    install_signal_handlers().map_err(|e| format!("failed to install signal handlers: {}", e))?;
+    reload::install_reload_handler().map_err(|e| format!("failed to install the SIGHUP handler: {}", e))?;
+    serve_metrics(config, &[id])?;
+    config.set_local_servers(&[id])?;
+    config.with_transport(RoleUser::<S> { config, role, id, machine: PhantomData })
+}
+
+// runs the replica, leader and acceptor with index `idx` on one reactor
+pub fn run_colocated<S>(config: &ClusterConfig, idx: usize) -> Result<i32, String> where
+    S: StateMachine + 'static,
+    S::Op: serde::Serialize + serde::de::DeserializeOwned + Clone + Eq + Hash + Debug + Send + 'static,
+    S::Result: serde::Serialize + serde::de::DeserializeOwned + Clone + Debug + Send + 'static {
+    install_signal_handlers().map_err(|e| format!("failed to install signal handlers: {}", e))?;
+    reload::install_reload_handler().map_err(|e| format!("failed to install the SIGHUP handler: {}", e))?;
+    let ids = [Role::Replica, Role::Leader, Role::Acceptor].iter()
+        .filter_map(|role| config.servers_with(*role).get(idx).map(|(id, _)| *id))
+        .collect::<Vec<_>>();
+    serve_metrics(config, ids.as_slice())?;
+    config.set_local_servers(ids.as_slice())?;
+    config.with_transport(ColocatedUser::<S> { config, idx, machine: PhantomData })
+}
+
+// one endpoint per process, on the first metrics address among the hosted
+// servers; it reports all of them
+fn serve_metrics(config: &ClusterConfig, ids: &[ServerID]) -> Result<(), String> {
+    let addr = ids.iter().filter_map(|id| config.server(*id).and_then(|s| s.metrics.clone())).next();
+    match addr {
+        Some(addr) => metrics::serve(&addr).map_err(|e| format!("failed to serve metrics on {}: {}", addr, e)),
+        None => Ok(()),
+    }
+}
+
+// sends one operation from a client listening on `addr`
+pub fn submit<S>(config: &ClusterConfig, addr: &Addr, op: S::Op) -> Result<S::Result, Error> where
+    S: StateMachine + 'static,
+    S::Op: serde::Serialize + serde::de::DeserializeOwned + Clone + Eq + Hash + Debug + Send + 'static,
+    S::Result: serde::Serialize + serde::de::DeserializeOwned + Clone + Debug + Send + 'static {
+    config.with_transport(SubmitUser::<S> { config, addr: addr.clone(), op })
+}
+
+// asks server `id` for its status from a client listening on `addr`
+pub fn query_status<S>(config: &ClusterConfig, addr: &Addr, id: ServerID) -> Result<NodeStatus, String> where
+    S: StateMachine + 'static,
+    S::Op: serde::Serialize + serde::de::DeserializeOwned + Clone + Eq + Hash + Debug + Send + 'static,
+    S::Result: serde::Serialize + serde::de::DeserializeOwned + Clone + Debug + Send + 'static {
+    let server = config.server(id).ok_or(format!("no server with id {} in the config", id))?;
+    config.with_transport(StatusUser::<S> { config, addr: addr.clone(), server: server.addr.clone(), machine: PhantomData })
+        .map_err(|e| format!("status request to {} failed: {}", id, e))
+}
+
+// asks server `id` to reload its settings from a client listening on `addr`,
+// returns its report
+pub fn reload_settings<S>(config: &ClusterConfig, addr: &Addr, id: ServerID) -> Result<Result<Vec<String>, String>, String> where
+    S: StateMachine + 'static,
+    S::Op: serde::Serialize + serde::de::DeserializeOwned + Clone + Eq + Hash + Debug + Send + 'static,
+    S::Result: serde::Serialize + serde::de::DeserializeOwned + Clone + Debug + Send + 'static {
+    let server = config.server(id).ok_or(format!("no server with id {} in the config", id))?;
+    config.with_transport(ReloadUser::<S> { config, addr: addr.clone(), server: server.addr.clone(), machine: PhantomData })
+        .map_err(|e| format!("reload request to {} failed: {}", id, e))
+}
+
+struct RoleUser<'c, S> {
+    config: &'c ClusterConfig,
+    role: Role,
+    id: ServerID,
+    machine: PhantomData<S>,
+}
+
+impl<'c, S> TransportUser<Message<S::Op, S::Result>> for RoleUser<'c, S> where
+    S: StateMachine + 'static,
+    S::Op: serde::Serialize + serde::de::DeserializeOwned + Clone + Eq + Hash + Debug + Send + 'static,
+    S::Result: serde::Serialize + serde::de::DeserializeOwned + Clone + Debug + Send + 'static {
+    type Output = Result<i32, String>;
+
+    fn run<ServerT, ClientT>(self) -> Self::Output where
+        ServerT: MsgRecver<Message<S::Op, S::Result>> + 'static,
+        ClientT: MsgSender<Message<S::Op, S::Result>> + 'static {
+        let config = self.config;
+        let idx = self.id;
+        let addr = config.server(idx).map(|s| s.addr.clone()).ok_or("unknown server")?;
+        let server_addrs = config.server_addrs();
+        let replicas = config.ids_with(Role::Replica);
+        let leaders = config.ids_with(Role::Leader);
+        let acceptors = config.ids_with(Role::Acceptor);
+        let timeouts = &config.timeouts;
+        let grace_ms = timeouts.shutdown_grace_ms;
+        if config.runtime == Runtime::Threaded {
+            return run_threaded::<S, ServerT, ClientT>(config, &[(self.role, idx)]);
+        }
+
+        let role = self.role;
+        let failed = |e: Error| format!("failed to start {:?} {}: {}", role, idx, e);
+        let code = match role {
+            Role::Leader => {
+                log_info!("runner", { id = idx, addr = addr, role = Role::Leader }, "starting");
+                let node = LeaderNode::<S::Op, S::Result,
+                                        ServerT, ClientT>::new(&addr, &acceptors, &replicas, idx,
+                                                               &server_addrs).map_err(failed)?
+                    .with_timeouts(timeouts.leader());
+                let mut node = match config.multicast {
+                    Some(ref m) => node.with_multicast::<McastSender<_>>(&m.acceptors, &m.replicas),
+                    None => node,
+                };
+                run_until_shutdown(&mut node, grace_ms)
+            },
+            Role::Acceptor => {
+                log_info!("runner", { id = idx, addr = addr, role = Role::Acceptor }, "starting");
+                let node = AcceptorNode::<S::Op, S::Result,
+                                          ServerT, ClientT>::new(&addr, idx, &server_addrs).map_err(failed)?;
+                let mut node = match config.multicast {
+                    Some(ref m) => node.with_multicast::<McastRecver<_>>(&m.acceptors).map_err(failed)?,
+                    None => node,
+                };
+                run_until_shutdown(&mut node, grace_ms)
+            },
+            Role::Replica => {
+                log_info!("runner", { id = idx, addr = addr, role = Role::Replica }, "starting");
+                let node = ReplicaNode::<S, ServerT, ClientT>::new(&addr, idx, &server_addrs, &leaders).map_err(failed)?
+                    .with_peers(&replicas);
+                let mut node = match config.multicast {
+                    Some(ref m) => node.with_multicast::<McastRecver<_>>(&m.replicas).map_err(failed)?,
+                    None => node,
+                };
+                run_until_shutdown(&mut node, grace_ms)
+            },
+        };
+        log_info!("runner", { id = idx, role = self.role, status = code }, "stopped");
+        Ok(code)
+    }
+}
+
+struct ColocatedUser<'c, S> {
+    config: &'c ClusterConfig,
+    idx: usize,
+    machine: PhantomData<S>,
+}
+
+impl<'c, S> TransportUser<Message<S::Op, S::Result>> for ColocatedUser<'c, S> where
+    S: StateMachine + 'static,
+    S::Op: serde::Serialize + serde::de::DeserializeOwned + Clone + Eq + Hash + Debug + Send + 'static,
+    S::Result: serde::Serialize + serde::de::DeserializeOwned + Clone + Debug + Send + 'static {
+    type Output = Result<i32, String>;
+
+    fn run<ServerT, ClientT>(self) -> Self::Output where
+        ServerT: MsgRecver<Message<S::Op, S::Result>> + 'static,
+        ClientT: MsgSender<Message<S::Op, S::Result>> + 'static {
+        let config = self.config;
+        let i = self.idx;
+        let server_addrs = config.server_addrs();
+        let replicas = config.ids_with(Role::Replica);
+        let leaders = config.ids_with(Role::Leader);
+        let acceptors = config.ids_with(Role::Acceptor);
+        let timeouts = &config.timeouts;
+        let mcast = &config.multicast;
+        if config.runtime == Runtime::Threaded {
+            let servers = [Role::Replica, Role::Leader, Role::Acceptor].iter()
+                .filter_map(|role| config.servers_with(*role).get(i).map(|(id, _)| (*role, *id)))
+                .collect::<Vec<_>>();
+            if servers.is_empty() {
+                return Err(format!("no server with index {}", i));
+            }
+            return run_threaded::<S, ServerT, ClientT>(config, servers.as_slice());
+        }
+
+        log_info!("runner", { index = i }, "starting colocated roles");
+        let bus = LocalBus::new();
+        let mut reactor = Reactor::new();
+        let failed = |role: Role, id: ServerID| move |e: Error| format!("failed to start {:?} {}: {}", role, id, e);
+        if let Some((idx, addr)) = config.servers_with(Role::Replica).get(i) {
+            let node = ReplicaNode::<S, ServerT, ClientT>::new(addr, *idx, &server_addrs, &leaders)
+                .map_err(failed(Role::Replica, *idx))?
+                .with_peers(&replicas);
+            let node = node.with_local_bus(bus.clone());
+            match mcast {
+                Some(ref m) => reactor.register(Box::new(node.with_multicast::<McastRecver<_>>(&m.replicas)
+                                                         .map_err(failed(Role::Replica, *idx))?)),
+                None => reactor.register(Box::new(node)),
+            };
+        }
+        if let Some((idx, addr)) = config.servers_with(Role::Leader).get(i) {
+            let node = LeaderNode::<S::Op, S::Result,
+                                    ServerT, ClientT>::new(addr, &acceptors, &replicas, *idx,
+                                                           &server_addrs)
+                .map_err(failed(Role::Leader, *idx))?
+                .with_timeouts(timeouts.leader());
+            let node = node.with_local_bus(bus.clone());
+            match mcast {
+                Some(ref m) => reactor.register(Box::new(node.with_multicast::<McastSender<_>>(&m.acceptors,
+                                                                                              &m.replicas))),
+                None => reactor.register(Box::new(node)),
+            };
+        }
+        if let Some((idx, addr)) = config.servers_with(Role::Acceptor).get(i) {
+            let node = AcceptorNode::<S::Op, S::Result,
+                                      ServerT, ClientT>::new(addr, *idx, &server_addrs)
+                .map_err(failed(Role::Acceptor, *idx))?;
+            let node = node.with_local_bus(bus.clone());
+            match mcast {
+                Some(ref m) => reactor.register(Box::new(node.with_multicast::<McastRecver<_>>(&m.acceptors)
+                                                         .map_err(failed(Role::Acceptor, *idx))?)),
+                None => reactor.register(Box::new(node)),
+            };
+        }
+        if reactor.is_empty() {
+            return Err(format!("no server with index {}", i));
+        }
+        reactor.run().map_err(|e| format!("reactor failed: {}", e))?;
+        let code = match reactor.shutdown(timeouts.shutdown_grace_ms) {
+            Ok(()) => EXIT_CLEAN,
+            Err(e) => {
+                log_warn!("runner", { index = i }, "unclean shutdown: {}", e);
+                EXIT_UNCLEAN
+            },
+        };
+        log_info!("runner", { index = i, status = code }, "stopped");
+        Ok(code)
+    }
+}
+
+// runs each (role, id) on the threaded runtime and waits for all of them
+fn run_threaded<S, ServerT, ClientT>(config: &ClusterConfig, servers: &[(Role, ServerID)]) -> Result<i32, String> where
+    S: StateMachine + 'static,
+    S::Op: serde::Serialize + serde::de::DeserializeOwned + Clone + Eq + Hash + Debug + Send + 'static,
+    S::Result: serde::Serialize + serde::de::DeserializeOwned + Clone + Debug + Send + 'static,
+    ServerT: MsgRecver<Message<S::Op, S::Result>> + 'static,
+    ClientT: MsgSender<Message<S::Op, S::Result>> + 'static {
+    let server_addrs = config.server_addrs();
+    let replicas = config.ids_with(Role::Replica);
+    let leaders = config.ids_with(Role::Leader);
+    let acceptors = config.ids_with(Role::Acceptor);
+    let mut running: Option<Running> = None;
+    for (role, id) in servers.iter() {
+        let addr = config.server(*id).map(|s| s.addr.clone()).ok_or("unknown server")?;
+        log_info!("runner", { id = id, addr = addr, role = role }, "starting threaded");
+        let node = ThreadedNode::new(&addr, *id, &server_addrs)
+            .with_grace_ms(config.timeouts.shutdown_grace_ms)
+            .with_leader_timeouts(config.timeouts.leader());
+        let threads = match *role {
+            Role::Leader => node.spawn_leader::<S::Op, S::Result, ServerT, ClientT>(&acceptors, &replicas),
+            Role::Acceptor => node.spawn_acceptor::<S::Op, S::Result, ServerT, ClientT>(),
+            Role::Replica => node.spawn_replica::<S, ServerT, ClientT>(&leaders, &replicas),
+        }.map_err(|e| format!("failed to start {:?} {}: {}", role, id, e))?;
+        running = Some(match running {
+            Some(r) => r.merge(threads),
+            None => threads,
+        });
+    }
+    let code = running.map_or(EXIT_CLEAN, |r| r.join());
+    log_info!("runner", { servers = servers, status = code }, "stopped");
+    Ok(code)
+}
+
+struct SubmitUser<'c, S: StateMachine> {
+    config: &'c ClusterConfig,
+    addr: Addr,
+    op: S::Op,
+}
+
+impl<'c, S> TransportUser<Message<S::Op, S::Result>> for SubmitUser<'c, S> where
+    S: StateMachine + 'static,
+    S::Op: serde::Serialize + serde::de::DeserializeOwned + Clone + Eq + Hash + Debug + Send + 'static,
+    S::Result: serde::Serialize + serde::de::DeserializeOwned + Clone + Debug + Send + 'static {
+    type Output = Result<S::Result, Error>;
+
+    fn run<ServerT, ClientT>(self) -> Self::Output where
+        ServerT: MsgRecver<Message<S::Op, S::Result>> + 'static,
+        ClientT: MsgSender<Message<S::Op, S::Result>> + 'static {
+        let replicas: HashSet<_> = self.config.replica_addrs();
+        let mut client = ClientNode::<S, ServerT, ClientT>::new(&self.addr, &replicas)?
+            .with_timeout(self.config.timeouts.client_timeout_ms)
+            .with_attempt_timeout(self.config.timeouts.client_attempt_ms);
+        client.send_cmd(self.op)
+    }
+}
+
+struct StatusUser<'c, S> {
+    config: &'c ClusterConfig,
+    addr: Addr,
+    server: Addr,
+    machine: PhantomData<S>,
+}
+
+impl<'c, S> TransportUser<Message<S::Op, S::Result>> for StatusUser<'c, S> where
+    S: StateMachine + 'static,
+    S::Op: serde::Serialize + serde::de::DeserializeOwned + Clone + Eq + Hash + Debug + Send + 'static,
+    S::Result: serde::Serialize + serde::de::DeserializeOwned + Clone + Debug + Send + 'static {
+    type Output = Result<NodeStatus, Error>;
+
+    fn run<ServerT, ClientT>(self) -> Self::Output where
+        ServerT: MsgRecver<Message<S::Op, S::Result>> + 'static,
+        ClientT: MsgSender<Message<S::Op, S::Result>> + 'static {
+        let replicas: HashSet<_> = self.config.replica_addrs();
+        let mut client = ClientNode::<S, ServerT, ClientT>::new(&self.addr, &replicas)?
+            .with_timeout(self.config.timeouts.client_timeout_ms);
+        client.query_status(&self.server, &self.addr)
+    }
+}
+
+struct ReloadUser<'c, S> {
+    config: &'c ClusterConfig,
+    addr: Addr,
+    server: Addr,
+    machine: PhantomData<S>,
+}
+
+impl<'c, S> TransportUser<Message<S::Op, S::Result>> for ReloadUser<'c, S> where
+    S: StateMachine + 'static,
+    S::Op: serde::Serialize + serde::de::DeserializeOwned + Clone + Eq + Hash + Debug + Send + 'static,
+    S::Result: serde::Serialize + serde::de::DeserializeOwned + Clone + Debug + Send + 'static {
+    type Output = Result<Result<Vec<String>, String>, Error>;
+
+    fn run<ServerT, ClientT>(self) -> Self::Output where
+        ServerT: MsgRecver<Message<S::Op, S::Result>> + 'static,
+        ClientT: MsgSender<Message<S::Op, S::Result>> + 'static {
+        let replicas: HashSet<_> = self.config.replica_addrs();
+        let mut client = ClientNode::<S, ServerT, ClientT>::new(&self.addr, &replicas)?
+            .with_timeout(self.config.timeouts.client_timeout_ms);
+        client.reload_settings(&self.server, &self.addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Counter(u64);
+
+    impl StateMachine for Counter {
+        type Op = u64;
+        type Result = u64;
+
+        fn apply_op(&mut self, op: &u64) -> u64 {
+            self.0 += op;
+            self.0
+        }
+    }
+
+    fn add(m: &ArgMatches, _cid: &ClientID) -> Result<u64, String> {
+        m.value_of("N").unwrap_or("").parse::<u64>().map_err(|e| format!("bad number: {}", e))
+    }
+
+    fn runner() -> Runner<Counter> {
+        Runner::<Counter>::new("counter")
+            .with_client_command(SubCommand::with_name("add").arg(Arg::with_name("N").required(true)), add)
+    }
+
+    fn client_op(args: &[&str]) -> Result<u64, String> {
+        let runner = runner();
+        let matches = runner.app().get_matches_from_safe(args).map_err(|e| e.to_string())?;
+        let sub = matches.subcommand_matches("client").unwrap();
+        runner.client_op(&Addr::new("127.0.0.1", 7000), sub)
+    }
+
+    #[test]
+    fn client_operations_come_as_json_or_from_added_commands() {
+        assert_eq!(client_op(&["counter", "client", "7000", "op", "5"]), Ok(5));
+        assert!(client_op(&["counter", "client", "7000", "op", "five"]).unwrap_err().starts_with("bad operation"));
+        assert_eq!(client_op(&["counter", "client", "7000", "add", "3"]), Ok(3));
+        assert!(client_op(&["counter", "client", "7000", "add", "x"]).unwrap_err().starts_with("bad number"));
+        assert!(client_op(&["counter", "client", "7000", "sub", "3"]).is_err());
+    }
+
+    #[test]
+    fn every_role_has_a_subcommand() {
+        let app = runner().app();
+        for args in [&["counter", "replica", "0"][..], &["counter", "leader", "1"], &["counter", "acceptor", "2"],
+                     &["counter", "server", "0"], &["counter", "status", "7001", "10"]].iter() {
+            let matches = app.clone().get_matches_from_safe(args.iter()).unwrap();
+            assert_eq!(matches.subcommand_name(), Some(args[1]));
+        }
+        assert!(app.clone().get_matches_from_safe(["counter", "replica"]).is_err());
+        assert!(app.get_matches_from_safe(["counter"]).is_err());
+    }
+
+    #[test]
+    fn a_role_must_match_the_config() {
+        let config = ClusterConfig::local();
+        assert_eq!(run_role::<Counter>(&config, Role::Replica, 10), Err("no Replica with id 10 in the config".to_string()));
+        assert_eq!(run_role::<Counter>(&config, Role::Leader, 99), Err("no Leader with id 99 in the config".to_string()));
+    }
+
+    fn reply_addr_of(args: &[&str]) -> Result<Addr, String> {
+        let matches = Runner::<Counter>::new("counter").app().get_matches_from(args);
+        let sub = matches.subcommand().1.unwrap();
+        reply_addr(&matches, sub)
+    }
+
+    #[test]
+    fn answers_come_to_the_given_host() {
+        assert_eq!(reply_addr_of(&["counter", "status", "7001", "0"]), Ok(Addr::new("127.0.0.1", 7001)));
+        assert_eq!(reply_addr_of(&["counter", "--host", "10.0.0.5", "reload", "7001", "0"]),
+                   Ok(Addr::new("10.0.0.5", 7001)));
+        assert_eq!(reply_addr_of(&["counter", "--host", "client.example", "client", "7000", "op", "1"]),
+                   Ok(Addr::new("client.example", 7000)));
+        assert!(reply_addr_of(&["counter", "status", "port", "0"]).is_err());
+    }
+}
@@ -0,0 +1,82 @@
+// Orderly shutdown on SIGTERM/SIGINT. The handler only raises a flag, the node
+// loop notices it, stops taking new work and lets the node hand off or finish
+// what is in flight through Node::shutdown. A second signal exits right away.
+use std::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+use libc::c_int;
+use node::Node;
+use reload::reload_if_requested;
+
+// the node drained everything before exiting
+pub const EXIT_CLEAN: i32 = 0;
+// the grace period ran out with messages or client requests left behind
+pub const EXIT_UNCLEAN: i32 = 1;
+// longest a node loop blocks before checking for a shutdown request, covers a
+// signal that arrives just before the loop starts to wait
+pub const SHUTDOWN_CHECK_MS: i64 = 200;
+
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+static SIGNAL: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn on_signal(sig: c_int) {
+    if SHUTDOWN.swap(true, Ordering::SeqCst) {
+        unsafe { libc::_exit(128 + sig) };
+    }
+    SIGNAL.store(sig as usize, Ordering::SeqCst);
+}
+
+// SA_RESTART is left out so that blocking polls return EINTR on a signal
+pub fn install_signal_handlers() -> Result<(), i32> {
+    for sig in [libc::SIGTERM, libc::SIGINT].iter() {
+        let ret = unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = on_signal as extern "C" fn(c_int) as libc::sighandler_t;
+            libc::sigemptyset(&mut action.sa_mask);
+            action.sa_flags = 0;
+            libc::sigaction(*sig, &action, std::ptr::null_mut())
+        };
+        if ret != 0 {
+            return Err(std::io::Error::last_os_error().raw_os_error().unwrap_or(-1));
+        }
+    }
+    Ok(())
+}
+
+pub fn request_shutdown() {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst)
+}
+
+// the signal that asked for the shutdown, None if there was none
+pub fn shutdown_signal() -> Option<c_int> {
+    match SIGNAL.load(Ordering::SeqCst) {
+        0 => None,
+        sig => Some(sig as c_int),
+    }
+}
+
+// caps a node timeout so that the loop keeps checking for a shutdown request
+pub fn bounded_timeout(timeout_ms: Option<i64>) -> i64 {
+    timeout_ms.map_or(SHUTDOWN_CHECK_MS, |t| std::cmp::min(t, SHUTDOWN_CHECK_MS))
+}
+
+// runs the node until a shutdown is requested, then gives it grace_ms to wind
+// down; returns the process exit status. Reloads settings on SIGHUP.
+pub fn run_until_shutdown<N: Node + ?Sized>(node: &mut N, grace_ms: i64) -> i32 {
+    while !shutdown_requested() {
+        reload_if_requested();
+        let timeout = if node.pending() { 0 } else { bounded_timeout(node.next_timer_ms()) };
+        if let Err(e) = node.process_timeout(timeout) {
+            log_warn!("node", "failed to handle a message: {}", e);
+        }
+    }
+    match node.shutdown(grace_ms) {
+        Ok(()) => EXIT_CLEAN,
+        Err(e) => {
+            log_warn!("node", "unclean shutdown: {}", e);
+            EXIT_UNCLEAN
+        },
+    }
+}
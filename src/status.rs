@@ -0,0 +1,59 @@
+// Status dumps for the admin StatusRequest message, so that a stuck request can
+// be traced to the role holding it up. Commands are rendered with Debug, the
+// dumps do not depend on the state machine.
+use messages::*;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LeaderStatus {
+    pub id: ServerID,
+    pub ballot: Ballot,
+    pub is_active: bool,
+    // acceptors whose P1b for the current ballot is still missing
+    pub waitfor: Vec<ServerID>,
+    pub proposals: Vec<ProposalStatus>,
+}
+
+// a proposal the leader has not seen chosen yet
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ProposalStatus {
+    pub slot: u64,
+    pub cmd: String,
+    // acceptors whose P2b is still missing
+    pub waitfor: Vec<ServerID>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AcceptorStatus {
+    pub id: ServerID,
+    pub ballot: Ballot,
+    pub accepted: usize,
+    // lowest and highest accepted slot, None while nothing is accepted
+    pub slots: Option<(u64, u64)>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReplicaStatus {
+    pub id: ServerID,
+    pub slot_in: u64,
+    pub slot_out: u64,
+    // requests waiting for a free slot
+    pub requests: Vec<(ClientID, String)>,
+    // slots this replica proposed that are not decided yet
+    pub proposed: Vec<u64>,
+    // undecided slots below the highest decision, they block slot_out
+    pub gaps: Vec<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum NodeStatus {
+    Leader(LeaderStatus),
+    Acceptor(AcceptorStatus),
+    Replica(ReplicaStatus),
+}
+
+// ids in a stable order for printing
+pub fn sorted_ids<'a, I: Iterator<Item = &'a ServerID>>(ids: I) -> Vec<ServerID> {
+    let mut v: Vec<ServerID> = ids.cloned().collect();
+    v.sort();
+    v
+}
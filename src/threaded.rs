@@ -0,0 +1,639 @@
+// Threaded runtime. A node's transport, its protocol role and, for replicas, the
+// state machine each get their own thread, connected by channels:
+//
+//     io --inbound--> protocol --committed--> apply
+//      ^                 |                      |
+//      +----outbound-----+----------------------+
+//
+// The io thread owns the receiver and the outbox, so a slow peer only fills its
+// own queue, and a slow state machine only delays responses while the replica
+// keeps proposing and learning decisions. The io thread sleeps in poll on the
+// transport's fds and on a pipe that the other threads write to when they queue
+// a message. Multicast groups and local buses are only supported by the
+// single-threaded nodes. Settings reloads are handled by the io thread.
+use std::collections::{ HashMap, HashSet };
+use std::hash::Hash;
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::mpsc::{ channel, Receiver, RecvTimeoutError, Sender, TryRecvError };
+use std::thread::{ Builder, JoinHandle };
+use std::time::Duration;
+use libc::c_int;
+use acceptor::*;
+use clock::*;
+use error::*;
+use leader::*;
+use messages::*;
+use messaging::*;
+use metrics;
+use status::*;
+use node::{ HandOff, Routes };
+use outbox::*;
+use reload;
+use replica::*;
+use shutdown::*;
+use statemachine::*;
+
+// wakes the io thread when another thread has queued a message, and the
+// async_io reactor when a future wants it to poll other fds
+pub struct Waker {
+    rd: c_int,
+    wr: c_int,
+}
+
+impl Waker {
+    pub fn new() -> Result<Self, Error> {
+        let mut fds = [0 as c_int; 2];
+        let ret = unsafe { libc::pipe(fds.as_mut_ptr()) };
+        if ret != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        fds.iter().for_each(|fd| unsafe {
+            let flags = libc::fcntl(*fd, libc::F_GETFL);
+            libc::fcntl(*fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
+            libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        });
+        Ok(Waker { rd: fds[0], wr: fds[1] })
+    }
+
+    // a full pipe means the io thread has not woken up yet, nothing is lost
+    pub fn wake(&self) {
+        let _ = unsafe { libc::write(self.wr, b"w".as_ptr() as *const libc::c_void, 1) };
+    }
+
+    // readable once wake was called
+    pub fn fd(&self) -> c_int {
+        self.rd
+    }
+
+    pub fn clear(&self) {
+        let mut buf = [0u8; 64];
+        while unsafe { libc::read(self.rd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) } > 0 {}
+    }
+}
+
+impl Drop for Waker {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.rd);
+            libc::close(self.wr);
+        }
+    }
+}
+
+pub enum Outgoing<M> {
+    ToServer(ServerID, M),
+    ToClient(ClientID, M),
+}
+
+// hands messages to the io thread
+pub struct Outbound<M> {
+    tx: Sender<Outgoing<M>>,
+    waker: Arc<Waker>,
+}
+
+// the io thread, the messages it received, the way to it and whether its
+// outbox is saturated
+type IoHandles<M> = (Running, Receiver<M>, Outbound<M>, Arc<AtomicBool>);
+
+impl<M> Clone for Outbound<M> {
+    fn clone(&self) -> Self {
+        Outbound { tx: self.tx.clone(), waker: self.waker.clone() }
+    }
+}
+
+impl<M> Outbound<M> {
+    pub fn send(&self, out: Outgoing<M>) {
+        if self.tx.send(out).is_ok() {
+            self.waker.wake();
+        }
+    }
+}
+
+fn spawn<F>(name: String, f: F) -> Result<JoinHandle<Result<(), Error>>, Error> where
+    F: FnOnce() -> Result<(), Error> + Send + 'static {
+    Builder::new().name(name).spawn(f).map_err(|e| e.into())
+}
+
+// a thread at the other end of a channel is gone
+fn disconnected() -> Error {
+    TransportError::Os(libc::EPIPE).into()
+}
+
+// Ok(None) once timeout_ms passed without a message, EPIPE if the io thread is gone
+fn next_msg<M>(inbound: &Receiver<M>, timeout_ms: i64) -> Result<Option<M>, Error> {
+    match inbound.recv_timeout(Duration::from_millis(std::cmp::max(timeout_ms, 0) as u64)) {
+        Ok(m) => Ok(Some(m)),
+        Err(RecvTimeoutError::Timeout) => Ok(None),
+        Err(RecvTimeoutError::Disconnected) => Err(disconnected()),
+    }
+}
+
+// responses go back over the connection the request came in on if there is one
+fn reply_to_client<CmdT, ResultT, ServerT, ClientT>(server: &mut ServerT, outbox: &mut Outbox<Message<CmdT, ResultT>, ClientT>,
+                                                    routes: &mut Routes, my_id: ServerID,
+                                                    cid: ClientID, m: Message<CmdT, ResultT>) where
+    CmdT: serde::Serialize + serde::de::DeserializeOwned,
+    ResultT: serde::Serialize + serde::de::DeserializeOwned,
+    ServerT: MsgRecver<Message<CmdT, ResultT>>,
+    ClientT: MsgSender<Message<CmdT, ResultT>> {
+    metrics::message_sent(my_id, &m);
+    let replied = routes.remove(&cid)
+        .is_some_and(|route| server.reply(route.as_slice(), &m).is_ok());
+    if !replied {
+        let _ = outbox.push(&cid, m);
+    }
+}
+
+// runs until every Outbound is dropped, then sends what is still queued; takes
+// everything the io thread owns
+#[allow(clippy::too_many_arguments)]
+fn io_loop<CmdT, ResultT, ServerT, ClientT>(addr: Addr, my_id: ServerID, server_addrs: HashMap<ServerID, Addr>,
+                                            inbound: Sender<Message<CmdT, ResultT>>,
+                                            outbound: Receiver<Outgoing<Message<CmdT, ResultT>>>,
+                                            waker: Arc<Waker>, saturated: Arc<AtomicBool>,
+                                            grace_ms: i64) -> Result<(), Error> where
+    CmdT: serde::Serialize + serde::de::DeserializeOwned,
+    ResultT: serde::Serialize + serde::de::DeserializeOwned,
+    ServerT: MsgRecver<Message<CmdT, ResultT>>,
+    ClientT: MsgSender<Message<CmdT, ResultT>> {
+    let mut server = ServerT::bind(&addr)?;
+    let mut outbox: Outbox<Message<CmdT, ResultT>, ClientT> = Outbox::new(Default::default());
+    // connections that requests came in on, responses go back over them
+    let mut routes = Routes::default();
+    loop {
+        reload::reload_if_requested();
+        waker.clear();
+        loop {
+            match outbound.try_recv() {
+                Ok(Outgoing::ToServer(id, m)) => {
+                    metrics::message_sent(my_id, &m);
+                    if let Some(addr) = server_addrs.get(&id) {
+                        let _ = outbox.push(addr, m);
+                    }
+                },
+                Ok(Outgoing::ToClient(cid, m)) => reply_to_client(&mut server, &mut outbox, &mut routes, my_id, cid, m),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return outbox.drain(deadline_after(grace_ms)).map_err(|e| e.into()),
+            }
+        }
+        outbox.flush();
+        saturated.store(outbox.saturated(), Ordering::Relaxed);
+
+        while let Some(msg) = server.try_recv() {
+            metrics::message_received(my_id, &msg);
+            let cid = match msg {
+                Message::Request { ref cid, .. } | Message::StatusRequest { ref cid }
+                | Message::ReloadRequest { ref cid } => Some(cid.clone()),
+                _ => None,
+            };
+            if let Some(cid) = cid {
+                if let Some(route) = server.last_route() {
+                    routes.insert(cid, route);
+                }
+            }
+            // answered here for every role, the leader loop takes up new timeouts itself
+            if let Message::ReloadRequest { cid } = msg {
+                let report = Message::ReloadReport { changes: reload::reload() };
+                reply_to_client(&mut server, &mut outbox, &mut routes, my_id, cid, report);
+                continue;
+            }
+            // the protocol thread stopped, outbound disconnects shortly
+            if inbound.send(msg).is_err() {
+                break;
+            }
+        }
+
+        let timeout = if server.pending() { 0 } else { bounded_timeout(outbox.next_retry_ms()) };
+        let mut fds = server.get_io_fds();
+        fds.push(waker.rd);
+        match poll_readable(fds.as_slice(), timeout) {
+            Err(TransportError::Os(eno)) if eno == libc::EINTR => (),
+            Err(e) => return Err(e.into()),
+            Ok(_) => (),
+        }
+    }
+}
+
+fn leader_loop<CmdT, ResultT>(acceptors: HashSet<ServerID>, replicas: HashSet<ServerID>, my_id: ServerID,
+                              timeouts: LeaderTimeouts, clock: SharedClock, inbound: Receiver<Message<CmdT, ResultT>>,
+                              out: Outbound<Message<CmdT, ResultT>>) -> Result<(), Error> where
+    CmdT: serde::Serialize + serde::de::DeserializeOwned + Clone + std::fmt::Debug,
+    ResultT: serde::Serialize + serde::de::DeserializeOwned + Clone + std::fmt::Debug {
+    let mut leader = Leader::new(&acceptors, &replicas, my_id);
+    leader.set_timeouts(timeouts);
+    leader.set_clock(clock);
+    let mut reload_gen = reload::generation();
+    // proposals still in flight are picked up by the next leader's phase 1
+    while !shutdown_requested() {
+        if let Some(timeouts) = reload::reloaded_leader_timeouts(&mut reload_gen) {
+            leader.set_timeouts(timeouts);
+        }
+        let msg = match next_msg(&inbound, bounded_timeout(leader.next_timer_ms()))? {
+            Some(Message::StatusRequest { cid }) => {
+                out.send(Outgoing::ToClient(cid, Message::Status { status: NodeStatus::Leader(leader.status()) }));
+                continue;
+            },
+            msg => msg.unwrap_or(Message::Tick),
+        };
+        let to_send = leader.handle_msg(&msg)?;
+        metrics::leader_state(my_id, leader.ballot(), leader.is_active(), leader.proposal_count());
+        to_send.into_iter().for_each(|(id, m)| {
+            out.send(Outgoing::ToServer(id, m));
+        });
+    }
+    Ok(())
+}
+
+fn acceptor_loop<CmdT, ResultT>(my_id: ServerID, inbound: Receiver<Message<CmdT, ResultT>>,
+                                out: Outbound<Message<CmdT, ResultT>>) -> Result<(), Error> where
+    CmdT: serde::Serialize + serde::de::DeserializeOwned + Clone + std::fmt::Debug,
+    ResultT: serde::Serialize + serde::de::DeserializeOwned + Clone + std::fmt::Debug {
+    let mut acceptor = Acceptor::new(my_id);
+    while !shutdown_requested() {
+        let msg = match next_msg(&inbound, SHUTDOWN_CHECK_MS)? {
+            Some(Message::StatusRequest { cid }) => {
+                out.send(Outgoing::ToClient(cid, Message::Status { status: NodeStatus::Acceptor(acceptor.status()) }));
+                continue;
+            },
+            Some(msg) => msg,
+            None => continue,
+        };
+        let to_send = acceptor.handle_msg::<ResultT>(&msg);
+        metrics::acceptor_state(my_id, acceptor.ballot());
+        to_send.into_iter().for_each(|(id, m)| {
+            out.send(Outgoing::ToServer(id, m));
+        });
+    }
+    Ok(())
+}
+
+// orders requests like ReplicaNode but leaves the state machine to the apply
+// thread; shuts down the same way, handing requests to the peers
+#[allow(clippy::too_many_arguments)]
+fn replica_loop<S>(my_id: ServerID, leaders: HashSet<ServerID>, mut hand_off: HandOff, grace_ms: i64,
+                   applied: SharedApplied<S::Result>,
+                   inbound: Receiver<Message<S::Op, S::Result>>,
+                   out: Outbound<Message<S::Op, S::Result>>,
+                   committed: Sender<Vec<Committed<S::Op>>>,
+                   saturated: Arc<AtomicBool>) -> Result<(), Error> where
+    S: StateMachine,
+    S::Op: serde::Serialize + serde::de::DeserializeOwned + Clone + Eq + Hash + std::fmt::Debug,
+    S::Result: serde::Serialize + serde::de::DeserializeOwned + Clone + std::fmt::Debug {
+    let mut replica = Replica::<S>::new(&leaders);
+    replica.set_apply_inline(false);
+    replica.set_applied(applied);
+    let mut proposal_timer = metrics::ProposalTimer::default();
+
+    let mut deadline = None;
+    loop {
+        if deadline.is_none() && shutdown_requested() {
+            deadline = Some(deadline_after(grace_ms));
+        }
+        let timeout = match deadline {
+            Some(d) if replica.in_flight() == 0 || remaining_ms(d) == 0 => break,
+            Some(d) => bounded_timeout(Some(remaining_ms(d))),
+            None => SHUTDOWN_CHECK_MS,
+        };
+        let msg = match next_msg(&inbound, timeout)? {
+            Some(Message::StatusRequest { cid }) => {
+                out.send(Outgoing::ToClient(cid, Message::Status { status: NodeStatus::Replica(replica.status(my_id)) }));
+                continue;
+            },
+            Some(Message::Request { cid, cmd, req_id }) if deadline.is_some() => {
+                out.send(hand_off.route(cid, req_id, cmd));
+                continue;
+            },
+            Some(msg) => msg,
+            None => Message::Tick,
+        };
+        // new requests are turned away while a peer cannot keep up
+        replica.set_accepting(!saturated.load(Ordering::Relaxed));
+        let slot_out = replica.slot_out();
+        let (to_server, to_client) = replica.handle_msg(&msg);
+        proposal_timer.observe(my_id, slot_out, replica.slot_out(), &to_server);
+        metrics::replica_state(my_id, replica.slot_in(), replica.slot_out(), replica.pending_requests());
+        to_server.into_iter().for_each(|(id, m)| {
+            out.send(Outgoing::ToServer(id, m));
+        });
+        to_client.into_iter().for_each(|(cid, m)| {
+            out.send(Outgoing::ToClient(cid, m));
+        });
+        let batch = replica.take_committed();
+        if !batch.is_empty() && committed.send(batch).is_err() {
+            return Err(disconnected());
+        }
+    }
+    let mut left_behind = replica.in_flight();
+    for (cid, req_id, cmd) in replica.take_requests() {
+        let next = hand_off.route(cid, req_id, cmd);
+        if let Outgoing::ToClient(..) = next {
+            left_behind += 1;
+        }
+        out.send(next);
+    }
+    if left_behind > 0 {
+        Err(ProtocolError::Unfinished(left_behind).into())
+    } else {
+        Ok(())
+    }
+}
+
+// applies decided operations in order until the replica thread stops
+fn apply_loop<S>(committed: Receiver<Vec<Committed<S::Op>>>, applied: SharedApplied<S::Result>,
+                 out: Outbound<Message<S::Op, S::Result>>) -> Result<(), Error> where
+    S: StateMachine,
+    S::Op: std::fmt::Debug,
+    S::Result: Clone + std::fmt::Debug {
+    let mut state = S::init_state();
+    for batch in committed.iter() {
+        let mut applied = applied.lock().unwrap_or_else(|e| e.into_inner());
+        batch.into_iter().for_each(|(slot, op, req, client)| {
+            log_trace!("replica", { slot = slot }, "applying {:?}", op);
+            let result = applied.apply(&mut state, &op, &req);
+            if let (Some(cid), Some(result)) = (client, result) {
+                let req_id = req.map(|(_, id)| id);
+                out.send(Outgoing::ToClient(cid.clone(), Message::Response { cid, result, req_id }));
+            }
+        });
+    }
+    Ok(())
+}
+
+pub struct ThreadedNode {
+    addr: Addr,
+    my_id: ServerID,
+    server_addrs: HashMap<ServerID, Addr>,
+    grace_ms: i64,
+    leader_timeouts: LeaderTimeouts,
+    clock: SharedClock,
+}
+
+impl ThreadedNode {
+    pub fn new(addr: &Addr, my_id: ServerID, server_addrs: &HashMap<ServerID, Addr>) -> Self {
+        ThreadedNode {
+            addr: addr.clone(),
+            my_id,
+            server_addrs: server_addrs.clone(),
+            grace_ms: 2000,
+            leader_timeouts: Default::default(),
+            clock: system_clock(),
+        }
+    }
+
+    // how long the threads may take to wind down after a shutdown request
+    pub fn with_grace_ms(mut self, ms: i64) -> Self {
+        self.grace_ms = ms;
+        self
+    }
+
+    pub fn with_leader_timeouts(mut self, timeouts: LeaderTimeouts) -> Self {
+        self.leader_timeouts = timeouts;
+        self
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn spawn_io<CmdT, ResultT, ServerT, ClientT>(&self, role: &str)
+        -> Result<IoHandles<Message<CmdT, ResultT>>, Error> where
+        CmdT: serde::Serialize + serde::de::DeserializeOwned + Send + 'static,
+        ResultT: serde::Serialize + serde::de::DeserializeOwned + Send + 'static,
+        ServerT: MsgRecver<Message<CmdT, ResultT>> + 'static,
+        ClientT: MsgSender<Message<CmdT, ResultT>> + 'static {
+        let waker = Arc::new(Waker::new()?);
+        let saturated = Arc::new(AtomicBool::new(false));
+        let (in_tx, in_rx) = channel();
+        let (out_tx, out_rx) = channel();
+        let (addr, my_id, server_addrs, grace_ms) = (self.addr.clone(), self.my_id, self.server_addrs.clone(), self.grace_ms);
+        let (io_waker, io_saturated) = (waker.clone(), saturated.clone());
+        let io = spawn(format!("{}-{}-io", role, self.my_id), move || {
+            io_loop::<CmdT, ResultT, ServerT, ClientT>(addr, my_id, server_addrs, in_tx, out_rx,
+                                                       io_waker, io_saturated, grace_ms)
+        })?;
+        let running = Running { threads: vec![(format!("{}-{}-io", role, self.my_id), io)] };
+        Ok((running, in_rx, Outbound { tx: out_tx, waker }, saturated))
+    }
+
+    pub fn spawn_leader<CmdT, ResultT, ServerT, ClientT>(self, acceptors: &HashSet<ServerID>,
+                                                         replicas: &HashSet<ServerID>) -> Result<Running, Error> where
+        CmdT: serde::Serialize + serde::de::DeserializeOwned + Clone + std::fmt::Debug + Send + 'static,
+        ResultT: serde::Serialize + serde::de::DeserializeOwned + Clone + std::fmt::Debug + Send + 'static,
+        ServerT: MsgRecver<Message<CmdT, ResultT>> + 'static,
+        ClientT: MsgSender<Message<CmdT, ResultT>> + 'static {
+        let (mut running, inbound, out, _) = self.spawn_io::<CmdT, ResultT, ServerT, ClientT>("leader")?;
+        let (acceptors, replicas, my_id) = (acceptors.clone(), replicas.clone(), self.my_id);
+        let (timeouts, clock) = (self.leader_timeouts, self.clock.clone());
+        let name = format!("leader-{}", self.my_id);
+        let protocol = spawn(name.clone(), move || {
+            leader_loop(acceptors, replicas, my_id, timeouts, clock, inbound, out)
+        })?;
+        running.threads.push((name, protocol));
+        Ok(running)
+    }
+
+    pub fn spawn_acceptor<CmdT, ResultT, ServerT, ClientT>(self) -> Result<Running, Error> where
+        CmdT: serde::Serialize + serde::de::DeserializeOwned + Clone + std::fmt::Debug + Send + 'static,
+        ResultT: serde::Serialize + serde::de::DeserializeOwned + Clone + std::fmt::Debug + Send + 'static,
+        ServerT: MsgRecver<Message<CmdT, ResultT>> + 'static,
+        ClientT: MsgSender<Message<CmdT, ResultT>> + 'static {
+        let (mut running, inbound, out, _) = self.spawn_io::<CmdT, ResultT, ServerT, ClientT>("acceptor")?;
+        let my_id = self.my_id;
+        let name = format!("acceptor-{}", self.my_id);
+        let protocol = spawn(name.clone(), move || {
+            acceptor_loop(my_id, inbound, out)
+        })?;
+        running.threads.push((name, protocol));
+        Ok(running)
+    }
+
+    // `replicas` are the cluster's replicas, requests are handed to the others on shutdown
+    pub fn spawn_replica<S, ServerT, ClientT>(self, leaders: &HashSet<ServerID>,
+                                              replicas: &HashSet<ServerID>) -> Result<Running, Error> where
+        S: StateMachine + 'static,
+        S::Op: serde::Serialize + serde::de::DeserializeOwned + Clone + Eq + Hash + std::fmt::Debug + Send + 'static,
+        S::Result: serde::Serialize + serde::de::DeserializeOwned + Clone + std::fmt::Debug + Send + 'static,
+        ServerT: MsgRecver<Message<S::Op, S::Result>> + 'static,
+        ClientT: MsgSender<Message<S::Op, S::Result>> + 'static {
+        let (mut running, inbound, out, saturated) =
+            self.spawn_io::<S::Op, S::Result, ServerT, ClientT>("replica")?;
+        let (commit_tx, commit_rx) = channel();
+        let applied: SharedApplied<S::Result> = Default::default();
+        let apply_applied = applied.clone();
+        let apply_out = out.clone();
+        let apply_name = format!("replica-{}-apply", self.my_id);
+        let apply = spawn(apply_name.clone(), move || {
+            apply_loop::<S>(commit_rx, apply_applied, apply_out)
+        })?;
+        running.threads.push((apply_name, apply));
+
+        let my_id = self.my_id;
+        let hand_off = HandOff::new(my_id, replicas);
+        let (leaders, grace_ms) = (leaders.clone(), self.grace_ms);
+        let name = format!("replica-{}", self.my_id);
+        let protocol = spawn(name.clone(), move || {
+            replica_loop::<S>(my_id, leaders, hand_off, grace_ms, applied, inbound, out, commit_tx, saturated)
+        })?;
+        running.threads.push((name, protocol));
+        Ok(running)
+    }
+}
+
+// the threads of one or more nodes
+pub struct Running {
+    threads: Vec<(String, JoinHandle<Result<(), Error>>)>,
+}
+
+impl Running {
+    pub fn merge(mut self, other: Running) -> Running {
+        self.threads.extend(other.threads);
+        self
+    }
+
+    // waits for every thread, returns the exit status
+    pub fn join(self) -> i32 {
+        self.threads.into_iter().map(|(name, t)| {
+            match t.join() {
+                Ok(Ok(())) => EXIT_CLEAN,
+                Ok(Err(e)) => {
+                    log_error!("node", { thread = name, errno = e.errno() }, "thread stopped with an error: {}", e);
+                    EXIT_UNCLEAN
+                },
+                Err(_) => {
+                    log_error!("node", { thread = name }, "thread panicked");
+                    EXIT_UNCLEAN
+                },
+            }
+        }).fold(EXIT_CLEAN, std::cmp::max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Counter(u64);
+
+    impl StateMachine for Counter {
+        type Op = u64;
+        type Result = u64;
+
+        fn apply_op(&mut self, op: &u64) -> u64 {
+            self.0 += op;
+            self.0
+        }
+    }
+
+    fn outbound<M>() -> (Outbound<M>, Receiver<Outgoing<M>>) {
+        let (tx, rx) = channel();
+        (Outbound { tx, waker: Arc::new(Waker::new().unwrap()) }, rx)
+    }
+
+    fn next_out<M>(rx: &Receiver<Outgoing<M>>) -> Outgoing<M> {
+        rx.recv_timeout(Duration::from_secs(5)).unwrap_or_else(|_| panic!("nothing was sent"))
+    }
+
+    #[test]
+    fn the_waker_is_readable_until_cleared() {
+        let waker = Waker::new().unwrap();
+        assert!(!wait_readable(&[waker.fd()], 0));
+        // more wakes than the pipe holds must not block
+        (0..100_000).for_each(|_| waker.wake());
+        assert!(wait_readable(&[waker.fd()], 0));
+        waker.clear();
+        assert!(!wait_readable(&[waker.fd()], 0));
+    }
+
+    #[test]
+    fn the_acceptor_thread_answers_until_its_inbound_closes() {
+        let (inbound_tx, inbound) = channel();
+        let (out, rx) = outbound::<Message<u64, u64>>();
+        let acceptor = std::thread::spawn(move || acceptor_loop(20, inbound, out));
+
+        let ballot = Ballot::zero(10);
+        inbound_tx.send(Message::P1a { sender: 10, ballot: ballot.clone() }).unwrap();
+        match next_out(&rx) {
+            Outgoing::ToServer(10, Message::P1b { sender: 20, ballot: b, .. }) => assert_eq!(b, ballot),
+            _ => panic!("no P1b"),
+        }
+        let cid = Addr::new("127.0.0.1", 7001);
+        inbound_tx.send(Message::StatusRequest { cid: cid.clone() }).unwrap();
+        match next_out(&rx) {
+            Outgoing::ToClient(to, Message::Status { status: NodeStatus::Acceptor(status) }) => {
+                assert_eq!(to, cid);
+                assert_eq!(status.ballot, ballot);
+            },
+            _ => panic!("no status"),
+        }
+        drop(inbound_tx);
+        assert!(acceptor.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn the_replica_threads_propose_and_answer_decided_requests() {
+        let (inbound_tx, inbound) = channel();
+        let (out, rx) = outbound::<Message<u64, u64>>();
+        let (commit_tx, commit_rx) = channel();
+        let applied: SharedApplied<u64> = Default::default();
+        let (apply_applied, apply_out) = (applied.clone(), out.clone());
+        let apply = std::thread::spawn(move || apply_loop::<Counter>(commit_rx, apply_applied, apply_out));
+        let replica = std::thread::spawn(move || {
+            let hand_off = HandOff::new(0, &vec![0].into_iter().collect());
+            replica_loop::<Counter>(0, vec![10].into_iter().collect(), hand_off, 0, applied, inbound, out, commit_tx,
+                                    Arc::new(AtomicBool::new(false)))
+        });
+
+        let cid = Addr::new("127.0.0.1", 7000);
+        inbound_tx.send(Message::Request { cid: cid.clone(), cmd: 4, req_id: Some(1) }).unwrap();
+        let (slot, cmd) = match next_out(&rx) {
+            Outgoing::ToServer(10, Message::Propose { slot, cmd, req }) => {
+                assert_eq!(req, Some((cid.clone(), 1)));
+                (slot, cmd)
+            },
+            _ => panic!("no proposal"),
+        };
+        inbound_tx.send(Message::Decision { slot, cmd, req: Some((cid.clone(), 1)) }).unwrap();
+        match next_out(&rx) {
+            Outgoing::ToClient(to, Message::Response { result: 4, req_id: Some(1), .. }) => assert_eq!(to, cid),
+            _ => panic!("no response"),
+        }
+        drop(inbound_tx);
+        assert!(replica.join().unwrap().is_err());
+        assert!(apply.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn retries_are_answered_from_what_the_apply_thread_applied() {
+        let leaders = [0].iter().cloned().collect();
+        let mut replica = Replica::<Counter>::new(&leaders);
+        let applied: SharedApplied<u64> = Default::default();
+        replica.set_apply_inline(false);
+        replica.set_applied(applied.clone());
+
+        let (commit_tx, commit_rx) = channel();
+        let (out, rx) = outbound();
+        let apply = std::thread::spawn(move || apply_loop::<Counter>(commit_rx, applied, out));
+
+        let cid = Addr::new("127.0.0.1", 7000);
+        let request = Message::Request { cid: cid.clone(), cmd: 5, req_id: Some(1) };
+        replica.handle_msg(&request);
+        replica.handle_msg(&Message::Decision { slot: 1, cmd: 5, req: Some((cid.clone(), 1)) });
+        commit_tx.send(replica.take_committed()).unwrap();
+        drop(commit_tx);
+        apply.join().unwrap().unwrap();
+        match rx.try_recv() {
+            Ok(Outgoing::ToClient(_, Message::Response { result: 5, req_id: Some(1), .. })) => (),
+            _ => panic!("the apply thread did not answer"),
+        }
+
+        let (to_server, to_client) = replica.handle_msg(&request);
+        assert!(to_server.is_empty());
+        match &to_client[..] {
+            [(_, Message::Response { result: 5, req_id: Some(1), .. })] => (),
+            _ => panic!("the retry was not answered with the first result"),
+        }
+    }
+}
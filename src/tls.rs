@@ -0,0 +1,482 @@
+use std::cell::RefCell;
+use std::collections::{ HashMap, VecDeque };
+use std::io::{ Read, Write, ErrorKind };
+use std::marker::PhantomData;
+use std::net::{ TcpListener, TcpStream };
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::{ Arc, RwLock };
+use std::time::Duration;
+use libc::c_int;
+use openssl::nid::Nid;
+use openssl::ssl::{ SslAcceptor, SslConnector, SslMethod, SslVerifyMode, SslFiletype,
+                          SslStream, MidHandshakeSslStream, HandshakeError };
+use openssl::error::ErrorStack;
+use openssl::x509::X509Ref;
+use messaging::*;
+use messages::*;
+use config::Role;
+use error::*;
+
+// frames are a 4-byte big-endian length followed by the payload
+const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+const WRITE_TIMEOUT_MS: u64 = 1000;
+// bounds connecting and each read of the handshake, so that a peer that takes
+// the connection but never answers does not hold up the sending thread
+const HANDSHAKE_TIMEOUT_MS: u64 = 1000;
+
+// the servers one certificate authenticates, one id or a list of them for
+// processes that host several roles
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ServerIDs {
+    One(ServerID),
+    Many(Vec<ServerID>),
+}
+
+fn deserialize_identities<'de, D>(d: D) -> Result<HashMap<String, Vec<ServerID>>, D::Error> where
+    D: serde::Deserializer<'de> {
+    let raw = <HashMap<String, ServerIDs> as serde::Deserialize>::deserialize(d)?;
+    Ok(raw.into_iter().map(|(name, ids)| match ids {
+        ServerIDs::One(id) => (name, vec![id]),
+        ServerIDs::Many(ids) => (name, ids),
+    }).collect())
+}
+
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+pub struct TlsConfig {
+    pub cert_file: String,
+    pub key_file: String,
+    pub ca_file: String,
+    // certificate common name -> the servers it authenticates
+    #[serde(deserialize_with = "deserialize_identities")]
+    pub identities: HashMap<String, Vec<ServerID>>,
+    // the cluster layout, filled in by ClusterConfig::apply
+    #[serde(skip)]
+    pub servers: HashMap<Addr, ServerID>,
+    #[serde(skip)]
+    pub roles: HashMap<ServerID, Role>,
+}
+
+struct TlsContext {
+    config: TlsConfig,
+    connector: SslConnector,
+}
+
+static TLS_CTX: RwLock<Option<Arc<TlsContext>>> = RwLock::new(None);
+
+// must be called before any TlsServer/TlsClient is created through the Msg* traits
+pub fn init_tls(config: TlsConfig) -> Result<(), ErrorStack> {
+    let connector = build_connector(&config)?;
+    let ctx = TlsContext { config, connector };
+    *TLS_CTX.write().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(ctx));
+    Ok(())
+}
+
+fn get_tls_context() -> Result<Arc<TlsContext>, TransportError> {
+    TLS_CTX.read().unwrap_or_else(|e| e.into_inner()).clone()
+        .ok_or_else(|| TransportError::Setup("tls is not initialized".to_string()))
+}
+
+fn setup_error(e: ErrorStack) -> TransportError {
+    TransportError::Setup(e.to_string())
+}
+
+fn build_acceptor(config: &TlsConfig) -> Result<SslAcceptor, ErrorStack> {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    builder.set_private_key_file(&config.key_file, SslFiletype::PEM)?;
+    builder.set_certificate_chain_file(&config.cert_file)?;
+    builder.set_ca_file(&config.ca_file)?;
+    builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    builder.check_private_key()?;
+    Ok(builder.build())
+}
+
+fn build_connector(config: &TlsConfig) -> Result<SslConnector, ErrorStack> {
+    let mut builder = SslConnector::builder(SslMethod::tls())?;
+    builder.set_private_key_file(&config.key_file, SslFiletype::PEM)?;
+    builder.set_certificate_chain_file(&config.cert_file)?;
+    builder.set_ca_file(&config.ca_file)?;
+    builder.set_verify(SslVerifyMode::PEER);
+    builder.check_private_key()?;
+    Ok(builder.build())
+}
+
+fn common_name(cert: &X509Ref) -> Option<String> {
+    cert.subject_name().entries_by_nid(Nid::COMMONNAME).next()
+        .and_then(|e| String::from_utf8(e.data().as_slice().to_vec()).ok())
+}
+
+fn peer_identity(stream: &SslStream<TcpStream>, identities: &HashMap<String, Vec<ServerID>>) -> Option<Vec<ServerID>> {
+    stream.ssl().peer_certificate()
+        .and_then(|cert| common_name(&cert))
+        .and_then(|cn| identities.get(&cn).cloned())
+}
+
+// whether a peer holding the certificate of `peer` may send `m`: server messages
+// need a server certificate, a claimed sender must be one of its servers and the
+// sending role one that it hosts
+fn peer_may_send<T: Origin>(peer: Option<&[ServerID]>, roles: &HashMap<ServerID, Role>, m: &T) -> bool {
+    let ids = match peer {
+        Some(ids) => ids,
+        None => return !m.server_only(),
+    };
+    let has_role = |id: &ServerID| m.sender_role().is_none_or(|role| roles.get(id) == Some(&role));
+    match m.claimed_sender() {
+        Some(s) => ids.contains(&s) && has_role(&s),
+        None => ids.iter().any(has_role),
+    }
+}
+
+fn write_frame<W: Write>(w: &mut W, s: &[u8]) -> std::io::Result<()> {
+    let len = s.len() as u32;
+    let header = [(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8];
+    w.write_all(&header)?;
+    w.write_all(s)?;
+    w.flush()
+}
+
+// pops a complete frame off the front of the buffer
+fn take_frame(buf: &mut Vec<u8>) -> Option<Vec<u8>> {
+    if buf.len() < 4 {
+        return None;
+    }
+    let len = buf.iter().take(4).fold(0usize, |acc, x| (acc << 8) | (*x as usize));
+    if buf.len() < 4 + len {
+        return None;
+    }
+    let frame = buf[4..4 + len].to_vec();
+    buf.drain(..4 + len);
+    Some(frame)
+}
+
+enum ConnState {
+    Handshaking(MidHandshakeSslStream<TcpStream>),
+    Established(SslStream<TcpStream>),
+}
+
+struct TlsConn {
+    state: Option<ConnState>,
+    fd: c_int,
+    // None for authenticated peers that are not cluster servers, e.g. clients
+    peer: Option<Vec<ServerID>>,
+    rbuf: Vec<u8>,
+}
+
+pub struct TlsServer<T> {
+    listener: TcpListener,
+    acceptor: SslAcceptor,
+    identities: HashMap<String, Vec<ServerID>>,
+    roles: HashMap<ServerID, Role>,
+    conns: Vec<TlsConn>,
+    ready: VecDeque<(Option<Vec<ServerID>>, Vec<u8>)>,
+    // the peer of the frame try_recv_str returned last
+    last_peer: Option<Vec<ServerID>>,
+    rejected: u64,
+    msg_type: PhantomData<T>,
+}
+
+impl<T> TlsServer<T> {
+    pub fn bind_with(addr: &Addr, config: &TlsConfig) -> Result<Self, Error> {
+        let listener = TcpListener::bind(resolve(addr)?)?;
+        listener.set_nonblocking(true)?;
+        Ok(TlsServer {
+            listener,
+            acceptor: build_acceptor(config).map_err(setup_error)?,
+            identities: config.identities.clone(),
+            roles: config.roles.clone(),
+            conns: Vec::new(),
+            ready: VecDeque::new(),
+            last_peer: None,
+            rejected: 0,
+            msg_type: PhantomData,
+        })
+    }
+
+    // messages dropped because they claimed to come from someone else
+    pub fn rejected_count(&self) -> u64 {
+        self.rejected
+    }
+
+    fn accept_new(&mut self) {
+        while let Ok((stream, _)) = self.listener.accept() {
+            if stream.set_nonblocking(true).is_err() {
+                continue;
+            }
+            let fd = stream.as_raw_fd();
+            let state = match self.acceptor.accept(stream) {
+                Ok(s) => Some(ConnState::Established(s)),
+                Err(HandshakeError::WouldBlock(mid)) => Some(ConnState::Handshaking(mid)),
+                Err(_) => None,
+            };
+            if let Some(state) = state {
+                let mut conn = TlsConn { state: Some(state), fd, peer: None, rbuf: Vec::new() };
+                if let Some(ConnState::Established(ref s)) = conn.state {
+                    conn.peer = peer_identity(s, &self.identities);
+                }
+                self.conns.push(conn);
+            }
+        }
+    }
+
+    fn read_conns(&mut self) {
+        let identities = &self.identities;
+        let ready = &mut self.ready;
+        let mut alive = Vec::with_capacity(self.conns.len());
+        for mut conn in self.conns.drain(..) {
+            let state = match conn.state.take() {
+                Some(ConnState::Handshaking(mid)) => match mid.handshake() {
+                    Ok(s) => {
+                        conn.peer = peer_identity(&s, identities);
+                        Some(ConnState::Established(s))
+                    },
+                    Err(HandshakeError::WouldBlock(mid)) => Some(ConnState::Handshaking(mid)),
+                    Err(_) => None,
+                },
+                other => other,
+            };
+            let state = match state {
+                Some(ConnState::Established(mut s)) => {
+                    let mut buf = [0u8; 4096];
+                    let mut closed = false;
+                    loop {
+                        match s.read(&mut buf) {
+                            Ok(0) => { closed = true; break; },
+                            Ok(n) => conn.rbuf.extend_from_slice(&buf[..n]),
+                            Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                            Err(_) => { closed = true; break; },
+                        }
+                    }
+                    while let Some(frame) = take_frame(&mut conn.rbuf) {
+                        ready.push_back((conn.peer.clone(), frame));
+                    }
+                    if closed || conn.rbuf.len() > 4 + MAX_FRAME_LEN {
+                        None
+                    } else {
+                        Some(ConnState::Established(s))
+                    }
+                },
+                other => other,
+            };
+            if state.is_some() {
+                conn.state = state;
+                alive.push(conn);
+            }
+        }
+        self.conns = alive;
+    }
+
+    fn next_frame(&mut self) -> Option<(Option<Vec<ServerID>>, Vec<u8>)> {
+        if self.ready.is_empty() {
+            self.accept_new();
+            self.read_conns();
+        }
+        self.ready.pop_front()
+    }
+}
+
+impl<T> MsgRecver<T> for TlsServer<T> where
+    T: serde::Serialize + serde::de::DeserializeOwned + Origin {
+    type Ctx = ();
+
+    fn bind(addr: &Addr) -> Result<Self, Error> {
+        Self::bind_with(addr, &get_tls_context()?.config)
+    }
+
+    // the identity check happens in accepts, once the frame is decoded
+    fn try_recv_str(&mut self) -> Option<Vec<u8>> {
+        self.next_frame().map(|(peer, frame)| {
+            self.last_peer = peer;
+            frame
+        })
+    }
+
+    fn accepts(&mut self, m: &T) -> bool {
+        if !peer_may_send(self.last_peer.as_deref(), &self.roles, m) {
+            self.rejected += 1;
+            return false;
+        }
+        true
+    }
+
+    fn get_io_fds(&self) -> Vec<c_int> {
+        let mut fds = vec![self.listener.as_raw_fd()];
+        fds.extend(self.conns.iter().map(|c| c.fd));
+        fds
+    }
+
+    fn pending(&self) -> bool {
+        !self.ready.is_empty()
+    }
+}
+
+thread_local! {
+    // nodes connect for every message, so established sessions are kept per destination
+    static TLS_POOL: RefCell<HashMap<Addr, SslStream<TcpStream>>> = RefCell::new(HashMap::new());
+}
+
+pub struct TlsClient<T> {
+    addr: Addr,
+    // the server expected at `addr`, None for clients
+    server: Option<ServerID>,
+    connector: SslConnector,
+    identities: HashMap<String, Vec<ServerID>>,
+    msg_type: PhantomData<T>,
+}
+
+impl<T> TlsClient<T> {
+    pub fn connect_with(addr: &Addr, config: &TlsConfig) -> Result<Self, Error> {
+        Ok(TlsClient {
+            addr: addr.clone(),
+            server: config.servers.get(addr).cloned(),
+            connector: build_connector(config).map_err(setup_error)?,
+            identities: config.identities.clone(),
+            msg_type: PhantomData,
+        })
+    }
+
+    fn establish(&self) -> Result<SslStream<TcpStream>, TransportError> {
+        let stream = self.addr.resolve().and_then(|addrs| connect_tcp(addrs.as_slice()))?;
+        stream.set_write_timeout(Some(Duration::from_millis(WRITE_TIMEOUT_MS)))?;
+        stream.set_read_timeout(Some(Duration::from_millis(HANDSHAKE_TIMEOUT_MS)))?;
+        let _ = stream.set_nodelay(true);
+        // peers are identified by certificate name rather than host name
+        let s = self.connector.configure()
+            .map_err(setup_error)?
+            .verify_hostname(false)
+            .use_server_name_indication(false)
+            .connect("", stream)
+            .map_err(|e| TransportError::Setup(e.to_string()))?;
+        // a server address must present that server's certificate, for clients
+        // one the CA signed is enough
+        if let Some(server) = self.server {
+            if !peer_identity(&s, &self.identities).is_some_and(|ids| ids.contains(&server)) {
+                return Err(TransportError::Unauthenticated);
+            }
+        }
+        Ok(s)
+    }
+}
+
+fn connect_tcp(addrs: &[std::net::SocketAddr]) -> io::Result<TcpStream> {
+    let timeout = Duration::from_millis(HANDSHAKE_TIMEOUT_MS);
+    let mut last = Err(io::Error::new(ErrorKind::AddrNotAvailable, "no address to connect to"));
+    for addr in addrs {
+        match TcpStream::connect_timeout(addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last = Err(e),
+        }
+    }
+    last
+}
+
+impl<T> MsgSender<T> for TlsClient<T> where
+    T: serde::Serialize + serde::de::DeserializeOwned {
+
+    fn connect(addr: &Addr) -> Result<Self, Error> {
+        let ctx = get_tls_context()?;
+        Ok(TlsClient {
+            addr: addr.clone(),
+            server: ctx.config.servers.get(addr).cloned(),
+            connector: ctx.connector.clone(),
+            identities: ctx.config.identities.clone(),
+            msg_type: PhantomData,
+        })
+    }
+
+    fn send_str(&mut self, s: &[u8]) -> Result<(), Error> {
+        let pooled = TLS_POOL.with(|p| p.borrow_mut().remove(&self.addr));
+        // a pooled session may have been closed by the peer, retry once on a fresh one
+        let stream = match pooled {
+            Some(mut stream) => match write_frame(&mut stream, s) {
+                Ok(()) => Ok(stream),
+                Err(_) => self.establish().and_then(|mut stream| {
+                    write_frame(&mut stream, s).map(|_| stream).map_err(|e| e.into())
+                }),
+            },
+            None => self.establish().and_then(|mut stream| {
+                write_frame(&mut stream, s).map(|_| stream).map_err(|e| e.into())
+            }),
+        }?;
+        TLS_POOL.with(|p| p.borrow_mut().insert(self.addr.clone(), stream));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Msg = Message<String, String>;
+
+    const CONFIG: &str = r#"
+        cert_file = "node.pem"
+        key_file = "node.key"
+        ca_file = "ca.pem"
+        identities = { "node0" = [0, 10, 20], "node1" = 1 }
+    "#;
+
+    fn roles() -> HashMap<ServerID, Role> {
+        vec![(0, Role::Leader), (10, Role::Acceptor), (20, Role::Replica), (1, Role::Leader)]
+            .into_iter().collect()
+    }
+
+    fn p1a(sender: ServerID) -> Msg {
+        Message::P1a { sender, ballot: Ballot::zero(sender) }
+    }
+
+    fn p2b(sender: ServerID) -> Msg {
+        Message::P2b { sender, ballot: Ballot::zero(0), slot: 1 }
+    }
+
+    #[test]
+    fn identities_take_one_id_or_a_list() {
+        let config: TlsConfig = toml::from_str(CONFIG).unwrap();
+        assert_eq!(config.identities.get("node0"), Some(&vec![0, 10, 20]));
+        assert_eq!(config.identities.get("node1"), Some(&vec![1]));
+    }
+
+    #[test]
+    fn a_certificate_vouches_for_each_colocated_role() {
+        let roles = roles();
+        let node0: &[ServerID] = &[0, 10, 20];
+        assert!(peer_may_send(Some(node0), &roles, &p1a(0)));
+        assert!(peer_may_send(Some(node0), &roles, &p2b(10)));
+        let response: Msg = Message::Response { cid: Addr::new("127.0.0.1", 7000), result: "ok".to_string(), req_id: None };
+        assert!(peer_may_send(Some(node0), &roles, &response));
+    }
+
+    #[test]
+    fn senders_must_be_the_certificates_servers_in_their_roles() {
+        let roles = roles();
+        let node0: &[ServerID] = &[0, 10, 20];
+        let node1: &[ServerID] = &[1];
+        // another process's id
+        assert!(!peer_may_send(Some(node0), &roles, &p1a(1)));
+        // one of ours, but an acceptor sending a leader message
+        assert!(!peer_may_send(Some(node0), &roles, &p1a(10)));
+        // an unclaimed replica message from a process without a replica
+        let response: Msg = Message::Response { cid: Addr::new("127.0.0.1", 7000), result: "ok".to_string(), req_id: None };
+        assert!(!peer_may_send(Some(node1), &roles, &response));
+        assert!(peer_may_send(Some(node1), &roles, &p1a(1)));
+    }
+
+    #[test]
+    fn peers_without_a_server_certificate_only_send_client_messages() {
+        let roles = roles();
+        let request: Msg = Message::Request { cid: Addr::new("127.0.0.1", 7000), cmd: "x".to_string(), req_id: Some(1) };
+        assert!(peer_may_send(None, &roles, &request));
+        assert!(!peer_may_send(None, &roles, &p1a(0)));
+        let decision: Msg = Message::Decision { slot: 1, cmd: "x".to_string(), req: None };
+        assert!(!peer_may_send(None, &roles, &decision));
+    }
+
+    #[test]
+    fn take_frame_waits_for_a_whole_frame() {
+        let mut buf = vec![0, 0, 0, 3, b'a', b'b'];
+        assert_eq!(take_frame(&mut buf), None);
+        buf.extend_from_slice(&[b'c', 0, 0]);
+        assert_eq!(take_frame(&mut buf), Some(b"abc".to_vec()));
+        assert_eq!(buf, vec![0, 0]);
+    }
+}